@@ -126,6 +126,7 @@ mod interface_tests {
     fn pay() {
         should_parse(&[
             "ilp-cli pay alice --auth foo --amount 500 --to bar", // minimal
+            "ilp-cli pay alice --auth foo --deliver 500 --max-amount 600 --to bar", // fixed delivery
         ]);
     }
 
@@ -114,6 +114,13 @@ fn accounts_create<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("ilp_over_http_outgoing_token")
                 .long("ilp-over-http-outgoing-token")
                 .takes_value(true),
+            Arg::with_name("ilp_over_http_pool_size")
+                .long("ilp-over-http-pool-size")
+                .takes_value(true),
+            Arg::with_name("ilp_over_http_http2")
+                .long("ilp-over-http-http2")
+                .takes_value(true)
+                .possible_values(&["true", "false"]),
             Arg::with_name("ilp_over_btp_url")
                 .long("ilp-over-btp-url")
                 .takes_value(true),
@@ -135,6 +142,9 @@ fn accounts_create<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("round_trip_time")
                 .long("round-trip-time")
                 .takes_value(true),
+            Arg::with_name("routing_weight")
+                .long("routing-weight")
+                .takes_value(true),
             Arg::with_name("amount_per_minute_limit")
                 .long("amount-per-minute-limit")
                 .takes_value(true),
@@ -185,6 +195,13 @@ fn accounts_update<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("ilp_over_http_outgoing_token")
                 .long("ilp-over-http-outgoing-token")
                 .takes_value(true),
+            Arg::with_name("ilp_over_http_pool_size")
+                .long("ilp-over-http-pool-size")
+                .takes_value(true),
+            Arg::with_name("ilp_over_http_http2")
+                .long("ilp-over-http-http2")
+                .takes_value(true)
+                .possible_values(&["true", "false"]),
             Arg::with_name("ilp_over_btp_url")
                 .long("ilp-over-btp-url")
                 .takes_value(true),
@@ -206,6 +223,9 @@ fn accounts_update<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("round_trip_time")
                 .long("round-trip-time")
                 .takes_value(true),
+            Arg::with_name("routing_weight")
+                .long("routing-weight")
+                .takes_value(true),
             Arg::with_name("amount_per_minute_limit")
                 .long("amount-per-minute-limit")
                 .takes_value(true),
@@ -310,8 +330,19 @@ fn pay<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("source_amount")
                 .long("amount")
                 .takes_value(true)
-                .required(true)
+                .required_unless("destination_amount")
+                .conflicts_with("destination_amount")
                 .help("The amount to transfer from the sender to the receiver, denominated in units of the sender's assets"),
+            Arg::with_name("destination_amount")
+                .long("deliver")
+                .takes_value(true)
+                .requires("max_source_amount")
+                .help("The amount the receiver should get, denominated in units of the receiver's assets"),
+            Arg::with_name("max_source_amount")
+                .long("max-amount")
+                .takes_value(true)
+                .requires("destination_amount")
+                .help("The most the sender may spend to deliver the amount given with --deliver, denominated in units of the sender's assets"),
             Arg::with_name("receiver")
                 .long("to")
                 .takes_value(true)
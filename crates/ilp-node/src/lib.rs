@@ -1,4 +1,6 @@
 mod metrics;
 mod node;
+mod tls;
 mod trace;
 pub use node::*;
+pub use tls::TlsConfig;
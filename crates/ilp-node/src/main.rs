@@ -14,6 +14,7 @@ use tracing_subscriber::{
 
 mod metrics;
 mod node;
+mod tls;
 mod trace;
 use node::InterledgerNode;
 
@@ -70,6 +71,14 @@ pub fn main() {
             .takes_value(true)
             .default_value("redis://127.0.0.1:6379")
             .help("Redis URI (for example, \"redis://127.0.0.1:6379\" or \"unix:/tmp/redis.sock\")"),
+        Arg::with_name("in_memory")
+            .long("in_memory")
+            .help("Keep all data in memory instead of using Redis. Note that all of the node's accounts, balances, and routes are lost when the node is stopped"),
+        Arg::with_name("database_url")
+            .long("database_url")
+            .takes_value(true)
+            .help("Use an SQLite database instead of Redis (for example, \"sqlite:///var/lib/ilp-node/node.db\"). \
+                Other store backends can be selected with the `store` section of the config file"),
         Arg::with_name("http_bind_address")
             .long("http_bind_address")
             .takes_value(true)
@@ -86,6 +95,29 @@ pub fn main() {
             .long("route_broadcast_interval")
             .takes_value(true)
             .help("Interval, defined in milliseconds, on which the node will broadcast routing information to other nodes using CCP. Defaults to 30000ms (30 seconds)."),
+        Arg::with_name("route_load_splitting")
+            .long("route_load_splitting")
+            .help("Split the packets for a prefix between the equally good routes to it, in proportion to the routing_weight of their accounts. By default, all of the packets for a prefix are sent to the same account."),
+        Arg::with_name("route_failover_min_expiry")
+            .long("route_failover_min_expiry")
+            .takes_value(true)
+            .help("Time, in milliseconds, that must be left before a packet expires for the node to retry it on the next best route if the account it was sent to is unreachable. Defaults to 1000ms (1 second)."),
+        Arg::with_name("route_update_max_routes")
+            .long("route_update_max_routes")
+            .takes_value(true)
+            .help("The maximum number of routes the node sends to a peer in one CCP route update. Larger updates are split into several messages. Defaults to 1000."),
+        Arg::with_name("btp_ping_interval")
+            .long("btp_ping_interval")
+            .takes_value(true)
+            .help("Interval, defined in milliseconds, on which the node will Ping each of its BTP connections to check that they are still alive. Must be greater than 0. Defaults to 30000ms (30 seconds)."),
+        Arg::with_name("btp_pong_timeout")
+            .long("btp_pong_timeout")
+            .takes_value(true)
+            .help("Time, in milliseconds, that a BTP connection has to respond to a Ping before the node closes it. Must be greater than 0. Defaults to 10000ms (10 seconds)."),
+        Arg::with_name("btp_max_message_size")
+            .long("btp_max_message_size")
+            .takes_value(true)
+            .help("Largest BTP message, in bytes, that the node will accept. Connections that send larger messages are closed. Defaults to 40000 bytes."),
         Arg::with_name("exchange_rate_provider")
             .long("exchange_rate_provider")
             .takes_value(true)
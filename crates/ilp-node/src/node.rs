@@ -10,34 +10,53 @@ pub use interledger::service_util::ExchangeRateProvider;
 use std::sync::Arc;
 
 use crate::metrics::{incoming_metrics, outgoing_metrics};
+use crate::tls::{serve_tls, TlsConfig, TlsReloader};
 use crate::trace::{trace_forwarding, trace_incoming, trace_outgoing};
 use interledger::{
     api::{NodeApi, NodeStore},
-    btp::{connect_client, create_btp_service_and_filter, BtpStore},
-    ccp::CcpRouteManagerBuilder,
-    http::{error::*, HttpClientService, HttpServer as IlpOverHttpServer},
+    btp::{
+        connect_client, create_btp_service_and_filter, BtpAccount, BtpKeepalive, BtpStore,
+        DEFAULT_MAX_MESSAGE_SIZE,
+    },
+    ccp::{
+        CcpRouteManagerBuilder, CcpRoutingAccount, RouteAuthAnchor, RouteManagerStore, RoutePolicy,
+        DEFAULT_MAX_ROUTES_PER_UPDATE,
+    },
+    http::{
+        error::*, idempotency::IdempotentStore, HttpAccount, HttpClientService,
+        HttpServer as IlpOverHttpServer, HttpStore,
+    },
     ildcp::IldcpService,
     packet::Address,
     packet::{ErrorCode, RejectBuilder},
-    router::Router,
+    router::{Router, RouterStore, DEFAULT_MIN_FAILOVER_EXPIRY},
     service::{
-        outgoing_service_fn, Account as AccountTrait, IncomingService, OutgoingRequest,
-        OutgoingService, Username,
+        outgoing_service_fn, Account as AccountTrait, AddressStore, IncomingService,
+        OutgoingRequest, OutgoingService, Username,
     },
     service_util::{
-        BalanceService, EchoService, ExchangeRateFetcher, ExchangeRateService,
-        ExpiryShortenerService, MaxPacketAmountService, RateLimitService, ValidatorService,
+        BalanceService, BalanceStore, EchoService, ExchangeRateFetcher, ExchangeRateService,
+        ExchangeRateStore, ExpiryShortenerService, MaxPacketAmountAccount, MaxPacketAmountService,
+        RateLimitAccount, RateLimitService, RateLimitStore, RoundTripTimeAccount, ValidatorService,
+    },
+    settlement::{
+        create_settlements_filter, LeftoversStore, SettlementAccount, SettlementMessageService,
+        SettlementStore,
     },
-    settlement::{create_settlements_filter, SettlementMessageService},
-    store_redis::{Account, AccountId, ConnectionInfo, IntoConnectionInfo, RedisStoreBuilder},
-    stream::StreamReceiverService,
+    store_memory::MemoryStoreBuilder,
+    store_redis::{AccountId, ConnectionInfo, IntoConnectionInfo, RedisStoreBuilder},
+    store_sql::SqlStoreBuilder,
+    stream::{StreamNotificationsStore, StreamReceiverService},
 };
 use lazy_static::lazy_static;
 use metrics_core::{Builder, Drain, Observe};
 use metrics_runtime;
+use num_bigint::BigUint;
 use ring::hmac;
-use serde::{de::Error as DeserializeError, Deserialize, Deserializer};
-use std::{convert::TryFrom, net::SocketAddr, str, str::FromStr, time::Duration};
+use serde::{de::Error as DeserializeError, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::HashMap, convert::TryFrom, net::SocketAddr, str, str::FromStr, time::Duration,
+};
 use tokio::spawn;
 use tracing::{debug, debug_span, error, info};
 use tracing_futures::Instrument;
@@ -49,6 +68,8 @@ use warp::{
 };
 
 static REDIS_SECRET_GENERATION_STRING: &str = "ilp_redis_secret";
+static SQL_SECRET_GENERATION_STRING: &str = "ilp_sql_secret";
+static CCP_SECRET_GENERATION_STRING: &str = "ilp_ccp_routing_secret";
 static DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
 lazy_static! {
     static ref DEFAULT_ILP_ADDRESS: Address = Address::from_str("local.host").unwrap();
@@ -66,6 +87,47 @@ fn default_redis_url() -> ConnectionInfo {
 fn default_exchange_rate_poll_interval() -> u64 {
     60_000
 }
+fn default_btp_ping_interval() -> u64 {
+    30_000
+}
+fn default_btp_pong_timeout() -> u64 {
+    10_000
+}
+fn deserialize_nonzero_millis<'de, D>(deserializer: D, name: &str) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let millis = u64::deserialize(deserializer)?;
+    if millis == 0 {
+        Err(DeserializeError::custom(format!(
+            "{} must be greater than 0",
+            name
+        )))
+    } else {
+        Ok(millis)
+    }
+}
+fn deserialize_btp_ping_interval<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_nonzero_millis(deserializer, "btp_ping_interval")
+}
+fn deserialize_btp_pong_timeout<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_nonzero_millis(deserializer, "btp_pong_timeout")
+}
+fn default_btp_max_message_size() -> usize {
+    DEFAULT_MAX_MESSAGE_SIZE
+}
+fn default_route_failover_min_expiry() -> u64 {
+    DEFAULT_MIN_FAILOVER_EXPIRY
+}
+fn default_route_update_max_routes() -> usize {
+    DEFAULT_MAX_ROUTES_PER_UPDATE
+}
 fn default_exchange_rate_poll_failure_tolerance() -> u32 {
     5
 }
@@ -112,15 +174,97 @@ fn deserialize_redis_connection<'de, D>(deserializer: D) -> Result<ConnectionInf
 where
     D: Deserializer<'de>,
 {
-    Url::parse(&String::deserialize(deserializer)?)
-        .map_err(|err| DeserializeError::custom(format!("Invalid URL: {:?}", err)))?
+    parse_redis_connection(&String::deserialize(deserializer)?).map_err(DeserializeError::custom)
+}
+
+fn parse_redis_connection(url: &str) -> Result<ConnectionInfo, String> {
+    Url::parse(url)
+        .map_err(|err| format!("Invalid URL: {:?}", err))?
         .into_connection_info()
-        .map_err(|err| {
-            DeserializeError::custom(format!(
-                "Error converting into Redis connection info: {:?}",
-                err
-            ))
+        .map_err(|err| format!("Error converting into Redis connection info: {:?}", err))
+}
+
+/// The options that select the store. The `store` section takes precedence over
+/// the `in_memory`, `database_url`, and `redis_connection` shorthands.
+#[derive(Deserialize)]
+struct StoreOptions {
+    store: Option<StoreConfig>,
+    in_memory: Option<Flag>,
+    database_url: Option<String>,
+    #[serde(alias = "redis_url")]
+    redis_connection: Option<String>,
+}
+
+/// Flags set with environment variables are strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Flag {
+    Bool(bool),
+    String(String),
+}
+
+impl Flag {
+    fn is_set(&self) -> bool {
+        match self {
+            Flag::Bool(value) => *value,
+            Flag::String(value) => value == "true",
+        }
+    }
+}
+
+fn deserialize_store_config<'de, D>(deserializer: D) -> Result<StoreConfig, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let options = StoreOptions::deserialize(deserializer)?;
+    if let Some(store) = options.store {
+        Ok(store)
+    } else if options.in_memory.map(|flag| flag.is_set()).unwrap_or(false) {
+        Ok(StoreConfig::Memory)
+    } else if let Some(database_url) = options.database_url {
+        Ok(StoreConfig::Sql { database_url })
+    } else if let Some(redis_connection) = options.redis_connection {
+        Ok(StoreConfig::Redis {
+            redis_connection: parse_redis_connection(&redis_connection)
+                .map_err(DeserializeError::custom)?,
         })
+    } else {
+        Ok(StoreConfig::default())
+    }
+}
+
+/// Configuration for the database the node uses to store its accounts, balances, routes, etc.
+/// The `backend` field selects which store implementation is used.
+#[derive(Deserialize, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StoreConfig {
+    /// Use [Redis](https://redis.io) for persistence
+    Redis {
+        /// Redis URI (for example, "redis://127.0.0.1:6379" or "unix:/tmp/redis.sock")
+        #[serde(
+            deserialize_with = "deserialize_redis_connection",
+            default = "default_redis_url",
+            alias = "redis_url"
+        )]
+        redis_connection: ConnectionInfo,
+    },
+    /// Keep all data in memory. Note that all of the node's accounts, balances,
+    /// and routes are lost when the node is stopped.
+    Memory,
+    /// Use an SQL database for persistence
+    Sql {
+        /// Database URL (for example, "sqlite:///var/lib/ilp-node/node.db").
+        /// PostgreSQL support is experimental and is not included in the node.
+        database_url: String,
+    },
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig::Redis {
+            redis_connection: default_redis_url(),
+        }
+    }
 }
 
 /// Configuration for [Prometheus](https://prometheus.io) metrics collection.
@@ -150,7 +294,8 @@ impl PrometheusConfig {
 }
 
 /// An all-in-one Interledger node that includes sender and receiver functionality,
-/// a connector, and a management API. The node uses Redis for persistence.
+/// a connector, and a management API. The store used for persistence is selected
+/// with the `store` configuration (Redis is used by default).
 #[derive(Deserialize, Clone)]
 pub struct InterledgerNode {
     /// ILP address of the node
@@ -162,13 +307,11 @@ pub struct InterledgerNode {
     pub secret_seed: [u8; 32],
     /// HTTP Authorization token for the node admin (sent as a Bearer token)
     pub admin_auth_token: String,
-    /// Redis URI (for example, "redis://127.0.0.1:6379" or "unix:/tmp/redis.sock")
-    #[serde(
-        deserialize_with = "deserialize_redis_connection",
-        default = "default_redis_url",
-        alias = "redis_url"
-    )]
-    pub redis_connection: ConnectionInfo,
+    /// Store backend and its connection details (Redis at "redis://127.0.0.1:6379" by default).
+    /// It is configured with a `store` section, or with one of the `redis_connection`
+    /// (or `redis_url`), `database_url`, and `in_memory` options.
+    #[serde(flatten, deserialize_with = "deserialize_store_config")]
+    pub store: StoreConfig,
     /// IP address and port to listen for HTTP connections
     /// This is used for both the API and ILP over HTTP packets
     #[serde(default = "default_http_bind_address")]
@@ -176,6 +319,14 @@ pub struct InterledgerNode {
     /// IP address and port to listen for the Settlement Engine API
     #[serde(default = "default_settlement_api_bind_address")]
     pub settlement_api_bind_address: SocketAddr,
+    /// Serve the HTTP API over TLS, optionally authenticating peers with client certificates.
+    /// If this configuration is not provided, the node serves the HTTP API without TLS.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Serve the Settlement Engine API over TLS.
+    /// If this configuration is not provided, the node serves it without TLS.
+    #[serde(default)]
+    pub settlement_api_tls: Option<TlsConfig>,
     /// When SPSP payments are sent to the root domain, the payment pointer is resolved
     /// to <domain>/.well-known/pay. This value determines which account those payments
     /// will be sent to.
@@ -184,6 +335,54 @@ pub struct InterledgerNode {
     /// Interval, defined in milliseconds, on which the node will broadcast routing
     /// information to other nodes using CCP. Defaults to 30000ms (30 seconds).
     pub route_broadcast_interval: Option<u64>,
+    /// Split the packets for a prefix between the equally good routes to it, in proportion
+    /// to the `routing_weight` of their accounts. By default, all of the packets for a
+    /// prefix are sent to the same account.
+    #[serde(default)]
+    pub route_load_splitting: bool,
+    /// Time, in milliseconds, that must be left before a packet expires for the node to
+    /// retry it on the next best route if the account it was sent to is unreachable.
+    /// Defaults to 1000ms (1 second).
+    #[serde(default = "default_route_failover_min_expiry")]
+    pub route_failover_min_expiry: u64,
+    /// The maximum number of routes the node sends to a peer in one CCP route update.
+    /// Larger updates are split into several messages. Defaults to 1000.
+    #[serde(default = "default_route_update_max_routes")]
+    pub route_update_max_routes: usize,
+    /// The rules for the routes exchanged via CCP with the accounts that do not have their
+    /// own `route_policy`: which prefixes to accept from them and advertise to them, the
+    /// maximum path length of the routes to accept and the local preference of their routes.
+    /// By default, all valid routes are exchanged.
+    #[serde(default)]
+    pub default_route_policy: RoutePolicy,
+    /// The anchors that the routes for these prefixes must be authenticated with.
+    /// The node only accepts routes for these prefixes if the route auth hashes to the
+    /// anchor, so that peers cannot advertise routes they were never sent. Routes for the
+    /// prefixes that start with them are only accepted if they have their own anchor, so the
+    /// anchors of child nodes that advertise their own routes must be configured as well.
+    /// Each node logs the anchor for its own address when it starts.
+    /// Routes for the other prefixes are accepted without authentication.
+    #[serde(default)]
+    pub route_auth_anchors: HashMap<String, RouteAuthAnchor>,
+    /// Interval, defined in milliseconds, on which the node will Ping each of its BTP
+    /// connections to check that they are still alive. Must be greater than 0.
+    /// Defaults to 30000ms (30 seconds).
+    #[serde(
+        default = "default_btp_ping_interval",
+        deserialize_with = "deserialize_btp_ping_interval"
+    )]
+    pub btp_ping_interval: u64,
+    /// Time, in milliseconds, that a BTP connection has to respond to a Ping
+    /// before the node closes it. Must be greater than 0. Defaults to 10000ms (10 seconds).
+    #[serde(
+        default = "default_btp_pong_timeout",
+        deserialize_with = "deserialize_btp_pong_timeout"
+    )]
+    pub btp_pong_timeout: u64,
+    /// Largest BTP message, in bytes, that the node will accept. Connections that send
+    /// larger messages are closed. Defaults to 40000 bytes.
+    #[serde(default = "default_btp_max_message_size")]
+    pub btp_max_message_size: usize,
     /// Interval, defined in milliseconds, on which the node will poll the exchange rate provider.
     /// Defaults to 60000ms (60 seconds).
     #[serde(default = "default_exchange_rate_poll_interval")]
@@ -221,23 +420,107 @@ impl InterledgerNode {
     // TODO when a BTP connection is made, insert a outgoing HTTP entry into the Store to tell other
     // connector instances to forward packets for that account to us
     pub fn serve(&self) -> impl Future<Item = (), Error = ()> {
-        if self.prometheus.is_some() {
-            Either::A(
-                self.serve_prometheus()
-                    .join(self.serve_node())
-                    .and_then(|_| Ok(())),
-            )
+        let ilp_address = if let Some(address) = &self.ilp_address {
+            address.clone()
         } else {
-            Either::B(self.serve_node())
+            DEFAULT_ILP_ADDRESS.clone()
+        };
+        let node = self.clone();
+
+        match self.store.clone() {
+            StoreConfig::Memory => {
+                debug!(target: "interledger-node", "Using the in-memory store");
+                let store = MemoryStoreBuilder::new()
+                    .node_ilp_address(ilp_address)
+                    .build();
+                Either::A(self.serve_with_store(store))
+            }
+            StoreConfig::Redis { redis_connection } => {
+                let redis_secret =
+                    generate_store_secret(&self.secret_seed, REDIS_SECRET_GENERATION_STRING);
+                let redis_addr = redis_connection.addr.clone();
+                Either::B(Either::A(
+                    RedisStoreBuilder::new(redis_connection, redis_secret)
+                        .node_ilp_address(ilp_address)
+                        .connect()
+                        .map_err(move |err| error!(target: "interledger-node", "Error connecting to Redis: {:?} {:?}", redis_addr, err))
+                        .and_then(move |store| node.serve_with_store(store)),
+                ))
+            }
+            StoreConfig::Sql { database_url } => {
+                let sql_secret =
+                    generate_store_secret(&self.secret_seed, SQL_SECRET_GENERATION_STRING);
+                Either::B(Either::B(
+                    SqlStoreBuilder::new(database_url.clone(), sql_secret)
+                        .node_ilp_address(ilp_address)
+                        .connect()
+                        .map_err(move |_| error!(target: "interledger-node", "Error connecting to the database: {}", database_url))
+                        .and_then(move |store| node.serve_with_store(store)),
+                ))
+            }
         }
     }
 
-    #[allow(clippy::cognitive_complexity)]
-    fn serve_node(&self) -> impl Future<Item = (), Error = ()> {
-        let redis_secret = generate_redis_secret(&self.secret_seed);
+    /// Returns a future that runs the Interledger.rs Node on top of the given store.
+    ///
+    /// This can be used to run the node with a store implementation other than
+    /// the ones that can be selected with the `store` configuration.
+    /// If the Prometheus configuration was provided, it will
+    /// also run the Prometheus metrics server on the given address.
+    pub fn serve_with_store<S, A>(&self, store: S) -> Box<dyn Future<Item = (), Error = ()> + Send>
+    where
+        S: NodeStore<Account = A>
+            + AddressStore
+            + BtpStore<Account = A>
+            + HttpStore<Account = A>
+            + BalanceStore<Account = A>
+            + RateLimitStore<Account = A>
+            + ExchangeRateStore
+            + RouterStore
+            + RouteManagerStore<Account = A>
+            + SettlementStore<Account = A>
+            + LeftoversStore<AccountId = <A as AccountTrait>::AccountId, AssetType = BigUint>
+            + IdempotentStore
+            + StreamNotificationsStore<Account = A>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        A: AccountTrait
+            + BtpAccount
+            + CcpRoutingAccount
+            + HttpAccount
+            + MaxPacketAmountAccount
+            + RateLimitAccount
+            + RoundTripTimeAccount
+            + SettlementAccount
+            + Serialize
+            + Send
+            + Sync
+            + 'static,
+    {
         let secret_seed = Bytes::from(&self.secret_seed[..]);
         let http_bind_address = self.http_bind_address;
         let settlement_api_bind_address = self.settlement_api_bind_address;
+        let http_tls = match self.tls.clone().map(TlsReloader::new).transpose() {
+            Ok(tls) => tls,
+            Err(message) => {
+                error!(target: "interledger-node", "Error loading TLS configuration: {}", message);
+                return Box::new(err(()));
+            }
+        };
+        let settlement_api_tls = match self
+            .settlement_api_tls
+            .clone()
+            .map(TlsReloader::new)
+            .transpose()
+        {
+            Ok(tls) => tls,
+            Err(message) => {
+                error!(target: "interledger-node", "Error loading Settlement API TLS configuration: {}", message);
+                return Box::new(err(()));
+            }
+        };
         let ilp_address = if let Some(address) = &self.ilp_address {
             address.clone()
         } else {
@@ -247,8 +530,22 @@ impl InterledgerNode {
         let ilp_address_clone2 = ilp_address.clone();
         let admin_auth_token = self.admin_auth_token.clone();
         let default_spsp_account = self.default_spsp_account.clone();
-        let redis_addr = self.redis_connection.addr.clone();
         let route_broadcast_interval = self.route_broadcast_interval;
+        let route_load_splitting = self.route_load_splitting;
+        let route_failover_min_expiry = self.route_failover_min_expiry;
+        let route_update_max_routes = self.route_update_max_routes;
+        let default_route_policy = self.default_route_policy.clone();
+        let routing_secret = generate_store_secret(&self.secret_seed, CCP_SECRET_GENERATION_STRING);
+        let route_auth_anchors: HashMap<Bytes, RouteAuthAnchor> = self
+            .route_auth_anchors
+            .iter()
+            .map(|(prefix, anchor)| (Bytes::from(prefix.as_str()), *anchor))
+            .collect();
+        let btp_keepalive = BtpKeepalive {
+            ping_interval: Duration::from_millis(self.btp_ping_interval),
+            pong_timeout: Duration::from_millis(self.btp_pong_timeout),
+        };
+        let btp_max_message_size = self.btp_max_message_size;
         let exchange_rate_provider = self.exchange_rate_provider.clone();
         let exchange_rate_poll_interval = self.exchange_rate_poll_interval;
         let exchange_rate_poll_failure_tolerance = self.exchange_rate_poll_failure_tolerance;
@@ -259,184 +556,206 @@ impl InterledgerNode {
             ilp_address
         );
 
-        Box::new(RedisStoreBuilder::new(self.redis_connection.clone(), redis_secret)
-        .node_ilp_address(ilp_address.clone())
-        .connect()
-        .map_err(move |err| error!(target: "interledger-node", "Error connecting to Redis: {:?} {:?}", redis_addr, err))
-        .and_then(move |store| {
-                store.clone().get_btp_outgoing_accounts()
-                .map_err(|_| error!(target: "interledger-node", "Error getting accounts"))
-                .and_then(move |btp_accounts| {
+        let node_future = store.clone().get_btp_outgoing_accounts()
+        .map_err(|_| error!(target: "interledger-node", "Error getting accounts"))
+        .and_then(move |btp_accounts| {
+            let outgoing_service =
+                outgoing_service_fn(move |request: OutgoingRequest<A>| {
+                    error!(target: "interledger-node", "No route found for outgoing account ");
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &format!(
+                            "No outgoing route for account: {} (ILP address of the Prepare packet: {:?})",
+                            request.to.id(),
+                            request.prepare.destination(),
+                        )
+                        .as_bytes(),
+                        triggered_by: Some(&ilp_address_clone),
+                        data: &[],
+                    }
+                    .build())
+                });
+
+            // Connect to all of the accounts that have outgoing ilp_over_btp_urls configured
+            // but don't fail if we are unable to connect (the connections will be retried in the background)
+            connect_client(ilp_address_clone2.clone(), btp_accounts, false, btp_keepalive, btp_max_message_size, outgoing_service).and_then(
+                move |btp_client_service| {
+                    let (btp_server_service, btp_filter) = create_btp_service_and_filter(ilp_address_clone2, store.clone(), btp_keepalive, btp_max_message_size, btp_client_service.clone());
+                    let btp = btp_client_service.clone();
+
+                    // The BTP service is both an Incoming and Outgoing one so we pass it first as the Outgoing
+                    // service to others like the router and then call handle_incoming on it to set up the incoming handler
+                    let outgoing_service = btp_server_service.clone();
+                    let outgoing_service = HttpClientService::new(
+                        store.clone(),
+                        outgoing_service,
+                    );
+
+                    let outgoing_service = outgoing_service.wrap(outgoing_metrics);
+
+                    // Note: the expiry shortener must come after the Validator so that the expiry duration
+                    // is shortened before we check whether there is enough time left
+                    let outgoing_service = ValidatorService::outgoing(
+                        store.clone(),
+                        outgoing_service
+                    );
                     let outgoing_service =
-                        outgoing_service_fn(move |request: OutgoingRequest<Account>| {
-                            error!(target: "interledger-node", "No route found for outgoing account ");
-                            Err(RejectBuilder {
-                                code: ErrorCode::F02_UNREACHABLE,
-                                message: &format!(
-                                    "No outgoing route for account: {} (ILP address of the Prepare packet: {:?})",
-                                    request.to.id(),
-                                    request.prepare.destination(),
-                                )
-                                .as_bytes(),
-                                triggered_by: Some(&ilp_address_clone),
-                                data: &[],
-                            }
-                            .build())
-                        });
-
-                    // Connect to all of the accounts that have outgoing ilp_over_btp_urls configured
-                    // but don't fail if we are unable to connect
-                    // TODO try reconnecting to those accounts later
-                    connect_client(ilp_address_clone2.clone(), btp_accounts, false, outgoing_service).and_then(
-                        move |btp_client_service| {
-                            let (btp_server_service, btp_filter) = create_btp_service_and_filter(ilp_address_clone2, store.clone(), btp_client_service.clone());
-                            let btp = btp_client_service.clone();
-
-                            // The BTP service is both an Incoming and Outgoing one so we pass it first as the Outgoing
-                            // service to others like the router and then call handle_incoming on it to set up the incoming handler
-                            let outgoing_service = btp_server_service.clone();
-                            let outgoing_service = HttpClientService::new(
-                                store.clone(),
-                                outgoing_service,
-                            );
-
-                            let outgoing_service = outgoing_service.wrap(outgoing_metrics);
-
-                            // Note: the expiry shortener must come after the Validator so that the expiry duration
-                            // is shortened before we check whether there is enough time left
-                            let outgoing_service = ValidatorService::outgoing(
-                                store.clone(),
-                                outgoing_service
-                            );
-                            let outgoing_service =
-                                ExpiryShortenerService::new(outgoing_service);
-                            let outgoing_service = StreamReceiverService::new(
-                                secret_seed.clone(),
-                                store.clone(),
-                                outgoing_service,
-                            );
-                            let outgoing_service = BalanceService::new(
-                                store.clone(),
-                                outgoing_service,
-                            );
-                            let outgoing_service = ExchangeRateService::new(
-                                exchange_rate_spread,
-                                store.clone(),
-                                outgoing_service,
-                            );
-
-                            // Set up the Router and Routing Manager
-                            let incoming_service = Router::new(
-                                store.clone(),
-                                // Add tracing to add the outgoing request details to the incoming span
-                                outgoing_service.clone().wrap(trace_forwarding),
-                            );
-
-                            // Add tracing to track the outgoing request details
-                            let outgoing_service = outgoing_service.wrap(trace_outgoing).in_current_span();
-
-                            let mut ccp_builder = CcpRouteManagerBuilder::new(
-                                ilp_address.clone(),
-                                store.clone(),
-                                outgoing_service.clone(),
-                                incoming_service,
-                            );
-                            ccp_builder.ilp_address(ilp_address.clone());
-                            if let Some(ms) = route_broadcast_interval {
-                                ccp_builder.broadcast_interval(ms);
-                            }
-                            let incoming_service = ccp_builder.to_service();
-                            let incoming_service = EchoService::new(store.clone(), incoming_service);
-                            let incoming_service = SettlementMessageService::new(incoming_service);
-                            let incoming_service = IldcpService::new(incoming_service);
-                            let incoming_service =
-                                MaxPacketAmountService::new(
-                                    store.clone(),
-                                    incoming_service
-                            );
-                            let incoming_service =
-                                ValidatorService::incoming(store.clone(), incoming_service);
-                            let incoming_service = RateLimitService::new(
-                                store.clone(),
-                                incoming_service,
-                            );
-
-                            // Add tracing to track the incoming request details
-                            let incoming_service = incoming_service.wrap(trace_incoming).in_current_span();
-
-                            let incoming_service = incoming_service.wrap(incoming_metrics);
-
-                            // Handle incoming packets sent via BTP
-                            btp_server_service.handle_incoming(incoming_service.clone().wrap(|request, mut next| {
-                                let btp = debug_span!(target: "interledger-node", "btp");
-                                let _btp_scope = btp.enter();
-                                next.handle_request(request).in_current_span()
-                            }).in_current_span());
-                            btp_client_service.handle_incoming(incoming_service.clone().wrap(|request, mut next| {
-                                let btp = debug_span!(target: "interledger-node", "btp");
-                                let _btp_scope = btp.enter();
-                                next.handle_request(request).in_current_span()
-                            }).in_current_span());
-
-                            // Node HTTP API
-                            let mut api = NodeApi::new(
-                                secret_seed,
-                                admin_auth_token,
-                                store.clone(),
-                                incoming_service.clone().wrap(|request, mut next| {
-                                    let api = debug_span!(target: "interledger-node", "api");
-                                    let _api_scope = api.enter();
-                                    next.handle_request(request).in_current_span()
-                                }).in_current_span(),
-                                outgoing_service.clone(),
-                                btp.clone(),
-                            );
-                            if let Some(username) = default_spsp_account {
-                                api.default_spsp_account(username);
-                            }
-                            // add an API of ILP over HTTP and add rejection handler
-                            let api = api.into_warp_filter()
-                                .or(IlpOverHttpServer::new(incoming_service.clone().wrap(|request, mut next| {
-                                    let http = debug_span!(target: "interledger-node", "http");
-                                    let _http_scope = http.enter();
-                                    next.handle_request(request).in_current_span()
-                                }).in_current_span(), store.clone()).as_filter())
-                                .recover(default_rejection_handler);
-
-                            // Mount the BTP endpoint at /ilp/btp
-                            let btp_endpoint = warp::path("ilp")
-                                .and(warp::path("btp"))
-                                .and(warp::path::end())
-                                .and(btp_filter);
-                            // Note that other endpoints added to the API must come first
-                            // because the API includes error handling and consumes the request.
-                            // TODO should we just make BTP part of the API?
-                            let api = btp_endpoint.or(api).with(warp::log("interledger-api")).boxed();
-                            info!(target: "interledger-node", "Interledger.rs node HTTP API listening on: {}", http_bind_address);
-                            spawn(warp::serve(api).bind(http_bind_address));
-
-                            // Settlement API
-                            let settlement_api = create_settlements_filter(
-                                store.clone(),
-                                outgoing_service.clone(),
-                            );
-                            info!(target: "interledger-node", "Settlement API listening on: {}", settlement_api_bind_address);
-                            spawn(warp::serve(settlement_api).bind(settlement_api_bind_address));
-
-                            // Exchange Rate Polling
-                            if let Some(provider) = exchange_rate_provider {
-                                let exchange_rate_fetcher = ExchangeRateFetcher::new(provider, exchange_rate_poll_failure_tolerance, store.clone());
-                                exchange_rate_fetcher.spawn_interval(Duration::from_millis(exchange_rate_poll_interval));
-                            } else {
-                                debug!(target: "interledger-node", "Not using exchange rate provider. Rates must be set via the HTTP API");
-                            }
-
-                            Ok(())
-                        },
-                    )
-                })
+                        ExpiryShortenerService::new(outgoing_service);
+                    let outgoing_service = StreamReceiverService::new(
+                        secret_seed.clone(),
+                        store.clone(),
+                        outgoing_service,
+                    );
+                    let outgoing_service = BalanceService::new(
+                        store.clone(),
+                        outgoing_service,
+                    );
+                    let outgoing_service = ExchangeRateService::new(
+                        exchange_rate_spread,
+                        store.clone(),
+                        outgoing_service,
+                    );
+
+                    // Set up the Router and Routing Manager
+                    let mut router = Router::new(
+                        store.clone(),
+                        // Add tracing to add the outgoing request details to the incoming span
+                        outgoing_service.clone().wrap(trace_forwarding),
+                    );
+                    router
+                        .load_splitting(route_load_splitting)
+                        .min_failover_expiry(route_failover_min_expiry);
+                    let incoming_service = router;
+
+                    // Add tracing to track the outgoing request details
+                    let outgoing_service = outgoing_service.wrap(trace_outgoing).in_current_span();
+
+                    let mut ccp_builder = CcpRouteManagerBuilder::new(
+                        ilp_address.clone(),
+                        store.clone(),
+                        outgoing_service.clone(),
+                        incoming_service,
+                    );
+                    ccp_builder
+                        .ilp_address(ilp_address.clone())
+                        .default_route_policy(default_route_policy)
+                        .routing_secret(routing_secret)
+                        .route_auth_anchors(route_auth_anchors)
+                        .max_routes_per_update(route_update_max_routes);
+                    if let Some(ms) = route_broadcast_interval {
+                        ccp_builder.broadcast_interval(ms);
+                    }
+                    let incoming_service = ccp_builder.to_service();
+                    let incoming_service = EchoService::new(store.clone(), incoming_service);
+                    let incoming_service = SettlementMessageService::new(incoming_service);
+                    let incoming_service = IldcpService::new(incoming_service);
+                    let incoming_service =
+                        MaxPacketAmountService::new(
+                            store.clone(),
+                            incoming_service
+                    );
+                    let incoming_service =
+                        ValidatorService::incoming(store.clone(), incoming_service);
+                    let incoming_service = RateLimitService::new(
+                        store.clone(),
+                        incoming_service,
+                    );
+
+                    // Add tracing to track the incoming request details
+                    let incoming_service = incoming_service.wrap(trace_incoming).in_current_span();
+
+                    let incoming_service = incoming_service.wrap(incoming_metrics);
+
+                    // Handle incoming packets sent via BTP
+                    btp_server_service.handle_incoming(incoming_service.clone().wrap(|request, mut next| {
+                        let btp = debug_span!(target: "interledger-node", "btp");
+                        let _btp_scope = btp.enter();
+                        next.handle_request(request).in_current_span()
+                    }).in_current_span());
+                    btp_client_service.handle_incoming(incoming_service.clone().wrap(|request, mut next| {
+                        let btp = debug_span!(target: "interledger-node", "btp");
+                        let _btp_scope = btp.enter();
+                        next.handle_request(request).in_current_span()
+                    }).in_current_span());
+
+                    // Node HTTP API
+                    let mut api = NodeApi::new(
+                        secret_seed,
+                        admin_auth_token,
+                        store.clone(),
+                        incoming_service.clone().wrap(|request, mut next| {
+                            let api = debug_span!(target: "interledger-node", "api");
+                            let _api_scope = api.enter();
+                            next.handle_request(request).in_current_span()
+                        }).in_current_span(),
+                        outgoing_service.clone(),
+                        btp.clone(),
+                    );
+                    if let Some(username) = default_spsp_account {
+                        api.default_spsp_account(username);
+                    }
+                    // add an API of ILP over HTTP and add rejection handler
+                    let api = api.into_warp_filter()
+                        .or(IlpOverHttpServer::new(incoming_service.clone().wrap(|request, mut next| {
+                            let http = debug_span!(target: "interledger-node", "http");
+                            let _http_scope = http.enter();
+                            next.handle_request(request).in_current_span()
+                        }).in_current_span(), store.clone()).as_filter())
+                        .recover(default_rejection_handler);
+
+                    // Mount the BTP endpoint at /ilp/btp
+                    let btp_endpoint = warp::path("ilp")
+                        .and(warp::path("btp"))
+                        .and(warp::path::end())
+                        .and(btp_filter);
+                    // Note that other endpoints added to the API must come first
+                    // because the API includes error handling and consumes the request.
+                    // TODO should we just make BTP part of the API?
+                    let api = btp_endpoint.or(api).with(warp::log("interledger-api")).boxed();
+                    if let Some(tls) = http_tls {
+                        info!(target: "interledger-node", "Interledger.rs node HTTP API listening with TLS on: {}", http_bind_address);
+                        spawn(serve_tls(api, http_bind_address, tls));
+                    } else {
+                        info!(target: "interledger-node", "Interledger.rs node HTTP API listening on: {}", http_bind_address);
+                        spawn(warp::serve(api).bind(http_bind_address));
+                    }
+
+                    // Settlement API
+                    let settlement_api = create_settlements_filter(
+                        store.clone(),
+                        outgoing_service.clone(),
+                    );
+                    if let Some(tls) = settlement_api_tls {
+                        info!(target: "interledger-node", "Settlement API listening with TLS on: {}", settlement_api_bind_address);
+                        spawn(serve_tls(settlement_api, settlement_api_bind_address, tls));
+                    } else {
+                        info!(target: "interledger-node", "Settlement API listening on: {}", settlement_api_bind_address);
+                        spawn(warp::serve(settlement_api).bind(settlement_api_bind_address));
+                    }
+
+                    // Exchange Rate Polling
+                    if let Some(provider) = exchange_rate_provider {
+                        let exchange_rate_fetcher = ExchangeRateFetcher::new(provider, exchange_rate_poll_failure_tolerance, store.clone());
+                        exchange_rate_fetcher.spawn_interval(Duration::from_millis(exchange_rate_poll_interval));
+                    } else {
+                        debug!(target: "interledger-node", "Not using exchange rate provider. Rates must be set via the HTTP API");
+                    }
+
+                    Ok(())
+                },
+            )
         })
-        .in_current_span())
+        .in_current_span();
+
+        if self.prometheus.is_some() {
+            Box::new(
+                self.serve_prometheus()
+                    .join(node_future)
+                    .and_then(|_| Ok(())),
+            )
+        } else {
+            Box::new(node_future)
+        }
     }
 
     /// Starts a Prometheus metrics server that will listen on the configured address.
@@ -506,8 +825,21 @@ impl InterledgerNode {
         &self,
         account: AccountDetails,
     ) -> impl Future<Item = AccountId, Error = ()> {
-        let redis_secret = generate_redis_secret(&self.secret_seed);
-        result(self.redis_connection.clone().into_connection_info())
+        let redis_connection = match self.store.clone() {
+            StoreConfig::Redis { redis_connection } => redis_connection,
+            // The in-memory store only lives inside of the running node,
+            // so accounts can only be inserted into it via the HTTP API
+            StoreConfig::Memory => {
+                error!(target: "interledger-node", "Cannot insert accounts into an in-memory store that is not running");
+                return Either::A(err(()));
+            }
+            StoreConfig::Sql { .. } => {
+                error!(target: "interledger-node", "Accounts can only be inserted into the SQL store via the HTTP API");
+                return Either::A(err(()));
+            }
+        };
+        let redis_secret = generate_store_secret(&self.secret_seed, REDIS_SECRET_GENERATION_STRING);
+        Either::B(result(redis_connection.into_connection_info())
             .map_err(|err| error!(target: "interledger-node", "Invalid Redis connection details: {:?}", err))
             .and_then(move |redis_url| RedisStoreBuilder::new(redis_url, redis_secret).connect())
             .map_err(|err| error!(target: "interledger-node", "Error connecting to Redis: {:?}", err))
@@ -519,18 +851,19 @@ impl InterledgerNode {
                         debug!(target: "interledger-node", "Created account: {}", account.id());
                         Ok(account.id())
                     })
-            })
+            }))
     }
 }
 
-fn generate_redis_secret(secret_seed: &[u8; 32]) -> [u8; 32] {
-    let mut redis_secret: [u8; 32] = [0; 32];
+/// Derive the secret a store uses to encrypt its data from the node's secret seed
+fn generate_store_secret(secret_seed: &[u8; 32], generation_string: &str) -> [u8; 32] {
+    let mut store_secret: [u8; 32] = [0; 32];
     let sig = hmac::sign(
         &hmac::Key::new(hmac::HMAC_SHA256, secret_seed),
-        REDIS_SECRET_GENERATION_STRING.as_bytes(),
+        generation_string.as_bytes(),
     );
-    redis_secret.copy_from_slice(sig.as_ref());
-    redis_secret
+    store_secret.copy_from_slice(sig.as_ref());
+    store_secret
 }
 
 #[doc(hidden)]
@@ -0,0 +1,287 @@
+use futures::{future::result, Future, Stream};
+use hyper::{
+    server::conn::Http,
+    service::{service_fn, Service},
+    Body, Request,
+};
+use interledger::service::CertificateFingerprint;
+use parking_lot::{Mutex, RwLock};
+use ring::digest::{digest, SHA256};
+use rustls::{
+    internal::pemfile, AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+    Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig, Session,
+};
+use serde::{de::Error as DeserializeError, Deserialize, Deserializer};
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{net::TcpListener, spawn, timer::Interval};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+use warp::{filters::BoxedFilter, Reply};
+
+fn default_reload_interval() -> u64 {
+    10_000
+}
+
+fn deserialize_reload_interval<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let interval = u64::deserialize(deserializer)?;
+    if interval == 0 {
+        Err(DeserializeError::custom(
+            "reload_interval must be greater than 0",
+        ))
+    } else {
+        Ok(interval)
+    }
+}
+
+/// Configuration for serving one of the node's APIs over TLS.
+///
+/// If `client_ca` is set, peers may authenticate to the HTTP API with a TLS client
+/// certificate instead of an auth token. The certificate must be signed by one of the
+/// given CAs, and it is matched to the account whose `client_certificate_fingerprint`
+/// is the certificate's SHA-256 fingerprint.
+///
+/// The files are loaded again when they change or when the node receives SIGHUP,
+/// so certificates can be renewed without restarting the node.
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+    /// Path to the PEM-encoded certificate chain the node presents to clients
+    pub certificate: PathBuf,
+    /// Path to the PEM-encoded private key (PKCS#8 or RSA) of the certificate
+    pub private_key: PathBuf,
+    /// Path to the PEM-encoded CA certificates that client certificates are verified with
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Reject connections that do not present a client certificate signed by the `client_ca`.
+    /// Note that this also applies to the admin API.
+    #[serde(default)]
+    pub require_client_certificate: bool,
+    /// Interval, defined in milliseconds, on which the node checks whether the files were
+    /// modified. Must be greater than 0. Defaults to 10000ms (10 seconds).
+    #[serde(
+        default = "default_reload_interval",
+        deserialize_with = "deserialize_reload_interval"
+    )]
+    pub reload_interval: u64,
+}
+
+impl TlsConfig {
+    /// Load the certificates and key from disk
+    pub(crate) fn server_config(&self) -> Result<ServerConfig, String> {
+        let verifier = match self.client_ca {
+            Some(ref path) => {
+                let mut roots = RootCertStore::empty();
+                let (valid, _invalid) = roots
+                    .add_pem_file(&mut open(path)?)
+                    .map_err(|_| format!("Invalid CA certificates in {}", path.display()))?;
+                if valid == 0 {
+                    return Err(format!("No CA certificates found in {}", path.display()));
+                }
+                if self.require_client_certificate {
+                    AllowAnyAuthenticatedClient::new(roots)
+                } else {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                }
+            }
+            None if self.require_client_certificate => {
+                return Err("client_ca must be set to require client certificates".to_string());
+            }
+            None => NoClientAuth::new(),
+        };
+
+        let certificates = pemfile::certs(&mut open(&self.certificate)?)
+            .map_err(|_| format!("Invalid certificate in {}", self.certificate.display()))?;
+        let key = load_private_key(&self.private_key)?;
+        let mut config = ServerConfig::new(verifier);
+        config
+            .set_single_cert(certificates, key)
+            .map_err(|err| format!("Invalid certificate or private key: {:?}", err))?;
+        // Offer HTTP/1.1 only, because BTP connections are upgraded to WebSockets
+        config.set_protocols(&[b"http/1.1".to_vec()]);
+        Ok(config)
+    }
+
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        let mut paths = vec![&self.certificate, &self.private_key];
+        if let Some(ref path) = self.client_ca {
+            paths.push(path);
+        }
+        paths
+            .into_iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+/// Keeps the TLS configuration a listener uses up to date with the files it was loaded from.
+#[derive(Clone)]
+pub(crate) struct TlsReloader {
+    config: Arc<TlsConfig>,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+    modified: Arc<Mutex<Vec<Option<SystemTime>>>>,
+}
+
+impl TlsReloader {
+    pub(crate) fn new(config: TlsConfig) -> Result<Self, String> {
+        let modified = config.modified_times();
+        let server_config = config.server_config()?;
+        Ok(TlsReloader {
+            config: Arc::new(config),
+            current: Arc::new(RwLock::new(Arc::new(server_config))),
+            modified: Arc::new(Mutex::new(modified)),
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().clone())
+    }
+
+    /// Load the files again. Connections that are already open keep using the
+    /// configuration they were accepted with, and if the files are invalid the
+    /// previous configuration stays in use.
+    fn reload(&self) {
+        *self.modified.lock() = self.config.modified_times();
+        match self.config.server_config() {
+            Ok(config) => {
+                *self.current.write() = Arc::new(config);
+                info!(target: "interledger-node", "Reloaded TLS certificate from: {}", self.config.certificate.display());
+            }
+            Err(message) => {
+                error!(target: "interledger-node", "Error reloading TLS configuration (the previous one is still used): {}", message);
+            }
+        }
+    }
+
+    fn reload_if_modified(&self) {
+        if *self.modified.lock() != self.config.modified_times() {
+            self.reload();
+        }
+    }
+
+    /// Spawn the tasks that reload the configuration when the files change or
+    /// the process receives SIGHUP.
+    fn spawn_watchers(&self) {
+        let reloader = self.clone();
+        spawn(
+            Interval::new_interval(Duration::from_millis(self.config.reload_interval))
+                .map_err(|err| {
+                    error!(target: "interledger-node", "Interval error, no longer checking for new TLS certificates: {:?}", err)
+                })
+                .for_each(move |_| {
+                    reloader.reload_if_modified();
+                    Ok(())
+                }),
+        );
+
+        #[cfg(unix)]
+        {
+            use tokio_signal::unix::{Signal, SIGHUP};
+            let reloader = self.clone();
+            spawn(
+                Signal::new(SIGHUP)
+                    .flatten_stream()
+                    .map_err(|err| {
+                        error!(target: "interledger-node", "Error listening for SIGHUP, TLS certificates are only reloaded when they change: {:?}", err)
+                    })
+                    .for_each(move |_| {
+                        reloader.reload();
+                        Ok(())
+                    }),
+            );
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("Unable to open {}: {}", path.display(), err))
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey, String> {
+    let invalid = |_| format!("Invalid private key in {}", path.display());
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(invalid)?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(invalid)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", path.display()))
+}
+
+fn certificate_fingerprint(certificate: &Certificate) -> CertificateFingerprint {
+    let mut fingerprint = [0; 32];
+    fingerprint.copy_from_slice(digest(&SHA256, &certificate.0).as_ref());
+    CertificateFingerprint::new(fingerprint)
+}
+
+/// Serve the filter over TLS on the given address.
+///
+/// If the client presented a certificate, its fingerprint is added to the extensions of
+/// each of the connection's requests, which is where the ILP-over-HTTP and BTP servers
+/// look for it.
+pub(crate) fn serve_tls<T>(
+    filter: BoxedFilter<(T,)>,
+    bind_address: SocketAddr,
+    tls: TlsReloader,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    T: Reply + 'static,
+{
+    let service = warp::service(filter);
+    result(TcpListener::bind(&bind_address))
+        .map_err(move |err| {
+            error!(target: "interledger-node", "Error binding to {}: {:?}", bind_address, err)
+        })
+        .and_then(move |listener| {
+            tls.spawn_watchers();
+            listener
+                .incoming()
+                // Keep accepting connections if one of them fails
+                .then(|result| {
+                    Ok::<_, ()>(result
+                        .map_err(|err| {
+                            error!(target: "interledger-node", "Error accepting TCP connection: {:?}", err)
+                        })
+                        .ok())
+                })
+                .filter_map(|stream| stream)
+                .for_each(move |stream| {
+                    let service = service.clone();
+                    let connection = tls
+                        .acceptor()
+                        .accept(stream)
+                        .map_err(|err| debug!(target: "interledger-node", "TLS handshake failed: {:?}", err))
+                        .and_then(move |stream| {
+                            let fingerprint = stream
+                                .get_ref()
+                                .1
+                                .get_peer_certificates()
+                                .and_then(|certificates| {
+                                    certificates.first().map(certificate_fingerprint)
+                                });
+                            let service = service_fn(move |mut request: Request<Body>| {
+                                if let Some(fingerprint) = fingerprint {
+                                    request.extensions_mut().insert(fingerprint);
+                                }
+                                service.clone().call(request)
+                            });
+                            Http::new()
+                                .serve_connection(stream, service)
+                                .with_upgrades()
+                                .map_err(|err| debug!(target: "interledger-node", "Error serving TLS connection: {:?}", err))
+                        });
+                    spawn(connection);
+                    Ok(())
+                })
+        })
+}
@@ -177,3 +177,22 @@ fn two_nodes_btp() {
         })
         .unwrap();
 }
+
+#[test]
+fn rejects_zero_btp_keepalive_intervals() {
+    let config = json!({
+        "admin_auth_token": "admin",
+        "in_memory": true,
+        "secret_seed": random_secret(),
+    });
+    for field in &["btp_ping_interval", "btp_pong_timeout"] {
+        let mut config = config.clone();
+        config[field] = json!(0);
+        let node: Result<InterledgerNode, _> = serde_json::from_value(config);
+        assert!(node.is_err());
+    }
+
+    let node: InterledgerNode = serde_json::from_value(config).unwrap();
+    assert_eq!(node.btp_ping_interval, 30_000);
+    assert_eq!(node.btp_pong_timeout, 10_000);
+}
@@ -0,0 +1,174 @@
+use futures::Future;
+use ilp_node::{InterledgerNode, TlsConfig};
+use rustls::{internal::pemfile, Certificate, ClientConfig, Session};
+use serde_json::{self, json};
+use std::{fs, path::Path, sync::Arc};
+use tempfile::TempDir;
+use tokio::{
+    io::{read_to_end, write_all},
+    net::TcpStream,
+    runtime::Builder as RuntimeBuilder,
+};
+use tokio_rustls::TlsConnector;
+use webpki::DNSNameRef;
+
+mod redis_helpers;
+use redis_helpers::*;
+
+mod test_helpers;
+use test_helpers::*;
+
+/// Generate a self-signed certificate for localhost, write it and its key
+/// into the directory and return the certificate
+fn write_certificate(dir: &Path) -> Certificate {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    // Each serialization signs the certificate again, so only serialize it once
+    let pem = generated.serialize_pem().unwrap();
+    fs::write(dir.join("cert.pem"), &pem).unwrap();
+    fs::write(dir.join("key.pem"), generated.serialize_private_key_pem()).unwrap();
+    pemfile::certs(&mut pem.as_bytes()).unwrap().remove(0)
+}
+
+fn tls_config(dir: &TempDir) -> serde_json::Value {
+    json!({
+        "certificate": dir.path().join("cert.pem"),
+        "private_key": dir.path().join("key.pem"),
+        "reload_interval": 100,
+    })
+}
+
+/// Connect to the port, trusting only the given certificate, send a request for the
+/// root path and return the response
+fn get_root(port: u16, certificate: &Certificate) -> impl Future<Item = String, Error = ()> {
+    let mut config = ClientConfig::new();
+    config.root_store.add(certificate).unwrap();
+    let connector = TlsConnector::from(Arc::new(config));
+    let certificate = certificate.clone();
+    TcpStream::connect(&([127, 0, 0, 1], port).into())
+        .and_then(move |stream| {
+            connector.connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+        })
+        .and_then(move |stream| {
+            // The handshake succeeds if the server uses a certificate we trust,
+            // but make sure it is the one we expect
+            assert_eq!(
+                stream.get_ref().1.get_peer_certificates().unwrap()[0],
+                certificate
+            );
+            write_all(
+                stream,
+                &b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"[..],
+            )
+        })
+        .and_then(|(stream, _)| read_to_end(stream, Vec::new()))
+        .map_err(|err| panic!("Error requesting / over TLS: {:?}", err))
+        .map(|(_, response)| String::from_utf8(response).unwrap())
+}
+
+#[test]
+fn serves_apis_over_tls() {
+    install_tracing_subscriber();
+    let http_dir = TempDir::new().unwrap();
+    let http_certificate = write_certificate(http_dir.path());
+    let settlement_dir = TempDir::new().unwrap();
+    let settlement_certificate = write_certificate(settlement_dir.path());
+
+    let node_http = get_open_port(None);
+    let node_settlement = get_open_port(None);
+    let node: InterledgerNode = serde_json::from_value(json!({
+        "ilp_address": "example.node",
+        "admin_auth_token": "admin",
+        "in_memory": true,
+        "http_bind_address": format!("127.0.0.1:{}", node_http),
+        "settlement_api_bind_address": format!("127.0.0.1:{}", node_settlement),
+        "secret_seed": random_secret(),
+        "tls": tls_config(&http_dir),
+        "settlement_api_tls": tls_config(&settlement_dir),
+    }))
+    .unwrap();
+
+    let mut runtime = RuntimeBuilder::new()
+        .panic_handler(|_| panic!("Tokio worker panicked"))
+        .build()
+        .unwrap();
+    runtime.spawn(node.serve());
+    runtime
+        .block_on(
+            // Wait for the node to spin up
+            delay(500)
+                .and_then(move |_| get_root(node_http, &http_certificate))
+                .and_then(move |response| {
+                    assert!(response.starts_with("HTTP/1.1 200"));
+                    assert!(response.contains("example.node"));
+                    // The settlement API has no route for /,
+                    // so any response means the TLS connection worked
+                    get_root(node_settlement, &settlement_certificate)
+                })
+                .and_then(|response| {
+                    assert!(response.starts_with("HTTP/1.1 "));
+                    Ok(())
+                }),
+        )
+        .unwrap();
+}
+
+#[test]
+fn reloads_certificate_when_it_changes() {
+    install_tracing_subscriber();
+    let dir = TempDir::new().unwrap();
+    let certificate = write_certificate(dir.path());
+
+    let node_http = get_open_port(None);
+    let node: InterledgerNode = serde_json::from_value(json!({
+        "admin_auth_token": "admin",
+        "in_memory": true,
+        "http_bind_address": format!("127.0.0.1:{}", node_http),
+        "settlement_api_bind_address": format!("127.0.0.1:{}", get_open_port(None)),
+        "secret_seed": random_secret(),
+        "tls": tls_config(&dir),
+    }))
+    .unwrap();
+
+    let mut runtime = RuntimeBuilder::new()
+        .panic_handler(|_| panic!("Tokio worker panicked"))
+        .build()
+        .unwrap();
+    runtime.spawn(node.serve());
+    runtime
+        .block_on(
+            delay(500)
+                .and_then(move |_| get_root(node_http, &certificate))
+                .and_then(move |response| {
+                    assert!(response.starts_with("HTTP/1.1 200"));
+                    let new_certificate = write_certificate(dir.path());
+                    // Wait for the node to notice that the files changed
+                    delay(500).and_then(move |_| {
+                        let _ = dir;
+                        get_root(node_http, &new_certificate)
+                    })
+                })
+                .and_then(|response| {
+                    assert!(response.starts_with("HTTP/1.1 200"));
+                    Ok(())
+                }),
+        )
+        .unwrap();
+}
+
+#[test]
+fn rejects_zero_reload_interval() {
+    let config = json!({
+        "certificate": "cert.pem",
+        "private_key": "key.pem",
+        "reload_interval": 0,
+    });
+    let result: Result<TlsConfig, _> = serde_json::from_value(config);
+    assert!(result.is_err());
+
+    let config = json!({
+        "certificate": "cert.pem",
+        "private_key": "key.pem",
+    });
+    let config: TlsConfig = serde_json::from_value(config).unwrap();
+    assert_eq!(config.reload_interval, 10_000);
+}
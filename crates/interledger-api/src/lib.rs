@@ -3,7 +3,9 @@ use futures::Future;
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_packet::Address;
 use interledger_router::RouterStore;
-use interledger_service::{Account, AddressStore, IncomingService, OutgoingService, Username};
+use interledger_service::{
+    Account, AddressStore, CertificateFingerprint, IncomingService, OutgoingService, Username,
+};
 use interledger_service_util::{BalanceStore, ExchangeRateStore};
 use interledger_settlement::{SettlementAccount, SettlementStore};
 use interledger_stream::StreamNotificationsStore;
@@ -12,7 +14,7 @@ use std::{boxed::*, collections::HashMap, fmt::Display, net::SocketAddr, str::Fr
 use warp::{self, Filter};
 mod routes;
 use interledger_btp::{BtpAccount, BtpOutgoingService};
-use interledger_ccp::CcpRoutingAccount;
+use interledger_ccp::{CcpRoutingAccount, RoutePolicy};
 use secrecy::SecretString;
 use url::Url;
 
@@ -184,9 +186,16 @@ pub struct AccountDetails {
     pub ilp_over_http_url: Option<String>,
     pub ilp_over_http_incoming_token: Option<SecretString>,
     pub ilp_over_http_outgoing_token: Option<SecretString>,
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub ilp_over_http_pool_size: Option<u32>,
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub ilp_over_http_http2: bool,
+    #[serde(default)]
+    pub ilp_over_http_streaming: bool,
     pub ilp_over_btp_url: Option<String>,
     pub ilp_over_btp_outgoing_token: Option<SecretString>,
     pub ilp_over_btp_incoming_token: Option<SecretString>,
+    pub client_certificate_fingerprint: Option<CertificateFingerprint>,
     #[serde(default, deserialize_with = "optional_number_or_string")]
     pub settle_threshold: Option<i64>,
     #[serde(default, deserialize_with = "optional_number_or_string")]
@@ -195,6 +204,9 @@ pub struct AccountDetails {
     #[serde(default, deserialize_with = "optional_number_or_string")]
     pub round_trip_time: Option<u32>,
     #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub routing_weight: Option<u32>,
+    pub route_policy: Option<RoutePolicy>,
+    #[serde(default, deserialize_with = "optional_number_or_string")]
     pub amount_per_minute_limit: Option<u64>,
     #[serde(default, deserialize_with = "optional_number_or_string")]
     pub packets_per_minute_limit: Option<u32>,
@@ -268,10 +280,14 @@ where
             self.default_spsp_account,
             self.incoming_handler,
             self.outgoing_handler,
-            self.btp,
+            self.btp.clone(),
             self.store.clone(),
         )
-        .or(routes::node_settings_api(self.admin_api_token, self.store))
+        .or(routes::node_settings_api(
+            self.admin_api_token,
+            self.btp,
+            self.store,
+        ))
         .boxed()
     }
 
@@ -1,4 +1,6 @@
-use crate::{http_retry::Client, number_or_string, AccountDetails, AccountSettings, NodeStore};
+use crate::{
+    http_retry::Client, optional_number_or_string, AccountDetails, AccountSettings, NodeStore,
+};
 use bytes::Bytes;
 use futures::{
     future::{err, join_all, ok, Either},
@@ -15,11 +17,11 @@ use interledger_service::{
 };
 use interledger_service_util::{BalanceStore, ExchangeRateStore};
 use interledger_settlement::SettlementAccount;
-use interledger_spsp::{pay, SpspResponder};
-use interledger_stream::{PaymentNotification, StreamNotificationsStore};
+use interledger_spsp::{pay, pay_to_deliver, ExchangeRateLimit, SpspResponder};
+use interledger_stream::{PaymentNotification, PaymentResult, StreamNotificationsStore};
 use log::{debug, error, trace};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Map, Value};
 use std::convert::TryFrom;
 use std::str::FromStr;
 use warp::{self, Filter, Rejection};
@@ -27,8 +29,95 @@ use warp::{self, Filter, Rejection};
 #[derive(Deserialize, Debug)]
 struct SpspPayRequest {
     receiver: String,
-    #[serde(deserialize_with = "number_or_string")]
-    source_amount: u64,
+    /// The amount to send, in the sender's units
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    source_amount: Option<u64>,
+    /// The amount to deliver, in the receiver's units (instead of a `source_amount`)
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    destination_amount: Option<u64>,
+    /// The most the sender is willing to spend to deliver the `destination_amount`
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    max_source_amount: Option<u64>,
+    /// The lowest exchange rate (in the receiver's units per the sender's unit) to accept
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    min_exchange_rate: Option<f64>,
+    /// How much worse than the probed rate the exchange rate may get, as a fraction (e.g. 0.01 for 1%)
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    max_slippage: Option<f64>,
+}
+
+/// Whether a payment sends a fixed amount or delivers one
+enum PaymentAmount {
+    Source(u64),
+    Destination {
+        destination_amount: u64,
+        max_source_amount: u64,
+    },
+}
+
+impl SpspPayRequest {
+    fn amount(&self) -> Result<PaymentAmount, ApiError> {
+        match (
+            self.source_amount,
+            self.destination_amount,
+            self.max_source_amount,
+        ) {
+            (Some(source_amount), None, None) => Ok(PaymentAmount::Source(source_amount)),
+            (None, Some(destination_amount), Some(max_source_amount)) => {
+                Ok(PaymentAmount::Destination {
+                    destination_amount,
+                    max_source_amount,
+                })
+            }
+            (None, Some(_), None) => Err(ApiError::bad_request()
+                .detail("max_source_amount is required when paying a destination_amount")),
+            _ => Err(ApiError::bad_request()
+                .detail("Either source_amount or destination_amount must be given")),
+        }
+    }
+
+    fn rate_limit(&self) -> Result<ExchangeRateLimit, ApiError> {
+        match (self.min_exchange_rate, self.max_slippage) {
+            (None, None) => Ok(ExchangeRateLimit::None),
+            (Some(rate), None) if rate >= 0.0 => Ok(ExchangeRateLimit::MinExchangeRate(rate)),
+            (None, Some(slippage)) if slippage >= 0.0 && slippage <= 1.0 => {
+                Ok(ExchangeRateLimit::MaxSlippage(slippage))
+            }
+            (Some(_), Some(_)) => Err(ApiError::bad_request()
+                .detail("Only one of min_exchange_rate and max_slippage may be given")),
+            _ => Err(ApiError::bad_request().detail("Invalid exchange rate limit")),
+        }
+    }
+}
+
+fn payment_result_to_json(result: &PaymentResult) -> Map<String, Value> {
+    let mut response = Map::new();
+    response.insert("source_amount".to_string(), json!(result.source_amount));
+    response.insert(
+        "delivered_amount".to_string(),
+        json!(result.delivered_amount),
+    );
+    response.insert(
+        "destination_asset".to_string(),
+        json!(result.destination_asset),
+    );
+    response.insert(
+        "fulfilled_packets".to_string(),
+        json!(result.fulfilled_packets),
+    );
+    response.insert(
+        "rejected_packets".to_string(),
+        json!(result.rejected_packets),
+    );
+    response.insert(
+        "duration_ms".to_string(),
+        json!(result.duration.as_millis() as u64),
+    );
+    response.insert(
+        "error".to_string(),
+        json!(result.error.as_ref().map(|error| error.to_string())),
+    );
+    response
 }
 
 pub fn accounts_api<I, O, S, A, B>(
@@ -230,6 +319,7 @@ where
         .boxed();
 
     // PUT /accounts/:username
+    let btp_clone = btp.clone();
     let put_account = warp::put2()
         .and(account_username_to_id.clone())
         .and(warp::path::end())
@@ -240,11 +330,15 @@ where
             move |id: A::AccountId, account_details: AccountDetails, store: S| {
                 let store_clone = store.clone();
                 let handler = outgoing_handler.clone();
-                let btp = btp.clone();
+                let btp = btp_clone.clone();
                 store
                     .update_account(id, account_details)
                     .map_err::<_, Rejection>(move |_| ApiError::internal_server_error().into())
                     .and_then(move |account| {
+                        // Connecting again replaces the connection to the account's old BTP server
+                        if account.get_ilp_over_btp_url().is_none() {
+                            btp.disconnect(account.id());
+                        }
                         connect_to_external_services(handler, account, store_clone, btp)
                     })
                     .and_then(|account: A| Ok(warp::reply::json(&account)))
@@ -300,14 +394,19 @@ where
         .and(warp::path::end())
         .and(admin_only.clone())
         .and(with_store.clone())
-        .and_then(|id: A::AccountId, store: S| {
+        .and_then(move |id: A::AccountId, store: S| {
+            let btp = btp.clone();
             store
                 .delete_account(id)
                 .map_err::<_, Rejection>(move |_| {
                     error!("Error deleting account {}", id);
                     ApiError::internal_server_error().into()
                 })
-                .and_then(|account| Ok(warp::reply::json(&account)))
+                .and_then(move |account| {
+                    // Stop reconnecting to the deleted account's BTP server
+                    btp.disconnect(id);
+                    Ok(warp::reply::json(&account))
+                })
         })
         .boxed();
 
@@ -364,26 +463,57 @@ where
         .and(with_incoming_handler.clone())
         .and_then(
             |account: A, pay_request: SpspPayRequest, incoming_handler: I| {
-                pay(
-                    incoming_handler,
-                    account,
-                    &pay_request.receiver,
-                    pay_request.source_amount,
+                let amount = match pay_request.amount() {
+                    Ok(amount) => amount,
+                    Err(error) => return Either::A(err(error.into())),
+                };
+                let rate_limit = match pay_request.rate_limit() {
+                    Ok(rate_limit) => rate_limit,
+                    Err(error) => return Either::A(err(error.into())),
+                };
+                let payment = match amount {
+                    PaymentAmount::Source(source_amount) => Either::A(pay(
+                        incoming_handler,
+                        account,
+                        &pay_request.receiver,
+                        source_amount,
+                        rate_limit,
+                    )),
+                    PaymentAmount::Destination {
+                        destination_amount,
+                        max_source_amount,
+                    } => Either::B(pay_to_deliver(
+                        incoming_handler,
+                        account,
+                        &pay_request.receiver,
+                        destination_amount,
+                        max_source_amount,
+                        rate_limit,
+                    )),
+                };
+                Either::B(
+                    payment
+                        .map_err::<_, Rejection>(|err| {
+                            error!("Error sending SPSP payment: {:?}", err);
+                            // TODO give a different error message depending on what type of error it is
+                            ApiError::internal_server_error().into()
+                        })
+                        .and_then(|result| {
+                            debug!(
+                                "Sent SPSP payment of {} and delivered: {} of the receiver's units",
+                                result.source_amount, result.delivered_amount
+                            );
+                            let response = payment_result_to_json(&result);
+                            match result.error {
+                                None => Ok(warp::reply::json(&response)),
+                                // Still tell the sender how much went through
+                                Some(error) => Err(ApiError::internal_server_error()
+                                    .detail(format!("Payment stopped early: {}", error))
+                                    .extension_members(Some(response))
+                                    .into()),
+                            }
+                        }),
                 )
-                .and_then(|delivered_amount| {
-                    debug!(
-                        "Sent SPSP payment and delivered: {} of the receiver's units",
-                        delivered_amount
-                    );
-                    Ok(warp::reply::json(&json!({
-                        "delivered_amount": delivered_amount
-                    })))
-                })
-                .map_err::<_, Rejection>(|err| {
-                    error!("Error sending SPSP payment: {:?}", err);
-                    // TODO give a different error message depending on what type of error it is
-                    ApiError::internal_server_error().into()
-                })
             },
         )
         .boxed();
@@ -561,15 +691,21 @@ where
     O: OutgoingService<A> + Clone + Send + Sync + 'static,
     A: CcpRoutingAccount + BtpAccount + SettlementAccount + Clone + Send + Sync + 'static,
     S: NodeStore<Account = A> + AddressStore + Clone + Send + Sync + 'static,
-    B: OutgoingService<A> + Clone + 'static,
+    B: OutgoingService<A> + Clone + Send + 'static,
 {
     // Try to connect to the account's BTP socket if they have
     // one configured
     let btp_connect_fut = if account.get_ilp_over_btp_url().is_some() {
         trace!("Newly inserted account has a BTP URL configured, will try to connect");
         Either::A(
-            connect_to_service_account(account.clone(), true, btp)
-                .map_err(|_| ApiError::internal_server_error().into()),
+            connect_to_service_account(account.clone(), true, btp).map_err(|err| {
+                ApiError::internal_server_error()
+                    .detail(format!(
+                        "Unable to connect to the account's BTP server: {}",
+                        err
+                    ))
+                    .into()
+            }),
         )
     } else {
         Either::B(ok(()))
@@ -4,14 +4,15 @@ use futures::{
     future::{err, join_all, Either},
     Future,
 };
+use interledger_btp::{BtpAccount, BtpConnectionState, BtpOutgoingService};
 use interledger_http::{deserialize_json, error::*, HttpAccount, HttpStore};
 use interledger_router::RouterStore;
-use interledger_service::{Account, Username};
+use interledger_service::{Account, OutgoingService, Username};
 use interledger_service_util::{BalanceStore, ExchangeRateStore};
 use interledger_settlement::SettlementAccount;
 use log::{error, trace};
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use std::{
     collections::HashMap,
     iter::FromIterator,
@@ -20,8 +21,9 @@ use std::{
 use url::Url;
 use warp::{self, Filter, Rejection};
 
-pub fn node_settings_api<S, A>(
+pub fn node_settings_api<S, B, A>(
     admin_api_token: String,
+    btp: BtpOutgoingService<B, A>,
     store: S,
 ) -> warp::filters::BoxedFilter<(impl warp::Reply,)>
 where
@@ -30,7 +32,8 @@ where
         + BalanceStore<Account = A>
         + ExchangeRateStore
         + RouterStore,
-    A: Account + HttpAccount + SettlementAccount + Serialize + 'static,
+    B: OutgoingService<A> + Clone + Send + Sync + 'static,
+    A: BtpAccount + HttpAccount + SettlementAccount + Serialize + Send + Sync + 'static,
 {
     // Helper filters
     let admin_auth_header = format!("Bearer {}", admin_api_token);
@@ -54,10 +57,29 @@ where
         .and(with_store.clone())
         .map(move |store: S| {
             // TODO add more to this response
+            let btp_connections: HashMap<String, Value> = btp
+                .connection_states()
+                .into_iter()
+                .map(|(username, state)| {
+                    let details = match state {
+                        BtpConnectionState::Disconnected {
+                            failed_attempts,
+                            ref error,
+                        } => json!({
+                            "state": state.to_string(),
+                            "failed_attempts": failed_attempts,
+                            "error": error.to_string(),
+                        }),
+                        _ => json!({ "state": state.to_string() }),
+                    };
+                    (username.to_string(), details)
+                })
+                .collect();
             warp::reply::json(&json!({
                 "status": "Ready".to_string(),
                 "ilp_address": store.get_ilp_address(),
                 "version": env!("CARGO_PKG_VERSION"),
+                "btp_connections": btp_connections,
             }))
         })
         .boxed();
@@ -5,11 +5,11 @@ use common::{random_secret, redis_test_helpers};
 use futures::Future;
 use reqwest::{self, r#async::Body};
 use serde_json::{json, Number, Value};
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr};
 use tokio::runtime::Builder as RuntimeBuilder;
 
-use ilp_node::InterledgerNode;
-use interledger::packet::Address;
+use ilp_node::{InterledgerNode, StoreConfig};
+use interledger::{ccp::RoutePolicy, packet::Address};
 
 // Integration tests of node settings APIs
 
@@ -40,11 +40,23 @@ fn node_settings_test() {
         ),
         default_spsp_account: None,
         admin_auth_token: "admin".to_string(),
-        redis_connection: connection_info,
+        store: StoreConfig::Redis {
+            redis_connection: connection_info,
+        },
         http_bind_address: ([127, 0, 0, 1], node_http_port).into(),
         settlement_api_bind_address: ([127, 0, 0, 1], node_settlement_port).into(),
+        tls: None,
+        settlement_api_tls: None,
         secret_seed: random_secret(),
         route_broadcast_interval: Some(200),
+        route_load_splitting: false,
+        route_failover_min_expiry: 1000,
+        route_update_max_routes: 1000,
+        default_route_policy: RoutePolicy::default(),
+        route_auth_anchors: HashMap::new(),
+        btp_ping_interval: 30000,
+        btp_pong_timeout: 10000,
+        btp_max_message_size: 40000,
         exchange_rate_poll_interval: 60000,
         exchange_rate_poll_failure_tolerance: 5,
         exchange_rate_provider: None,
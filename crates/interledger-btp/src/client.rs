@@ -1,15 +1,60 @@
+use super::errors::ConnectError;
 use super::packet::*;
-use super::service::BtpOutgoingService;
+use super::service::{BtpKeepalive, BtpOutgoingService};
 use super::BtpAccount;
-use futures::{future::join_all, Future, Sink, Stream};
+use futures::{
+    future::{join_all, result, Either},
+    stream::unfold,
+    sync::oneshot,
+    Future, Sink, Stream,
+};
 use interledger_packet::Address;
 use interledger_service::*;
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use rand::random;
-use tokio_tungstenite::connect_async;
-use tungstenite::Message;
+use std::{
+    cmp::min,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use stream_cancel::Valve;
+use tokio_executor::spawn;
+use tokio_timer::{Delay, Timeout};
+use tokio_tungstenite::connect_async_with_config;
+use tungstenite::{protocol::WebSocketConfig, Message};
 use url::{ParseError, Url};
 
+const INITIAL_RECONNECT_DELAY: u64 = 1000; // milliseconds
+const MAX_RECONNECT_DELAY: u64 = 5 * 60 * 1000; // milliseconds
+/// Give up on the connection if the server has not responded to our auth message within this timeout
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The state of an outgoing BTP connection
+#[derive(Clone, Debug, PartialEq)]
+pub enum BtpConnectionState {
+    /// Connecting to the account's server and authenticating
+    Connecting,
+    /// Authenticated and ready to send packets
+    Connected,
+    /// Waiting to reconnect after the connection dropped or could not be established,
+    /// or stopped reconnecting because the server rejected the account's auth token
+    Disconnected {
+        failed_attempts: u32,
+        error: ConnectError,
+    },
+}
+
+impl fmt::Display for BtpConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BtpConnectionState::Connecting => write!(f, "connecting"),
+            BtpConnectionState::Connected => write!(f, "connected"),
+            BtpConnectionState::Disconnected { .. } => write!(f, "disconnected"),
+        }
+    }
+}
+
 pub fn parse_btp_url(uri: &str) -> Result<Url, ParseError> {
     let uri = if uri.starts_with("btp+") {
         uri.split_at(4).1
@@ -22,17 +67,24 @@ pub fn parse_btp_url(uri: &str) -> Result<Url, ParseError> {
 /// Create a BtpOutgoingService wrapping BTP connections to the accounts specified.
 /// Calling `handle_incoming` with an `IncomingService` will turn the returned
 /// BtpOutgoingService into a bidirectional handler.
+/// Connections are pinged as configured by the `keepalive`, and are closed
+/// if they send messages larger than `max_message_size` bytes.
 pub fn connect_client<A, S>(
     ilp_address: Address,
     accounts: Vec<A>,
     error_on_unavailable: bool,
+    keepalive: BtpKeepalive,
+    max_message_size: usize,
     next_outgoing: S,
 ) -> impl Future<Item = BtpOutgoingService<S, A>, Error = ()>
 where
-    S: OutgoingService<A> + Clone + 'static,
+    S: OutgoingService<A> + Clone + Send + 'static,
     A: BtpAccount + 'static,
 {
-    let service = BtpOutgoingService::new(ilp_address, next_outgoing);
+    let mut service = BtpOutgoingService::new(ilp_address, next_outgoing);
+    service
+        .keepalive(keepalive)
+        .max_message_size(max_message_size);
     let mut connect_btp = Vec::new();
     for account in accounts {
         // Can we make this take a reference to a service?
@@ -42,18 +94,183 @@ where
             service.clone(),
         ));
     }
-    join_all(connect_btp).and_then(move |_| Ok(service))
+    join_all(connect_btp)
+        .map_err(|_| ())
+        .and_then(move |_| Ok(service))
 }
 
+/// Connect to the given account's BTP server and keep the connection open.
+///
+/// If the connection drops (or, when `error_on_unavailable` is false, cannot be
+/// established in the first place), it will be retried with exponential backoff
+/// until `close` or `disconnect` is called on the service. If the server rejects the
+/// account's auth token, it stops reconnecting and the account's connection state stays
+/// `Disconnected` with the `Unauthorized` error. Calling this again for
+/// the same account (for example, after its BTP settings changed) closes the
+/// previous connection and stops reconnecting to it.
 pub fn connect_to_service_account<O, A>(
     account: A,
     error_on_unavailable: bool,
     service: BtpOutgoingService<O, A>,
+) -> impl Future<Item = (), Error = ConnectError>
+where
+    O: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    let supervisor = Arc::new(service.supervise_outgoing(account.id()));
+    connect_and_authenticate(account.clone(), service.clone(), supervisor.clone()).then(
+        move |result| match result {
+            Ok(closed) => {
+                spawn(keep_connected(
+                    account,
+                    service,
+                    supervisor,
+                    ReconnectStep::Connected(closed),
+                ));
+                Ok(())
+            }
+            Err(error) => {
+                service.set_connection_state(
+                    &account,
+                    BtpConnectionState::Disconnected {
+                        failed_attempts: 1,
+                        error: error.clone(),
+                    },
+                );
+                if error_on_unavailable {
+                    Err(error)
+                } else if let ConnectError::Unauthorized(_) = error {
+                    error!(
+                        "BTP server rejected the auth token of account {}, not reconnecting",
+                        account.id()
+                    );
+                    Ok(())
+                } else {
+                    spawn(keep_connected(
+                        account,
+                        service,
+                        supervisor,
+                        ReconnectStep::Reconnect(1),
+                    ));
+                    Ok(())
+                }
+            }
+        },
+    )
+}
+
+enum ReconnectStep {
+    /// Wait for the open connection to close
+    Connected(oneshot::Receiver<()>),
+    /// Wait out the backoff for the given number of failed attempts, then reconnect
+    Reconnect(u32),
+    /// The server rejected the account's credentials, so trying again will not help
+    Stop,
+}
+
+/// Reconnect to the account whenever its connection closes, until the service
+/// or the `supervisor` is closed or the server rejects the account's auth token
+fn keep_connected<O, A>(
+    account: A,
+    service: BtpOutgoingService<O, A>,
+    supervisor: Arc<Valve>,
+    first_step: ReconnectStep,
 ) -> impl Future<Item = (), Error = ()>
 where
-    O: OutgoingService<A> + Clone + 'static,
+    O: OutgoingService<A> + Clone + Send + 'static,
     A: BtpAccount + 'static,
 {
+    let service_clone = service.clone();
+    let supervisor_clone = supervisor.clone();
+    let steps = unfold(first_step, move |step| {
+        let account = account.clone();
+        let service = service.clone();
+        let supervisor = supervisor.clone();
+        let next_step = match step {
+            ReconnectStep::Connected(closed) => Either::A(closed.then(move |_| {
+                warn!(
+                    "BTP connection to account {} closed, reconnecting",
+                    account.id()
+                );
+                service.set_connection_state(
+                    &account,
+                    BtpConnectionState::Disconnected {
+                        failed_attempts: 0,
+                        error: ConnectError::Network("Connection closed".to_string()),
+                    },
+                );
+                Ok(((), ReconnectStep::Reconnect(0)))
+            })),
+            ReconnectStep::Reconnect(failed_attempts) => {
+                let delay = reconnect_delay(failed_attempts);
+                debug!(
+                    "Reconnecting to account {} in {}ms",
+                    account.id(),
+                    delay.as_millis()
+                );
+                Either::B(
+                    Delay::new(Instant::now() + delay)
+                        .map_err(|err| error!("Timer error waiting to reconnect: {:?}", err))
+                        .and_then(move |_| {
+                            connect_and_authenticate(account.clone(), service.clone(), supervisor)
+                                .then(move |result| match result {
+                                    Ok(closed) => Ok(((), ReconnectStep::Connected(closed))),
+                                    Err(error) => {
+                                        let failed_attempts = failed_attempts.saturating_add(1);
+                                        let next_step = match error {
+                                            ConnectError::Unauthorized(_) => {
+                                                error!("BTP server rejected the auth token of account {}, not reconnecting", account.id());
+                                                ReconnectStep::Stop
+                                            }
+                                            _ => ReconnectStep::Reconnect(failed_attempts),
+                                        };
+                                        service.set_connection_state(
+                                            &account,
+                                            BtpConnectionState::Disconnected {
+                                                failed_attempts,
+                                                error,
+                                            },
+                                        );
+                                        Ok(((), next_step))
+                                    }
+                                })
+                        }),
+                )
+            }
+            ReconnectStep::Stop => return None,
+        };
+        Some(next_step)
+    });
+    service_clone
+        .until_closed(supervisor_clone.wrap(steps))
+        .for_each(|_| Ok(()))
+}
+
+/// Exponential backoff with jitter, so that accounts that
+/// disconnected at the same time don't all reconnect at once
+fn reconnect_delay(failed_attempts: u32) -> Duration {
+    let max_delay = min(
+        INITIAL_RECONNECT_DELAY.saturating_mul(1 << min(failed_attempts, 20)),
+        MAX_RECONNECT_DELAY,
+    );
+    let jitter = (random::<f64>() * (max_delay / 2) as f64) as u64;
+    Duration::from_millis(max_delay / 2 + jitter)
+}
+
+/// Open a WebSocket connection to the account's BTP server, authenticate,
+/// and add the connection to the service. The connection is closed when the `supervisor` closes.
+///
+/// Resolves to a Receiver that resolves once the connection closes.
+fn connect_and_authenticate<O, A>(
+    account: A,
+    service: BtpOutgoingService<O, A>,
+    supervisor: Arc<Valve>,
+) -> impl Future<Item = oneshot::Receiver<()>, Error = ConnectError>
+where
+    O: OutgoingService<A> + Clone + Send + 'static,
+    A: BtpAccount + 'static,
+{
+    service.set_connection_state(&account, BtpConnectionState::Connecting);
     let account_id = account.id();
     let mut url = account
         .get_ilp_over_btp_url()
@@ -66,13 +283,15 @@ where
         .get_ilp_over_btp_outgoing_token()
         .map(|s| s.to_vec())
         .unwrap_or_default();
+    let config = WebSocketConfig {
+        max_message_size: Some(service.get_max_message_size()),
+        max_frame_size: Some(service.get_max_message_size()),
+        ..WebSocketConfig::default()
+    };
     debug!("Connecting to {}", url);
-    connect_async(url.clone())
-        .map_err(move |err| {
-            error!(
-                "Error connecting to WebSocket server for account: {} {:?}",
-                account_id, err
-            )
+    connect_async_with_config(url.clone(), Some(config))
+        .map_err(|err| {
+            ConnectError::Network(format!("Error connecting to WebSocket server: {}", err))
         })
         .and_then(move |(connection, _)| {
             trace!(
@@ -81,9 +300,10 @@ where
                 url
             );
             // Send BTP authentication
+            let request_id = random();
             let auth_packet = Message::Binary(
                 BtpPacket::Message(BtpMessage {
-                    request_id: random(),
+                    request_id,
                     protocol_data: vec![
                         ProtocolData {
                             protocol_name: String::from("auth"),
@@ -100,25 +320,99 @@ where
                 .to_bytes(),
             );
 
-            // TODO check that the response is a success before proceeding
-            // (right now we just assume they'll close the connection if the auth didn't work)
-            connection
+            let authenticate = connection
                 .send(auth_packet)
-                .map_err(move |_| error!("Error sending auth packet on connection: {}", url))
-                .then(move |result| match result {
-                    Ok(connection) => {
-                        debug!("Connected to account {}'s server", account.id());
-                        let connection = connection.from_err().sink_from_err();
-                        service.add_connection(account, connection);
-                        Ok(())
-                    }
-                    Err(_) => {
-                        if error_on_unavailable {
-                            Err(())
-                        } else {
-                            Ok(())
-                        }
-                    }
+                .map_err(|err| ConnectError::Network(format!("Error sending auth packet: {}", err)))
+                .and_then(move |connection| {
+                    // Skip Pings and other non-binary messages the server
+                    // might send before it responds to the auth message
+                    connection
+                        .skip_while(|message| Ok(!message.is_binary()))
+                        .into_future()
+                        .map_err(|(err, _connection)| {
+                            ConnectError::Network(format!("Error reading auth response: {}", err))
+                        })
+                })
+                .and_then(move |(message, connection)| {
+                    result(check_auth_response(request_id, message).map(|_| connection))
+                });
+            Timeout::new(authenticate, AUTH_TIMEOUT).map_err(|err| {
+                err.into_inner().unwrap_or_else(|| {
+                    ConnectError::Network("Timed out waiting for the auth response".to_string())
                 })
+            })
         })
+        .then(move |result| match result {
+            Ok(connection) => {
+                debug!("Connected to account {}'s server", account.id());
+                let connection = connection.from_err().sink_from_err();
+                let closed = service.add_connection(account.clone(), connection, Some(supervisor));
+                service.set_connection_state(&account, BtpConnectionState::Connected);
+                Ok(closed)
+            }
+            Err(err) => {
+                error!("Unable to connect to account {}: {}", account_id, err);
+                Err(err)
+            }
+        })
+}
+
+/// Check that the first binary message the server sent is a successful response to our auth message
+fn check_auth_response(request_id: u32, message: Option<Message>) -> Result<(), ConnectError> {
+    let data = match message {
+        Some(Message::Binary(data)) => data,
+        _ => {
+            return Err(ConnectError::Protocol(
+                "Connection closed before the server responded to the auth message".to_string(),
+            ))
+        }
+    };
+    match BtpPacket::from_bytes(&data) {
+        Ok(BtpPacket::Response(ref response)) if response.request_id == request_id => Ok(()),
+        // BTP servers respond with an F00 NotAcceptedError if the auth token is wrong
+        Ok(BtpPacket::Error(ref error))
+            if error.request_id == request_id && error.code == "F00" =>
+        {
+            Err(ConnectError::Unauthorized(format!(
+                "{}: {}",
+                error.name, error.data
+            )))
+        }
+        Ok(BtpPacket::Error(ref error)) if error.request_id == request_id => {
+            Err(ConnectError::Protocol(format!(
+                "Got error {} {} in response to the auth message: {}",
+                error.code, error.name, error.data
+            )))
+        }
+        Ok(packet) => Err(ConnectError::Protocol(format!(
+            "Expected a response to the auth message, got: {:?}",
+            packet
+        ))),
+        Err(err) => Err(ConnectError::Protocol(format!(
+            "Unable to parse the response to the auth message: {:?}",
+            err
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod reconnect {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        for failed_attempts in 0..4 {
+            let delay = reconnect_delay(failed_attempts).as_millis() as u64;
+            let max_delay = INITIAL_RECONNECT_DELAY << failed_attempts;
+            assert!(delay >= max_delay / 2);
+            assert!(delay <= max_delay);
+        }
+    }
+
+    #[test]
+    fn caps_delay() {
+        let delay = reconnect_delay(u32::MAX).as_millis() as u64;
+        assert!(delay >= MAX_RECONNECT_DELAY / 2);
+        assert!(delay <= MAX_RECONNECT_DELAY);
+    }
 }
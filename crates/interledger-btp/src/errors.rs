@@ -42,3 +42,49 @@ quick_error! {
         }
     }
 }
+
+quick_error! {
+    /// Why an outgoing BTP connection could not be established
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum ConnectError {
+        /// The server rejected our auth token
+        Unauthorized(descr: String) {
+            description(descr)
+            display("BTP server rejected the auth token: {}", descr)
+        }
+        /// The server responded with something other than a valid auth response
+        Protocol(descr: String) {
+            description(descr)
+            display("BTP protocol error: {}", descr)
+        }
+        /// The WebSocket connection could not be opened or was lost
+        Network(descr: String) {
+            description(descr)
+            display("Network error: {}", descr)
+        }
+    }
+}
+
+quick_error! {
+    /// Why sub-protocol data sent to a BTP peer did not get a Response
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum ProtocolDataError {
+        /// There is no open BTP connection to the account
+        NotConnected {
+            description("No open BTP connection to the account")
+        }
+        /// The peer responded with a BTP Error
+        Rejected(descr: String) {
+            description(descr)
+            display("BTP peer responded with an error: {}", descr)
+        }
+        /// The connection closed before the peer responded
+        ConnectionClosed {
+            description("BTP connection closed before the peer responded")
+        }
+        /// The peer did not respond within the protocol data timeout
+        Timeout {
+            description("BTP peer did not respond in time")
+        }
+    }
+}
@@ -6,8 +6,8 @@
 //! Because this protocol uses WebSockets, only one party needs to have a publicly-accessible HTTPS
 //! endpoint but both sides can send and receive ILP packets.
 
-use futures::Future;
-use interledger_service::{Account, Username};
+use futures::{future::err, Future};
+use interledger_service::{Account, CertificateFingerprint, Username};
 use url::Url;
 
 mod client;
@@ -17,9 +17,16 @@ mod packet;
 mod server;
 mod service;
 
-pub use self::client::{connect_client, connect_to_service_account, parse_btp_url};
+pub use self::client::{
+    connect_client, connect_to_service_account, parse_btp_url, BtpConnectionState,
+};
+pub use self::errors::{ConnectError, ProtocolDataError};
+pub use self::packet::{ContentType, ProtocolData};
 pub use self::server::create_btp_service_and_filter;
-pub use self::service::{BtpOutgoingService, BtpService};
+pub use self::service::{
+    BtpKeepalive, BtpOutgoingService, BtpProtocolFuture, BtpService, DEFAULT_MAX_MESSAGE_SIZE,
+    DEFAULT_PROTOCOL_DATA_TIMEOUT,
+};
 
 pub trait BtpAccount: Account {
     fn get_ilp_over_btp_url(&self) -> Option<&Url>;
@@ -37,6 +44,16 @@ pub trait BtpStore {
         token: &str,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send>;
 
+    /// Load Account details based on the fingerprint of the TLS client certificate
+    /// the peer authenticated with. Stores that do not support client certificates
+    /// do not match any account.
+    fn get_account_from_btp_certificate(
+        &self,
+        _fingerprint: &CertificateFingerprint,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        Box::new(err(()))
+    }
+
     /// Load accounts that have a ilp_over_btp_url configured
     fn get_btp_outgoing_accounts(
         &self,
@@ -46,17 +63,30 @@ pub trait BtpStore {
 #[cfg(test)]
 mod client_server {
     use super::*;
-    use futures::future::{err, lazy, ok, result};
-    use interledger_packet::{Address, ErrorCode, FulfillBuilder, PrepareBuilder, RejectBuilder};
+    use crate::packet::{BtpMessage, BtpPacket, BtpResponse, Serializable};
+    use bytes::BytesMut;
+    use futures::{
+        future::{empty, err, lazy, ok, result, Either},
+        Sink, Stream,
+    };
+    use interledger_packet::{
+        Address, ErrorCode, Fulfill, FulfillBuilder, PrepareBuilder, Reject, RejectBuilder,
+    };
     use interledger_service::*;
     use net2::TcpBuilder;
     use std::str::FromStr;
     use std::{
         net::SocketAddr,
-        sync::Arc,
-        time::{Duration, SystemTime},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant, SystemTime},
     };
-    use tokio::runtime::Runtime;
+    use tokio::{net::TcpListener, runtime::Runtime};
+    use tokio_timer::Delay;
+    use tokio_tungstenite::{accept_async, connect_async};
+    use tungstenite::Message;
 
     use lazy_static::lazy_static;
 
@@ -217,6 +247,8 @@ mod client_server {
                 let (btp_service, filter) = create_btp_service_and_filter(
                     server_address.clone(),
                     server_store,
+                    BtpKeepalive::default(),
+                    DEFAULT_MAX_MESSAGE_SIZE,
                     outgoing_service_fn(move |_| {
                         Err(RejectBuilder {
                             code: ErrorCode::F02_UNREACHABLE,
@@ -249,6 +281,8 @@ mod client_server {
                     addr.clone(),
                     accounts,
                     true,
+                    BtpKeepalive::default(),
+                    DEFAULT_MAX_MESSAGE_SIZE,
                     outgoing_service_fn(move |_| {
                         Err(RejectBuilder {
                             code: ErrorCode::F02_UNREACHABLE,
@@ -296,4 +330,693 @@ mod client_server {
             }))
             .unwrap();
     }
+
+    #[test]
+    fn sends_sub_protocol_data() {
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(|| {
+            let bind_addr = get_open_port();
+
+            let server_store = TestStore {
+                accounts: Arc::new(vec![TestAccount {
+                    id: 0,
+                    ilp_over_btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                    ilp_over_btp_outgoing_token: None,
+                    ilp_over_btp_url: None,
+                }]),
+            };
+            let server_address = Address::from_str("example.server").unwrap();
+            let (mut btp_service, filter) = create_btp_service_and_filter(
+                server_address.clone(),
+                server_store,
+                BtpKeepalive::default(),
+                DEFAULT_MAX_MESSAGE_SIZE,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address),
+                        data: &[],
+                    }
+                    .build())
+                }),
+            );
+            btp_service
+                .register_protocol_handler("info", |account: TestAccount, data| {
+                    let mut response = format!("account {} sent: ", account.id).into_bytes();
+                    response.extend_from_slice(&data.data);
+                    Ok(vec![ProtocolData {
+                        protocol_name: "info".to_string(),
+                        content_type: ContentType::TextPlainUtf8,
+                        data: response,
+                    }])
+                })
+                .register_protocol_handler("paychan", |_, _| Err("Invalid claim".to_string()));
+            tokio::spawn(warp::serve(filter).bind(bind_addr));
+
+            let account = TestAccount {
+                id: 0,
+                ilp_over_btp_url: Some(Url::parse(&format!("btp+ws://{}", bind_addr)).unwrap()),
+                ilp_over_btp_outgoing_token: Some("alice:test_auth_token".to_string()),
+                ilp_over_btp_incoming_token: None,
+            };
+            let addr = Address::from_str("example.address").unwrap();
+            let addr_clone = addr.clone();
+            connect_client(
+                addr,
+                vec![account],
+                true,
+                BtpKeepalive::default(),
+                DEFAULT_MAX_MESSAGE_SIZE,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        data: &[],
+                        triggered_by: Some(&addr_clone),
+                    }
+                    .build())
+                }),
+            )
+            .and_then(|client| {
+                let protocol_data = |protocol_name: &str| ProtocolData {
+                    protocol_name: protocol_name.to_string(),
+                    content_type: ContentType::TextPlainUtf8,
+                    data: b"hello".to_vec(),
+                };
+                let info = client.send_protocol_data(0, vec![protocol_data("info")]);
+                let paychan = client.send_protocol_data(0, vec![protocol_data("paychan")]);
+                let unknown = client.send_protocol_data(0, vec![protocol_data("unknown")]);
+                info.then(move |info| {
+                    paychan.then(move |paychan| {
+                        unknown.then(move |unknown| {
+                            client.close();
+                            Ok::<_, ()>((info, paychan, unknown))
+                        })
+                    })
+                })
+            })
+        }));
+        let (info, paychan, unknown) = result.unwrap();
+        assert_eq!(
+            info.unwrap(),
+            vec![ProtocolData {
+                protocol_name: "info".to_string(),
+                content_type: ContentType::TextPlainUtf8,
+                data: b"account 0 sent: hello".to_vec(),
+            }]
+        );
+        assert_eq!(
+            paychan,
+            Err(ProtocolDataError::Rejected(
+                "F99 ApplicationError: Invalid claim".to_string()
+            ))
+        );
+        match unknown {
+            Err(ProtocolDataError::Rejected(message)) => assert!(message.starts_with("F00")),
+            other => panic!("Expected the request to be rejected, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn protocol_data_times_out() {
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(|| {
+            let bind_addr = get_open_port();
+
+            let server_store = TestStore {
+                accounts: Arc::new(vec![TestAccount {
+                    id: 0,
+                    ilp_over_btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                    ilp_over_btp_outgoing_token: None,
+                    ilp_over_btp_url: None,
+                }]),
+            };
+            let server_address = Address::from_str("example.server").unwrap();
+            let (mut btp_service, filter) = create_btp_service_and_filter(
+                server_address.clone(),
+                server_store,
+                BtpKeepalive::default(),
+                DEFAULT_MAX_MESSAGE_SIZE,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address),
+                        data: &[],
+                    }
+                    .build())
+                }),
+            );
+            // The handler never responds
+            btp_service
+                .register_protocol_handler("info", |_, _| empty::<Vec<ProtocolData>, String>());
+            tokio::spawn(warp::serve(filter).bind(bind_addr));
+
+            let (account, mut client) = test_client(bind_addr);
+            client.protocol_data_timeout(Duration::from_millis(100));
+            connect_to_service_account(account, true, client.clone())
+                .map_err(|err| panic!("Unable to connect: {:?}", err))
+                .and_then(move |_| {
+                    client
+                        .send_protocol_data(
+                            0,
+                            vec![ProtocolData {
+                                protocol_name: "info".to_string(),
+                                content_type: ContentType::TextPlainUtf8,
+                                data: b"hello".to_vec(),
+                            }],
+                        )
+                        .then(move |result| {
+                            client.close();
+                            Ok::<_, ()>(result)
+                        })
+                })
+        }));
+        assert_eq!(result.unwrap(), Err(ProtocolDataError::Timeout));
+    }
+
+    #[test]
+    fn rejects_bad_auth_token() {
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(|| {
+            let bind_addr = get_open_port();
+
+            let server_store = TestStore {
+                accounts: Arc::new(vec![TestAccount {
+                    id: 0,
+                    ilp_over_btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                    ilp_over_btp_outgoing_token: None,
+                    ilp_over_btp_url: None,
+                }]),
+            };
+            let server_address = Address::from_str("example.server").unwrap();
+            let (_btp_service, filter) = create_btp_service_and_filter(
+                server_address.clone(),
+                server_store,
+                BtpKeepalive::default(),
+                DEFAULT_MAX_MESSAGE_SIZE,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"No other outgoing handler",
+                        triggered_by: Some(&server_address),
+                        data: &[],
+                    }
+                    .build())
+                }),
+            );
+            tokio::spawn(warp::serve(filter).bind(bind_addr));
+
+            let account = TestAccount {
+                id: 0,
+                ilp_over_btp_url: Some(Url::parse(&format!("btp+ws://{}", bind_addr)).unwrap()),
+                ilp_over_btp_outgoing_token: Some("alice:wrong_token".to_string()),
+                ilp_over_btp_incoming_token: None,
+            };
+            let addr = Address::from_str("example.address").unwrap();
+            let addr_clone = addr.clone();
+            let client = BtpOutgoingService::new(
+                addr,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        data: &[],
+                        triggered_by: Some(&addr_clone),
+                    }
+                    .build())
+                }),
+            );
+            connect_to_service_account(account, true, client.clone()).then(move |result| {
+                let state = client
+                    .connection_states()
+                    .into_iter()
+                    .find(|(username, _)| username == &*ALICE)
+                    .map(|(_, state)| state);
+                Ok::<_, ()>((result, state))
+            })
+        }));
+        let (result, state) = result.unwrap();
+        match result {
+            Err(ConnectError::Unauthorized(_)) => {}
+            other => panic!("Expected the auth token to be rejected, got: {:?}", other),
+        }
+        match state {
+            Some(BtpConnectionState::Disconnected {
+                failed_attempts: 1,
+                error: ConnectError::Unauthorized(_),
+            }) => {}
+            other => panic!(
+                "Expected the connection to be disconnected, got: {:?}",
+                other
+            ),
+        }
+    }
+
+    #[test]
+    fn stops_reconnecting_when_auth_is_rejected() {
+        let mut runtime = Runtime::new().unwrap();
+        let state = runtime.block_on(lazy(|| {
+            let bind_addr = get_open_port();
+            spawn_test_server(bind_addr);
+            let (mut account, client) = test_client(bind_addr);
+            account.ilp_over_btp_outgoing_token = Some("alice:wrong_token".to_string());
+            connect_to_service_account(account, false, client.clone())
+                .map_err(|err| panic!("Expected the error to be ignored, got: {:?}", err))
+                // Longer than the backoff after the first failed attempt
+                .and_then(|_| {
+                    Delay::new(Instant::now() + Duration::from_millis(2500))
+                        .map_err(|err| panic!("Timer error: {:?}", err))
+                })
+                .map(move |_| {
+                    client
+                        .connection_states()
+                        .into_iter()
+                        .find(|(username, _)| username == &*ALICE)
+                        .map(|(_, state)| state)
+                })
+        }));
+        match state.unwrap() {
+            Some(BtpConnectionState::Disconnected {
+                failed_attempts: 1,
+                error: ConnectError::Unauthorized(_),
+            }) => {}
+            other => panic!("Expected the client to stop reconnecting, got: {:?}", other),
+        }
+    }
+
+    fn spawn_test_server(
+        bind_addr: SocketAddr,
+    ) -> BtpOutgoingService<impl OutgoingService<TestAccount> + Clone, TestAccount> {
+        let server_store = TestStore {
+            accounts: Arc::new(vec![TestAccount {
+                id: 0,
+                ilp_over_btp_incoming_token: Some("alice:test_auth_token".to_string()),
+                ilp_over_btp_outgoing_token: None,
+                ilp_over_btp_url: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let (btp_service, filter) = create_btp_service_and_filter(
+            server_address.clone(),
+            server_store,
+            BtpKeepalive::default(),
+            DEFAULT_MAX_MESSAGE_SIZE,
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&server_address),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        tokio::spawn(warp::serve(filter).bind(bind_addr));
+        btp_service
+    }
+
+    fn test_client(
+        bind_addr: SocketAddr,
+    ) -> (
+        TestAccount,
+        BtpOutgoingService<impl OutgoingService<TestAccount> + Clone, TestAccount>,
+    ) {
+        let account = TestAccount {
+            id: 0,
+            ilp_over_btp_url: Some(Url::parse(&format!("btp+ws://{}", bind_addr)).unwrap()),
+            ilp_over_btp_outgoing_token: Some("alice:test_auth_token".to_string()),
+            ilp_over_btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.address").unwrap();
+        let addr_clone = addr.clone();
+        let client = BtpOutgoingService::new(
+            addr,
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: Some(&addr_clone),
+                }
+                .build())
+            }),
+        );
+        (account, client)
+    }
+
+    // Long enough for a connection that was not stopped to be reestablished
+    fn wait_for_reconnect() -> impl Future<Item = (), Error = ()> {
+        Delay::new(Instant::now() + Duration::from_millis(1200))
+            .map_err(|err| panic!("Timer error: {:?}", err))
+    }
+
+    #[test]
+    fn reconnecting_replaces_previous_connection() {
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(|| {
+            let bind_addr = get_open_port();
+            spawn_test_server(bind_addr);
+            let (account, client) = test_client(bind_addr);
+            let account_clone = account.clone();
+            let client_clone = client.clone();
+            connect_to_service_account(account, true, client.clone())
+                .and_then(move |_| connect_to_service_account(account_clone, true, client_clone))
+                .map_err(|err| panic!("Unable to connect: {:?}", err))
+                .and_then(|_| wait_for_reconnect())
+                .and_then(move |_| {
+                    let open_connections = client.open_connections(0);
+                    let states = client.connection_states();
+                    client.close();
+                    Ok::<_, ()>((open_connections, states))
+                })
+        }));
+        let (open_connections, states) = result.unwrap();
+        assert_eq!(open_connections, 1);
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].0, *ALICE);
+        match states[0].1 {
+            BtpConnectionState::Connected => {}
+            ref other => panic!("Expected the account to be connected, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn disconnect_stops_reconnecting() {
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(|| {
+            let bind_addr = get_open_port();
+            spawn_test_server(bind_addr);
+            let (account, client) = test_client(bind_addr);
+            connect_to_service_account(account, true, client.clone())
+                .map_err(|err| panic!("Unable to connect: {:?}", err))
+                .and_then(move |_| {
+                    client.disconnect(0);
+                    wait_for_reconnect().and_then(move |_| {
+                        let open_connections = client.open_connections(0);
+                        let states = client.connection_states();
+                        client.close();
+                        Ok::<_, ()>((open_connections, states))
+                    })
+                })
+        }));
+        let (open_connections, states) = result.unwrap();
+        assert_eq!(open_connections, 0);
+        assert!(states.is_empty());
+    }
+
+    fn auth_message(request_id: u32) -> Message {
+        Message::Binary(
+            BtpPacket::Message(BtpMessage {
+                request_id,
+                protocol_data: vec![
+                    ProtocolData {
+                        protocol_name: "auth".to_string(),
+                        content_type: ContentType::ApplicationOctetStream,
+                        data: vec![],
+                    },
+                    ProtocolData {
+                        protocol_name: "auth_token".to_string(),
+                        content_type: ContentType::TextPlainUtf8,
+                        data: b"alice:test_auth_token".to_vec(),
+                    },
+                ],
+            })
+            .to_bytes(),
+        )
+    }
+
+    #[test]
+    fn answers_pings() {
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(|| {
+            let bind_addr = get_open_port();
+            spawn_test_server(bind_addr);
+            connect_async(Url::parse(&format!("ws://{}", bind_addr)).unwrap())
+                .and_then(|(connection, _)| connection.send(auth_message(1)))
+                .and_then(|connection| {
+                    // Wait for the auth response so the Ping is not taken for the auth message
+                    connection
+                        .skip_while(|message| Ok(!message.is_binary()))
+                        .into_future()
+                        .map_err(|(err, _)| err)
+                })
+                .and_then(|(_auth_response, connection)| {
+                    connection.send(Message::Ping(b"are you there?".to_vec()))
+                })
+                .and_then(|connection| {
+                    connection
+                        .skip_while(|message| Ok(!message.is_pong()))
+                        .into_future()
+                        .map_err(|(err, _)| err)
+                })
+                .map(|(message, _)| message)
+        }));
+        assert_eq!(
+            result.unwrap(),
+            Some(Message::Pong(b"are you there?".to_vec()))
+        );
+    }
+
+    /// Accept BTP connections with any auth token. The first connection stops being read
+    /// (so its Pings are not answered anymore) once a Ping arrives on it. The ones after
+    /// it are read until they close, so their Pings are answered.
+    fn spawn_unresponsive_server(
+        bind_addr: SocketAddr,
+        connections: Arc<AtomicUsize>,
+        pinged: Arc<AtomicBool>,
+    ) {
+        let listener = TcpListener::bind(&bind_addr).unwrap();
+        tokio::spawn(
+            listener
+                .incoming()
+                .map_err(|err| panic!("Error accepting connection: {:?}", err))
+                .for_each(move |stream| {
+                    let connection_number = connections.fetch_add(1, Ordering::SeqCst);
+                    let pinged = pinged.clone();
+                    let connection = accept_async(stream)
+                        .and_then(|connection| connection.into_future().map_err(|(err, _)| err))
+                        .and_then(|(auth, connection)| {
+                            let request_id = match auth {
+                                Some(Message::Binary(data)) => match BtpPacket::from_bytes(&data) {
+                                    Ok(BtpPacket::Message(message)) => message.request_id,
+                                    other => panic!("Expected the auth message, got: {:?}", other),
+                                },
+                                other => panic!("Expected the auth message, got: {:?}", other),
+                            };
+                            connection.send(Message::Binary(
+                                BtpPacket::Response(BtpResponse {
+                                    request_id,
+                                    protocol_data: Vec::new(),
+                                })
+                                .to_bytes(),
+                            ))
+                        })
+                        .and_then(move |connection| {
+                            if connection_number == 0 {
+                                Either::A(
+                                    connection
+                                        .skip_while(|message| Ok(!message.is_ping()))
+                                        .into_future()
+                                        .map_err(|(err, _)| err)
+                                        .and_then(move |(_ping, connection)| {
+                                            pinged.store(true, Ordering::SeqCst);
+                                            // Keep the connection open without reading from it
+                                            empty().then(move |_: Result<(), ()>| {
+                                                drop(connection);
+                                                Ok(())
+                                            })
+                                        }),
+                                )
+                            } else {
+                                Either::B(connection.for_each(|_| Ok(())))
+                            }
+                        })
+                        .then(|_| Ok(()));
+                    tokio::spawn(connection);
+                    Ok(())
+                }),
+        );
+    }
+
+    #[test]
+    fn closes_connections_that_stop_answering_pings() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let pinged = Arc::new(AtomicBool::new(false));
+        let connections_clone = connections.clone();
+        let pinged_clone = pinged.clone();
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(move || {
+            let bind_addr = get_open_port();
+            spawn_unresponsive_server(bind_addr, connections_clone, pinged_clone);
+            let (account, mut client) = test_client(bind_addr);
+            client.keepalive(BtpKeepalive {
+                ping_interval: Duration::from_millis(100),
+                pong_timeout: Duration::from_millis(200),
+            });
+            connect_to_service_account(account, true, client.clone())
+                .map_err(|err| panic!("Unable to connect: {:?}", err))
+                // The first connection is closed after the first Ping goes unanswered,
+                // then the account reconnects and the second connection stays open
+                .and_then(|_| wait_for_reconnect())
+                .and_then(|_| wait_for_reconnect())
+                .and_then(move |_| {
+                    let open_connections = client.open_connections(0);
+                    let states = client.connection_states();
+                    client.close();
+                    Ok::<_, ()>((open_connections, states))
+                })
+        }));
+        let (open_connections, states) = result.unwrap();
+        assert!(pinged.load(Ordering::SeqCst));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        assert_eq!(open_connections, 1);
+        match states[0].1 {
+            BtpConnectionState::Connected => {}
+            ref other => panic!("Expected the account to be connected, got: {:?}", other),
+        }
+    }
+
+    /// Connect to the server as alice without the BTP client. Prepares are fulfilled with
+    /// the `name` as their data, unless `answer` is false, in which case the connection
+    /// is closed as soon as the first Prepare arrives.
+    fn connect_raw_client(
+        bind_addr: SocketAddr,
+        name: &'static [u8],
+        answer: bool,
+    ) -> impl Future<Item = (), Error = ()> {
+        connect_async(Url::parse(&format!("ws://{}", bind_addr)).unwrap())
+            .and_then(|(connection, _)| connection.send(auth_message(1)))
+            .and_then(|connection| {
+                connection
+                    .skip_while(|message| Ok(!message.is_binary()))
+                    .into_future()
+                    .map_err(|(err, _)| err)
+            })
+            .map_err(|err| panic!("WebSocket error: {:?}", err))
+            .and_then(move |(_auth_response, connection)| {
+                let (sink, stream) = connection.into_inner().split();
+                let responses = stream
+                    .take_while(move |_| Ok(answer))
+                    .filter_map(|message| match message {
+                        Message::Binary(data) => match BtpPacket::from_bytes(&data) {
+                            Ok(BtpPacket::Message(message)) => Some(message.request_id),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .map(move |request_id| {
+                        let fulfill = FulfillBuilder {
+                            fulfillment: &[0; 32],
+                            data: name,
+                        }
+                        .build();
+                        Message::Binary(
+                            BtpPacket::Response(BtpResponse {
+                                request_id,
+                                protocol_data: vec![ProtocolData {
+                                    protocol_name: "ilp".to_string(),
+                                    content_type: ContentType::ApplicationOctetStream,
+                                    data: BytesMut::from(fulfill).to_vec(),
+                                }],
+                            })
+                            .to_bytes(),
+                        )
+                    });
+                tokio::spawn(sink.send_all(responses).then(|_| Ok(())));
+                Ok(())
+            })
+    }
+
+    fn send_prepare<O>(service: &BtpOutgoingService<O, TestAccount>) -> BoxedIlpFuture
+    where
+        O: OutgoingService<TestAccount> + Clone,
+    {
+        let account = TestAccount {
+            id: 0,
+            ilp_over_btp_url: None,
+            ilp_over_btp_outgoing_token: None,
+            ilp_over_btp_incoming_token: None,
+        };
+        service.clone().send_request(OutgoingRequest {
+            from: account.clone(),
+            to: account,
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &[],
+            }
+            .build(),
+        })
+    }
+
+    #[test]
+    fn balances_requests_across_connections_and_fails_over() {
+        let mut runtime = Runtime::new().unwrap();
+        let result = runtime.block_on(lazy(|| {
+            let bind_addr = get_open_port();
+            let service = spawn_test_server(bind_addr);
+            let service_clone = service.clone();
+            connect_raw_client(bind_addr, b"a", false)
+                .join(connect_raw_client(bind_addr, b"b", true))
+                // Give the server a moment to add the connections
+                .and_then(|_| {
+                    Delay::new(Instant::now() + Duration::from_millis(100))
+                        .map_err(|err| panic!("Timer error: {:?}", err))
+                })
+                .and_then(move |_| {
+                    let open_connections = service.open_connections(0);
+                    let started = Instant::now();
+                    // Each connection gets one of the requests, and the connection
+                    // that gets closed takes its pending request with it
+                    send_prepare(&service)
+                        .then(Ok)
+                        .join(send_prepare(&service).then(Ok))
+                        .map(move |(first, second)| {
+                            (open_connections, started.elapsed(), vec![first, second])
+                        })
+                })
+                .and_then(|first_requests| {
+                    Delay::new(Instant::now() + Duration::from_millis(100))
+                        .map_err(|err| panic!("Timer error: {:?}", err))
+                        .map(|_| first_requests)
+                })
+                .and_then(move |first_requests| {
+                    let open_connections = service_clone.open_connections(0);
+                    send_prepare(&service_clone)
+                        .then(Ok)
+                        .join(send_prepare(&service_clone).then(Ok))
+                        .map(move |(first, second)| {
+                            (first_requests, open_connections, vec![first, second])
+                        })
+                })
+        }));
+        let ((open_before, elapsed, first_results), open_after, second_results): (
+            (usize, Duration, Vec<Result<Fulfill, Reject>>),
+            usize,
+            Vec<Result<Fulfill, Reject>>,
+        ) = result.unwrap();
+        assert_eq!(open_before, 2);
+        let fulfilled: Vec<&[u8]> = first_results
+            .iter()
+            .filter_map(|result| result.as_ref().ok().map(|fulfill| fulfill.data()))
+            .collect();
+        assert_eq!(fulfilled, vec![&b"b"[..]]);
+        let rejected: Vec<ErrorCode> = first_results
+            .iter()
+            .filter_map(|result| result.as_ref().err().map(|reject| reject.code()))
+            .collect();
+        assert_eq!(rejected, vec![ErrorCode::T00_INTERNAL_ERROR]);
+        // The request on the closed connection was rejected right away, not when it expired
+        assert!(elapsed < Duration::from_secs(5));
+
+        assert_eq!(open_after, 1);
+        for result in second_results {
+            assert_eq!(result.unwrap().data(), b"b");
+        }
+    }
 }
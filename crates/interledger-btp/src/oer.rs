@@ -19,21 +19,55 @@ pub trait ReadOerExt: Read + ReadBytesExt + Debug {
 
         let actual_length: u64 = if length & HIGH_BIT != 0 {
             let length_prefix_length = length & LOWER_SEVEN_BITS;
-            // TODO check for canonical length
-            self.read_uint::<BigEndian>(length_prefix_length as usize)? as u64
+            if length_prefix_length == 0 || length_prefix_length > 8 {
+                return Err(invalid_data(format!(
+                    "Invalid length prefix length: {}",
+                    length_prefix_length
+                )));
+            }
+            let actual_length = self.read_uint::<BigEndian>(length_prefix_length as usize)?;
+            // The length must be encoded in as few bytes as possible, and
+            // lengths that fit in 7 bits must use the short form
+            if actual_length <= 127
+                || actual_length >> (8 * (u64::from(length_prefix_length) - 1)) == 0
+            {
+                return Err(invalid_data(format!(
+                    "Length {} is not canonically encoded",
+                    actual_length
+                )));
+            }
+            actual_length
         } else {
             u64::from(length)
         };
 
-        // TODO handle if the length is too long
-        let mut buf = Vec::with_capacity(actual_length as usize);
+        // Don't allocate space for the whole length up front, because
+        // it may be much longer than the data that is actually there
+        let mut buf = Vec::new();
         self.take(actual_length).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < actual_length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Length prefix says {} bytes follow but only {} do",
+                    actual_length,
+                    buf.len()
+                ),
+            ));
+        }
         Ok(buf)
     }
 
     #[inline]
     fn read_var_uint(&mut self) -> Result<BigUint> {
         let contents = self.read_var_octet_string()?;
+        // VarUInts must have at least one byte and no leading zeros
+        if contents.is_empty() || (contents.len() > 1 && contents[0] == 0) {
+            return Err(invalid_data(format!(
+                "VarUInt is not canonically encoded: {:x?}",
+                contents
+            )));
+        }
         Ok(BigUint::from_bytes_be(&contents))
     }
 }
@@ -41,12 +75,16 @@ pub trait ReadOerExt: Read + ReadBytesExt + Debug {
 // Add this trait to all Readable things when this is used
 impl<R: io::Read + ?Sized + Debug> ReadOerExt for R {}
 
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
 pub trait WriteOerExt: Write + WriteBytesExt + Debug {
     #[inline]
     fn write_var_octet_string(&mut self, string: &[u8]) -> Result<()> {
         let length = string.len();
 
-        if length < 127 {
+        if length <= 127 {
             self.write_u8(length as u8)?;
         } else {
             let bit_length_of_length = format!("{:b}", length).chars().count();
@@ -111,7 +149,7 @@ pub trait MutBufOerExt: BufMut + Sized {
         let buf = buf.into_buf();
         let length = buf.remaining();
 
-        if length < 127 {
+        if length <= 127 {
             self.put_u8(length as u8);
         } else {
             let bit_length_of_length = format!("{:b}", length).chars().count();
@@ -188,4 +226,61 @@ mod reader_ext {
             &larger_string[..]
         );
     }
+
+    #[test]
+    fn rejects_non_canonical_lengths() {
+        // 1 byte encoded with the long form
+        let short_in_long_form = vec![0x81, 0x01, 0xb0];
+        assert!(Cursor::new(short_in_long_form)
+            .read_var_octet_string()
+            .is_err());
+
+        // 256 bytes with a leading zero in the length
+        let mut leading_zero = vec![0x83, 0x00, 0x01, 0x00];
+        leading_zero.extend(vec![0xb0; 256]);
+        assert!(Cursor::new(leading_zero).read_var_octet_string().is_err());
+
+        // Length of length of 0 or more than 8 bytes
+        assert!(Cursor::new(vec![0x80]).read_var_octet_string().is_err());
+        assert!(Cursor::new(vec![0x89, 0, 0, 0, 0, 0, 0, 0, 0, 0xff])
+            .read_var_octet_string()
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_lengths_longer_than_the_data() {
+        assert!(Cursor::new(vec![0x03, 0xb0, 0xb0])
+            .read_var_octet_string()
+            .is_err());
+        assert!(
+            Cursor::new(vec![0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
+                .read_var_octet_string()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn reads_127_byte_strings_in_short_form() {
+        let mut encoded = vec![];
+        encoded.write_var_octet_string(&[0xb0; 127]).unwrap();
+        assert_eq!(encoded[0], 127);
+        assert_eq!(
+            Cursor::new(encoded).read_var_octet_string().unwrap(),
+            vec![0xb0; 127]
+        );
+    }
+
+    #[test]
+    fn rejects_non_canonical_var_uints() {
+        assert_eq!(
+            Cursor::new(vec![0x01, 0x00]).read_var_uint().unwrap(),
+            BigUint::from(0u8)
+        );
+        assert_eq!(
+            Cursor::new(vec![0x02, 0x01, 0x00]).read_var_uint().unwrap(),
+            BigUint::from(256u16)
+        );
+        assert!(Cursor::new(vec![0x00]).read_var_uint().is_err());
+        assert!(Cursor::new(vec![0x02, 0x00, 0x01]).read_var_uint().is_err());
+    }
 }
@@ -47,6 +47,9 @@ pub enum BtpPacket {
 
 impl Serializable<BtpPacket> for BtpPacket {
     fn from_bytes(bytes: &[u8]) -> Result<BtpPacket, ParseError> {
+        if bytes.is_empty() {
+            return Err(ParseError::InvalidPacket("Packet is empty".to_string()));
+        }
         match PacketType::from(bytes[0]) {
             PacketType::Message => Ok(BtpPacket::Message(BtpMessage::from_bytes(bytes)?)),
             PacketType::Response => Ok(BtpPacket::Response(BtpResponse::from_bytes(bytes)?)),
@@ -111,6 +114,20 @@ where
     Ok(protocol_data)
 }
 
+/// Strict parsing means there must not be any bytes left over after the
+/// last field (otherwise there could be multiple encodings of the same packet)
+fn ensure_fully_read<T: AsRef<[u8]>>(reader: &Cursor<T>) -> Result<(), ParseError> {
+    let length = reader.get_ref().as_ref().len() as u64;
+    if reader.position() < length {
+        Err(ParseError::InvalidPacket(format!(
+            "{} unexpected bytes at the end of the packet",
+            length - reader.position()
+        )))
+    } else {
+        Ok(())
+    }
+}
+
 fn put_protocol_data<T>(buf: &mut T, protocol_data: &[ProtocolData])
 where
     T: BufMut,
@@ -142,7 +159,9 @@ impl Serializable<BtpMessage> for BtpMessage {
         }
         let request_id = reader.read_u32::<BigEndian>()?;
         let mut contents = Cursor::new(reader.read_var_octet_string()?);
+        ensure_fully_read(&reader)?;
         let protocol_data = read_protocol_data(&mut contents)?;
+        ensure_fully_read(&contents)?;
         Ok(BtpMessage {
             request_id,
             protocol_data,
@@ -179,7 +198,9 @@ impl Serializable<BtpResponse> for BtpResponse {
         }
         let request_id = reader.read_u32::<BigEndian>()?;
         let mut contents = Cursor::new(reader.read_var_octet_string()?);
+        ensure_fully_read(&reader)?;
         let protocol_data = read_protocol_data(&mut contents)?;
+        ensure_fully_read(&contents)?;
         Ok(BtpResponse {
             request_id,
             protocol_data,
@@ -219,6 +240,7 @@ impl Serializable<BtpError> for BtpError {
         }
         let request_id = reader.read_u32::<BigEndian>()?;
         let mut contents = Cursor::new(reader.read_var_octet_string()?);
+        ensure_fully_read(&reader)?;
         let mut code: [u8; 3] = [0; 3];
         contents.read_exact(&mut code)?;
         let name = String::from_utf8(contents.read_var_octet_string()?)?;
@@ -226,6 +248,7 @@ impl Serializable<BtpError> for BtpError {
         let triggered_at = Utc.datetime_from_str(&triggered_at_string, GENERALIZED_TIME_FORMAT)?;
         let data = String::from_utf8(contents.read_var_octet_string()?)?;
         let protocol_data = read_protocol_data(&mut contents)?;
+        ensure_fully_read(&contents)?;
         Ok(BtpError {
             request_id,
             code: String::from_utf8(code.to_vec())?,
@@ -356,4 +379,81 @@ mod tests {
             assert_eq!(ERROR_1.to_bytes(), *ERROR_1_SERIALIZED);
         }
     }
+
+    mod from_bytes_properties {
+        use super::*;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        const ITERATIONS: usize = 20_000;
+
+        lazy_static! {
+            static ref VALID_PACKETS: Vec<Vec<u8>> = vec![
+                hex::decode("060000000217010204746573740002ffff0474657874010568656c6c6f")
+                    .unwrap(),
+                hex::decode("01000000811b010113736f6d65206f746865722070726f746f636f6c0003aaaaaa")
+                    .unwrap(),
+                hex::decode("02000001f52f54303010556e726561636861626c654572726f721332303138303833313032353332342e3839395a046f6f70730100").unwrap(),
+            ];
+        }
+
+        /// Whatever the input, parsing must not panic, and anything that does parse
+        /// must come out the same after being serialized and parsed again
+        fn check(bytes: &[u8]) {
+            if let Ok(packet) = BtpPacket::from_bytes(bytes) {
+                let reparsed = BtpPacket::from_bytes(&packet.to_bytes())
+                    .expect("Serialized packet could not be parsed");
+                assert_eq!(packet, reparsed, "Input: {}", hex::encode(bytes));
+            }
+        }
+
+        #[test]
+        fn random_bytes() {
+            let mut rng = StdRng::seed_from_u64(0);
+            for _ in 0..ITERATIONS {
+                let length = rng.gen_range(0, 64);
+                let mut bytes: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+                // Start most of them with a valid packet type so they get past the first check
+                if !bytes.is_empty() && rng.gen_bool(0.75) {
+                    bytes[0] = [1, 2, 6][rng.gen_range(0, 3)];
+                }
+                check(&bytes);
+            }
+        }
+
+        #[test]
+        fn mutated_packets() {
+            let mut rng = StdRng::seed_from_u64(1);
+            for _ in 0..ITERATIONS {
+                let mut bytes = VALID_PACKETS[rng.gen_range(0, VALID_PACKETS.len())].clone();
+                for _ in 0..rng.gen_range(1, 4) {
+                    let index = rng.gen_range(0, bytes.len());
+                    match rng.gen_range(0, 4) {
+                        0 => bytes[index] = rng.gen(),
+                        1 => bytes[index] ^= 1 << rng.gen_range(0, 8),
+                        2 => bytes.insert(index, rng.gen()),
+                        _ => bytes.truncate(index + 1),
+                    }
+                }
+                check(&bytes);
+            }
+        }
+
+        #[test]
+        fn valid_packets_round_trip() {
+            for bytes in VALID_PACKETS.iter() {
+                let packet = BtpPacket::from_bytes(bytes).unwrap();
+                assert_eq!(&packet.to_bytes(), bytes);
+            }
+        }
+
+        #[test]
+        fn rejects_trailing_bytes() {
+            for bytes in VALID_PACKETS.iter() {
+                let mut bytes = bytes.clone();
+                bytes.push(0);
+                assert!(BtpPacket::from_bytes(&bytes).is_err());
+            }
+            assert!(BtpPacket::from_bytes(&[]).is_err());
+        }
+    }
 }
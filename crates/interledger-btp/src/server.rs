@@ -1,6 +1,10 @@
-use super::service::{BtpOutgoingService, WsError};
+use super::service::{BtpKeepalive, BtpOutgoingService, WsError};
 use super::{packet::*, BtpAccount, BtpStore};
-use futures::{future::result, Async, AsyncSink, Future, Poll, Sink, Stream};
+use chrono::Utc;
+use futures::{
+    future::{err, result, Either},
+    Async, AsyncSink, Future, Poll, Sink, Stream,
+};
 use interledger_packet::Address;
 use interledger_service::*;
 use log::{debug, error, warn};
@@ -17,8 +21,6 @@ use warp::{
 // have not been received within this timeout
 const WEBSOCKET_TIMEOUT: Duration = Duration::from_secs(10);
 
-// const MAX_MESSAGE_SIZE: usize = 40000;
-
 /// Returns a BtpOutgoingService and a warp Filter.
 ///
 /// The BtpOutgoingService wraps all BTP/WebSocket connections that come
@@ -30,9 +32,13 @@ const WEBSOCKET_TIMEOUT: Duration = Duration::from_secs(10);
 ///
 /// The warp filter handles the websocket upgrades and adds incoming connections
 /// to the BTP service so that it will handle each of the messages.
+/// Connections are pinged as configured by the `keepalive`, and are closed
+/// if they send messages larger than `max_message_size` bytes.
 pub fn create_btp_service_and_filter<O, S, A>(
     ilp_address: Address,
     store: S,
+    keepalive: BtpKeepalive,
+    max_message_size: usize,
     next_outgoing: O,
 ) -> (
     BtpOutgoingService<O, A>,
@@ -43,31 +49,49 @@ where
     S: BtpStore<Account = A> + Clone + Send + Sync + 'static,
     A: BtpAccount + 'static,
 {
-    let service = BtpOutgoingService::new(ilp_address, next_outgoing);
+    let mut service = BtpOutgoingService::new(ilp_address, next_outgoing);
+    service
+        .keepalive(keepalive)
+        .max_message_size(max_message_size);
     let service_clone = service.clone();
+    // The fingerprint of the TLS client certificate, if the node terminated TLS and the peer sent one
+    let certificate = warp::ext::get::<CertificateFingerprint>()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify();
     let filter = warp::ws2()
-        .map(move |ws: Ws2| {
-            let store = store.clone();
-            let service_clone = service_clone.clone();
-            ws.on_upgrade(move |ws: WebSocket| {
-                // TODO set max_message_size once https://github.com/seanmonstar/warp/pull/272 is merged
+        .and(certificate)
+        .map(
+            move |ws: Ws2, certificate: Option<CertificateFingerprint>| {
+                let store = store.clone();
                 let service_clone = service_clone.clone();
-                Timeout::new(validate_auth(store, ws), WEBSOCKET_TIMEOUT)
-                    .and_then(move |(account, connection)| {
-                        debug!(
-                            "Added connection for account {}: (id: {})",
-                            account.username(),
-                            account.id()
-                        );
-                        service_clone.add_connection(account, WsWrap { connection });
-                        Ok(())
-                    })
-                    .or_else(|_| {
-                        warn!("Closing Websocket connection because of an error");
-                        Ok(())
-                    })
-            })
-        })
+                // Larger messages are rejected as they are read, before they are buffered,
+                // including the unauthenticated first message
+                let ws = ws
+                    .max_message_size(max_message_size)
+                    .max_frame_size(max_message_size);
+                ws.on_upgrade(move |ws: WebSocket| {
+                    let service_clone = service_clone.clone();
+                    Timeout::new(validate_auth(store, certificate, ws), WEBSOCKET_TIMEOUT)
+                        .and_then(move |(account, connection)| {
+                            debug!(
+                                "Added connection for account {}: (id: {})",
+                                account.username(),
+                                account.id()
+                            );
+                            // Incoming connections are not re-established by us,
+                            // so we don't need to know when they close
+                            let _ =
+                                service_clone.add_connection(account, WsWrap { connection }, None);
+                            Ok(())
+                        })
+                        .or_else(|_| {
+                            warn!("Closing Websocket connection because of an error");
+                            Ok(())
+                        })
+                })
+            },
+        )
         .boxed();
     (service, filter)
 }
@@ -93,20 +117,19 @@ where
             Ok(Async::NotReady) => Ok(Async::NotReady),
             Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
             Ok(Async::Ready(Some(message))) => {
-                let message = if message.is_ping() {
-                    tungstenite::Message::Ping(message.into_bytes())
-                } else if message.is_binary() {
+                let message = if message.is_binary() {
                     tungstenite::Message::Binary(message.into_bytes())
                 } else if message.is_text() {
                     tungstenite::Message::Text(message.to_str().unwrap_or_default().to_string())
                 } else if message.is_close() {
                     tungstenite::Message::Close(None)
+                } else if message.is_ping() {
+                    // Passed on as a Ping so that the service replies to it
+                    // rather than treating it as a response to its own Pings
+                    tungstenite::Message::Ping(message.into_bytes())
                 } else {
-                    warn!(
-                        "Got unexpected websocket message, closing connection: {:?}",
-                        message
-                    );
-                    tungstenite::Message::Close(None)
+                    // Pongs are the only other type of WebSocket message
+                    tungstenite::Message::Pong(message.into_bytes())
                 };
                 Ok(Async::Ready(Some(message)))
             }
@@ -152,10 +175,26 @@ where
                     Err(err) => Err(WsError::from(err)),
                 }
             }
+            tungstenite::Message::Ping(data) => self
+                .connection
+                .start_send(Message::ping(data))
+                .map(|result| {
+                    if let AsyncSink::NotReady(message) = result {
+                        AsyncSink::NotReady(tungstenite::Message::Ping(message.into_bytes()))
+                    } else {
+                        AsyncSink::Ready
+                    }
+                })
+                .map_err(WsError::from),
+            // warp's WebSocket type doesn't allow us to send Pongs, but it queues one
+            // whenever it reads a Ping, so replying to a Ping means flushing that one
+            tungstenite::Message::Pong(_) => self
+                .connection
+                .poll_complete()
+                .map(|_| AsyncSink::Ready)
+                .map_err(WsError::from),
             // Ignore other message types because warp's WebSocket type doesn't
-            // allow us to send any other types of messages
-            // TODO make sure warp's websocket responds to pings and/or sends them to keep the
-            // connection alive
+            // allow us to send them
             _ => Ok(AsyncSink::Ready),
         }
     }
@@ -167,11 +206,14 @@ where
 
 struct Auth {
     request_id: u32,
-    token: AuthToken,
+    token: Option<AuthToken>,
 }
 
+/// Authenticate the connection with the fingerprint of the TLS client certificate, if there is one,
+/// or else with the token from the BTP auth message
 fn validate_auth<S, A>(
     store: S,
+    certificate: Option<CertificateFingerprint>,
     connection: impl Stream<Item = Message, Error = warp::Error>
         + Sink<SinkItem = Message, SinkError = warp::Error>,
 ) -> impl Future<
@@ -183,15 +225,44 @@ fn validate_auth<S, A>(
     Error = (),
 >
 where
-    S: BtpStore<Account = A> + 'static,
+    S: BtpStore<Account = A> + Clone + 'static,
     A: BtpAccount + 'static,
 {
     get_auth(connection).and_then(move |(auth, connection)| {
-        debug!("Got BTP connection for username: {}", auth.token.username());
-        store
-            .get_account_from_btp_auth(&auth.token.username(), &auth.token.password())
-            .map_err(move |_| warn!("BTP connection does not correspond to an account"))
-            .and_then(move |account| {
+        let store_clone = store.clone();
+        let token = auth.token.clone();
+        let from_token = move || match token {
+            Some(token) => {
+                debug!("Got BTP connection for username: {}", token.username());
+                Either::A(store_clone.get_account_from_btp_auth(token.username(), token.password()))
+            }
+            None => {
+                warn!("BTP packet is missing auth token");
+                Either::B(err(()))
+            }
+        };
+        let account = match certificate {
+            Some(fingerprint) => {
+                debug!(
+                    "Got BTP connection with TLS client certificate: {}",
+                    fingerprint
+                );
+                Either::A(
+                    store
+                        .get_account_from_btp_certificate(&fingerprint)
+                        .or_else(move |_| {
+                            debug!(
+                                "No account found for TLS client certificate: {}",
+                                fingerprint
+                            );
+                            from_token()
+                        }),
+                )
+            }
+            None => Either::B(from_token()),
+        };
+        account.then(move |result| match result {
+            Ok(account) => {
                 let auth_response = Message::binary(
                     BtpResponse {
                         request_id: auth.request_id,
@@ -199,11 +270,30 @@ where
                     }
                     .to_bytes(),
                 );
-                connection
-                    .send(auth_response)
-                    .map_err(|_err| error!("warp::Error sending auth response"))
-                    .and_then(|connection| Ok((account, connection)))
-            })
+                Either::A(
+                    connection
+                        .send(auth_response)
+                        .map_err(|_err| error!("warp::Error sending auth response"))
+                        .and_then(|connection| Ok((account, connection))),
+                )
+            }
+            Err(_) => {
+                warn!("BTP connection does not correspond to an account");
+                // Tell the client why we are closing the connection
+                let auth_error = Message::binary(
+                    BtpError {
+                        request_id: auth.request_id,
+                        code: "F00".to_string(),
+                        name: "NotAcceptedError".to_string(),
+                        triggered_at: Utc::now(),
+                        data: "Invalid auth token".to_string(),
+                        protocol_data: Vec::new(),
+                    }
+                    .to_bytes(),
+                );
+                Either::B(connection.send(auth_error).then(|_| Err(())))
+            }
+        })
     })
 }
 
@@ -256,19 +346,15 @@ fn parse_auth(ws_packet: Option<Message>) -> Option<Auth> {
                         }
                     }
 
-                    match (username, token) {
+                    // Peers that authenticate with a client certificate do not need to send a token
+                    let token = match (username, token) {
                         (Some(ref username), Some(ref token)) => {
-                            return AuthToken::new(username, token)
-                                .ok()
-                                .map(|token| Auth { request_id, token });
+                            AuthToken::new(username, token).ok()
                         }
-                        (None, Some(ref token)) => {
-                            return AuthToken::from_str(token)
-                                .ok()
-                                .map(|token| Auth { request_id, token });
-                        }
-                        _ => warn!("BTP packet is missing auth token"),
-                    }
+                        (None, Some(ref token)) => AuthToken::from_str(token).ok(),
+                        _ => None,
+                    };
+                    return Some(Auth { request_id, token });
                 }
                 Err(err) => {
                     warn!(
@@ -1,10 +1,11 @@
-use super::{packet::*, BtpAccount};
+use super::{client::BtpConnectionState, errors::ProtocolDataError, packet::*, BtpAccount};
 use bytes::BytesMut;
+use chrono::Utc;
 use futures::{
-    future::err,
+    future::{err, join_all, ok, Either},
     sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
     sync::oneshot,
-    Future, Sink, Stream,
+    Future, IntoFuture, Sink, Stream,
 };
 use interledger_packet::{Address, ErrorCode, Fulfill, Packet, Prepare, Reject, RejectBuilder};
 use interledger_service::*;
@@ -13,19 +14,39 @@ use parking_lot::{Mutex, RwLock};
 use rand::random;
 use std::collections::HashMap;
 use std::{
-    convert::TryFrom, error::Error, fmt, io, iter::IntoIterator, marker::PhantomData, sync::Arc,
-    time::Duration,
+    convert::TryFrom,
+    error::Error,
+    fmt, io,
+    iter::IntoIterator,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use stream_cancel::{Trigger, Valve};
+use stream_cancel::{Trigger, Valve, Valved};
 use tokio_executor::spawn;
-use tokio_timer::Interval;
+use tokio_timer::{Delay, Interval, Timeout};
 use tungstenite::Message;
 use warp;
 
-const PING_INTERVAL: u64 = 30; // seconds
-
 type IlpResultChannel = oneshot::Sender<Result<Fulfill, Reject>>;
-type IncomingRequestBuffer<A> = UnboundedReceiver<(A, u32, Prepare)>;
+type ProtocolDataResultChannel = oneshot::Sender<Result<Vec<ProtocolData>, BtpError>>;
+// The last element is the connection the request came in on, which the response is sent back on
+type BufferedRequest<A> = (A, u32, Prepare, UnboundedSender<Message>);
+type IncomingRequestBuffer<A> = UnboundedReceiver<BufferedRequest<A>>;
+
+/// The future returned by a sub-protocol handler. It resolves to the protocol data
+/// to send back in a BTP Response, or to an error message to send back in a BTP Error.
+pub type BtpProtocolFuture = Box<dyn Future<Item = Vec<ProtocolData>, Error = String> + Send>;
+type ProtocolHandler<A> = Arc<dyn Fn(A, ProtocolData) -> BtpProtocolFuture + Send + Sync>;
+
+/// Where to send the response to a request we sent
+enum ResponseChannel {
+    Ilp(IlpResultChannel),
+    ProtocolData(ProtocolDataResultChannel),
+}
 
 #[derive(Debug)]
 pub enum WsError {
@@ -56,16 +77,64 @@ impl From<warp::Error> for WsError {
     }
 }
 
+/// The default maximum size, in bytes, of the BTP messages we accept.
+/// This leaves room for the largest possible ILP packet plus the BTP headers.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 40_000;
+
+/// The default time to wait for the Response to sub-protocol data sent with `send_protocol_data`
+pub const DEFAULT_PROTOCOL_DATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to Ping each BTP connection, and how long to wait for a response before
+/// deciding the connection has gone dead and closing it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BtpKeepalive {
+    /// How often to Ping each connection. Must not be zero
+    pub ping_interval: Duration,
+    /// How long a connection has to respond to a Ping before it is closed
+    pub pong_timeout: Duration,
+}
+
+impl Default for BtpKeepalive {
+    fn default() -> Self {
+        BtpKeepalive {
+            ping_interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// One of the WebSocket connections open for an account
+struct Connection {
+    id: usize,
+    sender: UnboundedSender<Message>,
+    /// The number of outgoing requests sent on this connection that are awaiting a response
+    pending_requests: Arc<AtomicUsize>,
+}
+
 /// A container for BTP/WebSocket connections that implements OutgoingService
 /// for sending outgoing ILP Prepare packets over one of the connected BTP connections.
+///
+/// Each account may have multiple connections open at once (for example, if the peer runs
+/// multiple instances behind a load balancer). Outgoing requests are sent on whichever of
+/// the account's connections has the fewest requests awaiting a response.
 #[derive(Clone)]
 pub struct BtpOutgoingService<O, A: Account> {
-    // TODO support multiple connections per account
     ilp_address: Address,
-    connections: Arc<RwLock<HashMap<A::AccountId, UnboundedSender<Message>>>>,
-    pending_outgoing: Arc<Mutex<HashMap<u32, IlpResultChannel>>>,
+    keepalive: BtpKeepalive,
+    max_message_size: usize,
+    protocol_data_timeout: Duration,
+    connections: Arc<RwLock<HashMap<A::AccountId, Vec<Connection>>>>,
+    next_connection_id: Arc<AtomicUsize>,
+    // Used to take turns between connections with the same number of pending requests
+    round_robin: Arc<AtomicUsize>,
+    connection_states: Arc<RwLock<HashMap<A::AccountId, (Username, BtpConnectionState)>>>,
+    // Dropping an account's Trigger stops reconnecting to it and closes the connections opened to it
+    outgoing_supervisors: Arc<Mutex<HashMap<A::AccountId, Trigger>>>,
+    // Maps the request ID to the ID of the connection it was sent on and the channel for the response
+    pending_outgoing: Arc<Mutex<HashMap<u32, (usize, ResponseChannel)>>>,
+    protocol_handlers: Arc<RwLock<HashMap<String, ProtocolHandler<A>>>>,
     pending_incoming: Arc<Mutex<Option<IncomingRequestBuffer<A>>>>,
-    incoming_sender: UnboundedSender<(A, u32, Prepare)>,
+    incoming_sender: UnboundedSender<BufferedRequest<A>>,
     next: O,
     close_all_connections: Arc<Mutex<Option<Trigger>>>,
     stream_valve: Arc<Valve>,
@@ -81,8 +150,16 @@ where
         let (close_all_connections, stream_valve) = Valve::new();
         BtpOutgoingService {
             ilp_address,
+            keepalive: BtpKeepalive::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            protocol_data_timeout: DEFAULT_PROTOCOL_DATA_TIMEOUT,
             connections: Arc::new(RwLock::new(HashMap::new())),
+            next_connection_id: Arc::new(AtomicUsize::new(0)),
+            round_robin: Arc::new(AtomicUsize::new(0)),
+            connection_states: Arc::new(RwLock::new(HashMap::new())),
+            outgoing_supervisors: Arc::new(Mutex::new(HashMap::new())),
             pending_outgoing: Arc::new(Mutex::new(HashMap::new())),
+            protocol_handlers: Arc::new(RwLock::new(HashMap::new())),
             pending_incoming: Arc::new(Mutex::new(Some(incoming_receiver))),
             incoming_sender,
             next,
@@ -91,6 +168,129 @@ where
         }
     }
 
+    /// Set how often connections are pinged and how long they have to respond.
+    /// This only applies to connections added after it is called.
+    pub fn keepalive(&mut self, keepalive: BtpKeepalive) -> &mut Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Set the maximum size, in bytes, of the WebSocket messages that will be accepted
+    /// on connections added after it is called. Connections that send larger messages are closed.
+    pub fn max_message_size(&mut self, max_message_size: usize) -> &mut Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Set how long `send_protocol_data` waits for the peer's Response before failing
+    pub fn protocol_data_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.protocol_data_timeout = timeout;
+        self
+    }
+
+    pub(crate) fn get_max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Handle BTP Messages carrying data for the given sub-protocol, such as `paychan` or `info`.
+    ///
+    /// The handler is called with the account the Message came from and the `ProtocolData`
+    /// entry for its sub-protocol. The protocol data it resolves to is sent back in a BTP Response,
+    /// or if it fails, its error message is sent back in a BTP Error. If a Message has entries
+    /// for more than one registered sub-protocol, their responses are sent back together.
+    /// Messages whose entries are all for unregistered sub-protocols are rejected with a BTP Error,
+    /// while Messages with no protocol data at all are ignored.
+    ///
+    /// Messages carrying `ilp` data are always handled as ILP packets, so an `ilp` handler is never called.
+    pub fn register_protocol_handler<F, R>(&mut self, protocol_name: &str, handler: F) -> &mut Self
+    where
+        F: Fn(A, ProtocolData) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = Vec<ProtocolData>, Error = String>,
+        R::Future: Send + 'static,
+    {
+        let handler: ProtocolHandler<A> =
+            Arc::new(move |account: A, data: ProtocolData| -> BtpProtocolFuture {
+                Box::new(handler(account, data).into_future())
+            });
+        self.protocol_handlers
+            .write()
+            .insert(protocol_name.to_string(), handler);
+        self
+    }
+
+    /// Send a BTP Message carrying the given sub-protocol data (for example, a payment channel claim)
+    /// on one of the account's open connections, and resolve to the protocol data in the peer's Response.
+    /// Fails with `ProtocolDataError::Timeout` if the peer does not respond within the `protocol_data_timeout`.
+    pub fn send_protocol_data(
+        &self,
+        account_id: A::AccountId,
+        protocol_data: Vec<ProtocolData>,
+    ) -> impl Future<Item = Vec<ProtocolData>, Error = ProtocolDataError> {
+        let connections = self.connections.read();
+        let connections = match connections.get(&account_id) {
+            Some(connections) => connections,
+            None => return Either::A(err(ProtocolDataError::NotConnected)),
+        };
+        let request_id = random::<u32>();
+        trace!(
+            "Sending protocol data request {} to account {}",
+            request_id,
+            account_id
+        );
+        let message = Message::binary(
+            BtpMessage {
+                request_id,
+                protocol_data,
+            }
+            .to_bytes(),
+        );
+        let round_robin = self.round_robin.fetch_add(1, Ordering::Relaxed);
+        let pending_outgoing = self.pending_outgoing.clone();
+        let receiver = match send_on_least_busy(connections, round_robin, message, |connection| {
+            let (sender, receiver) = oneshot::channel();
+            pending_outgoing.lock().insert(
+                request_id,
+                (connection.id, ResponseChannel::ProtocolData(sender)),
+            );
+            receiver
+        }) {
+            Ok((_connection, receiver)) => receiver,
+            Err(_) => {
+                pending_outgoing.lock().remove(&request_id);
+                return Either::A(err(ProtocolDataError::NotConnected));
+            }
+        };
+        // Keep the connections open until we've gotten the response
+        let keep_connections_open = self.close_all_connections.clone();
+        let response = receiver
+            .then(move |result| {
+                let _ = keep_connections_open;
+                result
+            })
+            .map_err(|_| ProtocolDataError::ConnectionClosed)
+            .and_then(|result| {
+                result.map_err(|error| {
+                    ProtocolDataError::Rejected(format!(
+                        "{} {}: {}",
+                        error.code, error.name, error.data
+                    ))
+                })
+            });
+        Either::B(
+            Timeout::new(response, self.protocol_data_timeout).map_err(move |err| {
+                // Stop waiting for the response so the entry doesn't stick around forever
+                pending_outgoing.lock().remove(&request_id);
+                err.into_inner().unwrap_or_else(|| {
+                    warn!(
+                        "Protocol data request {} to account {} timed out",
+                        request_id, account_id
+                    );
+                    ProtocolDataError::Timeout
+                })
+            }),
+        )
+    }
+
     /// Close all of the open WebSocket connections
     // TODO is there some more automatic way of knowing when we should close the connections?
     // The problem is that the WS client can be a server too, so it's not clear when we are done with it
@@ -99,10 +299,62 @@ where
         self.close_all_connections.lock().take();
     }
 
+    /// Stop reconnecting to the account, close the outgoing connections to it, and forget
+    /// the state of its connection. This should be called when the account is deleted.
+    pub fn disconnect(&self, account_id: A::AccountId) {
+        debug!("Closing the outgoing connections to account {}", account_id);
+        self.outgoing_supervisors.lock().remove(&account_id);
+        self.connection_states.write().remove(&account_id);
+    }
+
+    /// The username and state of each of the outgoing connections this service is keeping open
+    pub fn connection_states(&self) -> Vec<(Username, BtpConnectionState)> {
+        self.connection_states.read().values().cloned().collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn open_connections(&self, account_id: A::AccountId) -> usize {
+        self.connections
+            .read()
+            .get(&account_id)
+            .map(Vec::len)
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn set_connection_state(&self, account: &A, state: BtpConnectionState) {
+        self.connection_states
+            .write()
+            .insert(account.id(), (account.username().clone(), state));
+    }
+
+    /// Start keeping the outgoing connection to the account open, stopping whatever was keeping
+    /// it open before. The returned Valve closes when `disconnect` is called for the account
+    /// or when this is called for it again.
+    pub(crate) fn supervise_outgoing(&self, account_id: A::AccountId) -> Valve {
+        let (trigger, valve) = Valve::new();
+        // Dropping the previous Trigger closes what it was supervising
+        let previous = self.outgoing_supervisors.lock().insert(account_id, trigger);
+        if previous.is_some() {
+            debug!(
+                "Replacing the outgoing connection to account {}",
+                account_id
+            );
+        }
+        valve
+    }
+
+    /// Wrap the given stream so that it ends when `close` is called
+    pub(crate) fn until_closed<S: Stream>(&self, stream: S) -> Valved<S> {
+        self.stream_valve.wrap(stream)
+    }
+
     /// Set up a WebSocket connection so that outgoing Prepare packets can be sent to it,
     /// incoming Prepare packets are buffered in a channel (until an IncomingService is added
     /// via the handle_incoming method), and ILP Fulfill and Reject packets will be
     /// sent back to the Future that sent the outgoing request originally.
+    ///
+    /// If a `supervisor` Valve is given, the connection is also closed when it closes.
+    /// The returned Receiver resolves (with a Canceled error) once the connection closes.
     pub(crate) fn add_connection(
         &self,
         account: A,
@@ -110,15 +362,24 @@ where
             + Sink<SinkItem = Message, SinkError = WsError>
             + Send
             + 'static,
-    ) {
+        supervisor: Option<Arc<Valve>>,
+    ) -> oneshot::Receiver<()> {
         let account_id = account.id();
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let (closed_sender, closed_receiver) = oneshot::channel();
 
         // Set up a channel to forward outgoing packets to the WebSocket connection
         let (tx, rx) = unbounded();
         let (sink, stream) = connection.split();
         let (close_connection, valve) = Valve::new();
+        let (close_dead_connection, dead_connection_valve) = Valve::new();
         let stream = valve.wrap(stream);
+        let stream = dead_connection_valve.wrap(stream);
         let stream = self.stream_valve.wrap(stream);
+        let stream = match supervisor {
+            Some(supervisor) => Either::A(supervisor.wrap(stream)),
+            None => Either::B(stream),
+        };
         let forward_to_connection = sink
             .send_all(rx.map_err(|_err| {
                 WsError::Tungstenite(io::Error::from(io::ErrorKind::ConnectionAborted).into())
@@ -132,23 +393,51 @@ where
                 Ok(())
             });
 
-        // Send pings every PING_INTERVAL until the connection closes or the Service is dropped
+        // Send pings every ping_interval until the connection closes or the Service is dropped.
+        // If nothing (not even a Pong) comes back within the pong_timeout, the connection
+        // is probably half-open, so we close it
+        let BtpKeepalive {
+            ping_interval,
+            pong_timeout,
+        } = self.keepalive;
+        let last_message_received = Arc::new(Mutex::new(Instant::now()));
+        let last_message_received_clone = last_message_received.clone();
         let tx_clone = tx.clone();
         let send_pings = valve
             .wrap(
                 self.stream_valve
-                    .wrap(Interval::new_interval(Duration::from_secs(PING_INTERVAL))),
+                    .wrap(Interval::new(Instant::now() + ping_interval, ping_interval)),
             )
             .map_err(|err| {
                 warn!("Timer error on Ping interval: {:?}", err);
             })
             .for_each(move |_| {
+                let ping_sent_at = Instant::now();
                 if let Err(err) = tx_clone.unbounded_send(Message::Ping(Vec::with_capacity(0))) {
                     warn!(
                         "Error sending Ping on connection to account {}: {:?}",
                         account_id, err
                     );
                 }
+                let last_message_received = last_message_received_clone.clone();
+                Delay::new(ping_sent_at + pong_timeout)
+                    .map_err(|err| warn!("Timer error waiting for Pong: {:?}", err))
+                    .and_then(move |_| {
+                        if *last_message_received.lock() >= ping_sent_at {
+                            Ok(())
+                        } else {
+                            warn!(
+                                "Connection to account {} did not respond to Ping within {:?}, closing it",
+                                account_id, pong_timeout
+                            );
+                            Err(())
+                        }
+                    })
+            })
+            .then(move |_| {
+                // Once we stop pinging (because the connection closed or stopped
+                // responding), make sure the connection is closed
+                drop(close_dead_connection);
                 Ok(())
             });
         spawn(send_pings);
@@ -157,43 +446,71 @@ where
         // TODO do we need all this cloning?
         let pending_requests = self.pending_outgoing.clone();
         let incoming_sender = self.incoming_sender.clone();
+        let protocol_handlers = self.protocol_handlers.clone();
         let tx_clone = tx.clone();
         let handle_incoming = stream.map_err(move |err| error!("Error reading from WebSocket stream for account {}: {:?}", account_id, err)).for_each(move |message| {
+          // Any message other than the peer's own Pings, including a Pong, shows that
+          // the connection is still responding to ours
+          if !message.is_ping() {
+              *last_message_received.lock() = Instant::now();
+          }
           // Handle the packets based on whether they are an incoming request or a response to something we sent
           if message.is_binary() {
-              match parse_ilp_packet(message) {
-                Ok((request_id, Packet::Prepare(prepare))) => {
+              match parse_message(message) {
+                Ok(IncomingMessage::Ilp(request_id, Packet::Prepare(prepare))) => {
                     trace!("Got incoming Prepare packet on request ID: {} {:?}", request_id, prepare);
-                    incoming_sender.clone().unbounded_send((account.clone(), request_id, prepare))
+                    incoming_sender.clone().unbounded_send((account.clone(), request_id, prepare, tx_clone.clone()))
                         .map_err(|err| error!("Unable to buffer incoming request: {:?}", err))
                 },
-                Ok((request_id, Packet::Fulfill(fulfill))) => {
+                Ok(IncomingMessage::Ilp(request_id, Packet::Fulfill(fulfill))) => {
                   trace!("Got fulfill response to request id {}", request_id);
-                  if let Some(channel) = (*pending_requests.lock()).remove(&request_id) {
+                  if let Some((_, ResponseChannel::Ilp(channel))) = (*pending_requests.lock()).remove(&request_id) {
                     channel.send(Ok(fulfill)).map_err(|fulfill| error!("Error forwarding Fulfill packet back to the Future that sent the Prepare: {:?}", fulfill))
                   } else {
                     warn!("Got Fulfill packet that does not match an outgoing Prepare we sent: {:?}", fulfill);
                     Ok(())
                   }
                 }
-                Ok((request_id, Packet::Reject(reject))) => {
+                Ok(IncomingMessage::Ilp(request_id, Packet::Reject(reject))) => {
                   trace!("Got reject response to request id {}", request_id);
-                  if let Some(channel) = (*pending_requests.lock()).remove(&request_id) {
+                  if let Some((_, ResponseChannel::Ilp(channel))) = (*pending_requests.lock()).remove(&request_id) {
                     channel.send(Err(reject)).map_err(|reject| error!("Error forwarding Reject packet back to the Future that sent the Prepare: {:?}", reject))
                   } else {
                     warn!("Got Reject packet that does not match an outgoing Prepare we sent: {:?}", reject);
                     Ok(())
                   }
                 },
+                Ok(IncomingMessage::ProtocolData(request_id, protocol_data)) => {
+                  trace!("Got protocol data on request ID: {}", request_id);
+                  spawn(handle_protocol_data(&protocol_handlers.read(), account.clone(), request_id, protocol_data, tx_clone.clone()));
+                  Ok(())
+                }
+                Ok(IncomingMessage::ProtocolDataResponse(request_id, protocol_data)) => {
+                  trace!("Got protocol data response to request id {}", request_id);
+                  if let Some((_, ResponseChannel::ProtocolData(channel))) = (*pending_requests.lock()).remove(&request_id) {
+                    channel.send(Ok(protocol_data)).map_err(|_| error!("Error forwarding protocol data response back to the Future that sent the request"))
+                  } else {
+                    warn!("Got protocol data response that does not match a protocol data request we sent: {}", request_id);
+                    Ok(())
+                  }
+                }
+                Ok(IncomingMessage::Error(error)) => {
+                  error!("Got BTP error: {:?}", error);
+                  // Dropping the channel of an ILP request rejects it
+                  if let Some((_, ResponseChannel::ProtocolData(channel))) = (*pending_requests.lock()).remove(&error.request_id) {
+                    let _ = channel.send(Err(error));
+                  }
+                  Ok(())
+                }
                 Err(_) => {
-                  debug!("Unable to parse ILP packet from BTP packet (if this is the first time this appears, the packet was probably the auth response)");
+                  debug!("Unable to parse ILP packet from BTP packet");
                   // TODO Send error back
                   Ok(())
                 }
               }
-          } else if message.is_ping() {
+          } else if let Message::Ping(data) = message {
               trace!("Responding to Ping message from account {}", account.id());
-              tx_clone.unbounded_send(Message::Pong(Vec::new())).map_err(|err| error!("Error sending Pong message back: {:?}", err))
+              tx_clone.unbounded_send(Message::Pong(data)).map_err(|err| error!("Error sending Pong message back: {:?}", err))
           } else {
               Ok(())
           }
@@ -203,24 +520,49 @@ where
         });
 
         let connections = self.connections.clone();
+        let pending_outgoing = self.pending_outgoing.clone();
         let keep_connections_open = self.close_all_connections.clone();
         let handle_connection = handle_incoming
             .select(forward_to_connection)
             .then(move |_| {
                 let _ = keep_connections_open;
+                drop(closed_sender);
+                // Drop the channels for requests that were still waiting for a response on this
+                // connection so they are rejected right away, rather than when they expire
+                pending_outgoing
+                    .lock()
+                    .retain(|_, (id, _)| *id != connection_id);
                 let mut connections = connections.write();
-                connections.remove(&account_id);
+                let still_open = match connections.get_mut(&account_id) {
+                    Some(account_connections) => {
+                        account_connections.retain(|connection| connection.id != connection_id);
+                        account_connections.len()
+                    }
+                    None => 0,
+                };
+                if still_open == 0 {
+                    connections.remove(&account_id);
+                }
                 debug!(
-                    "WebSocket connection closed for account {} ({} connections still open)",
+                    "WebSocket connection closed for account {} ({} connections to that account still open)",
                     account_id,
-                    connections.len()
+                    still_open
                 );
                 Ok(())
             });
         spawn(handle_connection);
 
         // Save the sender side of the channel so we have a way to forward outgoing requests to the WebSocket
-        self.connections.write().insert(account_id, tx);
+        self.connections
+            .write()
+            .entry(account_id)
+            .or_insert_with(Vec::new)
+            .push(Connection {
+                id: connection_id,
+                sender: tx,
+                pending_requests: Arc::new(AtomicUsize::new(0)),
+            });
+        closed_receiver
     }
 
     /// Convert this BtpOutgoingService into a bidirectional BtpService by adding a handler for incoming requests.
@@ -234,15 +576,13 @@ where
         // Now that we're adding an incoming handler, this will spawn a task to read
         // all Prepare packets from the buffer, handle them, and send the responses back
         let mut incoming_handler_clone = incoming_handler.clone();
-        let connections_clone = self.connections.clone();
         let handle_pending_incoming = self
             .pending_incoming
             .lock()
             .take()
             .expect("handle_incoming can only be called once")
-            .for_each(move |(account, request_id, prepare)| {
+            .for_each(move |(account, request_id, prepare, connection)| {
                 let account_id = account.id();
-                let connections_clone = connections_clone.clone();
                 let request = IncomingRequest {
                     from: account,
                     prepare,
@@ -260,23 +600,14 @@ where
                             Ok(fulfill) => Packet::Fulfill(fulfill),
                             Err(reject) => Packet::Reject(reject),
                         };
-                        if let Some(connection) = connections_clone
-                            .read()
-                            .get(&account_id) {
-                            let message = ilp_packet_to_ws_message(request_id, packet);
-                            connection
-                                .clone()
-                                .unbounded_send(message)
-                                .map_err(move |err| {
-                                    error!(
-                                        "Error sending response to account: {} {:?}",
-                                        account_id, err
-                                    )
-                                })
-                            } else {
-                                error!("Error sending response to account: {}, connection was closed. {:?}", account_id, packet);
-                                Err(())
-                            }
+                        // Send the response back on the same connection the request came in on
+                        let message = ilp_packet_to_ws_message(request_id, packet);
+                        connection.unbounded_send(message).map_err(move |err| {
+                            error!(
+                                "Error sending response to account: {}, connection was closed. {:?}",
+                                account_id, err
+                            )
+                        })
                     })
             })
             .then(move |_| {
@@ -305,7 +636,7 @@ where
     /// request will be passed through to the `next` handler.
     fn send_request(&mut self, request: OutgoingRequest<A>) -> Self::Future {
         let account_id = request.to.id();
-        if let Some(connection) = (*self.connections.read()).get(&account_id) {
+        if let Some(connections) = (*self.connections.read()).get(&account_id) {
             let request_id = random::<u32>();
             let ilp_address = self.ilp_address.clone();
 
@@ -319,16 +650,23 @@ where
                 account_id
             );
 
-            match connection.unbounded_send(ilp_packet_to_ws_message(
-                request_id,
-                Packet::Prepare(request.prepare),
-            )) {
-                Ok(_) => {
-                    let (sender, receiver) = oneshot::channel();
-                    (*self.pending_outgoing.lock()).insert(request_id, sender);
+            let message = ilp_packet_to_ws_message(request_id, Packet::Prepare(request.prepare));
+            let round_robin = self.round_robin.fetch_add(1, Ordering::Relaxed);
+            let pending_outgoing = self.pending_outgoing.clone();
+            match send_on_least_busy(connections, round_robin, message, |connection| {
+                let (sender, receiver) = oneshot::channel();
+                pending_outgoing
+                    .lock()
+                    .insert(request_id, (connection.id, ResponseChannel::Ilp(sender)));
+                receiver
+            }) {
+                Ok((connection, receiver)) => {
+                    let pending_requests = connection.pending_requests.clone();
+                    pending_requests.fetch_add(1, Ordering::SeqCst);
                     Box::new(
                         receiver
                             .then(move |result| {
+                                pending_requests.fetch_sub(1, Ordering::SeqCst);
                                 // Drop the trigger here since we've gotten the response
                                 // and don't need to keep the connections open if this was the
                                 // last thing we were waiting for
@@ -354,10 +692,11 @@ where
                             }),
                     )
                 }
-                Err(send_error) => {
+                Err(_) => {
+                    pending_outgoing.lock().remove(&request_id);
                     error!(
-                        "Error sending websocket message for request {} to account {}: {:?}",
-                        request_id, account_id, send_error
+                        "Error sending websocket message for request {} to account {}: all of the account's connections are closed",
+                        request_id, account_id
                     );
                     let reject = RejectBuilder {
                         code: ErrorCode::T00_INTERNAL_ERROR,
@@ -399,6 +738,22 @@ where
     pub fn close(&self) {
         self.outgoing.close();
     }
+
+    /// Stop reconnecting to the account and close the outgoing connections to it.
+    /// See `BtpOutgoingService::disconnect`.
+    pub fn disconnect(&self, account_id: A::AccountId) {
+        self.outgoing.disconnect(account_id);
+    }
+
+    /// Send sub-protocol data to the account and resolve to the data in its Response.
+    /// See `BtpOutgoingService::send_protocol_data`.
+    pub fn send_protocol_data(
+        &self,
+        account_id: A::AccountId,
+        protocol_data: Vec<ProtocolData>,
+    ) -> impl Future<Item = Vec<ProtocolData>, Error = ProtocolDataError> {
+        self.outgoing.send_protocol_data(account_id, protocol_data)
+    }
 }
 
 impl<I, O, A> OutgoingService<A> for BtpService<I, O, A>
@@ -417,40 +772,91 @@ where
     }
 }
 
-fn parse_ilp_packet(message: Message) -> Result<(u32, Packet), ()> {
-    if let Message::Binary(data) = message {
-        let (request_id, ilp_data) = match BtpPacket::from_bytes(&data) {
-            Ok(BtpPacket::Message(message)) => {
-                let ilp_data = message
-                    .protocol_data
-                    .into_iter()
-                    .find(|proto| proto.protocol_name == "ilp")
-                    .ok_or(())?
-                    .data;
-                (message.request_id, ilp_data)
+/// Send the message on whichever connection has the fewest requests awaiting a response.
+/// Connections with the same number of pending requests take turns, based on `round_robin`.
+///
+/// `before_send` is called with each connection right before the message is sent on it,
+/// so the response can be expected before it might arrive (or the connection might close).
+/// If a connection has closed, the next one is tried. Returns the message if all of them have closed.
+fn send_on_least_busy<F, R>(
+    connections: &[Connection],
+    round_robin: usize,
+    message: Message,
+    mut before_send: F,
+) -> Result<(&Connection, R), Message>
+where
+    F: FnMut(&Connection) -> R,
+{
+    if connections.is_empty() {
+        return Err(message);
+    }
+    let mut candidates: Vec<&Connection> = connections
+        .iter()
+        .cycle()
+        .skip(round_robin % connections.len())
+        .take(connections.len())
+        .collect();
+    // The sort is stable, so ties stay in round robin order
+    candidates.sort_by_key(|connection| connection.pending_requests.load(Ordering::SeqCst));
+
+    let mut message = message;
+    for connection in candidates {
+        let result = before_send(connection);
+        match connection.sender.unbounded_send(message) {
+            Ok(_) => return Ok((connection, result)),
+            Err(send_error) => {
+                trace!(
+                    "Connection {} is closed, trying the next one",
+                    connection.id
+                );
+                message = send_error.into_inner();
             }
+        }
+    }
+    Err(message)
+}
+
+/// The BTP packets we handle, with the ILP packets separated from other sub-protocols' data
+enum IncomingMessage {
+    /// A Message or Response carrying an ILP packet
+    Ilp(u32, Packet),
+    /// A Message carrying data for other sub-protocols
+    ProtocolData(u32, Vec<ProtocolData>),
+    /// A Response carrying data for other sub-protocols
+    ProtocolDataResponse(u32, Vec<ProtocolData>),
+    Error(BtpError),
+}
+
+fn parse_message(message: Message) -> Result<IncomingMessage, ()> {
+    if let Message::Binary(data) = message {
+        let (request_id, protocol_data, is_response) = match BtpPacket::from_bytes(&data) {
+            Ok(BtpPacket::Message(message)) => (message.request_id, message.protocol_data, false),
             Ok(BtpPacket::Response(response)) => {
-                let ilp_data = response
-                    .protocol_data
-                    .into_iter()
-                    .find(|proto| proto.protocol_name == "ilp")
-                    .ok_or(())?
-                    .data;
-                (response.request_id, ilp_data)
-            }
-            Ok(BtpPacket::Error(error)) => {
-                error!("Got BTP error: {:?}", error);
-                return Err(());
+                (response.request_id, response.protocol_data, true)
             }
+            Ok(BtpPacket::Error(error)) => return Ok(IncomingMessage::Error(error)),
             Err(err) => {
                 error!("Error parsing BTP packet: {:?}", err);
                 return Err(());
             }
         };
-        if let Ok(packet) = Packet::try_from(BytesMut::from(ilp_data)) {
-            Ok((request_id, packet))
-        } else {
-            Err(())
+        match protocol_data
+            .iter()
+            .position(|proto| proto.protocol_name == "ilp")
+        {
+            Some(index) => {
+                let ilp_data = protocol_data.into_iter().nth(index).ok_or(())?.data;
+                if let Ok(packet) = Packet::try_from(BytesMut::from(ilp_data)) {
+                    Ok(IncomingMessage::Ilp(request_id, packet))
+                } else {
+                    Err(())
+                }
+            }
+            None if is_response => Ok(IncomingMessage::ProtocolDataResponse(
+                request_id,
+                protocol_data,
+            )),
+            None => Ok(IncomingMessage::ProtocolData(request_id, protocol_data)),
         }
     } else {
         error!("Got a non-binary WebSocket message");
@@ -458,6 +864,80 @@ fn parse_ilp_packet(message: Message) -> Result<(u32, Packet), ()> {
     }
 }
 
+/// Pass each of the protocol data entries to the handler registered for its sub-protocol
+/// and send the handlers' responses back on the connection the Message came in on
+fn handle_protocol_data<A: Clone>(
+    handlers: &HashMap<String, ProtocolHandler<A>>,
+    account: A,
+    request_id: u32,
+    protocol_data: Vec<ProtocolData>,
+    connection: UnboundedSender<Message>,
+) -> impl Future<Item = (), Error = ()> {
+    let mut unhandled = Vec::new();
+    let mut responses = Vec::new();
+    for data in protocol_data {
+        match handlers.get(&data.protocol_name) {
+            Some(handler) => responses.push(handler(account.clone(), data)),
+            None => unhandled.push(data.protocol_name),
+        }
+    }
+    if responses.is_empty() && unhandled.is_empty() {
+        // Only Messages carrying data for sub-protocols we don't handle get an error back
+        debug!("Ignoring BTP Message {} without protocol data", request_id);
+        return Either::A(ok(()));
+    }
+    let response = if responses.is_empty() {
+        warn!(
+            "Got protocol data for sub-protocols without a handler: {:?}",
+            unhandled
+        );
+        Either::A(ok(btp_error_message(
+            request_id,
+            "F00",
+            "NotAcceptedError",
+            format!("No handler for sub-protocols: {}", unhandled.join(", ")),
+        )))
+    } else {
+        Either::B(join_all(responses).then(move |result| {
+            Ok::<_, ()>(match result {
+                Ok(responses) => Message::binary(
+                    BtpResponse {
+                        request_id,
+                        protocol_data: responses.into_iter().flatten().collect(),
+                    }
+                    .to_bytes(),
+                ),
+                Err(message) => {
+                    debug!(
+                        "Sub-protocol handler failed for request {}: {}",
+                        request_id, message
+                    );
+                    btp_error_message(request_id, "F99", "ApplicationError", message)
+                }
+            })
+        }))
+    };
+    Either::B(response.and_then(move |message| {
+        connection
+            .unbounded_send(message)
+            .map_err(|err| error!("Error sending protocol data response: {:?}", err))
+    }))
+}
+
+fn btp_error_message(request_id: u32, code: &str, name: &str, data: String) -> Message {
+    Message::binary(
+        BtpError {
+            request_id,
+            code: code.to_string(),
+            name: name.to_string(),
+            triggered_at: Utc::now(),
+            data,
+            protocol_data: Vec::new(),
+        }
+        .to_bytes(),
+    )
+}
+
 fn ilp_packet_to_ws_message(request_id: u32, packet: Packet) -> Message {
     match packet {
         Packet::Prepare(prepare) => {
@@ -498,3 +978,62 @@ fn ilp_packet_to_ws_message(request_id: u32, packet: Packet) -> Message {
         }
     }
 }
+
+#[cfg(test)]
+mod connection_selection {
+    use super::*;
+
+    fn connection(id: usize, pending_requests: usize) -> (Connection, UnboundedReceiver<Message>) {
+        let (sender, receiver) = unbounded();
+        let connection = Connection {
+            id,
+            sender,
+            pending_requests: Arc::new(AtomicUsize::new(pending_requests)),
+        };
+        (connection, receiver)
+    }
+
+    fn send(connections: &[Connection], round_robin: usize) -> Option<usize> {
+        send_on_least_busy(connections, round_robin, Message::Ping(Vec::new()), |_| ())
+            .ok()
+            .map(|(connection, _)| connection.id)
+    }
+
+    #[test]
+    fn picks_connection_with_fewest_pending_requests() {
+        let (a, _a) = connection(0, 3);
+        let (b, _b) = connection(1, 1);
+        let (c, _c) = connection(2, 2);
+        let connections = vec![a, b, c];
+        for round_robin in 0..3 {
+            assert_eq!(send(&connections, round_robin), Some(1));
+        }
+    }
+
+    #[test]
+    fn takes_turns_between_equally_busy_connections() {
+        let (a, _a) = connection(0, 0);
+        let (b, _b) = connection(1, 0);
+        let connections = vec![a, b];
+        assert_eq!(send(&connections, 0), Some(0));
+        assert_eq!(send(&connections, 1), Some(1));
+        assert_eq!(send(&connections, 2), Some(0));
+    }
+
+    #[test]
+    fn skips_closed_connections() {
+        let (a, a_receiver) = connection(0, 0);
+        let (b, _b) = connection(1, 5);
+        drop(a_receiver);
+        let connections = vec![a, b];
+        assert_eq!(send(&connections, 0), Some(1));
+    }
+
+    #[test]
+    fn fails_if_all_connections_are_closed() {
+        let (a, a_receiver) = connection(0, 0);
+        drop(a_receiver);
+        assert_eq!(send(&[a], 0), None);
+        assert_eq!(send(&[], 0), None);
+    }
+}
@@ -10,7 +10,8 @@
 //! we know about.
 
 use bytes::Bytes;
-use futures::Future;
+use futures::{future::ok, Future};
+use interledger_router::NextHop;
 use interledger_service::Account;
 use std::collections::HashMap;
 use std::{str::FromStr, string::ToString};
@@ -18,16 +19,23 @@ use std::{str::FromStr, string::ToString};
 #[cfg(test)]
 mod fixtures;
 mod packet;
+mod policy;
+mod route_auth;
 mod routing_table;
 mod server;
 #[cfg(test)]
 mod test_helpers;
 
 pub use packet::{Mode, RouteControlRequest};
-pub use server::{CcpRouteManager, CcpRouteManagerBuilder};
+pub use policy::RoutePolicy;
+pub use route_auth::{RouteAuthAnchor, ROUTE_AUTH_CHAIN_LENGTH};
+pub use server::{CcpRouteManager, CcpRouteManagerBuilder, DEFAULT_MAX_ROUTES_PER_UPDATE};
 
 use serde::{Deserialize, Serialize};
 
+/// The routing weight of accounts that do not have one configured
+pub const DEFAULT_ROUTING_WEIGHT: u32 = 1;
+
 /// Data structure used to describe the routing relation of an account with its peers.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
@@ -86,11 +94,24 @@ pub trait CcpRoutingAccount: Account {
         self.routing_relation() == RoutingRelation::Parent
             || self.routing_relation() == RoutingRelation::Peer
     }
+
+    /// The share of the packets this account gets when the Router splits the traffic
+    /// between equally good routes
+    fn routing_weight(&self) -> u32 {
+        DEFAULT_ROUTING_WEIGHT
+    }
+
+    /// The rules for the routes we exchange with this account. If this is not set,
+    /// the `CcpRouteManager`'s default policy is used.
+    fn route_policy(&self) -> Option<&RoutePolicy> {
+        None
+    }
 }
 
 // key = Bytes, key should be Address -- TODO
 type Route<T> = HashMap<Bytes, T>;
 type LocalAndConfiguredRoutes<T> = (Route<T>, Route<T>);
+type AlternateNextHops<A> = Vec<NextHop<<A as Account>::AccountId>>;
 
 pub trait RouteManagerStore: Clone {
     type Account: CcpRoutingAccount;
@@ -113,4 +134,13 @@ pub trait RouteManagerStore: Clone {
         &mut self,
         routes: impl IntoIterator<Item = (Bytes, Self::Account)>,
     ) -> Box<dyn Future<Item = (), Error = ()> + Send>;
+
+    /// Replace the ranked next hops for the prefixes that have more than one route.
+    /// Stores that do not keep alternate next hops can use the default, which ignores them.
+    fn set_alternate_routes(
+        &mut self,
+        _routes: impl IntoIterator<Item = (Bytes, AlternateNextHops<Self::Account>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(ok(()))
+    }
 }
@@ -0,0 +1,110 @@
+use crate::packet::Route;
+use serde::{Deserialize, Serialize};
+
+/// Rules for the routes we exchange with an account, which are applied in addition
+/// to the checks the `CcpRouteManager` does for every route.
+///
+/// Each entry in the prefix lists matches all of the prefixes that start with it, so
+/// `"example.foo."` matches the routes for `example.foo.bar` and `example.foo.baz`.
+/// An empty allow list allows every prefix that is not denied.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoutePolicy {
+    /// Only accept routes from the account for these prefixes
+    pub import_allow: Vec<String>,
+    /// Never accept routes from the account for these prefixes
+    pub import_deny: Vec<String>,
+    /// Only advertise routes for these prefixes to the account
+    pub export_allow: Vec<String>,
+    /// Never advertise routes for these prefixes to the account
+    pub export_deny: Vec<String>,
+    /// Ignore routes from the account with more hops in their path than this
+    pub max_path_length: Option<usize>,
+    /// Routes from accounts with a higher local preference are chosen over all other routes,
+    /// regardless of the routing relation of the accounts or the length of the paths.
+    /// Defaults to 0.
+    pub local_preference: u32,
+}
+
+impl RoutePolicy {
+    /// Whether we should accept the route if the account advertises it to us
+    pub(crate) fn imports(&self, route: &Route) -> bool {
+        let path_too_long = self
+            .max_path_length
+            .map(|max| route.path.len() > max)
+            .unwrap_or(false);
+        !path_too_long && allowed(&self.import_allow, &self.import_deny, &route.prefix)
+    }
+
+    /// Whether we may advertise a route for the prefix to the account
+    pub(crate) fn exports(&self, prefix: &[u8]) -> bool {
+        allowed(&self.export_allow, &self.export_deny, prefix)
+    }
+
+    /// Whether the account gets different route updates than the other accounts
+    pub(crate) fn filters_exports(&self) -> bool {
+        !self.export_allow.is_empty() || !self.export_deny.is_empty()
+    }
+}
+
+fn allowed(allow: &[String], deny: &[String], prefix: &[u8]) -> bool {
+    let matches = |entry: &String| prefix.starts_with(entry.as_bytes());
+    (allow.is_empty() || allow.iter().any(matches)) && !deny.iter().any(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn route(prefix: &'static str, path_length: usize) -> Route {
+        Route {
+            prefix: Bytes::from(prefix),
+            path: vec![Bytes::from("example.hop"); path_length],
+            auth: [0; 32],
+            props: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn default_policy_allows_everything() {
+        let policy = RoutePolicy::default();
+        assert!(policy.imports(&route("example.a", 10)));
+        assert!(policy.exports(b"example.a"));
+        assert!(!policy.filters_exports());
+    }
+
+    #[test]
+    fn denied_prefixes_take_precedence() {
+        let policy = RoutePolicy {
+            import_allow: vec!["example.a".to_string()],
+            import_deny: vec!["example.a.b".to_string()],
+            ..RoutePolicy::default()
+        };
+        assert!(policy.imports(&route("example.a", 0)));
+        assert!(policy.imports(&route("example.a.c", 0)));
+        assert!(!policy.imports(&route("example.a.b.c", 0)));
+        assert!(!policy.imports(&route("example.b", 0)));
+    }
+
+    #[test]
+    fn checks_path_length() {
+        let policy = RoutePolicy {
+            max_path_length: Some(2),
+            ..RoutePolicy::default()
+        };
+        assert!(policy.imports(&route("example.a", 2)));
+        assert!(!policy.imports(&route("example.a", 3)));
+    }
+
+    #[test]
+    fn checks_exported_prefixes() {
+        let policy = RoutePolicy {
+            export_deny: vec!["example.private.".to_string()],
+            ..RoutePolicy::default()
+        };
+        assert!(policy.filters_exports());
+        assert!(policy.exports(b"example.public"));
+        assert!(!policy.exports(b"example.private.a"));
+    }
+}
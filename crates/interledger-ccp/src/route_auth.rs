@@ -0,0 +1,150 @@
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// The number of times the auth of a route is hashed to get the anchor for its prefix.
+/// Routes whose paths have more hops than this cannot be authenticated.
+pub const ROUTE_AUTH_CHAIN_LENGTH: usize = 32;
+
+/// The value the routes for a prefix are authenticated with.
+///
+/// Route auth is a hash chain: the node that originates a route derives the route's auth
+/// from its routing secret and the prefix, and each node that forwards the route hashes
+/// the auth again. The anchor is the original auth hashed `ROUTE_AUTH_CHAIN_LENGTH` times,
+/// so a route with `n` hops in its path is authentic if hashing its auth
+/// `ROUTE_AUTH_CHAIN_LENGTH - n` more times gives the anchor. Since hashes cannot be
+/// reversed, only nodes that were sent the route can advertise it, and they cannot make
+/// its path look shorter than it is.
+///
+/// It is written as 64 lowercase hex characters.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RouteAuthAnchor([u8; 32]);
+
+impl RouteAuthAnchor {
+    /// The anchor for the routes to the prefix that the node with the given secret originates
+    pub fn new(routing_secret: &[u8; 32], prefix: &[u8]) -> Self {
+        RouteAuthAnchor(hash_times(
+            &origin_auth(routing_secret, prefix),
+            ROUTE_AUTH_CHAIN_LENGTH,
+        ))
+    }
+
+    /// Check the auth of a route that has the given number of hops in its path
+    pub(crate) fn verifies(&self, auth: &[u8; 32], hops: usize) -> bool {
+        hops <= ROUTE_AUTH_CHAIN_LENGTH
+            && hash_times(auth, ROUTE_AUTH_CHAIN_LENGTH - hops) == self.0
+    }
+}
+
+impl Display for RouteAuthAnchor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for RouteAuthAnchor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RouteAuthAnchor({})", self)
+    }
+}
+
+impl FromStr for RouteAuthAnchor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes =
+            hex::decode(s).map_err(|_| "route auth anchor must be hex-encoded".to_owned())?;
+        if bytes.len() != 32 {
+            return Err("route auth anchor must be 32 hex-encoded bytes".to_owned());
+        }
+        let mut anchor = [0; 32];
+        anchor.copy_from_slice(&bytes);
+        Ok(RouteAuthAnchor(anchor))
+    }
+}
+
+impl TryFrom<String> for RouteAuthAnchor {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        RouteAuthAnchor::from_str(&value)
+    }
+}
+
+impl From<RouteAuthAnchor> for String {
+    fn from(anchor: RouteAuthAnchor) -> String {
+        anchor.to_string()
+    }
+}
+
+/// The auth of the routes to the prefix that the node with the given secret originates
+pub(crate) fn origin_auth(routing_secret: &[u8; 32], prefix: &[u8]) -> [u8; 32] {
+    let mut auth = [0; 32];
+    let sig = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, routing_secret), prefix);
+    auth.copy_from_slice(sig.as_ref());
+    auth
+}
+
+pub(crate) fn hash(preimage: &[u8; 32]) -> [u8; 32] {
+    let mut out = [0; 32];
+    out.copy_from_slice(digest(&SHA256, preimage).as_ref());
+    out
+}
+
+fn hash_times(preimage: &[u8; 32], times: usize) -> [u8; 32] {
+    (0..times).fold(*preimage, |value, _| hash(&value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    #[test]
+    fn verifies_forwarded_routes() {
+        let anchor = RouteAuthAnchor::new(&SECRET, b"example.alice");
+        let mut auth = origin_auth(&SECRET, b"example.alice");
+        for hops in 0..=ROUTE_AUTH_CHAIN_LENGTH {
+            assert!(anchor.verifies(&auth, hops));
+            auth = hash(&auth);
+        }
+        assert!(!anchor.verifies(&auth, ROUTE_AUTH_CHAIN_LENGTH + 1));
+    }
+
+    #[test]
+    fn rejects_shortened_paths() {
+        let anchor = RouteAuthAnchor::new(&SECRET, b"example.alice");
+        let auth = hash(&hash(&origin_auth(&SECRET, b"example.alice")));
+        assert!(anchor.verifies(&auth, 2));
+        assert!(!anchor.verifies(&auth, 1));
+        // Making the path longer only means the route is less likely to be used
+        assert!(anchor.verifies(&hash(&auth), 3));
+    }
+
+    #[test]
+    fn rejects_auth_for_other_prefixes_and_secrets() {
+        let anchor = RouteAuthAnchor::new(&SECRET, b"example.alice");
+        assert!(!anchor.verifies(&origin_auth(&SECRET, b"example.bob"), 0));
+        assert!(!anchor.verifies(&origin_auth(&[8; 32], b"example.alice"), 0));
+        assert!(!anchor.verifies(&[0; 32], 0));
+    }
+
+    #[test]
+    fn parses_and_displays_hex() {
+        let anchor = RouteAuthAnchor::new(&SECRET, b"example.alice");
+        let string = anchor.to_string();
+        assert_eq!(string.len(), 64);
+        assert_eq!(RouteAuthAnchor::from_str(&string).unwrap(), anchor);
+        assert!(RouteAuthAnchor::from_str("abcd").is_err());
+        assert!(RouteAuthAnchor::from_str(&"zz".repeat(32)).is_err());
+    }
+}
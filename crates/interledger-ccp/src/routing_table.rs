@@ -1,6 +1,7 @@
 use crate::packet::{Route, RouteUpdateRequest};
 use bytes::Bytes;
 use hex;
+use interledger_router::RoutingTable as PrefixMap;
 use lazy_static::lazy_static;
 use log::{debug, trace};
 use ring::rand::{SecureRandom, SystemRandom};
@@ -11,36 +12,6 @@ lazy_static! {
     static ref RANDOM: SystemRandom = SystemRandom::new();
 }
 
-#[derive(Debug)]
-struct PrefixMap<T> {
-    map: HashMap<Bytes, T>,
-}
-
-impl<T> PrefixMap<T> {
-    pub fn new() -> Self {
-        PrefixMap {
-            map: HashMap::new(),
-        }
-    }
-
-    pub fn insert(&mut self, prefix: Bytes, item: T) -> bool {
-        self.map.insert(prefix.clone(), item).is_none()
-    }
-
-    pub fn remove(&mut self, prefix: &[u8]) -> bool {
-        self.map.remove(prefix).is_some()
-    }
-
-    pub fn resolve(&self, prefix: &[u8]) -> Option<&T> {
-        // TODO use parallel iterator
-        self.map
-            .iter()
-            .filter(|(p, _)| prefix.starts_with(p))
-            .max_by_key(|(p, _)| p.len())
-            .map(|(_prefix, item)| item)
-    }
-}
-
 /// The routing table is identified by an ID (a UUID in array form) and an "epoch".
 /// When an Interledger node reloads, it will generate a new UUID for its routing table.
 /// Each update applied increments the epoch number, so it acts as a version tracker.
@@ -91,30 +62,31 @@ where
 
     /// Set a particular route, overwriting the one that was there before
     pub fn set_route(&mut self, prefix: Bytes, account: A, route: Route) {
-        self.prefix_map.remove(&prefix[..]);
         self.prefix_map.insert(prefix, (account, route));
     }
 
     /// Remove the route for the given prefix. Returns true if that route existed before
     pub fn delete_route(&mut self, prefix: &[u8]) -> bool {
-        self.prefix_map.remove(prefix)
+        self.prefix_map.remove(prefix).is_some()
     }
 
     /// Add the given route. Returns true if that routed did not already exist
     pub fn add_route(&mut self, account: A, route: Route) -> bool {
         self.prefix_map
             .insert(route.prefix.clone(), (account, route))
+            .is_none()
     }
 
     /// Get the best route we have for the given prefix
     pub fn get_route(&self, prefix: &[u8]) -> Option<&(A, Route)> {
-        self.prefix_map.resolve(prefix)
+        self.prefix_map
+            .resolve(prefix)
+            .map(|(_prefix, account_and_route)| account_and_route)
     }
 
     pub fn get_simplified_table(&self) -> HashMap<Bytes, A> {
         HashMap::from_iter(
             self.prefix_map
-                .map
                 .iter()
                 .map(|(address, (account, _route))| (address.clone(), account.clone())),
         )
@@ -210,16 +182,17 @@ mod prefix_map {
     #[test]
     fn doesnt_insert_duplicates() {
         let mut map = PrefixMap::new();
-        assert!(map.insert(Bytes::from("example.a"), 1));
-        assert!(!map.insert(Bytes::from("example.a"), 1));
+        assert!(map.insert(Bytes::from("example.a"), 1).is_none());
+        assert!(map.insert(Bytes::from("example.a"), 1).is_some());
+        assert_eq!(map.len(), 1);
     }
 
     #[test]
     fn removes_entry() {
         let mut map = PrefixMap::new();
-        assert!(map.insert(Bytes::from("example.a"), 1));
-        assert!(map.remove(&b"example.a"[..]));
-        assert!(map.map.is_empty());
+        assert!(map.insert(Bytes::from("example.a"), 1).is_none());
+        assert!(map.remove(&b"example.a"[..]).is_some());
+        assert!(map.is_empty());
     }
 
     #[test]
@@ -229,9 +202,9 @@ mod prefix_map {
         map.insert(Bytes::from("example.a.b.c"), 2);
         map.insert(Bytes::from("example.a.b"), 3);
 
-        assert_eq!(map.resolve(b"example.a").unwrap(), &1);
-        assert_eq!(map.resolve(b"example.a.b.c").unwrap(), &2);
-        assert_eq!(map.resolve(b"example.a.b.c.d.e").unwrap(), &2);
+        assert_eq!(map.resolve(b"example.a").unwrap().1, &1);
+        assert_eq!(map.resolve(b"example.a.b.c").unwrap().1, &2);
+        assert_eq!(map.resolve(b"example.a.b.c.d.e").unwrap().1, &2);
         assert!(map.resolve(b"example.other").is_none());
     }
 }
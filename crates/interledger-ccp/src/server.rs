@@ -5,35 +5,36 @@ use crate::{
         Mode, Route, RouteControlRequest, RouteUpdateRequest, CCP_CONTROL_DESTINATION,
         CCP_RESPONSE, CCP_UPDATE_DESTINATION,
     },
+    route_auth::{hash, origin_auth, RouteAuthAnchor},
     routing_table::RoutingTable,
-    CcpRoutingAccount, RouteManagerStore, RoutingRelation,
+    CcpRoutingAccount, RouteManagerStore, RoutePolicy, RoutingRelation,
 };
 use bytes::Bytes;
 use futures::{
     future::{err, join_all, ok, Either},
+    stream::iter_ok,
     Future, Stream,
 };
 #[cfg(test)]
 use interledger_packet::PrepareBuilder;
-use interledger_packet::{Address, ErrorCode, Fulfill, Reject, RejectBuilder};
+use interledger_packet::{Address, ErrorCode, Fulfill, Prepare, Reject, RejectBuilder};
+use interledger_router::NextHop;
 use interledger_service::{
     Account, AddressStore, BoxedIlpFuture, IncomingRequest, IncomingService, OutgoingRequest,
     OutgoingService,
 };
 #[cfg(test)]
 use lazy_static::lazy_static;
-use log::{debug, error, trace, warn};
+use log::{debug, error, info, trace, warn};
+use metrics::{labels, recorder, Key};
 use parking_lot::{Mutex, RwLock};
-use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
 use std::collections::HashMap;
 use std::{
-    cmp::min,
+    cmp::{max, min, Reverse},
     convert::TryFrom,
-    str,
-    sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc,
-    },
+    mem, str,
+    sync::Arc,
     time::{Duration, Instant},
 };
 use tokio_timer::Interval;
@@ -48,14 +49,12 @@ use tokio_executor::spawn;
 const DEFAULT_ROUTE_EXPIRY_TIME: u32 = 30000;
 const DEFAULT_BROADCAST_INTERVAL: u64 = 30000;
 const DUMMY_ROUTING_TABLE_ID: [u8; 16] = [0; 16];
-
-fn hash(preimage: &[u8; 32]) -> [u8; 32] {
-    let mut out = [0; 32];
-    out.copy_from_slice(digest(&SHA256, preimage).as_ref());
-    out
-}
+/// The maximum number of new and withdrawn routes we put in one Route Update Request.
+/// Larger updates are split into several requests.
+pub const DEFAULT_MAX_ROUTES_PER_UPDATE: usize = 1000;
 
 type NewAndWithdrawnRoutes = (Vec<Route>, Vec<Bytes>);
+type AlternateRoutes<AccountId> = HashMap<Bytes, Vec<NextHop<AccountId>>>;
 
 pub struct CcpRouteManagerBuilder<I, O, S> {
     /// The next request handler that will be used both to pass on requests that are not CCP messages.
@@ -69,6 +68,10 @@ pub struct CcpRouteManagerBuilder<I, O, S> {
     store: S,
     ilp_address: Address,
     broadcast_interval: u64,
+    default_route_policy: RoutePolicy,
+    routing_secret: [u8; 32],
+    route_auth_anchors: HashMap<Bytes, RouteAuthAnchor>,
+    max_routes_per_update: usize,
 }
 
 impl<I, O, S, A> CcpRouteManagerBuilder<I, O, S>
@@ -79,12 +82,20 @@ where
     A: CcpRoutingAccount + Send + Sync + 'static,
 {
     pub fn new(ilp_address: Address, store: S, outgoing: O, next_incoming: I) -> Self {
+        let mut routing_secret = [0; 32];
+        SystemRandom::new()
+            .fill(&mut routing_secret)
+            .expect("Failed to securely generate random routing secret!");
         CcpRouteManagerBuilder {
             ilp_address,
             next_incoming,
             outgoing,
             store,
             broadcast_interval: DEFAULT_BROADCAST_INTERVAL,
+            default_route_policy: RoutePolicy::default(),
+            routing_secret,
+            route_auth_anchors: HashMap::new(),
+            max_routes_per_update: DEFAULT_MAX_ROUTES_PER_UPDATE,
         }
     }
 
@@ -99,6 +110,35 @@ where
         self
     }
 
+    /// Set the route policy for the accounts that do not have one of their own
+    pub fn default_route_policy(&mut self, policy: RoutePolicy) -> &mut Self {
+        self.default_route_policy = policy;
+        self
+    }
+
+    /// Set the secret that the auth of the routes we originate is derived from.
+    /// If it is not set, a random one is used, so the anchors for our routes change
+    /// every time the service is created.
+    pub fn routing_secret(&mut self, secret: [u8; 32]) -> &mut Self {
+        self.routing_secret = secret;
+        self
+    }
+
+    /// Set the anchors that the routes for the given prefixes are authenticated with.
+    /// Routes for the prefixes under them are only accepted if they have their own anchor.
+    /// Routes for other prefixes are accepted without checking their auth.
+    pub fn route_auth_anchors(&mut self, anchors: HashMap<Bytes, RouteAuthAnchor>) -> &mut Self {
+        self.route_auth_anchors = anchors;
+        self
+    }
+
+    /// Set the maximum number of routes to send in one Route Update Request.
+    /// Updates with more new and withdrawn routes than this are split into several requests.
+    pub fn max_routes_per_update(&mut self, max_routes: usize) -> &mut Self {
+        self.max_routes_per_update = max(max_routes, 1);
+        self
+    }
+
     pub fn to_service(&self) -> CcpRouteManager<I, O, S, A> {
         #[allow(clippy::let_and_return)]
         let service = CcpRouteManager {
@@ -108,11 +148,17 @@ where
             store: self.store.clone(),
             forwarding_table: Arc::new(RwLock::new(RoutingTable::default())),
             forwarding_table_updates: Arc::new(RwLock::new(Vec::new())),
-            last_epoch_updates_sent_for: Arc::new(AtomicU32::new(0)),
             local_table: Arc::new(RwLock::new(RoutingTable::default())),
             incoming_tables: Arc::new(RwLock::new(HashMap::new())),
+            alternate_routes: Arc::new(RwLock::new(HashMap::new())),
+            default_route_policy: Arc::new(self.default_route_policy.clone()),
+            routing_secret: self.routing_secret,
+            route_auth_anchors: Arc::new(self.route_auth_anchors.clone()),
             unavailable_accounts: Arc::new(Mutex::new(HashMap::new())),
+            peer_sync_states: Arc::new(Mutex::new(HashMap::new())),
+            max_routes_per_update: self.max_routes_per_update,
         };
+        service.log_route_auth_anchor();
 
         #[cfg(not(test))]
         {
@@ -133,6 +179,15 @@ struct BackoffParams {
     skip_intervals: u8,
 }
 
+/// What we know about the routes a peer has gotten from us
+#[derive(Debug)]
+struct PeerSyncState {
+    /// Whether the peer wants us to send it route updates
+    mode: Mode,
+    /// The epoch of our forwarding table that the peer has acknowledged the updates up to
+    last_acked_epoch: u32,
+}
+
 /// The Routing Manager Service.
 ///
 /// This implements the Connector-to-Connector Protocol (CCP)
@@ -152,7 +207,6 @@ pub struct CcpRouteManager<I, O, S, A: Account> {
     /// This represents the routing table we will forward to our peers.
     /// It is the same as the local_table with our own address added to the path of each route.
     forwarding_table: Arc<RwLock<RoutingTable<A>>>,
-    last_epoch_updates_sent_for: Arc<AtomicU32>,
     /// These updates are stored such that index 0 is the transition from epoch 0 to epoch 1
     forwarding_table_updates: Arc<RwLock<Vec<NewAndWithdrawnRoutes>>>,
     /// This is the routing table we have compile from configuration and
@@ -164,12 +218,28 @@ pub struct CcpRouteManager<I, O, S, A: Account> {
     /// Updates from peers are applied to our local_table if they are better than the
     /// existing best route and if they do not attempt to overwrite configured routes.
     incoming_tables: Arc<RwLock<HashMap<A::AccountId, RoutingTable<A>>>>,
+    /// The ranked next hops for the prefixes we have more than one route for.
+    /// They are saved to the Store so that the Router can fail over to the
+    /// other routes or split the traffic between them.
+    alternate_routes: Arc<RwLock<AlternateRoutes<A::AccountId>>>,
+    /// The rules for the routes we exchange with accounts that do not have their own
+    default_route_policy: Arc<RoutePolicy>,
+    /// The auth of the routes we originate is derived from this secret
+    routing_secret: [u8; 32],
+    /// The anchors we authenticate the routes for these prefixes with
+    route_auth_anchors: Arc<HashMap<Bytes, RouteAuthAnchor>>,
     store: S,
     /// If we get final errors while sending to specific accounts, we'll
     /// wait before trying to broadcast to them
     /// This maps the account ID to the number of route brodcast intervals
     /// we should wait before trying again
     unavailable_accounts: Arc<Mutex<HashMap<A::AccountId, BackoffParams>>>,
+    /// The mode each peer asked for in its last Route Control Request and the epoch
+    /// it has the updates up to. Peers we have not sent updates to yet get the whole table.
+    /// The peers we no longer send routes to are removed when the routes are broadcast.
+    peer_sync_states: Arc<Mutex<HashMap<A::AccountId, PeerSyncState>>>,
+    /// Route Update Requests with more routes than this are split into several requests
+    max_routes_per_update: usize,
 }
 
 impl<I, O, S, A> CcpRouteManager<I, O, S, A>
@@ -207,9 +277,20 @@ where
             // release the read lock
             drop(current_ilp_address);
             *self.ilp_address.write() = ilp_address;
+            self.log_route_auth_anchor();
         }
     }
 
+    /// Log the anchor that other nodes can authenticate the routes to our address with
+    fn log_route_auth_anchor(&self) {
+        let ilp_address = self.ilp_address.read().to_bytes();
+        info!(
+            "Routes to {} can be authenticated with the anchor: {}",
+            str::from_utf8(&ilp_address).unwrap_or("<not utf8>"),
+            RouteAuthAnchor::new(&self.routing_secret, &ilp_address)
+        );
+    }
+
     pub fn broadcast_routes(&self) -> impl Future<Item = (), Error = ()> {
         let clone = self.clone();
         self.update_best_routes(None)
@@ -265,8 +346,25 @@ where
             control
         );
 
-        // TODO stop sending updates if they are in Idle mode
-        if control.mode == Mode::Sync {
+        if control.mode == Mode::Idle {
+            debug!(
+                "Account {} (id: {}) is in Idle mode, will stop sending route updates to it",
+                request.from.username(),
+                request.from.id()
+            );
+            let mut peer_sync_states = self.peer_sync_states.lock();
+            let last_acked_epoch = peer_sync_states
+                .get(&request.from.id())
+                .map(|state| state.last_acked_epoch)
+                .unwrap_or(0);
+            peer_sync_states.insert(
+                request.from.id(),
+                PeerSyncState {
+                    mode: Mode::Idle,
+                    last_acked_epoch,
+                },
+            );
+        } else {
             // Don't skip them in the route update broadcasts anymore since this
             // tells us that they are online
             // TODO what happens if they can send to us but we can't send to them?
@@ -291,6 +389,15 @@ where
                     };
                 (from_epoch_index, to_epoch_index)
             };
+            // The peer told us which epoch it has the updates up to, so the
+            // next broadcasts continue from there
+            self.peer_sync_states.lock().insert(
+                request.from.id(),
+                PeerSyncState {
+                    mode: Mode::Sync,
+                    last_acked_epoch: from_epoch_index,
+                },
+            );
 
             #[cfg(test)]
             {
@@ -363,6 +470,110 @@ where
         update
     }
 
+    /// The rules for the routes we exchange with the given account
+    fn route_policy<'a>(&'a self, account: &'a A) -> &'a RoutePolicy {
+        account.route_policy().unwrap_or(&self.default_route_policy)
+    }
+
+    /// Remove the routes the account's policy does not let us accept. They are withdrawn
+    /// from the account's table instead, in case we accepted them before the policy changed.
+    fn apply_import_policy(
+        &self,
+        account: &A,
+        mut update: RouteUpdateRequest,
+    ) -> RouteUpdateRequest {
+        let policy = self.route_policy(account);
+        let (accepted, rejected): (Vec<Route>, Vec<Route>) = update
+            .new_routes
+            .into_iter()
+            .partition(|route| policy.imports(route));
+        for route in rejected {
+            debug!(
+                "Ignoring route from account {} (id: {}) because of its route policy: {:?}",
+                account.username(),
+                account.id(),
+                route
+            );
+            update.withdrawn_routes.push(route.prefix);
+        }
+        update.new_routes = accepted;
+        update
+    }
+
+    /// Remove the routes the account's policy does not let us advertise to it. They are
+    /// withdrawn instead, in case we advertised them to the account before the policy changed.
+    fn apply_export_policy(
+        &self,
+        account: &A,
+        mut update: RouteUpdateRequest,
+    ) -> RouteUpdateRequest {
+        let policy = self.route_policy(account);
+        let (exported, filtered): (Vec<Route>, Vec<Route>) = update
+            .new_routes
+            .into_iter()
+            .partition(|route| policy.exports(&route.prefix));
+        if !filtered.is_empty() {
+            trace!(
+                "Not advertising {} routes to account {} (id: {}) because of its route policy",
+                filtered.len(),
+                account.username(),
+                account.id()
+            );
+        }
+        update.new_routes = exported;
+        update
+            .withdrawn_routes
+            .extend(filtered.into_iter().map(|route| route.prefix));
+        update
+    }
+
+    /// Remove the routes whose auth does not match the anchor for their prefix. They are
+    /// withdrawn from the account's table instead, in case we accepted a valid route for
+    /// the prefix from the account before.
+    fn verify_route_auth(&self, account: &A, mut update: RouteUpdateRequest) -> RouteUpdateRequest {
+        if self.route_auth_anchors.is_empty() {
+            return update;
+        }
+        let (valid, invalid): (Vec<Route>, Vec<Route>) = update
+            .new_routes
+            .into_iter()
+            .partition(|route| self.route_auth_is_valid(route));
+        for route in invalid {
+            warn!(
+                "Ignoring route from account {} (id: {}) because its auth does not match the anchor for the prefix: {:?}",
+                account.username(),
+                account.id(),
+                route
+            );
+            recorder().increment_counter(
+                Key::from_name_and_labels(
+                    "ccp.route_auth.failure",
+                    labels!("from_username" => account.username().to_string()),
+                ),
+                1,
+            );
+            update.withdrawn_routes.push(route.prefix);
+        }
+        update.new_routes = valid;
+        update
+    }
+
+    /// Check the route's auth if we have an anchor for its prefix. The prefixes under a
+    /// prefix with an anchor belong to the node that owns it, so their routes are only
+    /// valid if they have their own anchor (the path cannot be used to tell whether the
+    /// owner forwarded them, because it is not authenticated). The other routes cannot be
+    /// checked, so they are valid.
+    fn route_auth_is_valid(&self, route: &Route) -> bool {
+        if let Some(anchor) = self.route_auth_anchors.get(&route.prefix[..]) {
+            return anchor.verifies(&route.auth, route.path.len());
+        }
+        let segments: Vec<&[u8]> = route.prefix.split(|c| c == &b'.').collect();
+        (1..segments.len()).all(|i| {
+            let prefix = segments[0..segments.len() - i].join(&b'.');
+            !self.route_auth_anchors.contains_key(&prefix[..])
+        })
+    }
+
     /// Check if this Route Update Request is valid and, if so, apply any updates it contains.
     /// If updates are applied to the Incoming Routing Table for this peer, we will
     /// then check whether those routes are better than the current best ones we have in the
@@ -398,6 +609,8 @@ where
 
         // Filter out routes that don't make sense or that we won't accept
         let update = self.filter_routes(update);
+        let update = self.verify_route_auth(&request.from, update);
+        let update = self.apply_import_policy(&request.from, update);
 
         let mut incoming_tables = self.incoming_tables.write();
         if !&incoming_tables.contains_key(&request.from.id()) {
@@ -536,15 +749,20 @@ where
         let forwarding_table = self.forwarding_table.clone();
         let forwarding_table_updates = self.forwarding_table_updates.clone();
         let incoming_tables = self.incoming_tables.clone();
+        let alternate_routes = self.alternate_routes.clone();
+        let default_route_policy = self.default_route_policy.clone();
+        let routing_secret = self.routing_secret;
         let ilp_address = self.ilp_address.read().clone();
         let mut store = self.store.clone();
 
         self.store.get_local_and_configured_routes().and_then(
             move |(ref local_routes, ref configured_routes)| {
-                let (better_routes, withdrawn_routes) = {
+                let (better_routes, withdrawn_routes, alternates_changed) = {
                     // Note we only use a read lock here and later get a write lock if we need to update the table
                     let local_table = local_table.read();
                     let incoming_tables = incoming_tables.read();
+                    let mut alternate_routes = alternate_routes.write();
+                    let mut alternates_changed = false;
 
                     // Either check the given prefixes or check all of our local and configured routes
                     let prefixes_to_check: Box<dyn Iterator<Item = Bytes>> =
@@ -561,38 +779,55 @@ where
                         Vec::with_capacity(prefixes_to_check.size_hint().0);
                     let mut withdrawn_routes: Vec<Bytes> = Vec::new();
                     for prefix in prefixes_to_check {
-                        // See which prefixes there is now a better route for
-                        if let Some((best_next_account, best_route)) = get_best_route_for_prefix(
+                        let ranked_routes = get_ranked_routes_for_prefix(
                             local_routes,
                             configured_routes,
                             &incoming_tables,
+                            &default_route_policy,
+                            &routing_secret,
                             prefix.as_ref(),
-                        ) {
-                            if let Some((ref next_account, ref route)) =
-                                local_table.get_route(&prefix)
-                            {
+                        );
+
+                        // Keep track of the other routes the Router can use for this prefix
+                        let next_hops = if ranked_routes.len() > 1 {
+                            Some(rank_next_hops(&ranked_routes, &default_route_policy))
+                        } else {
+                            None
+                        };
+                        if alternate_routes.get(&prefix) != next_hops.as_ref() {
+                            trace!(
+                                "Prefix {} now has {} alternate routes",
+                                str::from_utf8(prefix.as_ref()).unwrap_or("<not utf8>"),
+                                next_hops.as_ref().map(Vec::len).unwrap_or(0),
+                            );
+                            if let Some(next_hops) = next_hops {
+                                alternate_routes.insert(prefix.clone(), next_hops);
+                            } else {
+                                alternate_routes.remove(&prefix);
+                            }
+                            alternates_changed = true;
+                        }
+
+                        // See which prefixes there is now a better route for
+                        if let Some((best_next_account, best_route)) =
+                            ranked_routes.into_iter().next()
+                        {
+                            if let Some((ref next_account, _)) = local_table.get_route(&prefix) {
                                 if next_account.id() == best_next_account.id() {
                                     continue;
-                                } else {
-                                    better_routes.push((
-                                        prefix.clone(),
-                                        next_account.clone(),
-                                        route.clone(),
-                                    ));
                                 }
-                            } else {
-                                better_routes.push((prefix.clone(), best_next_account, best_route));
                             }
+                            better_routes.push((prefix.clone(), best_next_account, best_route));
                         } else {
                             // No longer have a route to this prefix
                             withdrawn_routes.push(prefix);
                         }
                     }
-                    (better_routes, withdrawn_routes)
+                    (better_routes, withdrawn_routes, alternates_changed)
                 };
 
                 // Update the local and forwarding tables
-                if !better_routes.is_empty() || !withdrawn_routes.is_empty() {
+                let routes_saved = if !better_routes.is_empty() || !withdrawn_routes.is_empty() {
                     let mut local_table = local_table.write();
                     let mut forwarding_table = forwarding_table.write();
                     let mut forwarding_table_updates = forwarding_table_updates.write();
@@ -659,6 +894,16 @@ where
                 } else {
                     // The routing table hasn't changed
                     Either::B(ok(()))
+                };
+
+                if alternates_changed {
+                    let alternate_routes = alternate_routes.read().clone();
+                    Either::A(
+                        routes_saved
+                            .and_then(move |_| store.set_alternate_routes(alternate_routes)),
+                    )
+                } else {
+                    Either::B(routes_saved)
                 }
             },
         )
@@ -683,53 +928,92 @@ where
         };
         trace!("Skipping accounts: {:?}", accounts_to_skip);
         self.store
-            .get_accounts_to_send_routes_to(accounts_to_skip)
+            .get_accounts_to_send_routes_to(accounts_to_skip.clone())
             .and_then(move |mut accounts| {
-                let mut outgoing = self_clone.outgoing.clone();
                 let to_epoch_index = self_clone.forwarding_table.read().epoch();
-                let from_epoch_index = self_clone.last_epoch_updates_sent_for.swap(to_epoch_index, Ordering::SeqCst);
-
-                let route_update_request =
-                    self_clone.create_route_update(from_epoch_index, to_epoch_index);
-
-                let prepare = route_update_request.to_prepare();
                 accounts.sort_unstable_by_key(|a| a.id().to_string());
                 accounts.dedup_by_key(|a| a.id());
 
+                {
+                    let mut peer_sync_states = self_clone.peer_sync_states.lock();
+                    // Forget the peers we no longer send routes to (for example, because
+                    // their accounts were deleted)
+                    peer_sync_states.retain(|id, _| {
+                        accounts_to_skip.contains(id)
+                            || accounts.iter().any(|account| account.id() == *id)
+                    });
+                    // Peers in Idle mode do not want any updates
+                    accounts.retain(|account| {
+                        peer_sync_states
+                            .get(&account.id())
+                            .map(|state| state.mode != Mode::Idle)
+                            .unwrap_or(true)
+                    });
+                }
+
+                // Each peer gets the updates since the last epoch it acknowledged, so peers
+                // that are at the same epoch share the same update (unless the account has
+                // its own export rules)
+                let mut updates: HashMap<u32, RouteUpdateRequest> = HashMap::new();
+                let mut prepares: HashMap<u32, Vec<Prepare>> = HashMap::new();
+                let accounts: Vec<(A, u32, Vec<Prepare>)> = {
+                    let peer_sync_states = self_clone.peer_sync_states.lock();
+                    accounts
+                        .into_iter()
+                        .map(|account| {
+                            let from_epoch_index = peer_sync_states
+                                .get(&account.id())
+                                .map(|state| min(state.last_acked_epoch, to_epoch_index))
+                                .unwrap_or(0);
+                            let update = updates.entry(from_epoch_index).or_insert_with(|| {
+                                self_clone.create_route_update(from_epoch_index, to_epoch_index)
+                            });
+                            let account_prepares =
+                                if self_clone.route_policy(&account).filters_exports() {
+                                    self_clone.to_prepares(
+                                        self_clone.apply_export_policy(&account, update.clone()),
+                                    )
+                                } else {
+                                    prepares
+                                        .entry(from_epoch_index)
+                                        .or_insert_with(|| self_clone.to_prepares(update.clone()))
+                                        .clone()
+                                };
+                            (account, from_epoch_index, account_prepares)
+                        })
+                        .collect()
+                };
+
                 let broadcasting = !accounts.is_empty();
                 if broadcasting {
                     trace!(
-                        "Sending route update for epochs {} - {} to accounts: {:?} {}",
-                        from_epoch_index,
+                        "Sending route updates up to epoch {} to accounts: {}",
                         to_epoch_index,
-                        route_update_request,
                         {
                             let account_list: Vec<String> = accounts
                                 .iter()
-                                .map(|a| {
+                                .map(|(a, from_epoch_index, prepares)| {
                                     format!(
-                                        "{} (id: {}, ilp_address: {})",
+                                        "{} (id: {}, ilp_address: {}, from epoch: {}, requests: {})",
                                         a.username(),
                                         a.id(),
-                                        a.ilp_address()
+                                        a.ilp_address(),
+                                        from_epoch_index,
+                                        prepares.len()
                                     )
                                 })
                                 .collect();
                             account_list.join(", ")
                         }
                     );
+                    let self_clone2 = self_clone.clone();
                     Either::A(
-                        join_all(accounts.into_iter().map(move |account| {
-                            outgoing
-                                .send_request(OutgoingRequest {
-                                    from: account.clone(),
-                                    to: account.clone(),
-                                    original_amount: prepare.amount(),
-                                    prepare: prepare.clone(),
-                                })
+                        join_all(accounts.into_iter().map(move |(account, _, prepares)| {
+                            self_clone
+                                .send_prepares(account.clone(), prepares)
                                 .then(move |res| Ok((account, res)))
                         }))
-                        .and_then(move |results: Vec<(A, Result<Fulfill, Reject>)>| {
+                        .and_then(move |results: Vec<(A, Result<(), Reject>)>| {
                             // Handle the results of the route broadcast attempts
                             trace!("Updating unavailable accounts");
                             let mut unavailable_accounts = unavailable_accounts.lock();
@@ -759,6 +1043,7 @@ where
                                         if unavailable_accounts.remove(&account.id()).is_some() {
                                             debug!("Account {} (id: {}) is no longer unavailable, resuming route broadcasts", account.username(), account.id());
                                         }
+                                        self_clone2.acknowledge_epoch(account.id(), to_epoch_index);
                                     }
                                 }
                             }
@@ -797,11 +1082,11 @@ where
         // or the updates is that there isn't necessarily an Account that
         // corresponds to this ILP address)
         if start == 0 {
+            let prefix = self.ilp_address.read().to_bytes();
             new_routes.push(Route {
-                prefix: self.ilp_address.read().to_bytes(),
+                auth: origin_auth(&self.routing_secret, &prefix),
+                prefix,
                 path: Vec::new(),
-                // TODO what should we include here?
-                auth: [0; 32],
                 props: Vec::new(),
             });
         }
@@ -858,41 +1143,124 @@ where
         from_epoch_index: u32,
         to_epoch_index: u32,
     ) -> impl Future<Item = (), Error = ()> {
-        let prepare = self
-            .create_route_update(from_epoch_index, to_epoch_index)
-            .to_prepare();
+        let update = self.create_route_update(from_epoch_index, to_epoch_index);
+        let prepares = self.to_prepares(self.apply_export_policy(&account, update));
         let account_id = account.id();
         debug!(
-            "Sending individual route update to account: {} for epochs from: {} to: {}",
-            account_id, from_epoch_index, to_epoch_index
+            "Sending individual route update to account: {} for epochs from: {} to: {} in {} request(s)",
+            account_id,
+            from_epoch_index,
+            to_epoch_index,
+            prepares.len()
         );
-        self.outgoing
-            .clone()
-            .send_request(OutgoingRequest {
-                from: account.clone(),
-                to: account,
-                original_amount: prepare.amount(),
-                prepare,
-            })
-            .and_then(|_| Ok(()))
-            .then(move |result| {
-                if let Err(err) = result {
-                    error!(
-                        "Error sending route update to account {}: {:?}",
-                        account_id, err
-                    )
-                }
-                Ok(())
-            })
+        let self_clone = self.clone();
+        self.send_prepares(account, prepares).then(move |result| {
+            match result {
+                Ok(_) => self_clone.acknowledge_epoch(account_id, to_epoch_index),
+                Err(err) => error!(
+                    "Error sending route update to account {}: {:?}",
+                    account_id, err
+                ),
+            }
+            Ok(())
+        })
+    }
+
+    /// Split the update into Route Update Requests with at most `max_routes_per_update` routes each
+    fn to_prepares(&self, update: RouteUpdateRequest) -> Vec<Prepare> {
+        split_route_update(update, self.max_routes_per_update)
+            .iter()
+            .map(RouteUpdateRequest::to_prepare)
+            .collect()
+    }
+
+    /// Send the Route Update Requests to the account one after the other,
+    /// stopping at the first one that is rejected
+    fn send_prepares(
+        &self,
+        account: A,
+        prepares: Vec<Prepare>,
+    ) -> impl Future<Item = (), Error = Reject> {
+        let outgoing = self.outgoing.clone();
+        iter_ok(prepares).for_each(move |prepare| {
+            outgoing
+                .clone()
+                .send_request(OutgoingRequest {
+                    from: account.clone(),
+                    to: account.clone(),
+                    original_amount: prepare.amount(),
+                    prepare,
+                })
+                .map(|_| ())
+        })
+    }
+
+    /// Remember that the peer has acknowledged the updates up to the given epoch,
+    /// so the next broadcast only includes the updates after it
+    fn acknowledge_epoch(&self, account_id: A::AccountId, epoch: u32) {
+        let mut peer_sync_states = self.peer_sync_states.lock();
+        let state = peer_sync_states.entry(account_id).or_insert(PeerSyncState {
+            mode: Mode::Sync,
+            last_acked_epoch: 0,
+        });
+        state.last_acked_epoch = max(state.last_acked_epoch, epoch);
     }
 }
 
-fn get_best_route_for_prefix<A: CcpRoutingAccount>(
+/// Split a Route Update Request into requests with at most `max_routes` new and withdrawn
+/// routes each. The first request covers the whole epoch range of the update and the
+/// others only the last epoch, so the peer applies them on top of the first one.
+fn split_route_update(
+    mut update: RouteUpdateRequest,
+    max_routes: usize,
+) -> Vec<RouteUpdateRequest> {
+    if update.new_routes.len() + update.withdrawn_routes.len() <= max_routes {
+        return vec![update];
+    }
+
+    let mut new_routes = mem::take(&mut update.new_routes).into_iter();
+    let mut withdrawn_routes = mem::take(&mut update.withdrawn_routes).into_iter();
+    let mut chunks: Vec<RouteUpdateRequest> = Vec::new();
+    loop {
+        let withdrawn: Vec<Bytes> = withdrawn_routes.by_ref().take(max_routes).collect();
+        let new: Vec<Route> = new_routes
+            .by_ref()
+            .take(max_routes - withdrawn.len())
+            .collect();
+        if new.is_empty() && withdrawn.is_empty() {
+            return chunks;
+        }
+        let from_epoch_index = if chunks.is_empty() {
+            update.from_epoch_index
+        } else {
+            update.to_epoch_index
+        };
+        chunks.push(RouteUpdateRequest {
+            from_epoch_index,
+            new_routes: new,
+            withdrawn_routes: withdrawn,
+            ..update.clone()
+        });
+    }
+}
+
+/// Get the routes for the given prefix, best first.
+///
+/// Configured and local routes take precedence, so if there is one of those it is the only
+/// route returned. Otherwise, the routes from our peers' tables are ranked by the local
+/// preference and routing relation of the account (child > peer > parent), the length of
+/// the path and the account ID.
+///
+/// The configured and local routes originate from us, so their auth is derived from our
+/// routing secret.
+fn get_ranked_routes_for_prefix<A: CcpRoutingAccount>(
     local_routes: &HashMap<Bytes, A>,
     configured_routes: &HashMap<Bytes, A>,
     incoming_tables: &HashMap<A::AccountId, RoutingTable<A>>,
+    default_policy: &RoutePolicy,
+    routing_secret: &[u8; 32],
     prefix: &[u8],
-) -> Option<(A, Route)> {
+) -> Vec<(A, Route)> {
     // Check if we have a configured route for that specific prefix
     // or any shorter prefix ("example.a.b.c" will match "example.a.b" and "example.a")
     // Note that this logic is duplicated from the Address type. We are not using
@@ -902,63 +1270,107 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
     for i in 0..segments.len() {
         let prefix = &segments[0..segments.len() - i].join(&b'.');
         if let Some(account) = configured_routes.get(prefix.as_ref() as &[u8]) {
-            return Some((
+            let prefix = account.ilp_address().to_bytes();
+            return vec![(
                 account.clone(),
                 Route {
-                    prefix: account.ilp_address().to_bytes(),
-                    auth: [0; 32],
+                    auth: origin_auth(routing_secret, &prefix),
+                    prefix,
                     path: Vec::new(),
                     props: Vec::new(),
                 },
-            ));
+            )];
         }
     }
 
     if let Some(account) = local_routes.get(prefix) {
-        return Some((
+        let prefix = account.ilp_address().to_bytes();
+        return vec![(
             account.clone(),
             Route {
-                prefix: account.ilp_address().to_bytes(),
-                auth: [0; 32],
+                auth: origin_auth(routing_secret, &prefix),
+                prefix,
                 path: Vec::new(),
                 props: Vec::new(),
             },
-        ));
+        )];
     }
 
-    let mut candidate_routes = incoming_tables
+    let mut candidate_routes: Vec<(A, Route)> = incoming_tables
         .values()
-        .filter_map(|incoming_table| incoming_table.get_route(prefix));
-    if let Some((account, route)) = candidate_routes.next() {
-        let (best_account, best_route) = candidate_routes.fold(
-            (account, route),
-            |(best_account, best_route), (account, route)| {
-                // Prioritize child > peer > parent
-                if best_account.routing_relation() > account.routing_relation() {
-                    return (best_account, best_route);
-                } else if best_account.routing_relation() < account.routing_relation() {
-                    return (account, route);
-                }
+        .filter_map(|incoming_table| incoming_table.get_route(prefix))
+        .cloned()
+        .collect();
+    candidate_routes.sort_by_cached_key(|(account, route)| {
+        (
+            route_preference(account, route, default_policy),
+            account.id().to_string(),
+        )
+    });
+    candidate_routes
+}
 
-                // Prioritize shortest path
-                if best_route.path.len() < route.path.len() {
-                    return (best_account, best_route);
-                } else if best_route.path.len() > route.path.len() {
-                    return (account, route);
-                }
+#[cfg(test)]
+fn get_best_route_for_prefix<A: CcpRoutingAccount>(
+    local_routes: &HashMap<Bytes, A>,
+    configured_routes: &HashMap<Bytes, A>,
+    incoming_tables: &HashMap<A::AccountId, RoutingTable<A>>,
+    prefix: &[u8],
+) -> Option<(A, Route)> {
+    get_ranked_routes_for_prefix(
+        local_routes,
+        configured_routes,
+        incoming_tables,
+        &RoutePolicy::default(),
+        &[0; 32],
+        prefix,
+    )
+    .into_iter()
+    .next()
+}
 
-                // Finally base it on account ID
-                if best_account.id().to_string() < account.id().to_string() {
-                    (best_account, best_route)
-                } else {
-                    (account, route)
-                }
-            },
-        );
-        Some((best_account.clone(), best_route.clone()))
-    } else {
-        None
-    }
+/// Routes with the same preference are equally good
+fn route_preference<A: CcpRoutingAccount>(
+    account: &A,
+    route: &Route,
+    default_policy: &RoutePolicy,
+) -> (Reverse<u32>, Reverse<u8>, usize) {
+    let local_preference = account
+        .route_policy()
+        .unwrap_or(default_policy)
+        .local_preference;
+    // Prioritize the highest local preference, then child > peer > parent, then the shortest path
+    (
+        Reverse(local_preference),
+        Reverse(account.routing_relation() as u8),
+        route.path.len(),
+    )
+}
+
+/// Turn the ranked routes for a prefix into the next hops the Router chooses between.
+/// The cost goes up by one each time the routes get worse, so equally good routes
+/// have the same cost.
+fn rank_next_hops<A: CcpRoutingAccount>(
+    ranked_routes: &[(A, Route)],
+    default_policy: &RoutePolicy,
+) -> Vec<NextHop<A::AccountId>> {
+    let mut cost = 0;
+    let mut previous = None;
+    ranked_routes
+        .iter()
+        .map(|(account, route)| {
+            let preference = route_preference(account, route, default_policy);
+            if previous.is_some() && previous != Some(preference) {
+                cost += 1;
+            }
+            previous = Some(preference);
+            NextHop {
+                account_id: account.id(),
+                cost,
+                weight: account.routing_weight(),
+            }
+        })
+        .collect()
 }
 
 impl<I, O, S, A> IncomingService<A> for CcpRouteManager<I, O, S, A>
@@ -1223,6 +1635,70 @@ mod handle_route_control_request {
         assert_eq!(update.current_epoch_index, 1);
         assert_eq!(update.new_routes.len(), 3);
     }
+
+    #[test]
+    fn stops_sending_updates_to_idle_peers() {
+        let (mut service, outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        let control_request = |mode: Mode| IncomingRequest {
+            from: ROUTING_ACCOUNT.clone(),
+            prepare: RouteControlRequest {
+                last_known_routing_table_id: [0; 16],
+                mode,
+                last_known_epoch: 0,
+                features: Vec::new(),
+            }
+            .to_prepare(),
+        };
+        service
+            .handle_request(control_request(Mode::Idle))
+            .wait()
+            .unwrap();
+        assert!(outgoing_requests.lock().is_empty());
+
+        service.send_route_updates().wait().unwrap();
+        let accounts: Vec<u64> = outgoing_requests
+            .lock()
+            .drain(..)
+            .map(|request| request.to.id())
+            .collect();
+        assert_eq!(accounts, vec![2]);
+
+        // They get updates again once they switch back to Sync mode
+        service
+            .handle_request(control_request(Mode::Sync))
+            .wait()
+            .unwrap();
+        service.send_route_updates().wait().unwrap();
+        let mut accounts: Vec<u64> = outgoing_requests
+            .lock()
+            .iter()
+            .map(|request| request.to.id())
+            .collect();
+        accounts.sort_unstable();
+        assert_eq!(accounts, vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn forgets_peers_it_no_longer_sends_routes_to() {
+        let (mut service, _outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        service.send_route_updates().wait().unwrap();
+        let mut peers: Vec<u64> = service.peer_sync_states.lock().keys().cloned().collect();
+        peers.sort_unstable();
+        assert_eq!(peers, vec![1, 2]);
+
+        // The account is removed
+        service.store.configured.clear();
+        service
+            .store
+            .routes
+            .lock()
+            .retain(|_, account| account.id() != 2);
+        service.send_route_updates().wait().unwrap();
+        let peers: Vec<u64> = service.peer_sync_states.lock().keys().cloned().collect();
+        assert_eq!(peers, vec![1]);
+    }
 }
 
 #[cfg(test)]
@@ -1232,6 +1708,7 @@ mod handle_route_update_request {
     use crate::test_helpers::*;
     use std::{
         iter::FromIterator,
+        str::FromStr,
         time::{Duration, SystemTime},
     };
 
@@ -1561,6 +2038,239 @@ mod handle_route_update_request {
             .is_none());
     }
 
+    #[test]
+    fn writes_alternate_routes_to_store() {
+        let mut service = test_service();
+        let other_peer = TestAccount::new(4, "example.other-peer");
+        let parent = TestAccount {
+            id: 5,
+            ilp_address: Address::from_str("example.parent").unwrap(),
+            relation: RoutingRelation::Parent,
+            route_policy: None,
+        };
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        for account in &[ROUTING_ACCOUNT.clone(), other_peer, parent] {
+            service
+                .handle_request(IncomingRequest {
+                    from: account.clone(),
+                    prepare: request.to_prepare(),
+                })
+                .wait()
+                .unwrap();
+        }
+
+        let alternate_routes = service.store.alternate_routes.lock();
+        assert_eq!(
+            alternate_routes[&b"example.prefix1"[..]],
+            vec![
+                NextHop {
+                    account_id: 1,
+                    cost: 0,
+                    weight: 1
+                },
+                NextHop {
+                    account_id: 4,
+                    cost: 0,
+                    weight: 1
+                },
+                NextHop {
+                    account_id: 5,
+                    cost: 1,
+                    weight: 1
+                },
+            ]
+        );
+        assert_eq!(
+            service.store.routes.lock()[&b"example.prefix1"[..]].id(),
+            ROUTING_ACCOUNT.id()
+        );
+    }
+
+    #[test]
+    fn applies_import_policy() {
+        let mut service = test_service();
+        let account = TestAccount {
+            route_policy: Some(RoutePolicy {
+                import_deny: vec!["example.prefix2".to_string()],
+                ..RoutePolicy::default()
+            }),
+            ..ROUTING_ACCOUNT.clone()
+        };
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        service
+            .handle_request(IncomingRequest {
+                from: account,
+                prepare: request.to_prepare(),
+            })
+            .wait()
+            .unwrap();
+        assert!((*service.local_table.read())
+            .get_route(b"example.prefix1")
+            .is_some());
+        assert!((*service.local_table.read())
+            .get_route(b"example.prefix2")
+            .is_none());
+    }
+
+    #[test]
+    fn withdraws_routes_the_import_policy_no_longer_accepts() {
+        let mut service = test_service();
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        service
+            .handle_request(IncomingRequest {
+                from: ROUTING_ACCOUNT.clone(),
+                prepare: request.to_prepare(),
+            })
+            .wait()
+            .unwrap();
+
+        // The path of the route for prefix2 is too long under the new policy
+        let account = TestAccount {
+            route_policy: Some(RoutePolicy {
+                max_path_length: Some(1),
+                ..RoutePolicy::default()
+            }),
+            ..ROUTING_ACCOUNT.clone()
+        };
+        request.from_epoch_index = 1;
+        request.to_epoch_index = 2;
+        service
+            .handle_request(IncomingRequest {
+                from: account,
+                prepare: request.to_prepare(),
+            })
+            .wait()
+            .unwrap();
+        assert!((*service.local_table.read())
+            .get_route(b"example.prefix1")
+            .is_some());
+        assert!((*service.local_table.read())
+            .get_route(b"example.prefix2")
+            .is_none());
+    }
+
+    #[test]
+    fn prefers_routes_with_higher_local_preference() {
+        let mut service = test_service();
+        let parent = TestAccount {
+            id: 5,
+            ilp_address: Address::from_str("example.parent").unwrap(),
+            relation: RoutingRelation::Parent,
+            route_policy: Some(RoutePolicy {
+                local_preference: 1,
+                ..RoutePolicy::default()
+            }),
+        };
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        for account in &[ROUTING_ACCOUNT.clone(), parent] {
+            service
+                .handle_request(IncomingRequest {
+                    from: account.clone(),
+                    prepare: request.to_prepare(),
+                })
+                .wait()
+                .unwrap();
+        }
+
+        assert_eq!(service.store.routes.lock()[&b"example.prefix1"[..]].id(), 5);
+        assert_eq!(
+            service.store.alternate_routes.lock()[&b"example.prefix1"[..]],
+            vec![
+                NextHop {
+                    account_id: 5,
+                    cost: 0,
+                    weight: 1
+                },
+                NextHop {
+                    account_id: 1,
+                    cost: 1,
+                    weight: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn verifies_route_auth() {
+        let mut service = test_service();
+        let secret = [9; 32];
+        // The child of prefix1 runs its own node, which has its own secret
+        let child_secret = [7; 32];
+        service.route_auth_anchors = Arc::new(HashMap::from_iter(
+            vec![
+                ("example.prefix1", secret),
+                ("example.prefix2", secret),
+                ("example.prefix1.child", child_secret),
+            ]
+            .into_iter()
+            .map(|(prefix, secret)| {
+                let anchor = RouteAuthAnchor::new(&secret, prefix.as_bytes());
+                (Bytes::from(prefix), anchor)
+            }),
+        ));
+        let route = |prefix: &'static str, auth: [u8; 32]| Route {
+            prefix: Bytes::from(prefix),
+            path: vec![Bytes::from("example.peer")],
+            auth,
+            props: Vec::new(),
+        };
+        let mut request = UPDATE_REQUEST_SIMPLE.clone();
+        request.from_epoch_index = 0;
+        request.to_epoch_index = 1;
+        request.new_routes = vec![
+            route(
+                "example.prefix1",
+                hash(&origin_auth(&secret, b"example.prefix1")),
+            ),
+            // The route for prefix2 was not forwarded by the peer, so the path is too short
+            route("example.prefix2", origin_auth(&secret, b"example.prefix2")),
+            // The prefixes under prefix1 need their own anchor
+            route(
+                "example.prefix1.other",
+                hash(&origin_auth(&secret, b"example.prefix1.other")),
+            ),
+            // The peer cannot make a route look like the owner of prefix1 forwarded it
+            Route {
+                prefix: Bytes::from("example.prefix1.forged"),
+                path: vec![Bytes::from("example.peer"), Bytes::from("example.prefix1")],
+                auth: hash(&hash(&origin_auth(&secret, b"example.prefix1"))),
+                props: Vec::new(),
+            },
+            // The route to the child of prefix1 is authenticated with the child's anchor
+            Route {
+                prefix: Bytes::from("example.prefix1.child"),
+                path: vec![Bytes::from("example.peer"), Bytes::from("example.prefix1")],
+                auth: hash(&hash(&origin_auth(&child_secret, b"example.prefix1.child"))),
+                props: Vec::new(),
+            },
+            // There is no anchor for this prefix so the route cannot be checked
+            route("example.other", [0; 32]),
+        ];
+        service
+            .handle_request(IncomingRequest {
+                from: ROUTING_ACCOUNT.clone(),
+                prepare: request.to_prepare(),
+            })
+            .wait()
+            .unwrap();
+
+        let routes = service.store.routes.lock();
+        assert!(routes.contains_key(&b"example.prefix1"[..]));
+        assert!(!routes.contains_key(&b"example.prefix2"[..]));
+        assert!(!routes.contains_key(&b"example.prefix1.other"[..]));
+        assert!(!routes.contains_key(&b"example.prefix1.forged"[..]));
+        assert!(routes.contains_key(&b"example.prefix1.child"[..]));
+        assert!(routes.contains_key(&b"example.other"[..]));
+    }
+
     #[test]
     fn sends_control_request_if_routing_table_id_changed() {
         let (mut service, outgoing_requests) = test_service_with_routes();
@@ -1710,6 +2420,50 @@ mod create_route_update {
         assert!(!new_routes.contains(&"example.m"));
         assert_eq!(update.withdrawn_routes[0], &Bytes::from("example.m"));
     }
+
+    #[test]
+    fn applies_export_policy() {
+        let service = test_service();
+        let account = TestAccount {
+            route_policy: Some(RoutePolicy {
+                export_allow: vec!["example.".to_string()],
+                export_deny: vec!["example.private.".to_string()],
+                ..RoutePolicy::default()
+            }),
+            ..ROUTING_ACCOUNT.clone()
+        };
+        let mut update = service.create_route_update(0, 0);
+        update.new_routes.push(Route {
+            prefix: Bytes::from("example.private.a"),
+            path: Vec::new(),
+            auth: [0; 32],
+            props: Vec::new(),
+        });
+        let update = service.apply_export_policy(&account, update);
+        assert_eq!(update.new_routes.len(), 1);
+        assert_eq!(
+            update.new_routes[0].prefix,
+            Bytes::from("example.connector")
+        );
+        assert_eq!(
+            update.withdrawn_routes,
+            vec![Bytes::from("example.private.a")]
+        );
+
+        // Accounts without a policy get every route
+        let update =
+            service.apply_export_policy(&ROUTING_ACCOUNT, service.create_route_update(0, 0));
+        assert_eq!(update.new_routes.len(), 1);
+        assert!(update.withdrawn_routes.is_empty());
+    }
+
+    #[test]
+    fn authenticates_our_own_route() {
+        let service = test_service();
+        let update = service.create_route_update(0, 0);
+        let anchor = RouteAuthAnchor::new(&service.routing_secret, b"example.connector");
+        assert!(anchor.verifies(&update.new_routes[0].auth, 0));
+    }
 }
 
 #[cfg(test)]
@@ -1860,6 +2614,76 @@ mod send_route_updates {
         );
     }
 
+    #[test]
+    fn sends_updates_since_the_last_acknowledged_epoch() {
+        let (service, outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).wait().unwrap();
+        service.send_route_updates().wait().unwrap();
+        outgoing_requests.lock().clear();
+
+        service
+            .handle_route_update_request(IncomingRequest {
+                from: TestAccount::new(10, "example.peer"),
+                prepare: RouteUpdateRequest {
+                    routing_table_id: [0; 16],
+                    current_epoch_index: 1,
+                    from_epoch_index: 0,
+                    to_epoch_index: 1,
+                    hold_down_time: 30000,
+                    speaker: Address::from_str("example.remote").unwrap(),
+                    new_routes: vec![Route {
+                        prefix: Bytes::from("example.remote"),
+                        path: vec![Bytes::from("example.peer")],
+                        auth: [0; 32],
+                        props: Vec::new(),
+                    }],
+                    withdrawn_routes: Vec::new(),
+                }
+                .to_prepare(),
+            })
+            .wait()
+            .unwrap();
+        service.send_route_updates().wait().unwrap();
+
+        let requests = outgoing_requests.lock();
+        assert_eq!(requests.len(), 3);
+        for request in requests.iter() {
+            let update = RouteUpdateRequest::try_from(&request.prepare).unwrap();
+            assert_eq!(update.to_epoch_index, 2);
+            if request.to.id() == 10 {
+                // We have not sent this account any updates before
+                assert_eq!(update.from_epoch_index, 0);
+                assert_eq!(update.new_routes.len(), 4);
+            } else {
+                assert_eq!(update.from_epoch_index, 1);
+                assert_eq!(update.new_routes.len(), 1);
+                assert_eq!(update.new_routes[0].prefix, Bytes::from("example.remote"));
+            }
+        }
+    }
+
+    #[test]
+    fn splits_large_updates() {
+        let (mut service, outgoing_requests) = test_service_with_routes();
+        service.max_routes_per_update = 2;
+        service.update_best_routes(None).wait().unwrap();
+        service.send_route_updates().wait().unwrap();
+
+        let updates: Vec<RouteUpdateRequest> = outgoing_requests
+            .lock()
+            .iter()
+            .filter(|request| request.to.id() == 1)
+            .map(|request| RouteUpdateRequest::try_from(&request.prepare).unwrap())
+            .collect();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].from_epoch_index, 0);
+        assert_eq!(updates[0].to_epoch_index, 1);
+        assert_eq!(updates[0].new_routes.len(), 2);
+        assert_eq!(updates[1].from_epoch_index, 1);
+        assert_eq!(updates[1].to_epoch_index, 1);
+        assert_eq!(updates[1].new_routes.len(), 1);
+    }
+
     #[test]
     fn backs_off_sending_to_unavailable_child_accounts() {
         let local_routes = HashMap::from_iter(vec![
@@ -1873,6 +2697,7 @@ mod send_route_updates {
                     id: 2,
                     ilp_address: Address::from_str("example.connector.other-local").unwrap(),
                     relation: RoutingRelation::Child,
+                    route_policy: None,
                 },
             ),
         ]);
@@ -1959,6 +2784,7 @@ mod send_route_updates {
             id: 2,
             ilp_address: Address::from_str("example.connector.other-local").unwrap(),
             relation: RoutingRelation::Child,
+            route_policy: None,
         };
         let local_routes = HashMap::from_iter(vec![
             (
@@ -23,16 +23,19 @@ lazy_static! {
         id: 1,
         ilp_address: Address::from_str("example.peer").unwrap(),
         relation: RoutingRelation::Peer,
+        route_policy: None,
     };
     pub static ref NON_ROUTING_ACCOUNT: TestAccount = TestAccount {
         id: 2,
         ilp_address: Address::from_str("example.me.nonroutingaccount").unwrap(),
         relation: RoutingRelation::NonRoutingAccount,
+        route_policy: None,
     };
     pub static ref CHILD_ACCOUNT: TestAccount = TestAccount {
         id: 3,
         ilp_address: Address::from_str("example.me.child").unwrap(),
         relation: RoutingRelation::Child,
+        route_policy: None,
     };
     pub static ref EXAMPLE_CONNECTOR: Address = Address::from_str("example.connector").unwrap();
     pub static ref ALICE: Username = Username::from_str("alice").unwrap();
@@ -43,6 +46,7 @@ pub struct TestAccount {
     pub id: u64,
     pub ilp_address: Address,
     pub relation: RoutingRelation,
+    pub route_policy: Option<RoutePolicy>,
 }
 
 impl TestAccount {
@@ -51,6 +55,7 @@ impl TestAccount {
             id,
             ilp_address: Address::from_str(ilp_address).unwrap(),
             relation: RoutingRelation::Peer,
+            route_policy: None,
         }
     }
 }
@@ -83,6 +88,10 @@ impl CcpRoutingAccount for TestAccount {
     fn routing_relation(&self) -> RoutingRelation {
         self.relation
     }
+
+    fn route_policy(&self) -> Option<&RoutePolicy> {
+        self.route_policy.as_ref()
+    }
 }
 
 #[derive(Clone)]
@@ -90,6 +99,7 @@ pub struct TestStore {
     pub local: HashMap<Bytes, TestAccount>,
     pub configured: HashMap<Bytes, TestAccount>,
     pub routes: Arc<Mutex<HashMap<Bytes, TestAccount>>>,
+    pub alternate_routes: Arc<Mutex<HashMap<Bytes, Vec<NextHop<u64>>>>>,
 }
 
 impl TestStore {
@@ -98,6 +108,7 @@ impl TestStore {
             local: HashMap::new(),
             configured: HashMap::new(),
             routes: Arc::new(Mutex::new(HashMap::new())),
+            alternate_routes: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -109,6 +120,7 @@ impl TestStore {
             local,
             configured,
             routes: Arc::new(Mutex::new(HashMap::new())),
+            alternate_routes: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
@@ -186,6 +198,14 @@ impl RouteManagerStore for TestStore {
         *self.routes.lock() = HashMap::from_iter(routes.into_iter());
         Box::new(ok(()))
     }
+
+    fn set_alternate_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Vec<NextHop<u64>>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        *self.alternate_routes.lock() = HashMap::from_iter(routes.into_iter());
+        Box::new(ok(()))
+    }
 }
 
 pub fn test_service() -> CcpRouteManager<
@@ -243,6 +263,7 @@ pub fn test_service_with_routes() -> (
                 id: 3,
                 ilp_address: Address::from_str("example.connector.other-local").unwrap(),
                 relation: RoutingRelation::NonRoutingAccount,
+                route_policy: None,
             },
         ),
     ]);
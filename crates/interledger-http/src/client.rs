@@ -1,22 +1,111 @@
-use super::{HttpAccount, HttpStore};
-use bytes::BytesMut;
+use super::{
+    framing::{decode_frames, encode_frame},
+    server::MAX_PACKET_SIZE,
+    HttpAccount, HttpClientSettings, HttpStore,
+};
+use bytes::{Bytes, BytesMut};
 use futures::{
-    future::{err, result},
+    future::{err, ok, result, Either},
+    sync::{
+        mpsc::{unbounded, UnboundedSender},
+        oneshot,
+    },
     Future, Stream,
 };
-use interledger_packet::{Address, ErrorCode, Fulfill, Packet, Reject, RejectBuilder};
+use interledger_packet::{Address, ErrorCode, Fulfill, Packet, Prepare, Reject, RejectBuilder};
 use interledger_service::*;
-use log::{error, trace};
+use log::{debug, error, trace, warn};
+use metrics::{labels, recorder, Key, Label};
+use parking_lot::{Mutex, RwLock};
 use reqwest::{
     header::{HeaderMap, HeaderName, HeaderValue},
-    r#async::{Chunk, Client, ClientBuilder, Response as HttpResponse},
+    r#async::{Body, Chunk, Client, ClientBuilder, Response as HttpResponse},
 };
 use std::str::FromStr;
-use std::{convert::TryFrom, marker::PhantomData, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_executor::spawn;
+use tokio_timer::Timeout;
+use url::Url;
+
+/// How long to wait before trying to open an ILP-over-HTTP stream again after the peer
+/// did not accept one. This doubles with each failed attempt, up to `MAX_STREAM_RETRY_INTERVAL`.
+const STREAM_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_STREAM_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Streams that close sooner than this after opening count as failed attempts to open one
+const MIN_STREAM_LIFETIME: Duration = Duration::from_secs(10);
+
+/// The ILP-over-HTTP stream to an account that has streaming turned on
+enum StreamState {
+    /// The stream is being opened. Packets are sent one per request in the meantime.
+    Connecting,
+    Open(OpenStream),
+    /// The peer did not accept the stream (`failures` times in a row), so packets are sent
+    /// one per request until it is time to try again.
+    Fallback {
+        retry_at: Instant,
+        failures: u32,
+    },
+}
+
+/// The state of an account's stream after `failures` failed attempts in a row to open it
+fn fallback(failures: u32) -> StreamState {
+    let delay = (STREAM_RETRY_INTERVAL * 2u32.pow(failures.saturating_sub(1).min(6)))
+        .min(MAX_STREAM_RETRY_INTERVAL);
+    StreamState::Fallback {
+        retry_at: Instant::now() + delay,
+        failures,
+    }
+}
+
+/// Where to send the response to each Prepare sent over a stream, by request ID
+type PendingRequests = Arc<Mutex<HashMap<u32, oneshot::Sender<Result<Fulfill, Reject>>>>>;
 
 #[derive(Clone)]
-pub struct HttpClientService<S, O, A> {
-    client: Client,
+struct OpenStream {
+    /// Frames written to the request body
+    frames: UnboundedSender<Bytes>,
+    /// The Prepare packets waiting for a response
+    pending: PendingRequests,
+    next_request_id: Arc<AtomicU32>,
+}
+
+/// Stops waiting for the response to a Prepare sent over a stream
+/// when the request finishes or is dropped (for example, when it times out)
+struct PendingRequest {
+    pending: PendingRequests,
+    request_id: u32,
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.pending.lock().remove(&self.request_id);
+    }
+}
+
+/// Sends ILP-over-HTTP requests to the accounts that have an HTTP URL configured.
+///
+/// Accounts with the same `HttpClientSettings` share an HTTP client, which keeps
+/// a separate pool of connections for each peer. Each request times out when
+/// the Prepare packet it carries expires.
+///
+/// For accounts with streaming turned on, packets are sent over one long-lived request
+/// to the peer's `/stream` endpoint instead. While that stream is being opened, or if
+/// the peer does not support it, packets are sent one per request. Turning streaming off
+/// for an account closes its stream the next time a packet is sent to it.
+#[derive(Clone)]
+pub struct HttpClientService<S, O, A: Account> {
+    clients: Arc<RwLock<HashMap<HttpClientSettings, Client>>>,
+    streams: Arc<Mutex<HashMap<A::AccountId, StreamState>>>,
     store: Arc<S>,
     next: O,
     account_type: PhantomData<A>,
@@ -26,34 +115,212 @@ impl<S, O, A> HttpClientService<S, O, A>
 where
     S: AddressStore + HttpStore,
     O: OutgoingService<A> + Clone,
-    A: HttpAccount,
+    A: HttpAccount + 'static,
 {
     pub fn new(store: S, next: O) -> Self {
+        HttpClientService {
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::new(store),
+            next,
+            account_type: PhantomData,
+        }
+    }
+
+    /// Get the client for the given settings, creating it the first time they are used
+    fn get_client(&self, settings: HttpClientSettings) -> Result<Client, reqwest::Error> {
+        // Streaming does not change how the client is configured
+        let settings = HttpClientSettings {
+            streaming: false,
+            ..settings
+        };
+        if let Some(client) = self.clients.read().get(&settings) {
+            return Ok(client.clone());
+        }
+
         let mut headers = HeaderMap::with_capacity(2);
         headers.insert(
             HeaderName::from_static("content-type"),
             HeaderValue::from_static("application/octet-stream"),
         );
-        let client = ClientBuilder::new()
-            .default_headers(headers)
-            .timeout(Duration::from_secs(30))
-            .build()
-            .unwrap();
-
-        HttpClientService {
-            client,
-            store: Arc::new(store),
-            next,
-            account_type: PhantomData,
+        let mut builder = ClientBuilder::new().default_headers(headers);
+        if let Some(pool_size) = settings.pool_size {
+            builder = builder.max_idle_per_host(pool_size);
         }
+        if settings.http2_prior_knowledge {
+            builder = builder.h2_prior_knowledge();
+        }
+        let client = builder.build()?;
+        Ok(self
+            .clients
+            .write()
+            .entry(settings)
+            .or_insert(client)
+            .clone())
+    }
+
+    /// Get the open stream to the account, or start opening one if there is none
+    /// (and the peer has not recently refused one).
+    /// Returns `None` if the packet should be sent in its own request.
+    fn get_stream(
+        &self,
+        account: &A,
+        client: Client,
+        url: &Url,
+        auth: &AuthToken,
+    ) -> Option<OpenStream> {
+        let account_id = account.id();
+        let failures = {
+            let mut streams = self.streams.lock();
+            let failures = match streams.get(&account_id) {
+                Some(StreamState::Open(stream)) => return Some(stream.clone()),
+                Some(StreamState::Connecting) => return None,
+                Some(StreamState::Fallback { retry_at, .. }) if Instant::now() < *retry_at => {
+                    return None
+                }
+                Some(StreamState::Fallback { failures, .. }) => *failures,
+                None => 0,
+            };
+            streams.insert(account_id, StreamState::Connecting);
+            failures
+        };
+
+        debug!("Opening ILP-over-HTTP stream to account: {}", account_id);
+        let (frames, body) = unbounded::<Bytes>();
+        let body: Box<dyn Stream<Item = Bytes, Error = io::Error> + Send> = Box::new(
+            body.map_err(|_| io::Error::new(io::ErrorKind::Other, "ILP-over-HTTP stream closed")),
+        );
+        let stream = OpenStream {
+            frames,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_request_id: Arc::new(AtomicU32::new(0)),
+        };
+        let pending = stream.pending.clone();
+        let streams = self.streams.clone();
+        let streams_clone = self.streams.clone();
+
+        let open = client
+            .post(&stream_url(url))
+            .header("authorization", auth.to_bearer())
+            .body(Body::from(body))
+            .send()
+            .then(move |result| {
+                let response = match result {
+                    Ok(response) if response.status().is_success() => {
+                        debug!("Opened ILP-over-HTTP stream to account: {}", account_id);
+                        streams
+                            .lock()
+                            .insert(account_id, StreamState::Open(stream));
+                        response
+                    }
+                    Ok(response) => {
+                        warn!("Account {} did not accept an ILP-over-HTTP stream (status: {}). Sending one packet per request instead", account_id, response.status());
+                        streams.lock().insert(account_id, fallback(failures + 1));
+                        return Either::A(ok(()));
+                    }
+                    Err(err) => {
+                        warn!("Error opening ILP-over-HTTP stream to account {}: {:?}. Sending one packet per request instead", account_id, err);
+                        streams.lock().insert(account_id, fallback(failures + 1));
+                        return Either::A(ok(()));
+                    }
+                };
+                let opened_at = Instant::now();
+
+                let pending_clone = pending.clone();
+                Either::B(
+                    decode_frames(response.into_body(), MAX_PACKET_SIZE as usize)
+                        .for_each(move |(request_id, packet)| {
+                            if let Some(sender) = pending.lock().remove(&request_id) {
+                                let response = match Packet::try_from(packet) {
+                                    Ok(Packet::Fulfill(fulfill)) => Ok(fulfill),
+                                    Ok(Packet::Reject(reject)) => Err(reject),
+                                    _ => {
+                                        error!("ILP-over-HTTP stream response did not contain a Fulfill or Reject packet");
+                                        return Err(());
+                                    }
+                                };
+                                let _ = sender.send(response);
+                            } else {
+                                trace!("Got response for unknown or expired request {} on ILP-over-HTTP stream", request_id);
+                            }
+                            Ok(())
+                        })
+                        .then(move |_| {
+                            debug!("ILP-over-HTTP stream to account {} closed", account_id);
+                            let mut streams = streams_clone.lock();
+                            // The stream was already forgotten if streaming was turned off
+                            let is_current = match streams.get(&account_id) {
+                                Some(StreamState::Open(current)) => {
+                                    Arc::ptr_eq(&current.pending, &pending_clone)
+                                }
+                                _ => false,
+                            };
+                            if is_current {
+                                if opened_at.elapsed() < MIN_STREAM_LIFETIME {
+                                    warn!("ILP-over-HTTP stream to account {} closed right after it was opened. Sending one packet per request instead", account_id);
+                                    streams.insert(account_id, fallback(failures + 1));
+                                } else {
+                                    streams.remove(&account_id);
+                                }
+                            }
+                            // Dropping the senders rejects the packets still waiting for a response
+                            pending_clone.lock().clear();
+                            Ok(())
+                        }),
+                )
+            });
+        spawn(open);
+        None
     }
 }
 
+/// The streaming endpoint is the `/stream` path under the account's ILP-over-HTTP URL
+fn stream_url(url: &Url) -> String {
+    format!("{}/stream", url.as_str().trim_end_matches('/'))
+}
+
+/// Send the Prepare over the stream and wait for the response with the same request ID
+fn send_over_stream(
+    stream: OpenStream,
+    prepare: Prepare,
+    ilp_address: Address,
+    peer: Vec<Label>,
+) -> impl Future<Item = Fulfill, Error = Reject> {
+    let request_id = stream.next_request_id.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = oneshot::channel();
+    stream.pending.lock().insert(request_id, sender);
+    let pending_request = PendingRequest {
+        pending: stream.pending.clone(),
+        request_id,
+    };
+
+    let frame = encode_frame(request_id, BytesMut::from(prepare));
+    if stream.frames.unbounded_send(frame).is_err() {
+        error!("Error sending packet over ILP-over-HTTP stream: stream is closed");
+        // Dropping the sender rejects the packet right away
+        stream.pending.lock().remove(&request_id);
+    }
+    receiver.then(move |result| {
+        drop(pending_request);
+        result.unwrap_or_else(|_| {
+            error!("ILP-over-HTTP stream closed before the response was received");
+            record_error(&peer, "connection");
+            Err(RejectBuilder {
+                code: ErrorCode::T01_PEER_UNREACHABLE,
+                message: b"ILP-over-HTTP stream closed",
+                triggered_by: Some(&ilp_address),
+                data: &[],
+            }
+            .build())
+        })
+    })
+}
+
 impl<S, O, A> OutgoingService<A> for HttpClientService<S, O, A>
 where
     S: AddressStore + HttpStore,
-    O: OutgoingService<A>,
-    A: HttpAccount,
+    O: OutgoingService<A> + Clone,
+    A: HttpAccount + 'static,
 {
     type Future = BoxedIlpFuture;
 
@@ -80,35 +347,134 @@ where
                     .build()))
                 }
             };
+            let settings = request.to.get_http_client_settings();
+            let client = match self.get_client(settings) {
+                Ok(client) => client,
+                Err(error) => {
+                    error!("Error creating HTTP client: {:?}", error);
+                    return Box::new(err(RejectBuilder {
+                        code: ErrorCode::T00_INTERNAL_ERROR,
+                        message: &[],
+                        triggered_by: Some(&ilp_address),
+                        data: &[],
+                    }
+                    .build()));
+                }
+            };
+            // There is no point waiting for the response after the packet expires
+            let timeout = match request
+                .prepare
+                .expires_at()
+                .duration_since(SystemTime::now())
+            {
+                Ok(timeout) => timeout,
+                Err(_) => {
+                    return Box::new(err(RejectBuilder {
+                        code: ErrorCode::R00_TRANSFER_TIMED_OUT,
+                        message: b"Packet expired before it was sent",
+                        triggered_by: Some(&ilp_address),
+                        data: &[],
+                    }
+                    .build()))
+                }
+            };
+
+            let peer = labels!("to_username" => request.to.username().to_string());
+            let peer_clone = peer.clone();
+            let ilp_address_clone2 = ilp_address.clone();
+            let start_time = Instant::now();
+            let stream = if settings.streaming {
+                self.get_stream(&request.to, client.clone(), url, &auth)
+            } else {
+                // Close the stream if streaming was turned off. Its request body ends once
+                // the packets already sent over it are done with it
+                if let Some(StreamState::Open(_)) = self.streams.lock().remove(&request.to.id()) {
+                    debug!(
+                        "Closing ILP-over-HTTP stream to account {} because streaming was turned off",
+                        request.to.id()
+                    );
+                }
+                None
+            };
+            let send_request = if let Some(stream) = stream {
+                Either::A(send_over_stream(
+                    stream,
+                    request.prepare,
+                    ilp_address.clone(),
+                    peer.clone(),
+                ))
+            } else {
+                Either::B(
+                    client
+                        .post(url.as_ref())
+                        .header("authorization", auth.to_bearer())
+                        .body(BytesMut::from(request.prepare).freeze())
+                        .send()
+                        .map_err(move |err| {
+                            error!("Error sending HTTP request: {:?}", err);
+                            record_error(&peer_clone, "connection");
+                            let code = if err.is_client_error() {
+                                ErrorCode::F00_BAD_REQUEST
+                            } else {
+                                ErrorCode::T01_PEER_UNREACHABLE
+                            };
+                            let message = if let Some(status) = err.status() {
+                                format!("Error sending ILP over HTTP request: {}", status)
+                            } else if let Some(err) = err.get_ref() {
+                                format!("Error sending ILP over HTTP request: {:?}", err)
+                            } else {
+                                "Error sending ILP over HTTP request".to_string()
+                            };
+                            RejectBuilder {
+                                code,
+                                message: message.as_str().as_bytes(),
+                                triggered_by: Some(&ilp_address),
+                                data: &[],
+                            }
+                            .build()
+                        })
+                        .and_then({
+                            let peer = peer.clone();
+                            move |resp| parse_packet_from_response(resp, ilp_address_clone, peer)
+                        }),
+                )
+            };
             Box::new(
-                self.client
-                    .post(url.as_ref())
-                    .header("authorization", auth.to_bearer())
-                    .body(BytesMut::from(request.prepare).freeze())
-                    .send()
-                    .map_err(move |err| {
-                        error!("Error sending HTTP request: {:?}", err);
-                        let code = if err.is_client_error() {
-                            ErrorCode::F00_BAD_REQUEST
-                        } else {
-                            ErrorCode::T01_PEER_UNREACHABLE
-                        };
-                        let message = if let Some(status) = err.status() {
-                            format!("Error sending ILP over HTTP request: {}", status)
-                        } else if let Some(err) = err.get_ref() {
-                            format!("Error sending ILP over HTTP request: {:?}", err)
-                        } else {
-                            "Error sending ILP over HTTP request".to_string()
-                        };
-                        RejectBuilder {
-                            code,
-                            message: message.as_str().as_bytes(),
-                            triggered_by: Some(&ilp_address),
-                            data: &[],
+                Timeout::new(send_request, timeout)
+                    .map_err({
+                        let peer = peer.clone();
+                        move |err| {
+                            if err.is_elapsed() {
+                                error!("ILP over HTTP request timed out");
+                                record_error(&peer, "timeout");
+                                RejectBuilder {
+                                    code: ErrorCode::R00_TRANSFER_TIMED_OUT,
+                                    message: b"ILP over HTTP request timed out",
+                                    triggered_by: Some(&ilp_address_clone2),
+                                    data: &[],
+                                }
+                                .build()
+                            } else if let Some(reject) = err.into_inner() {
+                                reject
+                            } else {
+                                error!("Timer error on ILP over HTTP request");
+                                RejectBuilder {
+                                    code: ErrorCode::T00_INTERNAL_ERROR,
+                                    message: &[],
+                                    triggered_by: Some(&ilp_address_clone2),
+                                    data: &[],
+                                }
+                                .build()
+                            }
                         }
-                        .build()
                     })
-                    .and_then(move |resp| parse_packet_from_response(resp, ilp_address_clone)),
+                    .then(move |result| {
+                        recorder().record_histogram(
+                            Key::from_name_and_labels("requests.outgoing.http.duration", peer),
+                            (Instant::now() - start_time).as_nanos() as u64,
+                        );
+                        result
+                    }),
             )
         } else {
             Box::new(self.next.send_request(request))
@@ -116,13 +482,27 @@ where
     }
 }
 
+/// Count an error communicating with the peer, by the kind of error
+fn record_error(peer: &[Label], kind: &'static str) {
+    let mut labels = peer.to_vec();
+    labels.push(Label::new("error", kind));
+    recorder().increment_counter(
+        Key::from_name_and_labels("requests.outgoing.http.error", labels),
+        1,
+    );
+}
+
 fn parse_packet_from_response(
     response: HttpResponse,
     ilp_address: Address,
+    peer: Vec<Label>,
 ) -> impl Future<Item = Fulfill, Error = Reject> {
     let ilp_address_clone = ilp_address.clone();
+    let peer_clone = peer.clone();
+    let peer_clone2 = peer.clone();
     result(response.error_for_status().map_err(|err| {
         error!("HTTP error sending ILP over HTTP packet: {:?}", err);
+        record_error(&peer, "status");
         let code = if let Some(status) = err.status() {
             if status.is_client_error() {
                 ErrorCode::F02_UNREACHABLE
@@ -146,6 +526,7 @@ fn parse_packet_from_response(
         let decoder = response.into_body();
         decoder.concat2().map_err(move |err| {
             error!("Error getting HTTP response body: {:?}", err);
+            record_error(&peer_clone, "connection");
             RejectBuilder {
                 code: ErrorCode::T01_PEER_UNREACHABLE,
                 message: &[],
@@ -161,13 +542,401 @@ fn parse_packet_from_response(
         match Packet::try_from(body) {
             Ok(Packet::Fulfill(fulfill)) => Ok(fulfill),
             Ok(Packet::Reject(reject)) => Err(reject),
-            _ => Err(RejectBuilder {
-                code: ErrorCode::T01_PEER_UNREACHABLE,
-                message: &[],
-                triggered_by: Some(&ilp_address_clone.clone()),
-                data: &[],
+            _ => {
+                error!("ILP over HTTP response did not contain a Fulfill or Reject packet");
+                record_error(&peer_clone2, "invalid_response");
+                Err(RejectBuilder {
+                    code: ErrorCode::T01_PEER_UNREACHABLE,
+                    message: &[],
+                    triggered_by: Some(&ilp_address_clone.clone()),
+                    data: &[],
+                }
+                .build())
             }
-            .build()),
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_helpers::*, HttpServer};
+    use futures::future::{empty, join_all, lazy, loop_fn, Loop};
+    use interledger_packet::{FulfillBuilder, PrepareBuilder};
+    use std::net::TcpListener;
+    use tokio_timer::Delay;
+    use warp::Filter;
+
+    fn test_service(
+        accounts: Vec<TestAccount>,
+    ) -> HttpClientService<TestStore, impl OutgoingService<TestAccount> + Clone, TestAccount> {
+        HttpClientService::new(
+            TestStore { accounts },
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+    }
+
+    fn prepare(expires_at: SystemTime) -> Prepare {
+        PrepareBuilder {
+            destination: Address::from_str("example.destination").unwrap(),
+            amount: 100,
+            execution_condition: &[0; 32],
+            expires_at,
+            data: &[],
+        }
+        .build()
+    }
+
+    fn send(
+        mut service: HttpClientService<
+            TestStore,
+            impl OutgoingService<TestAccount> + Clone,
+            TestAccount,
+        >,
+        account: &TestAccount,
+        data: u8,
+    ) -> BoxedIlpFuture {
+        service.send_request(OutgoingRequest {
+            from: account.clone(),
+            to: account.clone(),
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &[data],
+            }
+            .build(),
+        })
+    }
+
+    fn fulfill(data: &[u8]) -> Fulfill {
+        FulfillBuilder {
+            fulfillment: &[0; 32],
+            data,
+        }
+        .build()
+    }
+
+    fn streaming_account(username: &str, url: Url) -> TestAccount {
+        TestAccount {
+            settings: HttpClientSettings {
+                streaming: true,
+                ..HttpClientSettings::default()
+            },
+            ..TestAccount::new(username, Some(url))
+        }
+    }
+
+    /// Wait until the state of account 0's stream is ready
+    fn wait_for_stream(
+        streams: Arc<Mutex<HashMap<u64, StreamState>>>,
+        is_ready: fn(Option<&StreamState>) -> bool,
+    ) -> impl Future<Item = (), Error = Reject> {
+        loop_fn(0, move |attempts| {
+            if is_ready(streams.lock().get(&0)) {
+                return Either::A(ok(Loop::Break(())));
+            }
+            assert!(attempts < 500, "Timed out waiting for the stream state");
+            Either::B(
+                Delay::new(Instant::now() + Duration::from_millis(10))
+                    .then(move |_| Ok(Loop::Continue(attempts + 1))),
+            )
+        })
+    }
+
+    fn is_open(state: Option<&StreamState>) -> bool {
+        match state {
+            Some(StreamState::Open(_)) => true,
+            _ => false,
+        }
+    }
+
+    fn is_fallback(state: Option<&StreamState>) -> bool {
+        match state {
+            Some(StreamState::Fallback { .. }) => true,
+            _ => false,
+        }
+    }
+
+    /// A URL nothing is listening on
+    fn closed_url() -> Url {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        Url::parse(&format!("http://{}/ilp", addr)).unwrap()
+    }
+
+    #[test]
+    fn shares_clients_between_accounts_with_the_same_settings() {
+        let service = test_service(Vec::new());
+        let default = HttpClientSettings::default();
+        service.get_client(default).unwrap();
+        service
+            .get_client(HttpClientSettings {
+                streaming: true,
+                ..default
+            })
+            .unwrap();
+        assert_eq!(service.clients.read().len(), 1);
+
+        service
+            .get_client(HttpClientSettings {
+                pool_size: Some(2),
+                ..default
+            })
+            .unwrap();
+        service
+            .get_client(HttpClientSettings {
+                http2_prior_knowledge: true,
+                ..default
+            })
+            .unwrap();
+        service
+            .get_client(HttpClientSettings {
+                pool_size: Some(2),
+                ..default
+            })
+            .unwrap();
+        assert_eq!(service.clients.read().len(), 3);
+    }
+
+    #[test]
+    fn times_out_when_the_prepare_expires() {
+        init_metrics();
+        let account = TestAccount::new("timeout_alice", None);
+        let start = Instant::now();
+        let result = block_on(lazy(move || {
+            // The peer never responds
+            let (addr, server) =
+                warp::serve(warp::path("ilp").and_then(|| empty::<String, warp::Rejection>()))
+                    .bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+
+            let account = TestAccount {
+                http_url: Some(Url::parse(&format!("http://{}/ilp", addr)).unwrap()),
+                ..account
+            };
+            test_service(vec![account.clone()]).send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account,
+                original_amount: 100,
+                prepare: prepare(SystemTime::now() + Duration::from_millis(500)),
+            })
+        }));
+        let reject = result.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::R00_TRANSFER_TIMED_OUT);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            counter(
+                "requests.outgoing.http.error",
+                &[("to_username", "timeout_alice"), ("error", "timeout")]
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn rejects_expired_prepare_without_sending_it() {
+        init_metrics();
+        let account = TestAccount::new("expired_alice", Some(closed_url()));
+        let result = block_on(lazy(move || {
+            test_service(vec![account.clone()]).send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account,
+                original_amount: 100,
+                prepare: prepare(SystemTime::now() - Duration::from_secs(1)),
+            })
+        }));
+        let reject = result.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::R00_TRANSFER_TIMED_OUT);
+        assert_eq!(reject.message(), &b"Packet expired before it was sent"[..]);
+        assert_eq!(
+            counter(
+                "requests.outgoing.http.error",
+                &[("to_username", "expired_alice")]
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn records_connection_errors() {
+        init_metrics();
+        let account = TestAccount::new("unreachable_alice", Some(closed_url()));
+        let result = block_on(lazy(move || {
+            test_service(vec![account.clone()]).send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account,
+                original_amount: 100,
+                prepare: prepare(SystemTime::now() + Duration::from_secs(30)),
+            })
+        }));
+        let reject = result.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+        assert_eq!(
+            counter(
+                "requests.outgoing.http.error",
+                &[
+                    ("to_username", "unreachable_alice"),
+                    ("error", "connection")
+                ]
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn streams_packets_and_matches_responses() {
+        let account = streaming_account("stream_alice", closed_url());
+        let result = block_on(lazy(move || {
+            // Respond to the packets in the reverse order they were sent
+            let incoming = incoming_service_fn(|request: IncomingRequest<TestAccount>| {
+                let data = request.prepare.data().to_vec();
+                let delay = Duration::from_millis(20 * (10 - u64::from(data[0])));
+                Delay::new(Instant::now() + delay).then(move |_| Ok::<_, Reject>(fulfill(&data)))
+            });
+            let server = HttpServer::new(
+                incoming,
+                TestStore {
+                    accounts: vec![account.clone()],
+                },
+            );
+            let (addr, server) =
+                warp::serve(server.as_filter()).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+
+            let account = TestAccount {
+                http_url: Some(Url::parse(&format!("http://{}/ilp", addr)).unwrap()),
+                ..account
+            };
+            let service = test_service(vec![account.clone()]);
+            let streams = service.streams.clone();
+            // The first packet is sent in its own request while the stream is opened
+            send(service.clone(), &account, 0)
+                .and_then(move |_| wait_for_stream(streams, is_open))
+                .and_then({
+                    let service = service.clone();
+                    let account = account.clone();
+                    move |_| join_all((1..6).map(move |i| send(service.clone(), &account, i)))
+                })
+                .and_then(move |fulfills| {
+                    // Sending a packet after streaming is turned off closes the stream
+                    let account = TestAccount {
+                        settings: HttpClientSettings::default(),
+                        ..account
+                    };
+                    let streams = service.streams.clone();
+                    send(service, &account, 6).map(move |_| {
+                        let stream_closed = streams.lock().get(&0).is_none();
+                        (fulfills, stream_closed)
+                    })
+                })
+        }));
+        let (fulfills, stream_closed) = result.unwrap();
+        let data: Vec<Vec<u8>> = fulfills
+            .iter()
+            .map(|fulfill| fulfill.data().to_vec())
+            .collect();
+        assert_eq!(data, vec![vec![1], vec![2], vec![3], vec![4], vec![5]]);
+        assert!(stream_closed);
+    }
+
+    #[test]
+    fn falls_back_to_one_request_per_packet() {
+        let account = streaming_account("fallback_alice", closed_url());
+        let result = block_on(lazy(move || {
+            // The peer does not support streaming, so /ilp/stream is not found
+            let per_packet = warp::post2()
+                .and(warp::path("ilp"))
+                .and(warp::path::end())
+                .map(|| warp::http::Response::new(BytesMut::from(fulfill(b"posted")).to_vec()));
+            let (addr, server) = warp::serve(per_packet).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+
+            let account = TestAccount {
+                http_url: Some(Url::parse(&format!("http://{}/ilp", addr)).unwrap()),
+                ..account
+            };
+            let service = test_service(vec![account.clone()]);
+            let streams = service.streams.clone();
+            send(service.clone(), &account, 0)
+                .and_then(move |_| wait_for_stream(streams, is_fallback))
+                .and_then(move |_| {
+                    let streams = service.streams.clone();
+                    send(service, &account, 1).map(move |fulfill| {
+                        let failures = match streams.lock().get(&0) {
+                            Some(StreamState::Fallback { failures, .. }) => *failures,
+                            _ => 0,
+                        };
+                        (fulfill, failures)
+                    })
+                })
+        }));
+        let (fulfill, failures) = result.unwrap();
+        assert_eq!(fulfill.data(), b"posted");
+        assert_eq!(failures, 1);
+    }
+
+    #[test]
+    fn backs_off_when_stream_closes_right_away() {
+        let account = streaming_account("closing_alice", closed_url());
+        let result = block_on(lazy(move || {
+            // The peer accepts the stream but ends it right away
+            let stream = warp::post2()
+                .and(warp::path("ilp"))
+                .and(warp::path("stream"))
+                .and(warp::path::end())
+                .map(|| "");
+            let per_packet = warp::post2()
+                .and(warp::path("ilp"))
+                .and(warp::path::end())
+                .map(|| warp::http::Response::new(BytesMut::from(fulfill(b"posted")).to_vec()));
+            let (addr, server) =
+                warp::serve(stream.or(per_packet)).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+
+            let account = TestAccount {
+                http_url: Some(Url::parse(&format!("http://{}/ilp", addr)).unwrap()),
+                ..account
+            };
+            let service = test_service(vec![account.clone()]);
+            let streams = service.streams.clone();
+            send(service.clone(), &account, 0)
+                .and_then(move |_| wait_for_stream(streams, is_fallback))
+                .map(move |_| match service.streams.lock().get(&0) {
+                    Some(StreamState::Fallback { retry_at, failures }) => (
+                        *retry_at > Instant::now() + STREAM_RETRY_INTERVAL / 2,
+                        *failures,
+                    ),
+                    _ => (false, 0),
+                })
+        }));
+        assert_eq!(result.unwrap(), (true, 1));
+    }
+
+    #[test]
+    fn stream_retries_back_off_exponentially() {
+        let retry_delay = |failures| match fallback(failures) {
+            StreamState::Fallback { retry_at, .. } => {
+                // Round to the nearest second
+                (retry_at - Instant::now() + Duration::from_millis(500)).as_secs()
+            }
+            _ => unreachable!(),
+        };
+        assert_eq!(retry_delay(1), 60);
+        assert_eq!(retry_delay(2), 120);
+        assert_eq!(retry_delay(3), 240);
+        assert_eq!(retry_delay(100), 60 * 60);
+    }
+}
@@ -0,0 +1,150 @@
+//! Framing for ILP-over-HTTP streaming, where many packets are sent over one long-lived
+//! request (and its response) instead of one packet per request.
+//!
+//! Each frame is the request ID (4 bytes), the length of the packet (4 bytes), and the
+//! ILP packet itself. Both numbers are big endian. A response uses the same request ID as
+//! the Prepare it responds to.
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    stream::{iter_ok, once},
+    Stream,
+};
+use log::error;
+use std::fmt::Debug;
+
+const HEADER_LEN: usize = 8;
+
+pub(crate) fn encode_frame(request_id: u32, packet: BytesMut) -> Bytes {
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + packet.len());
+    frame.put_u32_be(request_id);
+    frame.put_u32_be(packet.len() as u32);
+    frame.put_slice(&packet);
+    frame.freeze()
+}
+
+/// Split a stream of body chunks into (request ID, packet) frames.
+///
+/// The stream ends with an error if a frame says its packet is longer than `max_packet_size`,
+/// or if the body ends partway through a frame.
+pub(crate) fn decode_frames<S, B>(
+    body: S,
+    max_packet_size: usize,
+) -> impl Stream<Item = (u32, BytesMut), Error = ()>
+where
+    S: Stream<Item = B>,
+    S::Error: Debug,
+    B: Buf,
+{
+    let mut buffer = BytesMut::new();
+    body.map_err(|err| error!("Error reading ILP-over-HTTP stream: {:?}", err))
+        .map(Some)
+        // None marks the end of the body
+        .chain(once(Ok(None)))
+        .and_then(move |chunk| {
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None if buffer.is_empty() => return Ok(iter_ok(Vec::new())),
+                None => {
+                    error!(
+                        "ILP-over-HTTP stream ended with an incomplete frame of {} bytes",
+                        buffer.len()
+                    );
+                    return Err(());
+                }
+            };
+            buffer.reserve(chunk.remaining());
+            buffer.put(chunk);
+
+            let mut frames = Vec::new();
+            while buffer.len() >= HEADER_LEN {
+                let request_id = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                let packet_len =
+                    u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as usize;
+                if packet_len > max_packet_size {
+                    error!(
+                        "ILP-over-HTTP stream frame is {} bytes, which is larger than the maximum of {}",
+                        packet_len, max_packet_size
+                    );
+                    return Err(());
+                }
+                if buffer.len() < HEADER_LEN + packet_len {
+                    break;
+                }
+                buffer.advance(HEADER_LEN);
+                frames.push((request_id, buffer.split_to(packet_len)));
+            }
+            Ok(iter_ok(frames))
+        })
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use std::io::Cursor;
+
+    fn decode(chunks: Vec<Vec<u8>>, max_packet_size: usize) -> Result<Vec<(u32, Vec<u8>)>, ()> {
+        let chunks: Vec<Cursor<Vec<u8>>> = chunks.into_iter().map(Cursor::new).collect();
+        decode_frames(iter_ok::<_, ()>(chunks), max_packet_size)
+            .map(|(request_id, packet)| (request_id, packet.to_vec()))
+            .collect()
+            .wait()
+    }
+
+    fn frames() -> Vec<u8> {
+        let mut frames = encode_frame(1, BytesMut::from(&b"first packet"[..])).to_vec();
+        frames.extend_from_slice(&encode_frame(2, BytesMut::from(&b"second"[..])));
+        frames.extend_from_slice(&encode_frame(3, BytesMut::new()));
+        frames
+    }
+
+    fn expected() -> Vec<(u32, Vec<u8>)> {
+        vec![
+            (1, b"first packet".to_vec()),
+            (2, b"second".to_vec()),
+            (3, Vec::new()),
+        ]
+    }
+
+    #[test]
+    fn encodes_request_id_and_length() {
+        let frame = encode_frame(0x0102_0304, BytesMut::from(&b"abc"[..]));
+        assert_eq!(&frame[..], &[1, 2, 3, 4, 0, 0, 0, 3, b'a', b'b', b'c'][..]);
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(decode(vec![frames()], 100), Ok(expected()));
+    }
+
+    #[test]
+    fn frames_split_across_chunks() {
+        let frames = frames();
+        // Split the body at every possible point, including inside the headers
+        for split in 1..frames.len() {
+            let chunks = vec![frames[..split].to_vec(), frames[split..].to_vec()];
+            assert_eq!(decode(chunks, 100), Ok(expected()), "split at {}", split);
+        }
+        // And send it one byte at a time
+        let chunks = frames.iter().map(|byte| vec![*byte]).collect();
+        assert_eq!(decode(chunks, 100), Ok(expected()));
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let frames = frames();
+        assert!(decode(vec![frames[..frames.len() - 9].to_vec()], 100).is_err());
+        // Only part of the header
+        assert!(decode(vec![frames[..4].to_vec()], 100).is_err());
+    }
+
+    #[test]
+    fn oversize_frame() {
+        let frame = encode_frame(1, BytesMut::from(&[0; 101][..])).to_vec();
+        assert!(decode(vec![frame.clone()], 100).is_err());
+        // The length is checked as soon as the header arrives
+        assert!(decode(vec![frame[..8].to_vec()], 100).is_err());
+        assert_eq!(decode(vec![frame], 101).unwrap().len(), 1);
+    }
+}
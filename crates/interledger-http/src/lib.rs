@@ -4,14 +4,17 @@
 //! This protocol is intended primarily for server-to-server communication between peers on the Interledger network.
 use bytes::Buf;
 use error::*;
-use futures::Future;
-use interledger_service::{Account, Username};
+use futures::{future::err, Future};
+use interledger_service::{Account, CertificateFingerprint, Username};
 use serde::de::DeserializeOwned;
 use url::Url;
 use warp::{self, filters::body::FullBody, Filter, Rejection};
 
 mod client;
+mod framing;
 mod server;
+#[cfg(test)]
+mod test_helpers;
 
 // So that settlement engines can use errors
 pub mod error;
@@ -26,6 +29,25 @@ pub use self::server::HttpServer;
 pub trait HttpAccount: Account {
     fn get_http_url(&self) -> Option<&Url>;
     fn get_http_auth_token(&self) -> Option<&str>;
+
+    /// How to connect to the account's ILP-over-HTTP endpoint
+    fn get_http_client_settings(&self) -> HttpClientSettings {
+        HttpClientSettings::default()
+    }
+}
+
+/// Settings for the HTTP connections used to send ILP-over-HTTP requests to an account
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct HttpClientSettings {
+    /// The maximum number of idle connections to keep open to the account's endpoint.
+    /// If this is not set, there is no limit.
+    pub pool_size: Option<usize>,
+    /// Use HTTP/2 for all requests, without first negotiating it with the peer
+    /// (HTTP/2 with "prior knowledge")
+    pub http2_prior_knowledge: bool,
+    /// Send packets over one long-lived ILP-over-HTTP stream instead of one request per
+    /// packet, falling back to one request per packet if the peer does not support it
+    pub streaming: bool,
 }
 
 /// The interface for Stores that can be used with the HttpServerService.
@@ -40,6 +62,16 @@ pub trait HttpStore: Clone + Send + Sync + 'static {
         username: &Username,
         token: &str,
     ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send>;
+
+    /// Load account details based on the fingerprint of the TLS client certificate
+    /// the peer authenticated with. Stores that do not support client certificates
+    /// do not match any account.
+    fn get_account_from_http_certificate(
+        &self,
+        _fingerprint: &CertificateFingerprint,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        Box::new(err(()))
+    }
 }
 
 pub fn deserialize_json<T: DeserializeOwned + Send>(
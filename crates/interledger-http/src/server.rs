@@ -1,20 +1,35 @@
-use super::{error::*, HttpStore};
-use bytes::{buf::Buf, Bytes, BytesMut};
+use super::{
+    error::*,
+    framing::{decode_frames, encode_frame},
+    HttpStore,
+};
+use bytes::{buf::Buf, BytesMut};
 use futures::{
     future::{err, Either, FutureResult},
-    Future,
+    Future, Stream,
 };
-use interledger_packet::Prepare;
-use interledger_service::{AuthToken, IncomingRequest, IncomingService};
+use hyper::Body;
+use interledger_packet::{ErrorCode, Prepare, RejectBuilder};
+use interledger_service::{AuthToken, CertificateFingerprint, IncomingRequest, IncomingService};
 use log::error;
-use std::{convert::TryFrom, net::SocketAddr};
+use std::{convert::TryFrom, io, net::SocketAddr};
 use warp::{self, Filter, Rejection};
 
 /// Max message size that is allowed to transfer from a request or a message.
 pub const MAX_PACKET_SIZE: u64 = 40000;
 
+/// How many packets from one ILP-over-HTTP stream are handled at the same time.
+const MAX_CONCURRENT_STREAM_REQUESTS: usize = 100;
+
 /// A warp filter that parses incoming ILP-Over-HTTP requests, validates the authorization,
 /// and passes the request to an IncomingService handler.
+///
+/// Peers send one packet per request to `/ilp`, or many packets over one long-lived
+/// request to `/ilp/stream` (see the `framing` module for the format).
+///
+/// Peers authenticate with a bearer token or, if the node terminates TLS itself, with a
+/// client certificate. The fingerprint of a verified client certificate is expected as a
+/// `CertificateFingerprint` in the request's extensions.
 #[derive(Clone)]
 pub struct HttpServer<I, S> {
     incoming: I,
@@ -32,16 +47,29 @@ where
 
     pub fn as_filter(
         &self,
-    ) -> impl warp::Filter<Extract = (warp::http::Response<Bytes>,), Error = warp::Rejection> + Clone
-    {
+    ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         let incoming = self.incoming.clone();
+        let incoming_clone = self.incoming.clone();
         let store = self.store.clone();
+        let store_clone = self.store.clone();
 
-        warp::post2()
-            .and(warp::path("ilp"))
-            .and(warp::path::end())
-            .and(warp::header::<AuthToken>("authorization"))
-            .and_then(move |auth: AuthToken| {
+        // Peers that connected with a TLS client certificate are authenticated by its fingerprint.
+        // If the certificate does not belong to an account, the authorization header is checked instead
+        let certificate_account = warp::ext::get::<CertificateFingerprint>().and_then(
+            move |fingerprint: CertificateFingerprint| {
+                store_clone
+                    .get_account_from_http_certificate(&fingerprint)
+                    .map_err(move |_| -> Rejection {
+                        error!(
+                            "No account found for TLS client certificate: {}",
+                            fingerprint
+                        );
+                        ApiError::unauthorized().into()
+                    })
+            },
+        );
+        let token_account =
+            warp::header::<AuthToken>("authorization").and_then(move |auth: AuthToken| {
                 store
                     .get_account_from_http_auth(auth.username(), auth.password())
                     .map_err(move |_| -> Rejection {
@@ -51,7 +79,13 @@ where
                         );
                         ApiError::unauthorized().into()
                     })
-            })
+            });
+        let authorized_account = certificate_account.or(token_account).unify();
+
+        let per_packet = warp::post2()
+            .and(warp::path("ilp"))
+            .and(warp::path::end())
+            .and(authorized_account.clone())
             .and(warp::body::content_length_limit(MAX_PACKET_SIZE))
             .and(warp::body::concat())
             .and_then(
@@ -85,7 +119,61 @@ where
                         Either::B(err(ApiError::invalid_ilp_packet().into()))
                     }
                 },
-            )
+            );
+
+        // Streaming mode: the request body is a stream of framed Prepare packets and the
+        // response body is a stream of the framed Fulfill or Reject packets that answer them
+        let stream = warp::post2()
+            .and(warp::path("ilp"))
+            .and(warp::path("stream"))
+            .and(warp::path::end())
+            .and(authorized_account)
+            .and(warp::body::stream())
+            .map(move |account: S::Account, body: warp::body::BodyStream| {
+                let incoming = incoming_clone.clone();
+                let responses = decode_frames(body, MAX_PACKET_SIZE as usize)
+                    .map(move |(request_id, packet)| {
+                        let response = match Prepare::try_from(packet) {
+                            Ok(prepare) => Either::A(incoming.clone().handle_request(
+                                IncomingRequest {
+                                    from: account.clone(),
+                                    prepare,
+                                },
+                            )),
+                            Err(_) => {
+                                error!(
+                                    "Frame {} in ILP-over-HTTP stream was not a valid Prepare packet",
+                                    request_id
+                                );
+                                Either::B(err(RejectBuilder {
+                                    code: ErrorCode::F00_BAD_REQUEST,
+                                    message: b"Invalid Prepare packet",
+                                    triggered_by: None,
+                                    data: &[],
+                                }
+                                .build()))
+                            }
+                        };
+                        response.then(move |result| {
+                            let bytes: BytesMut = match result {
+                                Ok(fulfill) => fulfill.into(),
+                                Err(reject) => reject.into(),
+                            };
+                            Ok(encode_frame(request_id, bytes))
+                        })
+                    })
+                    .buffer_unordered(MAX_CONCURRENT_STREAM_REQUESTS)
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Invalid ILP-over-HTTP stream")
+                    });
+                warp::http::Response::builder()
+                    .header("Content-Type", "application/octet-stream")
+                    .status(200)
+                    .body(Body::wrap_stream(responses))
+                    .unwrap()
+            });
+
+        stream.or(per_packet)
     }
 
     pub fn bind(&self, addr: SocketAddr) -> impl Future<Item = (), Error = ()> + Send {
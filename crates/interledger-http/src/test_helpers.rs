@@ -0,0 +1,161 @@
+use super::{HttpAccount, HttpClientSettings, HttpStore};
+use futures::{future::result, Future};
+use interledger_packet::Address;
+use interledger_service::{Account, AddressStore, Username};
+use lazy_static::lazy_static;
+use metrics::{Key, Recorder};
+use parking_lot::Mutex;
+use std::{str::FromStr, sync::Once};
+use tokio::runtime::Runtime;
+use url::Url;
+
+lazy_static! {
+    pub static ref EXAMPLE_ADDRESS: Address = Address::from_str("example.alice").unwrap();
+    static ref NODE_ADDRESS: Address = Address::from_str("example.node").unwrap();
+    static ref COUNTERS: Mutex<Vec<(Key, u64)>> = Mutex::new(Vec::new());
+}
+
+#[derive(Clone, Debug)]
+pub struct TestAccount {
+    pub id: u64,
+    pub username: Username,
+    pub http_url: Option<Url>,
+    pub http_token: String,
+    pub settings: HttpClientSettings,
+}
+
+impl TestAccount {
+    pub fn new(username: &str, http_url: Option<Url>) -> Self {
+        TestAccount {
+            id: 0,
+            username: Username::from_str(username).unwrap(),
+            http_url,
+            http_token: format!("{}:test_token", username),
+            settings: HttpClientSettings::default(),
+        }
+    }
+}
+
+impl Account for TestAccount {
+    type AccountId = u64;
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn username(&self) -> &Username {
+        &self.username
+    }
+
+    fn asset_scale(&self) -> u8 {
+        9
+    }
+
+    fn asset_code(&self) -> &str {
+        "XYZ"
+    }
+
+    fn ilp_address(&self) -> &Address {
+        &EXAMPLE_ADDRESS
+    }
+}
+
+impl HttpAccount for TestAccount {
+    fn get_http_url(&self) -> Option<&Url> {
+        self.http_url.as_ref()
+    }
+
+    fn get_http_auth_token(&self) -> Option<&str> {
+        Some(&self.http_token)
+    }
+
+    fn get_http_client_settings(&self) -> HttpClientSettings {
+        self.settings
+    }
+}
+
+#[derive(Clone)]
+pub struct TestStore {
+    pub accounts: Vec<TestAccount>,
+}
+
+impl AddressStore for TestStore {
+    fn set_ilp_address(
+        &self,
+        _ilp_address: Address,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        unimplemented!()
+    }
+
+    fn clear_ilp_address(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        unimplemented!()
+    }
+
+    fn get_ilp_address(&self) -> Address {
+        NODE_ADDRESS.clone()
+    }
+}
+
+impl HttpStore for TestStore {
+    type Account = TestAccount;
+
+    fn get_account_from_http_auth(
+        &self,
+        username: &Username,
+        token: &str,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        let token = format!("{}:{}", username, token);
+        Box::new(result(
+            self.accounts
+                .iter()
+                .find(|account| account.http_token == token)
+                .cloned()
+                .ok_or(()),
+        ))
+    }
+}
+
+/// Records the counters incremented by the code under test, so tests can check them.
+/// Tests run in parallel and share the recorder, so each test should use its own usernames.
+struct TestRecorder;
+
+impl Recorder for TestRecorder {
+    fn increment_counter(&self, key: Key, value: u64) {
+        COUNTERS.lock().push((key, value));
+    }
+
+    fn update_gauge(&self, _key: Key, _value: i64) {}
+
+    fn record_histogram(&self, _key: Key, _value: u64) {}
+}
+
+pub fn init_metrics() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| metrics::set_boxed_recorder(Box::new(TestRecorder)).unwrap());
+}
+
+/// The total of the counter with the given name and labels
+pub fn counter(name: &str, labels: &[(&str, &str)]) -> u64 {
+    COUNTERS
+        .lock()
+        .iter()
+        .filter(|(key, _)| {
+            key.name() == name
+                && labels.iter().all(|(label_key, label_value)| {
+                    key.labels()
+                        .any(|label| label.key() == *label_key && label.value() == *label_value)
+                })
+        })
+        .map(|(_, value)| value)
+        .sum()
+}
+
+pub fn block_on<F>(f: F) -> Result<F::Item, F::Error>
+where
+    F: Future + Send + 'static,
+    F::Item: Send,
+    F::Error: Send,
+{
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(f)
+}
@@ -0,0 +1,84 @@
+//! Benchmark routing table lookups and updates with large numbers of routes.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use interledger_router::RoutingTable;
+use std::collections::HashMap;
+
+const TABLE_SIZES: [usize; 2] = [10_000, 50_000];
+
+/// Prefixes spread over a few levels of the address hierarchy,
+/// similar to what a connector with many peers and child accounts would have
+fn prefixes(size: usize) -> Vec<Bytes> {
+    (0..size)
+        .map(|i| Bytes::from(format!("g.connector{}.peer{}", i % 100, i)))
+        .collect()
+}
+
+/// Addresses under every 100th prefix
+fn destinations(prefixes: &[Bytes]) -> Vec<Bytes> {
+    prefixes
+        .iter()
+        .step_by(100)
+        .map(|prefix| {
+            let mut address = prefix.to_vec();
+            address.extend_from_slice(b".receiver.a1b2c3d4");
+            Bytes::from(address)
+        })
+        .collect()
+}
+
+fn benchmark_resolve(c: &mut Criterion) {
+    let mut group = c.benchmark_group("RoutingTable (resolve)");
+    for &size in TABLE_SIZES.iter() {
+        let prefixes = prefixes(size);
+        let destinations = destinations(&prefixes);
+        let table: RoutingTable<usize> = prefixes.into_iter().zip(0..).collect();
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                for destination in destinations.iter() {
+                    assert!(table.resolve(destination).is_some());
+                }
+            });
+        });
+    }
+    group.finish();
+
+    // The linear scan over a HashMap that the Router used before, for comparison
+    let mut group = c.benchmark_group("HashMap scan (resolve)");
+    for &size in TABLE_SIZES.iter() {
+        let prefixes = prefixes(size);
+        let destinations = destinations(&prefixes);
+        let table: HashMap<Bytes, usize> = prefixes.into_iter().zip(0..).collect();
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                for destination in destinations.iter() {
+                    let best = table
+                        .iter()
+                        .filter(|(prefix, _)| destination.starts_with(prefix))
+                        .max_by_key(|(prefix, _)| prefix.len());
+                    assert!(best.is_some());
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("RoutingTable (insert)");
+    group.sample_size(10);
+    for &size in TABLE_SIZES.iter() {
+        let prefixes = prefixes(size);
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                let table: RoutingTable<usize> = prefixes.iter().cloned().zip(0..).collect();
+                assert_eq!(table.len(), size);
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_resolve, benchmark_insert);
+criterion_main!(benches);
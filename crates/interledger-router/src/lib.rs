@@ -12,20 +12,48 @@
 //! store can either be configured or populated using the `CcpRouteManager`
 //! (see the `interledger-ccp` crate for more details).
 
-use bytes::Bytes;
 use interledger_service::{Account, AccountStore};
-use std::collections::HashMap;
+use std::sync::Arc;
 
 mod router;
+mod routing_table;
 
-pub use self::router::Router;
+pub use self::router::{Router, DEFAULT_MIN_FAILOVER_EXPIRY};
+pub use self::routing_table::RoutingTable;
+
+/// One of the next hops that packets for a prefix can be forwarded to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NextHop<AccountId> {
+    pub account_id: AccountId,
+    /// Next hops with a lower cost are preferred. Next hops with the same cost are equally good.
+    pub cost: u32,
+    /// The share of the packets this next hop gets, relative to the other next hops
+    /// with the same cost, if the Router splits the traffic between them
+    pub weight: u32,
+}
+
+/// The ranked next hops for each prefix that has more than one.
+pub type AlternateRoutes<AccountId> = RoutingTable<Vec<NextHop<AccountId>>>;
 
 /// A trait for Store implmentations that have ILP routing tables.
 pub trait RouterStore: AccountStore + Clone + Send + Sync + 'static {
-    /// **Synchronously** return a copy of the routing table.
+    /// **Synchronously** return a snapshot of the routing table.
     /// Note that this is synchronous because it assumes that Stores should
     /// keep the routing table in memory and use PubSub or polling to keep it updated.
     /// This ensures that individual packets can be routed without hitting the underlying store.
-    // TODO avoid using HashMap because it means it'll be cloned a lot
-    fn routing_table(&self) -> HashMap<Bytes, <Self::Account as Account>::AccountId>;
+    ///
+    /// Stores should replace the whole table when it changes rather than modifying it
+    /// in place, so that returning the snapshot only clones the `Arc`.
+    fn routing_table(&self) -> Arc<RoutingTable<<Self::Account as Account>::AccountId>>;
+
+    /// **Synchronously** return a snapshot of the ranked next hops for the prefixes
+    /// that have more than one.
+    ///
+    /// For each prefix, the next hops are sorted by cost and include the account from
+    /// the `routing_table`. The Router fails over to the other ones if that account is
+    /// unreachable and may split the traffic between the ones with the lowest cost.
+    /// Stores that only keep one route per prefix can use the default implementation.
+    fn alternate_routes(&self) -> Arc<AlternateRoutes<<Self::Account as Account>::AccountId>> {
+        Arc::new(AlternateRoutes::new())
+    }
 }
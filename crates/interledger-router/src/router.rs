@@ -1,10 +1,20 @@
-use super::RouterStore;
-use bytes::Bytes;
-use futures::{future::err, Future};
-use interledger_packet::{ErrorCode, RejectBuilder};
+use super::{NextHop, RouterStore};
+use futures::{
+    future::{err, Either},
+    Future,
+};
+use interledger_packet::{Address, ErrorCode, RejectBuilder};
 use interledger_service::*;
-use log::{error, trace};
-use std::str;
+use log::{debug, error, trace};
+use std::{
+    collections::VecDeque,
+    str,
+    time::{Duration, SystemTime},
+};
+
+/// The minimum time (in milliseconds) that must be left before a Prepare expires
+/// for the Router to retry it on an alternate next hop
+pub const DEFAULT_MIN_FAILOVER_EXPIRY: u64 = 1000;
 
 /// # Interledger Router
 ///
@@ -22,11 +32,18 @@ use std::str;
 ///   - reduce the Prepare packet's expiry
 ///
 /// That is done by OutgoingServices.
+///
+/// If the store has alternate routes for the prefix, the router retries the request on the
+/// next best account when the first one rejects it with `T01_PEER_UNREACHABLE` or
+/// `R00_TRANSFER_TIMED_OUT`, as long as the Prepare does not expire too soon. It can also
+/// split the packets between equally good routes according to their weights.
 
 #[derive(Clone)]
 pub struct Router<S, O> {
     store: S,
     next: O,
+    load_splitting: bool,
+    min_failover_expiry: Duration,
 }
 
 impl<S, O> Router<S, O>
@@ -35,8 +52,163 @@ where
     O: OutgoingService<S::Account>,
 {
     pub fn new(store: S, next: O) -> Self {
-        Router { store, next }
+        Router {
+            store,
+            next,
+            load_splitting: false,
+            min_failover_expiry: Duration::from_millis(DEFAULT_MIN_FAILOVER_EXPIRY),
+        }
+    }
+
+    /// Split the packets for a prefix between its next hops with the lowest cost,
+    /// in proportion to their weights, instead of sending them all to the account
+    /// in the routing table
+    pub fn load_splitting(&mut self, enabled: bool) -> &mut Self {
+        self.load_splitting = enabled;
+        self
+    }
+
+    /// Set how much time (in milliseconds) must be left before a Prepare expires
+    /// to retry it on an alternate next hop
+    pub fn min_failover_expiry(&mut self, milliseconds: u64) -> &mut Self {
+        self.min_failover_expiry = Duration::from_millis(milliseconds);
+        self
+    }
+
+    /// Order the accounts to try for the given prefix: the one the packet should be
+    /// sent to first, followed by the ones to fail over to (best first)
+    fn next_hops(
+        &self,
+        prefix: &[u8],
+        account_id: <S::Account as Account>::AccountId,
+        execution_condition: &[u8],
+    ) -> VecDeque<<S::Account as Account>::AccountId> {
+        let mut next_hops = VecDeque::with_capacity(1);
+        next_hops.push_back(account_id);
+
+        let alternate_routes = self.store.alternate_routes();
+        if let Some(alternates) = alternate_routes.get(prefix) {
+            if self.load_splitting {
+                let lowest_cost = alternates.first().map(|hop| hop.cost);
+                let equal_cost = alternates
+                    .iter()
+                    .take_while(|hop| Some(hop.cost) == lowest_cost);
+                if let Some(chosen) = choose_weighted(equal_cost, execution_condition) {
+                    next_hops[0] = chosen;
+                    if chosen != account_id {
+                        next_hops.push_back(account_id);
+                    }
+                }
+            }
+            for hop in alternates.iter() {
+                if !next_hops.contains(&hop.account_id) {
+                    next_hops.push_back(hop.account_id);
+                }
+            }
+        }
+        next_hops
+    }
+}
+
+/// Pick one of the next hops with a probability proportional to its weight.
+/// The execution condition is a hash, so it is used as the source of randomness.
+fn choose_weighted<'a, I, AccountId>(next_hops: I, execution_condition: &[u8]) -> Option<AccountId>
+where
+    I: Iterator<Item = &'a NextHop<AccountId>> + Clone,
+    AccountId: Copy + 'a,
+{
+    let total_weight: u64 = next_hops.clone().map(|hop| u64::from(hop.weight)).sum();
+    if total_weight == 0 {
+        return None;
+    }
+    let mut random = [0; 8];
+    random.copy_from_slice(&execution_condition[..8]);
+    let mut point = u64::from_be_bytes(random) % total_weight;
+    for hop in next_hops {
+        let weight = u64::from(hop.weight);
+        if point < weight {
+            return Some(hop.account_id);
+        }
+        point -= weight;
     }
+    None
+}
+
+/// Send the request to the first of the next hops. If it rejects the request because
+/// it is unreachable or the request timed out, try the next one, as long as there is
+/// enough time left before the Prepare expires.
+///
+/// Note that the request is only sent to the next account once the previous one has
+/// rejected it. Waiting for a response is never cut short, because the account could
+/// still fulfill the packet.
+fn send_to_next_hops<S, O>(
+    store: S,
+    mut next: O,
+    request: IncomingRequest<S::Account>,
+    mut next_hops: VecDeque<<S::Account as Account>::AccountId>,
+    min_failover_expiry: Duration,
+    ilp_address: Address,
+) -> BoxedIlpFuture
+where
+    S: RouterStore,
+    O: OutgoingService<S::Account> + Clone + Send + 'static,
+{
+    let account_id = next_hops
+        .pop_front()
+        .expect("Must be given at least one next hop");
+    let ilp_address_clone = ilp_address.clone();
+    Box::new(
+        store
+            .get_accounts(vec![account_id])
+            .map_err(move |_| {
+                error!("No record found for account: {}", account_id);
+                RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    triggered_by: Some(&ilp_address_clone),
+                    data: &[],
+                }
+                .build()
+            })
+            .and_then(move |mut accounts| {
+                let to = accounts.remove(0);
+                if next_hops.is_empty() {
+                    return Either::A(next.send_request(request.into_outgoing(to)));
+                }
+
+                Either::B(
+                    next.send_request(request.clone().into_outgoing(to))
+                        .or_else(move |reject| {
+                            let can_fail_over = reject.code() == ErrorCode::T01_PEER_UNREACHABLE
+                                || reject.code() == ErrorCode::R00_TRANSFER_TIMED_OUT;
+                            let time_left = request
+                                .prepare
+                                .expires_at()
+                                .duration_since(SystemTime::now())
+                                .unwrap_or_default();
+                            if can_fail_over && time_left >= min_failover_expiry {
+                                debug!(
+                                    "Account {} rejected the request with code {}, trying the next best account: {} ({}ms until the packet expires)",
+                                    account_id,
+                                    reject.code(),
+                                    next_hops[0],
+                                    time_left.as_millis(),
+                                );
+                                Either::A(send_to_next_hops(
+                                    store,
+                                    next,
+                                    request,
+                                    next_hops,
+                                    min_failover_expiry,
+                                    ilp_address,
+                                ))
+                            } else {
+                                Either::B(err(reject))
+                            }
+                        }),
+                )
+            }),
+    )
 }
 
 impl<S, O> IncomingService<S::Account> for Router<S, O>
@@ -48,67 +220,45 @@ where
 
     /// Figures out the next node to pass the received Prepare packet to.
     ///
-    /// Firstly, it checks if there is a direct path for that account and uses that.
-    /// If not it scans through the routing table and checks if the route prefix matches
-    /// the prepare packet's destination or if it's a catch-all address (i.e. empty prefix)
+    /// It looks up the route with the longest prefix matching the prepare packet's
+    /// destination, which may be an exact match for the address or
+    /// a catch-all route (i.e. empty prefix)
     fn handle_request(&mut self, request: IncomingRequest<S::Account>) -> Self::Future {
         let destination = request.prepare.destination();
-        let mut next_hop = None;
+        let mut next_hops = None;
         let routing_table = self.store.routing_table();
         let ilp_address = self.store.get_ilp_address();
 
-        // Check if we have a direct path for that account or if we need to scan
-        // through the routing table
         let dest: &[u8] = destination.as_ref();
-        if let Some(account_id) = routing_table.get(dest) {
-            trace!(
-                "Found direct route for address: \"{}\". Account: {}",
-                destination,
-                account_id
-            );
-            next_hop = Some(*account_id);
-        } else if !routing_table.is_empty() {
-            let mut matching_prefix = Bytes::new();
-            for route in self.store.routing_table() {
-                // Check if the route prefix matches or is empty (meaning it's a catch-all address)
-                if (route.0.is_empty() || dest.starts_with(&route.0[..]))
-                    && route.0.len() >= matching_prefix.len()
-                {
-                    next_hop.replace(route.1);
-                    matching_prefix = route.0;
-                }
-            }
-            if let Some(account_id) = next_hop {
+        if let Some((prefix, account_id)) = routing_table.resolve(dest) {
+            if prefix.len() == dest.len() {
+                trace!(
+                    "Found direct route for address: \"{}\". Account: {}",
+                    destination,
+                    account_id
+                );
+            } else {
                 trace!(
                     "Found matching route for address: \"{}\". Prefix: \"{}\", account: {}",
                     destination,
-                    str::from_utf8(&matching_prefix[..]).unwrap_or("<not utf8>"),
+                    str::from_utf8(&prefix[..]).unwrap_or("<not utf8>"),
                     account_id,
                 );
             }
-        } else {
+            next_hops =
+                Some(self.next_hops(prefix, *account_id, request.prepare.execution_condition()));
+        } else if routing_table.is_empty() {
             error!("Unable to route request because routing table is empty");
         }
 
-        if let Some(account_id) = next_hop {
-            let mut next = self.next.clone();
-            Box::new(
-                self.store
-                    .get_accounts(vec![account_id])
-                    .map_err(move |_| {
-                        error!("No record found for account: {}", account_id);
-                        RejectBuilder {
-                            code: ErrorCode::F02_UNREACHABLE,
-                            message: &[],
-                            triggered_by: Some(&ilp_address),
-                            data: &[],
-                        }
-                        .build()
-                    })
-                    .and_then(move |mut accounts| {
-                        let request = request.into_outgoing(accounts.remove(0));
-                        next.send_request(request)
-                    }),
+        if let Some(next_hops) = next_hops {
+            send_to_next_hops(
+                self.store.clone(),
+                self.next.clone(),
+                request,
+                next_hops,
+                self.min_failover_expiry,
+                ilp_address,
             )
         } else {
             error!(
@@ -143,16 +293,17 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{AlternateRoutes, NextHop, RoutingTable};
+    use bytes::Bytes;
     use futures::future::ok;
-    use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+    use interledger_packet::{Address, FulfillBuilder, Prepare, PrepareBuilder};
     use interledger_service::outgoing_service_fn;
     use lazy_static::lazy_static;
     use parking_lot::Mutex;
-    use std::collections::HashMap;
     use std::iter::FromIterator;
     use std::str::FromStr;
     use std::sync::Arc;
-    use std::time::UNIX_EPOCH;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
     #[derive(Debug, Clone)]
     struct TestAccount(u64);
@@ -187,7 +338,8 @@ mod tests {
 
     #[derive(Clone)]
     struct TestStore {
-        routes: HashMap<Bytes, u64>,
+        routes: Arc<RoutingTable<u64>>,
+        alternate_routes: Arc<AlternateRoutes<u64>>,
     }
 
     impl AccountStore for TestStore {
@@ -229,16 +381,21 @@ mod tests {
     }
 
     impl RouterStore for TestStore {
-        fn routing_table(&self) -> HashMap<Bytes, u64> {
+        fn routing_table(&self) -> Arc<RoutingTable<u64>> {
             self.routes.clone()
         }
+
+        fn alternate_routes(&self) -> Arc<AlternateRoutes<u64>> {
+            self.alternate_routes.clone()
+        }
     }
 
     #[test]
     fn empty_routing_table() {
         let mut router = Router::new(
             TestStore {
-                routes: HashMap::new(),
+                routes: Arc::new(RoutingTable::new()),
+                alternate_routes: Arc::default(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -269,7 +426,10 @@ mod tests {
     fn no_route() {
         let mut router = Router::new(
             TestStore {
-                routes: HashMap::from_iter(vec![(Bytes::from("example.other"), 1)].into_iter()),
+                routes: Arc::new(RoutingTable::from_iter(
+                    vec![(Bytes::from("example.other"), 1)].into_iter(),
+                )),
+                alternate_routes: Arc::default(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -300,9 +460,10 @@ mod tests {
     fn finds_exact_route() {
         let mut router = Router::new(
             TestStore {
-                routes: HashMap::from_iter(
+                routes: Arc::new(RoutingTable::from_iter(
                     vec![(Bytes::from("example.destination"), 1)].into_iter(),
-                ),
+                )),
+                alternate_routes: Arc::default(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -333,7 +494,10 @@ mod tests {
     fn catch_all_route() {
         let mut router = Router::new(
             TestStore {
-                routes: HashMap::from_iter(vec![(Bytes::from(""), 0)].into_iter()),
+                routes: Arc::new(RoutingTable::from_iter(
+                    vec![(Bytes::from(""), 0)].into_iter(),
+                )),
+                alternate_routes: Arc::default(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -364,7 +528,10 @@ mod tests {
     fn finds_matching_prefix() {
         let mut router = Router::new(
             TestStore {
-                routes: HashMap::from_iter(vec![(Bytes::from("example."), 1)].into_iter()),
+                routes: Arc::new(RoutingTable::from_iter(
+                    vec![(Bytes::from("example."), 1)].into_iter(),
+                )),
+                alternate_routes: Arc::default(),
             },
             outgoing_service_fn(|_| {
                 Ok(FulfillBuilder {
@@ -397,14 +564,15 @@ mod tests {
         let to_clone = to.clone();
         let mut router = Router::new(
             TestStore {
-                routes: HashMap::from_iter(
+                routes: Arc::new(RoutingTable::from_iter(
                     vec![
                         (Bytes::from(""), 0),
                         (Bytes::from("example.destination"), 2),
                         (Bytes::from("example."), 1),
                     ]
                     .into_iter(),
-                ),
+                )),
+                alternate_routes: Arc::default(),
             },
             outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
                 *to_clone.lock() = Some(request.to.clone());
@@ -433,4 +601,254 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(to.lock().take().unwrap().0, 2);
     }
+
+    fn test_store_with_alternates(alternates: Vec<NextHop<u64>>) -> TestStore {
+        TestStore {
+            routes: Arc::new(RoutingTable::from_iter(vec![(
+                Bytes::from("example.destination"),
+                alternates[0].account_id,
+            )])),
+            alternate_routes: Arc::new(RoutingTable::from_iter(vec![(
+                Bytes::from("example.destination"),
+                alternates,
+            )])),
+        }
+    }
+
+    fn prepare(expires_in: Duration, execution_condition: &[u8; 32]) -> Prepare {
+        PrepareBuilder {
+            destination: Address::from_str("example.destination").unwrap(),
+            amount: 100,
+            execution_condition,
+            expires_at: SystemTime::now() + expires_in,
+            data: &[],
+        }
+        .build()
+    }
+
+    /// An outgoing service that records which accounts requests were sent to
+    /// and rejects the ones sent to the unreachable account with the given code
+    fn outgoing_recording_next_hops(
+        unreachable_account: u64,
+        code: ErrorCode,
+    ) -> (
+        impl OutgoingService<TestAccount> + Clone,
+        Arc<Mutex<Vec<u64>>>,
+    ) {
+        let next_hops = Arc::new(Mutex::new(Vec::new()));
+        let next_hops_clone = next_hops.clone();
+        let outgoing = outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+            next_hops_clone.lock().push(request.to.0);
+            if request.to.0 == unreachable_account {
+                Err(RejectBuilder {
+                    code,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build())
+            } else {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }
+        });
+        (outgoing, next_hops)
+    }
+
+    #[test]
+    fn fails_over_to_alternate_next_hop() {
+        let (outgoing, next_hops) =
+            outgoing_recording_next_hops(1, ErrorCode::T01_PEER_UNREACHABLE);
+        let mut router = Router::new(
+            test_store_with_alternates(vec![
+                NextHop {
+                    account_id: 1,
+                    cost: 0,
+                    weight: 1,
+                },
+                NextHop {
+                    account_id: 2,
+                    cost: 1,
+                    weight: 1,
+                },
+            ]),
+            outgoing,
+        );
+
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(0),
+                prepare: prepare(Duration::from_secs(30), &[1; 32]),
+            })
+            .wait();
+        assert!(result.is_ok());
+        assert_eq!(*next_hops.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn fails_over_when_next_hop_times_out() {
+        let (outgoing, next_hops) =
+            outgoing_recording_next_hops(1, ErrorCode::R00_TRANSFER_TIMED_OUT);
+        let mut router = Router::new(
+            test_store_with_alternates(vec![
+                NextHop {
+                    account_id: 1,
+                    cost: 0,
+                    weight: 1,
+                },
+                NextHop {
+                    account_id: 2,
+                    cost: 0,
+                    weight: 1,
+                },
+            ]),
+            outgoing,
+        );
+
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(0),
+                prepare: prepare(Duration::from_secs(30), &[1; 32]),
+            })
+            .wait();
+        assert!(result.is_ok());
+        assert_eq!(*next_hops.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn doesnt_fail_over_if_prepare_expires_soon() {
+        let (outgoing, next_hops) =
+            outgoing_recording_next_hops(1, ErrorCode::T01_PEER_UNREACHABLE);
+        let mut router = Router::new(
+            test_store_with_alternates(vec![
+                NextHop {
+                    account_id: 1,
+                    cost: 0,
+                    weight: 1,
+                },
+                NextHop {
+                    account_id: 2,
+                    cost: 1,
+                    weight: 1,
+                },
+            ]),
+            outgoing,
+        );
+        router.min_failover_expiry(5000);
+
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(0),
+                prepare: prepare(Duration::from_secs(2), &[1; 32]),
+            })
+            .wait();
+        assert_eq!(result.unwrap_err().code(), ErrorCode::T01_PEER_UNREACHABLE);
+        assert_eq!(*next_hops.lock(), vec![1]);
+    }
+
+    #[test]
+    fn doesnt_fail_over_on_other_errors() {
+        let (outgoing, next_hops) =
+            outgoing_recording_next_hops(1, ErrorCode::F99_APPLICATION_ERROR);
+        let mut router = Router::new(
+            test_store_with_alternates(vec![
+                NextHop {
+                    account_id: 1,
+                    cost: 0,
+                    weight: 1,
+                },
+                NextHop {
+                    account_id: 2,
+                    cost: 1,
+                    weight: 1,
+                },
+            ]),
+            outgoing,
+        );
+
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(0),
+                prepare: prepare(Duration::from_secs(30), &[1; 32]),
+            })
+            .wait();
+        assert_eq!(result.unwrap_err().code(), ErrorCode::F99_APPLICATION_ERROR);
+        assert_eq!(*next_hops.lock(), vec![1]);
+    }
+
+    #[test]
+    fn splits_load_between_equal_cost_next_hops() {
+        let (outgoing, next_hops) =
+            outgoing_recording_next_hops(0, ErrorCode::T01_PEER_UNREACHABLE);
+        let mut router = Router::new(
+            test_store_with_alternates(vec![
+                NextHop {
+                    account_id: 1,
+                    cost: 0,
+                    weight: 1,
+                },
+                NextHop {
+                    account_id: 2,
+                    cost: 0,
+                    weight: 3,
+                },
+                NextHop {
+                    account_id: 3,
+                    cost: 1,
+                    weight: 100,
+                },
+            ]),
+            outgoing,
+        );
+        router.load_splitting(true);
+
+        for i in 0..=255 {
+            router
+                .handle_request(IncomingRequest {
+                    from: TestAccount(0),
+                    prepare: prepare(Duration::from_secs(30), &[i; 32]),
+                })
+                .wait()
+                .unwrap();
+        }
+        let next_hops = next_hops.lock();
+        assert_eq!(next_hops.iter().filter(|id| **id == 1).count(), 64);
+        assert_eq!(next_hops.iter().filter(|id| **id == 2).count(), 192);
+        assert!(!next_hops.contains(&3));
+    }
+
+    #[test]
+    fn doesnt_split_load_by_default() {
+        let (outgoing, next_hops) =
+            outgoing_recording_next_hops(0, ErrorCode::T01_PEER_UNREACHABLE);
+        let mut router = Router::new(
+            test_store_with_alternates(vec![
+                NextHop {
+                    account_id: 1,
+                    cost: 0,
+                    weight: 1,
+                },
+                NextHop {
+                    account_id: 2,
+                    cost: 0,
+                    weight: 3,
+                },
+            ]),
+            outgoing,
+        );
+
+        for i in 0..=255 {
+            router
+                .handle_request(IncomingRequest {
+                    from: TestAccount(0),
+                    prepare: prepare(Duration::from_secs(30), &[i; 32]),
+                })
+                .wait()
+                .unwrap();
+        }
+        assert!(next_hops.lock().iter().all(|id| *id == 1));
+    }
 }
@@ -0,0 +1,368 @@
+use bytes::{Bytes, BytesMut};
+use std::{fmt, iter::FromIterator, mem, ops::Index};
+
+/// A routing table that maps ILP address prefixes to the next hop (usually an account ID)
+/// and finds the longest prefix that matches a given address.
+///
+/// The prefixes are stored in a radix tree, so looking up an address takes time
+/// proportional to the length of the address rather than to the number of routes.
+/// The empty prefix is a catch-all route that matches every address.
+#[derive(Clone)]
+pub struct RoutingTable<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Clone)]
+struct Node<T> {
+    /// The part of the prefix between the parent node and this one
+    label: Bytes,
+    /// The full prefix and its value, if there is a route for this node's prefix
+    route: Option<(Bytes, T)>,
+    /// Sorted by the first byte of their labels (which are never empty)
+    children: Vec<Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new(label: Bytes) -> Self {
+        Node {
+            label,
+            route: None,
+            children: Vec::new(),
+        }
+    }
+
+    fn child_index(&self, byte: u8) -> Result<usize, usize> {
+        self.children
+            .binary_search_by_key(&byte, |child| child.label[0])
+    }
+
+    /// Find the child whose label is the beginning of the given (non-empty) key
+    fn child_matching(&self, key: &[u8]) -> Option<&Node<T>> {
+        self.child_index(key[0])
+            .ok()
+            .map(|index| &self.children[index])
+            .filter(|child| key.starts_with(&child.label))
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<T> {
+        if key.is_empty() {
+            return self.route.take().map(|(_prefix, value)| value);
+        }
+
+        let index = self.child_index(key[0]).ok()?;
+        let child = &mut self.children[index];
+        if !key.starts_with(&child.label) {
+            return None;
+        }
+        let value = child.remove(&key[child.label.len()..])?;
+
+        // Remove nodes that no longer lead to any routes and
+        // merge the ones that only lead to one other node
+        if child.route.is_none() {
+            match child.children.len() {
+                0 => {
+                    self.children.remove(index);
+                }
+                1 => {
+                    let mut grandchild = child.children.pop().unwrap();
+                    let mut label =
+                        BytesMut::with_capacity(child.label.len() + grandchild.label.len());
+                    label.extend_from_slice(&child.label);
+                    label.extend_from_slice(&grandchild.label);
+                    grandchild.label = label.freeze();
+                    *child = grandchild;
+                }
+                _ => {}
+            }
+        }
+        Some(value)
+    }
+}
+
+impl<T> RoutingTable<T> {
+    pub fn new() -> Self {
+        RoutingTable {
+            root: Node::new(Bytes::new()),
+            len: 0,
+        }
+    }
+
+    /// The number of routes in the table
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Set the route for the given prefix, returning the value it had before (if any)
+    pub fn insert(&mut self, prefix: Bytes, value: T) -> Option<T> {
+        let mut node = &mut self.root;
+        let mut key = prefix.clone();
+        loop {
+            if key.is_empty() {
+                let previous = node.route.replace((prefix, value));
+                if previous.is_none() {
+                    self.len += 1;
+                }
+                return previous.map(|(_prefix, value)| value);
+            }
+
+            let index = match node.child_index(key[0]) {
+                Ok(index) => index,
+                Err(index) => {
+                    let mut child = Node::new(key);
+                    child.route = Some((prefix, value));
+                    node.children.insert(index, child);
+                    self.len += 1;
+                    return None;
+                }
+            };
+
+            let child = &mut node.children[index];
+            let common = common_prefix_len(&child.label, &key);
+            if common < child.label.len() {
+                // Split the child so that the node for this key sits in between
+                let split = Node::new(child.label.slice_to(common));
+                let mut suffix = mem::replace(child, split);
+                suffix.label = suffix.label.slice_from(common);
+                child.children.push(suffix);
+            }
+            node = &mut node.children[index];
+            key = key.slice_from(common);
+        }
+    }
+
+    /// Remove the route for the given prefix, returning its value (if there was one)
+    pub fn remove<K>(&mut self, prefix: &K) -> Option<T>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let value = self.root.remove(prefix.as_ref());
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+
+    /// Get the route for exactly the given prefix
+    pub fn get<K>(&self, prefix: &K) -> Option<&T>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let mut node = &self.root;
+        let mut key = prefix.as_ref();
+        while !key.is_empty() {
+            node = node.child_matching(key)?;
+            key = &key[node.label.len()..];
+        }
+        node.route.as_ref().map(|(_prefix, value)| value)
+    }
+
+    /// Find the route with the longest prefix that the given address starts with
+    pub fn resolve<K>(&self, address: &K) -> Option<(&Bytes, &T)>
+    where
+        K: AsRef<[u8]> + ?Sized,
+    {
+        let mut node = &self.root;
+        let mut best = node.route.as_ref();
+        let mut key = address.as_ref();
+        while !key.is_empty() {
+            match node.child_matching(key) {
+                Some(child) => node = child,
+                None => break,
+            }
+            key = &key[node.label.len()..];
+            if node.route.is_some() {
+                best = node.route.as_ref();
+            }
+        }
+        best.map(|(prefix, value)| (prefix, value))
+    }
+
+    /// Iterate over the routes, ordered by prefix
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            stack: vec![&self.root],
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        self.iter().map(|(prefix, _value)| prefix)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.iter().map(|(_prefix, value)| value)
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+pub struct Iter<'a, T> {
+    stack: Vec<&'a Node<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (&'a Bytes, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.stack.pop()?;
+            self.stack.extend(node.children.iter().rev());
+            if let Some((ref prefix, ref value)) = node.route {
+                return Some((prefix, value));
+            }
+        }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a RoutingTable<T> {
+    type Item = (&'a Bytes, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T> Default for RoutingTable<T> {
+    fn default() -> Self {
+        RoutingTable::new()
+    }
+}
+
+impl<T> FromIterator<(Bytes, T)> for RoutingTable<T> {
+    fn from_iter<I: IntoIterator<Item = (Bytes, T)>>(iter: I) -> Self {
+        let mut table = RoutingTable::new();
+        for (prefix, value) in iter {
+            table.insert(prefix, value);
+        }
+        table
+    }
+}
+
+impl<T, K> Index<&K> for RoutingTable<T>
+where
+    K: AsRef<[u8]> + ?Sized,
+{
+    type Output = T;
+
+    fn index(&self, prefix: &K) -> &T {
+        self.get(prefix).expect("No route for prefix")
+    }
+}
+
+impl<T: PartialEq> PartialEq for RoutingTable<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RoutingTable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(routes: &[(&'static str, u32)]) -> RoutingTable<u32> {
+        routes
+            .iter()
+            .map(|(prefix, value)| (Bytes::from(*prefix), *value))
+            .collect()
+    }
+
+    #[test]
+    fn inserts_and_gets_exact_prefixes() {
+        let mut table = table(&[("example.a", 1), ("example.b", 2)]);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.insert(Bytes::from("example.a"), 3), Some(1));
+        assert_eq!(table.insert(Bytes::from("example."), 4), None);
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.get(&b"example.a"[..]), Some(&3));
+        assert_eq!(table[&Bytes::from("example.")], 4);
+        assert_eq!(table.get("example"), None);
+        assert_eq!(table.get("example.a.b"), None);
+    }
+
+    #[test]
+    fn resolves_to_longest_matching_prefix() {
+        let table = table(&[
+            ("example.a", 1),
+            ("example.a.b.c", 2),
+            ("example.a.b", 3),
+            ("example.ab", 4),
+        ]);
+        assert_eq!(table.resolve("example.a").unwrap().1, &1);
+        assert_eq!(table.resolve("example.a.b.c").unwrap().1, &2);
+        assert_eq!(
+            table.resolve("example.a.b.c.d.e").unwrap(),
+            (&Bytes::from("example.a.b.c"), &2)
+        );
+        assert_eq!(table.resolve("example.a.bb").unwrap().1, &3);
+        assert_eq!(table.resolve("example.abc").unwrap().1, &4);
+        assert!(table.resolve("example.other").is_none());
+        assert!(table.resolve("example").is_none());
+    }
+
+    #[test]
+    fn empty_prefix_matches_everything() {
+        let table = table(&[("", 0), ("example.a", 1)]);
+        assert_eq!(table.resolve("example.a.b").unwrap().1, &1);
+        assert_eq!(table.resolve("example.b").unwrap().1, &0);
+        assert_eq!(table.resolve("").unwrap().1, &0);
+    }
+
+    #[test]
+    fn removes_routes() {
+        let mut table = table(&[("example.a", 1), ("example.a.b", 2), ("example.b", 3)]);
+        assert_eq!(table.remove("example."), None);
+        assert_eq!(table.remove("example.a"), Some(1));
+        assert_eq!(table.remove("example.a"), None);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.resolve("example.a.b.c").unwrap().1, &2);
+        assert!(table.resolve("example.a").is_none());
+
+        assert_eq!(table.remove("example.a.b"), Some(2));
+        assert_eq!(table.remove("example.b"), Some(3));
+        assert!(table.is_empty());
+        assert!(table.root.children.is_empty());
+    }
+
+    #[test]
+    fn iterates_in_order_of_prefix() {
+        let table = table(&[
+            ("example.b", 2),
+            ("", 0),
+            ("example.a.b", 3),
+            ("example.a", 1),
+        ]);
+        let prefixes: Vec<&[u8]> = table.keys().map(|prefix| prefix.as_ref()).collect();
+        assert_eq!(
+            prefixes,
+            vec![&b""[..], b"example.a", b"example.a.b", b"example.b"]
+        );
+        assert_eq!(
+            table.values().cloned().collect::<Vec<u32>>(),
+            vec![0, 1, 3, 2]
+        );
+    }
+
+    #[test]
+    fn compares_routes() {
+        let mut a = table(&[("example.a", 1), ("example.b", 2)]);
+        let b = table(&[("example.b", 2), ("example.a", 1)]);
+        assert_eq!(a, b);
+        a.insert(Bytes::from("example.c"), 3);
+        assert_ne!(a, b);
+        a.remove("example.c");
+        assert_eq!(a, b);
+    }
+}
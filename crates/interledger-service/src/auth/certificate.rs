@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// The SHA-256 fingerprint of a TLS client certificate (the hash of the DER-encoded certificate).
+///
+/// Peers that connect with mutual TLS are matched to accounts by the fingerprint
+/// of the certificate they present.
+///
+/// It is written as 64 lowercase hex characters. When parsing, the hex may also be
+/// uppercase and the bytes may be separated by colons, as they are in the output of
+/// `openssl x509 -noout -fingerprint -sha256`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "&str", into = "String")]
+pub struct CertificateFingerprint([u8; 32]);
+
+impl CertificateFingerprint {
+    pub fn new(fingerprint: [u8; 32]) -> Self {
+        CertificateFingerprint(fingerprint)
+    }
+}
+
+impl AsRef<[u8]> for CertificateFingerprint {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Display for CertificateFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CertificateFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CertificateFingerprint({})", self)
+    }
+}
+
+impl FromStr for CertificateFingerprint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: Vec<u8> = s.bytes().filter(|c| *c != b':').collect();
+        if hex.len() != 64 {
+            return Err("certificate fingerprint must be 32 hex-encoded bytes".to_owned());
+        }
+        let mut fingerprint = [0; 32];
+        for (byte, chunk) in fingerprint.iter_mut().zip(hex.chunks(2)) {
+            *byte = std::str::from_utf8(chunk)
+                .ok()
+                .and_then(|chunk| u8::from_str_radix(chunk, 16).ok())
+                .ok_or_else(|| "certificate fingerprint must be hex-encoded".to_owned())?;
+        }
+        Ok(CertificateFingerprint(fingerprint))
+    }
+}
+
+impl TryFrom<&str> for CertificateFingerprint {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        CertificateFingerprint::from_str(value)
+    }
+}
+
+impl From<CertificateFingerprint> for String {
+    fn from(fingerprint: CertificateFingerprint) -> String {
+        fingerprint.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn parses_and_displays_hex() {
+        let fingerprint = CertificateFingerprint::from_str(FINGERPRINT).unwrap();
+        assert_eq!(fingerprint.as_ref()[..2], [0x01, 0x23]);
+        assert_eq!(fingerprint.to_string(), FINGERPRINT);
+    }
+
+    #[test]
+    fn parses_openssl_format() {
+        let openssl = "01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF";
+        assert_eq!(
+            CertificateFingerprint::from_str(openssl).unwrap(),
+            CertificateFingerprint::from_str(FINGERPRINT).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_fingerprints() {
+        assert!(CertificateFingerprint::from_str("0123").is_err());
+        assert!(CertificateFingerprint::from_str(&FINGERPRINT.replace("a", "g")).is_err());
+        assert!(CertificateFingerprint::from_str(&format!("{}00", FINGERPRINT)).is_err());
+    }
+
+    #[test]
+    fn serializes_as_string() {
+        let fingerprint: CertificateFingerprint =
+            serde_json::from_str(&format!("\"{}\"", FINGERPRINT)).unwrap();
+        assert_eq!(
+            serde_json::to_string(&fingerprint).unwrap(),
+            format!("\"{}\"", FINGERPRINT)
+        );
+    }
+}
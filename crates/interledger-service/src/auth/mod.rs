@@ -1,3 +1,6 @@
+mod certificate;
+pub use certificate::CertificateFingerprint;
+
 mod token;
 pub use token::Auth;
 
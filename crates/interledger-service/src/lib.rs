@@ -40,7 +40,7 @@ use std::{
 use serde::Serialize;
 
 mod auth;
-pub use auth::{Auth as AuthToken, Username};
+pub use auth::{Auth as AuthToken, CertificateFingerprint, Username};
 #[cfg(feature = "trace")]
 mod trace;
 #[cfg(feature = "trace")]
@@ -2,7 +2,7 @@ use super::{Error, SpspResponse};
 use futures::{future::result, Future};
 use interledger_packet::Address;
 use interledger_service::{Account, IncomingService};
-use interledger_stream::send_money;
+use interledger_stream::{send_money, send_money_to_deliver, ExchangeRateLimit, PaymentResult};
 use log::{debug, error, trace};
 use reqwest::r#async::Client;
 use std::convert::TryFrom;
@@ -25,40 +25,109 @@ pub fn query(server: &str) -> impl Future<Item = SpspResponse, Error = Error> {
 
 /// Query the details of the given Payment Pointer and send a payment using the STREAM protocol.
 ///
-/// This returns the amount delivered, as reported by the receiver and in the receiver's asset's units.
+/// The `rate_limit` is the worst exchange rate the sender accepts. If the path's rate is
+/// (or becomes) worse than that, the payment stops before sending the full amount.
+///
+/// If the payment stops part of the way through, this still returns the `PaymentResult`,
+/// which says how much was delivered and why the payment stopped.
 pub fn pay<S, A>(
     service: S,
     from_account: A,
     receiver: &str,
     source_amount: u64,
-) -> impl Future<Item = u64, Error = Error>
+    rate_limit: ExchangeRateLimit,
+) -> impl Future<Item = PaymentResult, Error = Error>
 where
     S: IncomingService<A> + Clone,
     A: Account,
 {
-    query(receiver).and_then(move |spsp| {
+    query_address(receiver).and_then(move |(addr, shared_secret)| {
+        debug!("Sending SPSP payment to address: {}", addr);
+
+        send_money(
+            service,
+            &from_account,
+            addr,
+            &shared_secret,
+            source_amount,
+            rate_limit,
+        )
+        .map(|(result, _plugin)| {
+            log_result(&result);
+            result
+        })
+        .map_err(move |err| {
+            error!("Error sending payment: {:?}", err);
+            Error::SendMoneyError(source_amount)
+        })
+    })
+}
+
+/// Query the details of the given Payment Pointer and send enough money using the STREAM protocol
+/// to deliver the `destination_amount` (in the receiver's units), spending at most `max_source_amount`.
+///
+/// As with `pay`, a payment that stops part of the way through still returns its `PaymentResult`.
+pub fn pay_to_deliver<S, A>(
+    service: S,
+    from_account: A,
+    receiver: &str,
+    destination_amount: u64,
+    max_source_amount: u64,
+    rate_limit: ExchangeRateLimit,
+) -> impl Future<Item = PaymentResult, Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    query_address(receiver).and_then(move |(addr, shared_secret)| {
+        debug!(
+            "Sending SPSP payment to deliver {} to address: {}",
+            destination_amount, addr
+        );
+
+        send_money_to_deliver(
+            service,
+            &from_account,
+            addr,
+            &shared_secret,
+            destination_amount,
+            max_source_amount,
+            rate_limit,
+        )
+        .map(|(result, _plugin)| {
+            log_result(&result);
+            result
+        })
+        .map_err(move |err| {
+            error!("Error sending payment: {:?}", err);
+            Error::SendMoneyError(max_source_amount)
+        })
+    })
+}
+
+fn log_result(result: &PaymentResult) {
+    match result.error {
+        None => debug!(
+            "Sent SPSP payment of {} and delivered {} of the receiver's units",
+            result.source_amount, result.delivered_amount
+        ),
+        Some(ref err) => error!(
+            "SPSP payment stopped after sending {} and delivering {} of the receiver's units: {:?}",
+            result.source_amount, result.delivered_amount, err
+        ),
+    }
+}
+
+/// Query the receiver and parse the ILP address it responded with
+fn query_address(receiver: &str) -> impl Future<Item = (Address, Vec<u8>), Error = Error> {
+    query(receiver).and_then(|spsp| {
         let shared_secret = spsp.shared_secret;
         let dest = spsp.destination_account;
         result(Address::try_from(dest).map_err(move |err| {
             error!("Error parsing address");
             Error::InvalidResponseError(err.to_string())
         }))
-        .and_then(move |addr| {
-            debug!("Sending SPSP payment to address: {}", addr);
-
-            send_money(service, &from_account, addr, &shared_secret, source_amount)
-                .map(move |(amount_delivered, _plugin)| {
-                    debug!(
-                        "Sent SPSP payment of {} and delivered {} of the receiver's units",
-                        source_amount, amount_delivered
-                    );
-                    amount_delivered
-                })
-                .map_err(move |err| {
-                    error!("Error sending payment: {:?}", err);
-                    Error::SendMoneyError(source_amount)
-                })
-        })
+        .map(move |addr| (addr, shared_secret))
     })
 }
 
@@ -13,7 +13,8 @@ use serde::{Deserialize, Serialize};
 mod client;
 mod server;
 
-pub use client::{pay, query};
+pub use client::{pay, pay_to_deliver, query};
+pub use interledger_stream::ExchangeRateLimit;
 pub use server::SpspResponder;
 
 #[derive(Fail, Debug)]
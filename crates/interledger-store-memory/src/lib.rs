@@ -0,0 +1,13 @@
+//! # interledger-store-memory
+//!
+//! A Store that keeps account details, balances, the routing table, etc. in memory.
+//!
+//! This implements all of the same traits as the Redis store so it can be used to
+//! run a full node without any external database. Note that all data is lost when
+//! the process exits.
+
+mod account;
+mod store;
+
+pub use account::{Account, AccountId};
+pub use store::{MemoryStore, MemoryStoreBuilder};
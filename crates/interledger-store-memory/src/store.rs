@@ -0,0 +1,1344 @@
+// The in-memory store mirrors the data model of the Redis store:
+//   accounts            account details, keyed by ID
+//   usernames           map of (lowercased) usernames to account IDs
+//   client_certificates map of TLS client certificate fingerprints to account IDs
+//   balances            balance and prepaid amount for each account
+//   routes              dynamic routing table (local accounts and CCP routes)
+//   static_routes       configured routing table
+//   default_route       account to forward packets to if no other route matches
+//   alternate_routes    ranked next hops for the prefixes with more than one CCP route
+// All of the mutable state lives behind a single lock so that balance
+// updates have the same atomicity as the Lua scripts used by the Redis store.
+
+use super::account::{Account, AccountId};
+use bytes::Bytes;
+use futures::{
+    future::{err, ok, result},
+    sync::mpsc::UnboundedSender,
+    Future,
+};
+use http::StatusCode;
+use interledger_api::{AccountDetails, AccountSettings, NodeStore};
+use interledger_btp::BtpStore;
+use interledger_ccp::{CcpRoutingAccount, RouteManagerStore, RoutingRelation};
+use interledger_http::{
+    idempotency::{IdempotentData, IdempotentStore},
+    HttpStore,
+};
+use interledger_packet::Address;
+use interledger_router::{AlternateRoutes, NextHop, RouterStore};
+use interledger_service::{
+    Account as AccountTrait, AccountStore, AddressStore, CertificateFingerprint, Username,
+};
+use interledger_service_util::{BalanceStore, ExchangeRateStore, RateLimitError, RateLimitStore};
+use interledger_settlement::{
+    scale_with_precision_loss, Convert, ConvertDetails, LeftoversStore, SettlementStore,
+};
+use interledger_stream::{PaymentNotification, StreamNotificationsStore};
+use lazy_static::lazy_static;
+use log::{debug, error, trace, warn};
+use num_bigint::BigUint;
+use parking_lot::RwLock;
+use secrecy::{ExposeSecret, SecretBytes};
+use std::{
+    cmp::max,
+    collections::HashMap,
+    iter::FromIterator,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// Idempotency keys are kept for 24 hours, like in the Redis store
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(86400);
+const RATE_LIMIT_WINDOW_MS: u128 = 60_000;
+
+lazy_static! {
+    static ref DEFAULT_ILP_ADDRESS: Address = Address::from_str("local.host").unwrap();
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Balance {
+    balance: i64,
+    prepaid_amount: i64,
+}
+
+impl Balance {
+    fn total(self) -> i64 {
+        self.balance + self.prepaid_amount
+    }
+}
+
+/// Leaky bucket used to apply the per-minute packet and throughput limits.
+/// The bucket drains at a rate of `limit` units per minute.
+#[derive(Debug, Clone, Copy)]
+struct LeakyBucket {
+    level: u64,
+    last_update: Instant,
+}
+
+impl LeakyBucket {
+    fn new() -> Self {
+        LeakyBucket {
+            level: 0,
+            last_update: Instant::now(),
+        }
+    }
+
+    fn drain(&mut self, limit: u64) {
+        let elapsed = self.last_update.elapsed().as_millis();
+        let drained = elapsed * u128::from(limit) / RATE_LIMIT_WINDOW_MS;
+        if drained > 0 {
+            self.level = self.level.saturating_sub(drained as u64);
+            self.last_update = Instant::now();
+        }
+    }
+
+    fn has_capacity(&mut self, limit: u64, amount: u64) -> bool {
+        self.drain(limit);
+        self.level.saturating_add(amount) <= limit
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RateLimitKind {
+    Packets,
+    Throughput,
+}
+
+#[derive(Default)]
+struct StoreState {
+    accounts: HashMap<AccountId, Account>,
+    usernames: HashMap<String, AccountId>,
+    client_certificates: HashMap<CertificateFingerprint, AccountId>,
+    balances: HashMap<AccountId, Balance>,
+    routes: HashMap<Bytes, AccountId>,
+    static_routes: HashMap<String, AccountId>,
+    default_route: Option<AccountId>,
+    settlement_engines: HashMap<String, Url>,
+    /// Set when the node's address was configured via a parent account (using ILDCP)
+    parent_ilp_address: Option<Address>,
+    idempotent_data: HashMap<String, (IdempotentData, Instant)>,
+    settlement_idempotency_keys: HashMap<String, Instant>,
+    uncredited_settlement_amounts: HashMap<AccountId, Vec<(BigUint, u8)>>,
+    rate_limits: HashMap<(AccountId, RateLimitKind), LeakyBucket>,
+}
+
+impl StoreState {
+    /// Returns a copy of the account, filling in the globally configured
+    /// settlement engine for the account's asset if it does not have one set
+    fn load_account(&self, id: AccountId) -> Option<Account> {
+        self.accounts.get(&id).map(|account| {
+            let mut account = account.clone();
+            if account.settlement_engine_url.is_none() {
+                account.settlement_engine_url =
+                    self.settlement_engines.get(&account.asset_code).cloned();
+            }
+            account
+        })
+    }
+
+    fn load_accounts<'a>(&self, ids: impl IntoIterator<Item = &'a AccountId>) -> Vec<Account> {
+        ids.into_iter()
+            .filter_map(|id| self.load_account(*id))
+            .collect()
+    }
+
+    fn account_from_username(&self, username: &Username) -> Option<Account> {
+        self.usernames
+            .get(&username.to_lowercase())
+            .and_then(|id| self.load_account(*id))
+    }
+
+    fn account_from_certificate(&self, fingerprint: &CertificateFingerprint) -> Option<Account> {
+        self.client_certificates
+            .get(fingerprint)
+            .and_then(|id| self.load_account(*id))
+    }
+
+    fn remove_expired_idempotency_keys(&mut self) {
+        self.idempotent_data
+            .retain(|_, (_, saved_at)| saved_at.elapsed() < IDEMPOTENCY_KEY_TTL);
+        self.settlement_idempotency_keys
+            .retain(|_, saved_at| saved_at.elapsed() < IDEMPOTENCY_KEY_TTL);
+    }
+
+    /// Combine the dynamic routes, the default route, and the static routes
+    /// into the table used by the Router. Static routes take precedence over
+    /// any dynamic routes with the same prefix.
+    fn routing_table(&self) -> interledger_router::RoutingTable<AccountId> {
+        let default_route_iter = self
+            .default_route
+            .iter()
+            .map(|account_id| (Bytes::new(), *account_id));
+        interledger_router::RoutingTable::from_iter(
+            self.routes
+                .iter()
+                .map(|(prefix, account_id)| (prefix.clone(), *account_id))
+                .chain(default_route_iter)
+                .chain(
+                    self.static_routes
+                        .iter()
+                        .map(|(prefix, account_id)| (Bytes::from(prefix.as_str()), *account_id)),
+                ),
+        )
+    }
+}
+
+pub struct MemoryStoreBuilder {
+    node_ilp_address: Address,
+}
+
+impl Default for MemoryStoreBuilder {
+    fn default() -> Self {
+        MemoryStoreBuilder {
+            node_ilp_address: DEFAULT_ILP_ADDRESS.clone(),
+        }
+    }
+}
+
+impl MemoryStoreBuilder {
+    pub fn new() -> Self {
+        MemoryStoreBuilder::default()
+    }
+
+    pub fn node_ilp_address(&mut self, node_ilp_address: Address) -> &mut Self {
+        self.node_ilp_address = node_ilp_address;
+        self
+    }
+
+    pub fn build(&self) -> MemoryStore {
+        MemoryStore {
+            ilp_address: Arc::new(RwLock::new(self.node_ilp_address.clone())),
+            state: Arc::new(RwLock::new(StoreState::default())),
+            subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            exchange_rates: Arc::new(RwLock::new(HashMap::new())),
+            routes: Arc::new(RwLock::new(Arc::default())),
+            alternate_routes: Arc::new(RwLock::new(Arc::default())),
+        }
+    }
+}
+
+/// A Store that keeps all of its data in memory.
+///
+/// All data is lost when the store is dropped, so this is intended for tests,
+/// development, and small deployments that do not need persistence.
+/// Balance updates are applied while holding a single lock, which gives them
+/// the same atomicity guarantees as the Redis store's Lua scripts.
+#[derive(Clone)]
+pub struct MemoryStore {
+    ilp_address: Arc<RwLock<Address>>,
+    state: Arc<RwLock<StoreState>>,
+    subscriptions: Arc<RwLock<HashMap<AccountId, UnboundedSender<PaymentNotification>>>>,
+    exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
+    routes: Arc<RwLock<Arc<interledger_router::RoutingTable<AccountId>>>>,
+    alternate_routes: Arc<RwLock<Arc<AlternateRoutes<AccountId>>>>,
+}
+
+impl MemoryStore {
+    pub fn get_all_accounts_ids(&self) -> Vec<AccountId> {
+        self.state.read().accounts.keys().cloned().collect()
+    }
+
+    /// Rebuild the in-memory routing table snapshot used by the Router.
+    /// The caller must pass in the (already locked) store state.
+    fn update_routes(&self, state: &StoreState) {
+        let routes = state.routing_table();
+        trace!("Routing table is: {:?}", routes);
+        *self.routes.write() = Arc::new(routes);
+    }
+
+    fn insert_account_into_state(&self, account: Account) -> Result<Account, ()> {
+        let mut state = self.state.write();
+        if state.accounts.contains_key(&account.id)
+            || state
+                .usernames
+                .contains_key(&account.username.to_lowercase())
+            || account
+                .client_certificate_fingerprint
+                .map_or(false, |fingerprint| {
+                    state.client_certificates.contains_key(&fingerprint)
+                })
+            || (account.routing_relation == RoutingRelation::Parent
+                && state.parent_ilp_address.is_some())
+        {
+            warn!(
+                "An account already exists with the same {}. Cannot insert account: {:?}",
+                account.id, account
+            );
+            return Err(());
+        }
+
+        state
+            .usernames
+            .insert(account.username.to_lowercase(), account.id);
+        if let Some(fingerprint) = account.client_certificate_fingerprint {
+            state.client_certificates.insert(fingerprint, account.id);
+        }
+        state.balances.insert(account.id, Balance::default());
+        state
+            .routes
+            .insert(account.ilp_address.to_bytes(), account.id);
+        state.accounts.insert(account.id, account.clone());
+        self.update_routes(&state);
+
+        debug!(
+            "Inserted account {} (ILP address: {})",
+            account.id, account.ilp_address
+        );
+        Ok(account)
+    }
+
+    fn update_account_in_state(&self, account: Account) -> Result<Account, ()> {
+        let mut state = self.state.write();
+        if let Some(other_id) = state.usernames.get(&account.username.to_lowercase()) {
+            if *other_id != account.id {
+                warn!(
+                    "Username {} is already taken by account {}, cannot update account {}",
+                    account.username, other_id, account.id
+                );
+                return Err(());
+            }
+        }
+        if let Some(fingerprint) = account.client_certificate_fingerprint {
+            if let Some(other_id) = state.client_certificates.get(&fingerprint) {
+                if *other_id != account.id {
+                    warn!(
+                        "Client certificate {} is already used by account {}, cannot update account {}",
+                        fingerprint, other_id, account.id
+                    );
+                    return Err(());
+                }
+            }
+        }
+        let previous = if let Some(previous) = state.accounts.remove(&account.id) {
+            previous
+        } else {
+            warn!(
+                "No account exists with ID {}, cannot update account {:?}",
+                account.id, account
+            );
+            return Err(());
+        };
+
+        state.usernames.remove(&previous.username.to_lowercase());
+        if let Some(fingerprint) = previous.client_certificate_fingerprint {
+            state.client_certificates.remove(&fingerprint);
+        }
+        state.routes.remove(&previous.ilp_address.to_bytes());
+        state
+            .usernames
+            .insert(account.username.to_lowercase(), account.id);
+        if let Some(fingerprint) = account.client_certificate_fingerprint {
+            state.client_certificates.insert(fingerprint, account.id);
+        }
+        state
+            .routes
+            .insert(account.ilp_address.to_bytes(), account.id);
+        state.accounts.insert(account.id, account.clone());
+        self.update_routes(&state);
+
+        debug!(
+            "Updated account {} (id: {}, ILP address: {})",
+            account.username, account.id, account.ilp_address
+        );
+        Ok(account)
+    }
+}
+
+impl AccountStore for MemoryStore {
+    type Account = Account;
+
+    fn get_accounts(
+        &self,
+        account_ids: Vec<AccountId>,
+    ) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
+        let accounts = self.state.read().load_accounts(account_ids.iter());
+        if accounts.len() == account_ids.len() {
+            Box::new(ok(accounts))
+        } else {
+            error!("Error loading accounts: {:?}", account_ids);
+            Box::new(err(()))
+        }
+    }
+
+    fn get_account_id_from_username(
+        &self,
+        username: &Username,
+    ) -> Box<dyn Future<Item = AccountId, Error = ()> + Send> {
+        let id = self
+            .state
+            .read()
+            .usernames
+            .get(&username.to_lowercase())
+            .cloned();
+        Box::new(result(
+            id.ok_or_else(|| debug!("Username not found: {}", username)),
+        ))
+    }
+}
+
+impl AddressStore for MemoryStore {
+    // Updates the ILP address of the store & iterates over all children and
+    // updates their ILP Address to match the new address.
+    fn set_ilp_address(
+        &self,
+        ilp_address: Address,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        debug!("Setting ILP address to: {}", ilp_address);
+        let mut state = self.state.write();
+        let state = &mut *state;
+
+        // Compute the new addresses of all children and non-routing accounts
+        // before changing anything, so a failure leaves the store untouched
+        let mut updated_addresses = Vec::new();
+        for account in state.accounts.values() {
+            if account.routing_relation == RoutingRelation::Parent
+                || account.routing_relation == RoutingRelation::Peer
+            {
+                continue;
+            }
+
+            // If the node's address already ends with the account's username,
+            // this account represents the node's non routing account
+            let new_ilp_address =
+                if ilp_address.segments().rev().next() == Some(account.username.as_ref()) {
+                    ilp_address.clone()
+                } else {
+                    match ilp_address.with_suffix(account.username.as_bytes()) {
+                        Ok(address) => address,
+                        Err(_) => {
+                            error!(
+                                "Could not append username {} to address {}",
+                                account.username, ilp_address
+                            );
+                            return Box::new(err(()));
+                        }
+                    }
+                };
+            updated_addresses.push((account.id, new_ilp_address));
+        }
+
+        *self.ilp_address.write() = ilp_address.clone();
+        state.parent_ilp_address = Some(ilp_address);
+        // Remove all of the old routes first so that an account's new route
+        // is not removed if another account previously had the same address
+        for (id, _) in updated_addresses.iter() {
+            let account = &state.accounts[id];
+            state.routes.remove(&account.ilp_address.to_bytes());
+        }
+        for (id, new_ilp_address) in updated_addresses {
+            state.routes.insert(new_ilp_address.to_bytes(), id);
+            if let Some(account) = state.accounts.get_mut(&id) {
+                account.ilp_address = new_ilp_address;
+            }
+        }
+        self.update_routes(state);
+        Box::new(ok(()))
+    }
+
+    fn clear_ilp_address(&self) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        self.state.write().parent_ilp_address = None;
+        *self.ilp_address.write() = DEFAULT_ILP_ADDRESS.clone();
+        Box::new(ok(()))
+    }
+
+    fn get_ilp_address(&self) -> Address {
+        self.ilp_address.read().clone()
+    }
+}
+
+impl NodeStore for MemoryStore {
+    type Account = Account;
+
+    fn insert_account(
+        &self,
+        account: AccountDetails,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        let id = AccountId::new();
+        let account = match Account::try_from(id, account, self.get_ilp_address()) {
+            Ok(account) => account,
+            Err(_) => return Box::new(err(())),
+        };
+        debug!(
+            "Generated account id for {}: {}",
+            account.username.clone(),
+            account.id
+        );
+        Box::new(result(self.insert_account_into_state(account)))
+    }
+
+    fn delete_account(&self, id: AccountId) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        let mut state = self.state.write();
+        let account = if let Some(account) = state.load_account(id) {
+            account
+        } else {
+            error!("Cannot delete account {} because it does not exist", id);
+            return Box::new(err(()));
+        };
+
+        state.accounts.remove(&id);
+        state.usernames.remove(&account.username.to_lowercase());
+        if let Some(fingerprint) = account.client_certificate_fingerprint {
+            state.client_certificates.remove(&fingerprint);
+        }
+        state.balances.remove(&id);
+        state.routes.remove(&account.ilp_address.to_bytes());
+        state.uncredited_settlement_amounts.remove(&id);
+        state
+            .rate_limits
+            .retain(|(account_id, _), _| *account_id != id);
+        self.update_routes(&state);
+        self.subscriptions.write().remove(&id);
+
+        debug!("Deleted account {}", account.id);
+        Box::new(ok(account))
+    }
+
+    fn update_account(
+        &self,
+        id: AccountId,
+        account: AccountDetails,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        let account = match Account::try_from(id, account, self.get_ilp_address()) {
+            Ok(account) => account,
+            Err(_) => return Box::new(err(())),
+        };
+        Box::new(result(self.update_account_in_state(account)))
+    }
+
+    fn modify_account_settings(
+        &self,
+        id: AccountId,
+        settings: AccountSettings,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        let ilp_over_http_url = match settings
+            .ilp_over_http_url
+            .as_ref()
+            .map(|url| Url::parse(url))
+        {
+            Some(Ok(url)) => Some(url),
+            Some(Err(e)) => {
+                error!("Invalid URL: {:?}", e);
+                return Box::new(err(()));
+            }
+            None => None,
+        };
+        let ilp_over_btp_url = match settings
+            .ilp_over_btp_url
+            .as_ref()
+            .map(|url| Url::parse(url))
+        {
+            Some(Ok(url)) => Some(url),
+            Some(Err(e)) => {
+                error!("Invalid URL: {:?}", e);
+                return Box::new(err(()));
+            }
+            None => None,
+        };
+
+        let mut state = self.state.write();
+        if let Some(account) = state.accounts.get_mut(&id) {
+            if ilp_over_http_url.is_some() {
+                account.ilp_over_http_url = ilp_over_http_url;
+            }
+            if ilp_over_btp_url.is_some() {
+                account.ilp_over_btp_url = ilp_over_btp_url;
+            }
+            if let Some(token) = settings.ilp_over_http_incoming_token {
+                account.ilp_over_http_incoming_token =
+                    Some(SecretBytes::new(token.expose_secret().to_string()));
+            }
+            if let Some(token) = settings.ilp_over_http_outgoing_token {
+                account.ilp_over_http_outgoing_token =
+                    Some(SecretBytes::new(token.expose_secret().to_string()));
+            }
+            if let Some(token) = settings.ilp_over_btp_incoming_token {
+                account.ilp_over_btp_incoming_token =
+                    Some(SecretBytes::new(token.expose_secret().to_string()));
+            }
+            if let Some(token) = settings.ilp_over_btp_outgoing_token {
+                account.ilp_over_btp_outgoing_token =
+                    Some(SecretBytes::new(token.expose_secret().to_string()));
+            }
+            if let Some(settle_threshold) = settings.settle_threshold {
+                account.settle_threshold = Some(settle_threshold);
+            }
+            if let Some(settle_to) = settings.settle_to {
+                account.settle_to = Some(settle_to as i64);
+            }
+        } else {
+            error!("Error modifying user account: no account with ID {}", id);
+            return Box::new(err(()));
+        }
+        Box::new(result(state.load_account(id).ok_or(())))
+    }
+
+    // TODO limit the number of results and page through them
+    fn get_all_accounts(&self) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
+        let state = self.state.read();
+        Box::new(ok(state.load_accounts(state.accounts.keys())))
+    }
+
+    fn set_static_routes<R>(&self, routes: R) -> Box<dyn Future<Item = (), Error = ()> + Send>
+    where
+        R: IntoIterator<Item = (String, AccountId)>,
+    {
+        let routes: HashMap<String, AccountId> = routes.into_iter().collect();
+        let mut state = self.state.write();
+        if !routes
+            .values()
+            .all(|account_id| state.accounts.contains_key(account_id))
+        {
+            error!("Error setting static routes because not all of the given accounts exist");
+            return Box::new(err(()));
+        }
+        state.static_routes = routes;
+        self.update_routes(&state);
+        Box::new(ok(()))
+    }
+
+    fn set_static_route(
+        &self,
+        prefix: String,
+        account_id: AccountId,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut state = self.state.write();
+        if !state.accounts.contains_key(&account_id) {
+            error!(
+                "Cannot set static route for prefix: {} because account {} does not exist",
+                prefix, account_id
+            );
+            return Box::new(err(()));
+        }
+        state.static_routes.insert(prefix, account_id);
+        self.update_routes(&state);
+        Box::new(ok(()))
+    }
+
+    fn set_default_route(
+        &self,
+        account_id: AccountId,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut state = self.state.write();
+        if !state.accounts.contains_key(&account_id) {
+            error!(
+                "Cannot set default route because account {} does not exist",
+                account_id
+            );
+            return Box::new(err(()));
+        }
+        state.default_route = Some(account_id);
+        self.update_routes(&state);
+        debug!("Set default route to account id: {}", account_id);
+        Box::new(ok(()))
+    }
+
+    fn set_settlement_engines(
+        &self,
+        asset_to_url_map: impl IntoIterator<Item = (String, Url)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let asset_to_url_map: Vec<(String, Url)> = asset_to_url_map.into_iter().collect();
+        debug!("Setting settlement engines to {:?}", asset_to_url_map);
+        self.state
+            .write()
+            .settlement_engines
+            .extend(asset_to_url_map);
+        Box::new(ok(()))
+    }
+
+    fn get_asset_settlement_engine(
+        &self,
+        asset_code: &str,
+    ) -> Box<dyn Future<Item = Option<Url>, Error = ()> + Send> {
+        Box::new(ok(self
+            .state
+            .read()
+            .settlement_engines
+            .get(asset_code)
+            .cloned()))
+    }
+}
+
+impl BalanceStore for MemoryStore {
+    /// Returns the balance **from the account holder's perspective**, meaning the sum of
+    /// the Payable Balance and Pending Outgoing minus the Receivable Balance and the Pending Incoming.
+    fn get_balance(&self, account: Account) -> Box<dyn Future<Item = i64, Error = ()> + Send> {
+        Box::new(result(
+            self.state
+                .read()
+                .balances
+                .get(&account.id)
+                .map(|balance| balance.total())
+                .ok_or_else(|| error!("Error getting balance for account: {}", account.id)),
+        ))
+    }
+
+    fn update_balances_for_prepare(
+        &self,
+        from_account: Account,
+        incoming_amount: u64,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if incoming_amount == 0 {
+            return Box::new(ok(()));
+        }
+        let from_account_id = from_account.id;
+        let incoming_amount = incoming_amount as i64;
+        let mut state = self.state.write();
+        let min_balance = state
+            .accounts
+            .get(&from_account_id)
+            .and_then(|account| account.min_balance);
+        let balance = if let Some(balance) = state.balances.get_mut(&from_account_id) {
+            balance
+        } else {
+            warn!(
+                "Error handling prepare from account: {}: account not found",
+                from_account_id
+            );
+            return Box::new(err(()));
+        };
+
+        // Check that the prepare wouldn't go under the account's minimum balance
+        if let Some(min_balance) = min_balance {
+            if balance.total() - incoming_amount < min_balance {
+                warn!("Incoming prepare of {} would bring account {} under its minimum balance. Current balance: {}, min balance: {}", incoming_amount, from_account_id, balance.balance, min_balance);
+                return Box::new(err(()));
+            }
+        }
+
+        // Deduct the amount from the prepaid_amount and/or the balance
+        if balance.prepaid_amount >= incoming_amount {
+            balance.prepaid_amount -= incoming_amount;
+        } else if balance.prepaid_amount > 0 {
+            balance.balance -= incoming_amount - balance.prepaid_amount;
+            balance.prepaid_amount = 0;
+        } else {
+            balance.balance -= incoming_amount;
+        }
+
+        trace!(
+            "Processed prepare with incoming amount: {}. Account {} has balance (including prepaid amount): {} ",
+            incoming_amount, from_account_id, balance.total()
+        );
+        Box::new(ok(()))
+    }
+
+    fn update_balances_for_fulfill(
+        &self,
+        to_account: Account,
+        outgoing_amount: u64,
+    ) -> Box<dyn Future<Item = (i64, u64), Error = ()> + Send> {
+        if outgoing_amount == 0 {
+            return Box::new(ok((0, 0)));
+        }
+        let to_account_id = to_account.id;
+        let mut state = self.state.write();
+        let (settle_threshold, settle_to) = state
+            .accounts
+            .get(&to_account_id)
+            .map(|account| (account.settle_threshold, account.settle_to))
+            .unwrap_or((None, None));
+        let balance = if let Some(balance) = state.balances.get_mut(&to_account_id) {
+            balance
+        } else {
+            error!(
+                "Error handling Fulfill received from account: {}: account not found",
+                to_account_id
+            );
+            return Box::new(err(()));
+        };
+        balance.balance += outgoing_amount as i64;
+
+        // Settlement is triggered if the balance reaches the settle_threshold,
+        // as long as the settle_threshold is greater than the settle_to amount.
+        // The balance is reduced _before_ the settlement is sent so that we don't
+        // accidentally send multiple settlements for the same balance. If the
+        // settlement fails, the amount is refunded with `refund_settlement`.
+        let mut amount_to_settle = 0;
+        if let (Some(settle_threshold), Some(settle_to)) = (settle_threshold, settle_to) {
+            if balance.balance >= settle_threshold && settle_threshold > settle_to {
+                amount_to_settle = (balance.balance - settle_to) as u64;
+                balance.balance = settle_to;
+            }
+        }
+
+        trace!(
+            "Processed fulfill for account {} for outgoing amount {}. Fulfill call result: {} {}",
+            to_account_id,
+            outgoing_amount,
+            balance.total(),
+            amount_to_settle,
+        );
+        Box::new(ok((balance.total(), amount_to_settle)))
+    }
+
+    fn update_balances_for_reject(
+        &self,
+        from_account: Account,
+        incoming_amount: u64,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if incoming_amount == 0 {
+            return Box::new(ok(()));
+        }
+        let from_account_id = from_account.id;
+        let mut state = self.state.write();
+        if let Some(balance) = state.balances.get_mut(&from_account_id) {
+            balance.balance += incoming_amount as i64;
+            trace!(
+                "Processed reject for incoming amount: {}. Account {} has balance (including prepaid amount): {}",
+                incoming_amount, from_account_id, balance.total()
+            );
+            Box::new(ok(()))
+        } else {
+            warn!(
+                "Error handling reject for packet from account: {}: account not found",
+                from_account_id
+            );
+            Box::new(err(()))
+        }
+    }
+}
+
+impl ExchangeRateStore for MemoryStore {
+    fn get_exchange_rates(&self, asset_codes: &[&str]) -> Result<Vec<f64>, ()> {
+        let exchange_rates = self.exchange_rates.read();
+        let rates: Vec<f64> = asset_codes
+            .iter()
+            .filter_map(|code| exchange_rates.get(*code).cloned())
+            .collect();
+        if rates.len() == asset_codes.len() {
+            Ok(rates)
+        } else {
+            Err(())
+        }
+    }
+
+    fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ()> {
+        Ok(self.exchange_rates.read().clone())
+    }
+
+    fn set_exchange_rates(&self, rates: HashMap<String, f64>) -> Result<(), ()> {
+        *self.exchange_rates.write() = rates;
+        Ok(())
+    }
+}
+
+impl BtpStore for MemoryStore {
+    type Account = Account;
+
+    fn get_account_from_btp_auth(
+        &self,
+        username: &Username,
+        token: &str,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        let account = if let Some(account) = self.state.read().account_from_username(username) {
+            account
+        } else {
+            warn!("No account found with BTP token");
+            return Box::new(err(()));
+        };
+        match account.ilp_over_btp_incoming_token {
+            Some(ref t) if t.expose_secret().as_ref() == token.as_bytes() => Box::new(ok(account)),
+            Some(_) => {
+                debug!(
+                    "Found account {} but BTP auth token was wrong",
+                    account.username
+                );
+                Box::new(err(()))
+            }
+            None => {
+                debug!(
+                    "Account {} does not have an incoming btp token configured",
+                    account.username
+                );
+                Box::new(err(()))
+            }
+        }
+    }
+
+    fn get_account_from_btp_certificate(
+        &self,
+        fingerprint: &CertificateFingerprint,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        Box::new(result(
+            self.state
+                .read()
+                .account_from_certificate(fingerprint)
+                .ok_or_else(|| warn!("No account found with given TLS client certificate")),
+        ))
+    }
+
+    fn get_btp_outgoing_accounts(&self) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
+        let state = self.state.read();
+        let ids: Vec<AccountId> = state
+            .accounts
+            .values()
+            .filter(|account| account.ilp_over_btp_url.is_some())
+            .map(|account| account.id)
+            .collect();
+        Box::new(ok(state.load_accounts(ids.iter())))
+    }
+}
+
+impl HttpStore for MemoryStore {
+    type Account = Account;
+
+    /// Checks if the stored token for the provided account id matches the
+    /// provided token, and if so, returns the account associated with that token
+    fn get_account_from_http_auth(
+        &self,
+        username: &Username,
+        token: &str,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        let account = if let Some(account) = self.state.read().account_from_username(username) {
+            account
+        } else {
+            warn!("No account found with given HTTP auth");
+            return Box::new(err(()));
+        };
+        match account.ilp_over_http_incoming_token {
+            Some(ref t) if t.expose_secret().as_ref() == token.as_bytes() => Box::new(ok(account)),
+            _ => Box::new(err(())),
+        }
+    }
+
+    fn get_account_from_http_certificate(
+        &self,
+        fingerprint: &CertificateFingerprint,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        Box::new(result(
+            self.state
+                .read()
+                .account_from_certificate(fingerprint)
+                .ok_or_else(|| warn!("No account found with given TLS client certificate")),
+        ))
+    }
+}
+
+impl RouterStore for MemoryStore {
+    fn routing_table(&self) -> Arc<interledger_router::RoutingTable<AccountId>> {
+        self.routes.read().clone()
+    }
+
+    fn alternate_routes(&self) -> Arc<AlternateRoutes<AccountId>> {
+        self.alternate_routes.read().clone()
+    }
+}
+
+type RoutingTable<A> = HashMap<Bytes, A>;
+
+impl RouteManagerStore for MemoryStore {
+    type Account = Account;
+
+    fn get_accounts_to_send_routes_to(
+        &self,
+        ignore_accounts: Vec<AccountId>,
+    ) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
+        let state = self.state.read();
+        let ids: Vec<AccountId> = state
+            .accounts
+            .values()
+            .filter(|account| {
+                account.should_send_routes() && !ignore_accounts.contains(&account.id)
+            })
+            .map(|account| account.id)
+            .collect();
+        Box::new(ok(state.load_accounts(ids.iter())))
+    }
+
+    fn get_accounts_to_receive_routes_from(
+        &self,
+    ) -> Box<dyn Future<Item = Vec<Account>, Error = ()> + Send> {
+        let state = self.state.read();
+        let ids: Vec<AccountId> = state
+            .accounts
+            .values()
+            .filter(|account| account.should_receive_routes())
+            .map(|account| account.id)
+            .collect();
+        Box::new(ok(state.load_accounts(ids.iter())))
+    }
+
+    fn get_local_and_configured_routes(
+        &self,
+    ) -> Box<dyn Future<Item = (RoutingTable<Account>, RoutingTable<Account>), Error = ()> + Send>
+    {
+        let state = self.state.read();
+        let accounts = state.load_accounts(state.accounts.keys());
+        let local_table = HashMap::from_iter(
+            accounts
+                .iter()
+                .map(|account| (account.ilp_address.to_bytes(), account.clone())),
+        );
+
+        let account_map: HashMap<AccountId, &Account> =
+            HashMap::from_iter(accounts.iter().map(|account| (account.id, account)));
+        let configured_table: HashMap<Bytes, Account> = HashMap::from_iter(
+            state
+                .static_routes
+                .iter()
+                .filter_map(|(prefix, account_id)| {
+                    if let Some(account) = account_map.get(account_id) {
+                        Some((Bytes::from(prefix.as_str()), (*account).clone()))
+                    } else {
+                        warn!(
+                            "No account for ID: {}, ignoring configured route for prefix: {}",
+                            account_id, prefix
+                        );
+                        None
+                    }
+                }),
+        );
+
+        Box::new(ok((local_table, configured_table)))
+    }
+
+    fn set_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Account)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let routes: HashMap<Bytes, AccountId> = routes
+            .into_iter()
+            .filter(|(prefix, _account)| std::str::from_utf8(prefix.as_ref()).is_ok())
+            .map(|(prefix, account)| (prefix, account.id))
+            .collect();
+        let num_routes = routes.len();
+
+        let mut state = self.state.write();
+        state.routes = routes;
+        self.update_routes(&state);
+        trace!("Saved {} routes", num_routes);
+        Box::new(ok(()))
+    }
+
+    fn set_alternate_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Vec<NextHop<AccountId>>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let routes: AlternateRoutes<AccountId> = routes
+            .into_iter()
+            .filter(|(prefix, _next_hops)| std::str::from_utf8(prefix.as_ref()).is_ok())
+            .collect();
+        trace!("Saved alternate routes for {} prefixes", routes.len());
+        *self.alternate_routes.write() = Arc::new(routes);
+        Box::new(ok(()))
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    type Account = Account;
+
+    /// Apply rate limits for number of packets per minute and amount of money per minute
+    fn apply_rate_limits(
+        &self,
+        account: Account,
+        prepare_amount: u64,
+    ) -> Box<dyn Future<Item = (), Error = RateLimitError> + Send> {
+        if account.packets_per_minute_limit.is_none() && account.amount_per_minute_limit.is_none() {
+            return Box::new(ok(()));
+        }
+
+        let mut state = self.state.write();
+        // Check both limits before updating either bucket so that a rejected
+        // packet does not count against the account's limits
+        if let Some(limit) = account.packets_per_minute_limit {
+            let bucket = state
+                .rate_limits
+                .entry((account.id, RateLimitKind::Packets))
+                .or_insert_with(LeakyBucket::new);
+            if !bucket.has_capacity(u64::from(limit), 1) {
+                return Box::new(err(RateLimitError::PacketLimitExceeded));
+            }
+        }
+        if let Some(limit) = account.amount_per_minute_limit {
+            let bucket = state
+                .rate_limits
+                .entry((account.id, RateLimitKind::Throughput))
+                .or_insert_with(LeakyBucket::new);
+            if !bucket.has_capacity(limit, prepare_amount) {
+                return Box::new(err(RateLimitError::ThroughputLimitExceeded));
+            }
+        }
+
+        if account.packets_per_minute_limit.is_some() {
+            if let Some(bucket) = state
+                .rate_limits
+                .get_mut(&(account.id, RateLimitKind::Packets))
+            {
+                bucket.level += 1;
+            }
+        }
+        if account.amount_per_minute_limit.is_some() {
+            if let Some(bucket) = state
+                .rate_limits
+                .get_mut(&(account.id, RateLimitKind::Throughput))
+            {
+                bucket.level = bucket.level.saturating_add(prepare_amount);
+            }
+        }
+        Box::new(ok(()))
+    }
+
+    fn refund_throughput_limit(
+        &self,
+        account: Account,
+        prepare_amount: u64,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        if account.amount_per_minute_limit.is_some() {
+            if let Some(bucket) = self
+                .state
+                .write()
+                .rate_limits
+                .get_mut(&(account.id, RateLimitKind::Throughput))
+            {
+                bucket.level = bucket.level.saturating_sub(prepare_amount);
+            }
+        }
+        Box::new(ok(()))
+    }
+}
+
+impl IdempotentStore for MemoryStore {
+    fn load_idempotent_data(
+        &self,
+        idempotency_key: String,
+    ) -> Box<dyn Future<Item = Option<IdempotentData>, Error = ()> + Send> {
+        let mut state = self.state.write();
+        state.remove_expired_idempotency_keys();
+        let data = state
+            .idempotent_data
+            .get(&idempotency_key)
+            .map(|(data, _)| data.clone());
+        trace!("Loaded idempotency key {:?} - {:?}", idempotency_key, data);
+        Box::new(ok(data))
+    }
+
+    fn save_idempotent_data(
+        &self,
+        idempotency_key: String,
+        input_hash: [u8; 32],
+        status_code: StatusCode,
+        data: Bytes,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        trace!(
+            "Cached {:?}: {:?}, {:?}",
+            idempotency_key,
+            status_code,
+            data,
+        );
+        self.state.write().idempotent_data.insert(
+            idempotency_key,
+            (
+                IdempotentData::new(status_code, data, input_hash),
+                Instant::now(),
+            ),
+        );
+        Box::new(ok(()))
+    }
+}
+
+impl SettlementStore for MemoryStore {
+    type Account = Account;
+
+    fn update_balance_for_incoming_settlement(
+        &self,
+        account_id: AccountId,
+        amount: u64,
+        idempotency_key: Option<String>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let mut state = self.state.write();
+        state.remove_expired_idempotency_keys();
+        if let Some(idempotency_key) = idempotency_key {
+            // If the idempotency key has been used, do not perform any operations
+            if state
+                .settlement_idempotency_keys
+                .contains_key(&idempotency_key)
+            {
+                return Box::new(ok(()));
+            }
+            state
+                .settlement_idempotency_keys
+                .insert(idempotency_key, Instant::now());
+        }
+
+        let balance = if let Some(balance) = state.balances.get_mut(&account_id) {
+            balance
+        } else {
+            error!("Error processing incoming settlement from account: {} for amount: {}: account not found", account_id, amount);
+            return Box::new(err(()));
+        };
+
+        // Credit the incoming settlement to the balance and/or prepaid amount,
+        // depending on whether that account currently owes money or not
+        let amount = amount as i64;
+        if balance.balance >= 0 {
+            balance.prepaid_amount += amount;
+        } else if balance.balance.abs() >= amount {
+            balance.balance += amount;
+        } else {
+            balance.prepaid_amount += amount + balance.balance;
+            balance.balance = 0;
+        }
+
+        trace!(
+            "Processed incoming settlement from account: {} for amount: {}. Balance is now: {}",
+            account_id,
+            amount,
+            balance.total()
+        );
+        Box::new(ok(()))
+    }
+
+    fn refund_settlement(
+        &self,
+        account_id: AccountId,
+        settle_amount: u64,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        trace!(
+            "Refunding settlement for account: {} of amount: {}",
+            account_id,
+            settle_amount
+        );
+        let mut state = self.state.write();
+        if let Some(balance) = state.balances.get_mut(&account_id) {
+            balance.balance += settle_amount as i64;
+            trace!(
+                "Refunded settlement for account: {} of amount: {}. Balance is now: {}",
+                account_id,
+                settle_amount,
+                balance.balance
+            );
+            Box::new(ok(()))
+        } else {
+            error!(
+                "Error refunding settlement for account: {} of amount: {}: account not found",
+                account_id, settle_amount
+            );
+            Box::new(err(()))
+        }
+    }
+}
+
+/// Sum the given amounts after scaling them all to the largest of their scales
+fn sum_amounts_with_scale(amounts: &[(BigUint, u8)]) -> (BigUint, u8) {
+    let max_scale = amounts.iter().map(|(_, scale)| *scale).max().unwrap_or(0);
+    let mut sum = BigUint::from(0u32);
+    for (amount, scale) in amounts {
+        sum += amount
+            .normalize_scale(ConvertDetails {
+                from: *scale,
+                to: max_scale,
+            })
+            .unwrap();
+    }
+    (sum, max_scale)
+}
+
+impl LeftoversStore for MemoryStore {
+    type AccountId = AccountId;
+    type AssetType = BigUint;
+
+    fn get_uncredited_settlement_amount(
+        &self,
+        account_id: AccountId,
+    ) -> Box<dyn Future<Item = (BigUint, u8), Error = ()> + Send> {
+        // get the amounts and instantly delete them
+        let amounts = self
+            .state
+            .write()
+            .uncredited_settlement_amounts
+            .remove(&account_id)
+            .unwrap_or_default();
+        Box::new(ok(sum_amounts_with_scale(&amounts)))
+    }
+
+    fn save_uncredited_settlement_amount(
+        &self,
+        account_id: AccountId,
+        uncredited_settlement_amount: (BigUint, u8),
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        trace!(
+            "Saving uncredited_settlement_amount {:?} {:?}",
+            account_id,
+            uncredited_settlement_amount
+        );
+        self.state
+            .write()
+            .uncredited_settlement_amounts
+            .entry(account_id)
+            .or_insert_with(Vec::new)
+            .push(uncredited_settlement_amount);
+        Box::new(ok(()))
+    }
+
+    fn load_uncredited_settlement_amount(
+        &self,
+        account_id: AccountId,
+        local_scale: u8,
+    ) -> Box<dyn Future<Item = BigUint, Error = ()> + Send> {
+        trace!("Loading uncredited_settlement_amount {:?}", account_id);
+        let mut state = self.state.write();
+        let amounts = state
+            .uncredited_settlement_amounts
+            .remove(&account_id)
+            .unwrap_or_default();
+        let (amount, scale) = sum_amounts_with_scale(&amounts);
+
+        // scale the amount from the max scale to the local scale, and then
+        // save any potential leftovers to the store
+        let (scaled_amount, precision_loss) = scale_with_precision_loss(amount, local_scale, scale);
+        if precision_loss > BigUint::from(0u32) {
+            state
+                .uncredited_settlement_amounts
+                .insert(account_id, vec![(precision_loss, max(local_scale, scale))]);
+        }
+        Box::new(ok(scaled_amount))
+    }
+}
+
+impl StreamNotificationsStore for MemoryStore {
+    type Account = Account;
+
+    fn add_payment_notification_subscription(
+        &self,
+        id: AccountId,
+        sender: UnboundedSender<PaymentNotification>,
+    ) {
+        trace!("Added payment notification listener for {}", id);
+        self.subscriptions.write().insert(id, sender);
+    }
+
+    fn publish_payment_notification(&self, payment: PaymentNotification) {
+        let account_id = if let Some(id) = self
+            .state
+            .read()
+            .usernames
+            .get(&payment.to_username.to_lowercase())
+        {
+            *id
+        } else {
+            error!(
+                "Failed to find account ID corresponding to username: {}",
+                payment.to_username
+            );
+            return;
+        };
+        debug!(
+            "Publishing payment notification {:?} for account {}",
+            payment, account_id
+        );
+        match self.subscriptions.read().get(&account_id) {
+            Some(sender) => {
+                if let Err(err) = sender.unbounded_send(payment) {
+                    error!("Failed to send message: {}", err);
+                }
+            }
+            None => trace!(
+                "Ignoring message for account {} because there were no open subscriptions",
+                account_id
+            ),
+        }
+    }
+}
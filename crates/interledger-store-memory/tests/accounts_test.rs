@@ -0,0 +1,4 @@
+mod common;
+
+#[path = "suite/accounts.rs"]
+mod accounts;
@@ -0,0 +1,4 @@
+mod common;
+
+#[path = "suite/balances.rs"]
+mod balances;
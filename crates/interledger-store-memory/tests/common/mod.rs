@@ -0,0 +1,8 @@
+#[path = "../suite/fixtures.rs"]
+mod fixtures;
+mod store_helpers;
+
+pub use fixtures::*;
+pub use futures::Future;
+pub use interledger_store_memory::*;
+pub use store_helpers::*;
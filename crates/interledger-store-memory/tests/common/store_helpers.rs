@@ -0,0 +1,48 @@
+use super::fixtures::*;
+use env_logger;
+use futures::Future;
+use interledger_api::NodeStore;
+use interledger_packet::Address;
+use interledger_service::{Account as AccountTrait, AddressStore};
+use interledger_store_memory::{Account, MemoryStore, MemoryStoreBuilder};
+use std::str::FromStr;
+use tokio::runtime::Runtime;
+
+pub fn test_store() -> impl Future<Item = (MemoryStore, Vec<Account>), Error = ()> {
+    let store = MemoryStoreBuilder::new()
+        .node_ilp_address(Address::from_str("example.node").unwrap())
+        .build();
+    let store_clone = store.clone();
+    let mut accs = Vec::new();
+    store
+        .clone()
+        .insert_account(ACCOUNT_DETAILS_0.clone())
+        .and_then(move |acc| {
+            accs.push(acc.clone());
+            // alice is a Parent, so the store's ilp address is updated to
+            // the value that would be received by the ILDCP request. here,
+            // we just assume alice appended some data to her address
+            store
+                .clone()
+                .set_ilp_address(acc.ilp_address().with_suffix(b"user1").unwrap())
+                .and_then(move |_| {
+                    store_clone
+                        .insert_account(ACCOUNT_DETAILS_1.clone())
+                        .and_then(move |acc| {
+                            accs.push(acc.clone());
+                            Ok((store, accs))
+                        })
+                })
+        })
+}
+
+pub fn block_on<F>(f: F) -> Result<F::Item, F::Error>
+where
+    F: Future + Send + 'static,
+    F::Item: Send,
+    F::Error: Send,
+{
+    let _ = env_logger::try_init();
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(f)
+}
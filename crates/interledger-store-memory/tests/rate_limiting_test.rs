@@ -0,0 +1,4 @@
+mod common;
+
+#[path = "suite/rate_limiting.rs"]
+mod rate_limiting;
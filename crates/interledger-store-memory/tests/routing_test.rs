@@ -0,0 +1,4 @@
+mod common;
+
+#[path = "suite/routing.rs"]
+mod routing;
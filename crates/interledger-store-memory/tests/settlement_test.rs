@@ -0,0 +1,4 @@
+mod common;
+
+#[path = "suite/settlement.rs"]
+mod settlement;
@@ -0,0 +1,271 @@
+use crate::common::*;
+use interledger_api::{AccountSettings, NodeStore};
+use interledger_btp::{BtpAccount, BtpStore};
+use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
+use interledger_http::{HttpAccount, HttpStore};
+use interledger_packet::Address;
+use interledger_router::RouterStore;
+use interledger_service::{
+    Account as AccountTrait, AccountStore, AddressStore, CertificateFingerprint, Username,
+};
+use secrecy::SecretString;
+use std::str::FromStr;
+
+#[test]
+fn insert_accounts() {
+    block_on(test_store().and_then(|(store, _accs)| {
+        store
+            .insert_account(ACCOUNT_DETAILS_2.clone())
+            .and_then(move |account| {
+                assert_eq!(
+                    *account.ilp_address(),
+                    Address::from_str("example.alice.user1.charlie").unwrap()
+                );
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn update_ilp_and_children_addresses() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let ilp_address = Address::from_str("test.parent.our_address").unwrap();
+        let store_clone = store.clone();
+        store
+            .set_ilp_address(ilp_address.clone())
+            .and_then(move |_| store_clone.get_accounts(vec![accs[0].id(), accs[1].id()]))
+            .and_then(move |accounts| {
+                // the parent keeps its address, the child is updated
+                assert_eq!(
+                    *accounts[0].ilp_address(),
+                    Address::from_str("example.alice").unwrap()
+                );
+                assert_eq!(
+                    *accounts[1].ilp_address(),
+                    ilp_address.with_suffix(b"bob").unwrap()
+                );
+                assert_eq!(store.get_ilp_address(), ilp_address);
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn failed_ilp_address_update_leaves_store_unchanged() {
+    block_on(test_store().and_then(|(store, accs)| {
+        // Long enough to be valid on its own but not once bob's username is appended
+        let ilp_address = Address::from_str(&format!("example.{}", "a".repeat(1012))).unwrap();
+        let original_address = store.get_ilp_address();
+        let original_routes = store.routing_table();
+        let store_clone = store.clone();
+        store
+            .set_ilp_address(ilp_address)
+            .then(move |result| {
+                assert!(result.is_err());
+                store_clone.get_accounts(vec![accs[0].id(), accs[1].id()])
+            })
+            .and_then(move |accounts| {
+                assert_eq!(store.get_ilp_address(), original_address);
+                assert_eq!(
+                    *accounts[1].ilp_address(),
+                    Address::from_str("example.alice.user1.bob").unwrap()
+                );
+                let routing_table = store.routing_table();
+                assert_eq!(routing_table.len(), original_routes.len());
+                for (prefix, account_id) in original_routes.iter() {
+                    assert_eq!(routing_table.get(prefix), Some(account_id));
+                }
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn only_one_parent_allowed() {
+    let mut acc = ACCOUNT_DETAILS_2.clone();
+    acc.routing_relation = Some("Parent".to_owned());
+    acc.username = Username::from_str("another_name").unwrap();
+    acc.ilp_address = Some(Address::from_str("example.another_name").unwrap());
+    block_on(test_store().and_then(|(store, _accs)| {
+        store.insert_account(acc).then(|result| {
+            // This should fail because there's already a parent
+            assert!(result.is_err());
+            Ok(())
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn rejects_duplicate_usernames() {
+    let mut acc = ACCOUNT_DETAILS_2.clone();
+    acc.username = Username::from_str("Bob").unwrap();
+    block_on(test_store().and_then(|(store, _accs)| {
+        store.insert_account(acc).then(|result| {
+            assert!(result.is_err());
+            Ok(())
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn delete_accounts() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let id = accs[0].id();
+        let store_clone = store.clone();
+        store.delete_account(id).and_then(move |account| {
+            assert_eq!(account.id(), id);
+            store_clone.get_all_accounts().and_then(move |accounts| {
+                assert!(accounts.iter().all(|a| a.id() != id));
+                assert!(store_clone
+                    .get_account_id_from_username(account.username())
+                    .wait()
+                    .is_err());
+                Ok(())
+            })
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn update_accounts() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let id = accs[0].id();
+        let mut new = ACCOUNT_DETAILS_0.clone();
+        new.asset_code = String::from("TUV");
+        store.update_account(id, new).and_then(move |account| {
+            assert_eq!(account.id(), id);
+            assert_eq!(account.asset_code(), "TUV");
+            Ok(())
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn modify_account_settings() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let settings = AccountSettings {
+            ilp_over_http_outgoing_token: Some(SecretString::new("test_token".to_owned())),
+            ilp_over_http_incoming_token: Some(SecretString::new("http_in_new".to_owned())),
+            ilp_over_btp_outgoing_token: Some(SecretString::new("dylan:test".to_owned())),
+            ilp_over_btp_incoming_token: Some(SecretString::new("btp_in_new".to_owned())),
+            ilp_over_http_url: Some("http://example.com/accounts/dylan/ilp".to_owned()),
+            ilp_over_btp_url: Some("http://example.com/accounts/dylan/ilp/btp".to_owned()),
+            settle_threshold: Some(-50),
+            settle_to: Some(100),
+        };
+        let account = accs[0].clone();
+        let id = account.id();
+        let username = account.username().clone();
+        store
+            .modify_account_settings(id, settings)
+            .and_then(move |account| {
+                assert_eq!(account.get_http_auth_token().unwrap(), "test_token");
+                assert_eq!(
+                    account.get_ilp_over_btp_outgoing_token().unwrap(),
+                    &b"dylan:test"[..],
+                );
+                assert_eq!(
+                    account.get_http_url().unwrap().as_str(),
+                    "http://example.com/accounts/dylan/ilp"
+                );
+                store
+                    .get_account_from_http_auth(&username, "http_in_new")
+                    .join(store.get_account_from_btp_auth(&username, "btp_in_new"))
+                    .and_then(move |(http_account, btp_account)| {
+                        assert_eq!(http_account.id(), id);
+                        assert_eq!(btp_account.id(), id);
+                        Ok(())
+                    })
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn gets_account_from_btp_and_http_auth() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let username = accs[0].username().clone();
+        store
+            .get_account_from_btp_auth(&username, "btp_token")
+            .join(store.get_account_from_http_auth(&username, "incoming_auth_token"))
+            .and_then(move |(btp_account, http_account)| {
+                assert_eq!(btp_account.id(), accs[0].id());
+                assert_eq!(http_account.id(), accs[0].id());
+                assert_eq!(btp_account.routing_relation(), RoutingRelation::Parent);
+                store
+                    .get_account_from_btp_auth(&username, "wrong_token")
+                    .then(|result| {
+                        assert!(result.is_err());
+                        Ok(())
+                    })
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn gets_account_from_client_certificate() {
+    let fingerprint = ACCOUNT_DETAILS_0.client_certificate_fingerprint.unwrap();
+    block_on(test_store().and_then(move |(store, accs)| {
+        store
+            .get_account_from_btp_certificate(&fingerprint)
+            .join(store.get_account_from_http_certificate(&fingerprint))
+            .and_then(move |(btp_account, http_account)| {
+                assert_eq!(btp_account.id(), accs[0].id());
+                assert_eq!(http_account.id(), accs[0].id());
+                store
+                    .get_account_from_http_certificate(&CertificateFingerprint::new([0; 32]))
+                    .then(|result| {
+                        assert!(result.is_err());
+                        Ok(())
+                    })
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn rejects_duplicate_client_certificates() {
+    let mut acc = ACCOUNT_DETAILS_2.clone();
+    acc.client_certificate_fingerprint = ACCOUNT_DETAILS_0.client_certificate_fingerprint;
+    block_on(test_store().and_then(|(store, _accs)| {
+        store.insert_account(acc).then(|result| {
+            assert!(result.is_err());
+            Ok(())
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn fetches_account_from_username() {
+    block_on(test_store().and_then(|(store, accs)| {
+        store
+            .get_account_id_from_username(&Username::from_str("ALICE").unwrap())
+            .and_then(move |account_id| {
+                assert_eq!(account_id, accs[0].id());
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn errors_for_unknown_accounts() {
+    block_on(test_store().and_then(|(store, accs)| {
+        store
+            .get_accounts(vec![accs[0].id(), AccountId::new()])
+            .then(|result| {
+                assert!(result.is_err());
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
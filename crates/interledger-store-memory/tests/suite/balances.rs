@@ -0,0 +1,133 @@
+use crate::common::*;
+use interledger_api::NodeStore;
+use interledger_packet::Address;
+use interledger_service::{Account as AccountTrait, AccountStore, Username};
+use interledger_service_util::BalanceStore;
+use interledger_settlement::SettlementStore;
+use std::str::FromStr;
+
+#[test]
+fn starts_with_zero_balance() {
+    block_on(test_store().and_then(|(store, accs)| {
+        store.get_balance(accs[0].clone()).and_then(|balance| {
+            assert_eq!(balance, 0);
+            Ok(())
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn prepare_then_fulfill_with_settlement() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let store_clone = store.clone();
+        store
+            .get_accounts(vec![accs[0].id(), accs[1].id()])
+            .and_then(move |accounts| {
+                let account0 = accounts[0].clone();
+                let account1 = accounts[1].clone();
+                store
+                    // reduce account 0's balance by 100
+                    .update_balances_for_prepare(account0.clone(), 100)
+                    .and_then(move |_| store.update_balances_for_fulfill(account1.clone(), 100))
+                    .and_then(move |(balance, amount_to_settle)| {
+                        // the account must be settled down to -1000
+                        assert_eq!(balance, -1000);
+                        assert_eq!(amount_to_settle, 1100);
+                        store_clone
+                            .get_balance(account0)
+                            .join(store_clone.get_balance(accounts[1].clone()))
+                    })
+                    .and_then(|(balance0, balance1)| {
+                        assert_eq!(balance0, -100);
+                        assert_eq!(balance1, -1000);
+                        Ok(())
+                    })
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn process_fulfill_settle_to_over_threshold() {
+    // account misconfigured with settle_to >= settle_threshold does not get settlements
+    let acc = {
+        let mut acc = ACCOUNT_DETAILS_1.clone();
+        acc.username = Username::from_str("charlie").unwrap();
+        acc.ilp_address = Some(Address::from_str("example.b").unwrap());
+        acc.settle_to = Some(101);
+        acc.settle_threshold = Some(100);
+        acc
+    };
+    block_on(test_store().and_then(|(store, _accs)| {
+        store.clone().insert_account(acc).and_then(move |acc| {
+            store.update_balances_for_fulfill(acc, 1000).and_then(
+                move |(balance, amount_to_settle)| {
+                    assert_eq!(balance, 1000);
+                    assert_eq!(amount_to_settle, 0);
+                    Ok(())
+                },
+            )
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn prepare_then_reject() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let store_clone = store.clone();
+        let account0 = accs[0].clone();
+        store
+            .update_balances_for_prepare(account0.clone(), 100)
+            .and_then(move |_| store.get_balance(account0.clone()).join(Ok(account0)))
+            .and_then(move |(balance, account0)| {
+                assert_eq!(balance, -100);
+                store_clone
+                    .update_balances_for_reject(account0.clone(), 100)
+                    .and_then(move |_| store_clone.get_balance(account0))
+            })
+            .and_then(|balance| {
+                assert_eq!(balance, 0);
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn enforces_minimum_balance() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let store_clone = store.clone();
+        let account0 = accs[0].clone();
+        store
+            .update_balances_for_prepare(account0.clone(), 10000)
+            .then(move |result| {
+                assert!(result.is_err());
+                // the balance must not have been touched
+                store_clone.get_balance(account0)
+            })
+            .and_then(|balance| {
+                assert_eq!(balance, 0);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn prepare_uses_prepaid_amount_first() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let store_clone = store.clone();
+        let account0 = accs[0].clone();
+        store
+            .update_balance_for_incoming_settlement(account0.id(), 50, None)
+            .and_then(move |_| store.update_balances_for_prepare(account0.clone(), 80))
+            .and_then(move |_| store_clone.get_balance(accs[0].clone()))
+            .and_then(|balance| {
+                assert_eq!(balance, -30);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
@@ -1,6 +1,7 @@
 use interledger_api::AccountDetails;
+use interledger_ccp::RoutePolicy;
 use interledger_packet::Address;
-use interledger_service::Username;
+use interledger_service::{CertificateFingerprint, Username};
 use lazy_static::lazy_static;
 use secrecy::SecretString;
 use std::str::FromStr;
@@ -24,9 +25,15 @@ lazy_static! {
         settle_to: Some(-1000),
         routing_relation: Some("Parent".to_owned()),
         round_trip_time: None,
+        routing_weight: None,
+        route_policy: None,
         amount_per_minute_limit: Some(1000),
         packets_per_minute_limit: Some(2),
         settlement_engine_url: Some("http://settlement.example".to_string()),
+        ilp_over_http_pool_size: None,
+        ilp_over_http_http2: false,
+        ilp_over_http_streaming: false,
+        client_certificate_fingerprint: Some(CertificateFingerprint::from_str("0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef").unwrap()),
     };
     pub static ref ACCOUNT_DETAILS_1: AccountDetails = AccountDetails {
         ilp_address: None,
@@ -46,9 +53,19 @@ lazy_static! {
         settle_to: Some(-1000),
         routing_relation: Some("Child".to_owned()),
         round_trip_time: None,
+        routing_weight: Some(2),
+        route_policy: Some(RoutePolicy {
+            import_deny: vec!["example.private.".to_owned()],
+            local_preference: 10,
+            ..RoutePolicy::default()
+        }),
         amount_per_minute_limit: Some(1000),
         packets_per_minute_limit: Some(20),
         settlement_engine_url: None,
+        ilp_over_http_pool_size: None,
+        ilp_over_http_http2: false,
+        ilp_over_http_streaming: false,
+        client_certificate_fingerprint: None,
     };
     pub static ref ACCOUNT_DETAILS_2: AccountDetails = AccountDetails {
         ilp_address: None,
@@ -67,8 +84,14 @@ lazy_static! {
         settle_to: None,
         routing_relation: None,
         round_trip_time: None,
+        routing_weight: None,
+        route_policy: None,
         amount_per_minute_limit: None,
         packets_per_minute_limit: None,
         settlement_engine_url: None,
+        ilp_over_http_pool_size: None,
+        ilp_over_http_http2: false,
+        ilp_over_http_streaming: false,
+        client_certificate_fingerprint: None,
     };
 }
@@ -0,0 +1,207 @@
+use crate::common::*;
+use bytes::Bytes;
+use interledger_api::NodeStore;
+use interledger_ccp::{CcpRoutingAccount, RouteManagerStore};
+use interledger_router::{NextHop, RouterStore};
+use interledger_service::Account as AccountTrait;
+use std::str::FromStr;
+
+#[test]
+fn polls_for_route_updates() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let alice_id = accs[0].id();
+        let bob_id = accs[1].id();
+        let routing_table = store.routing_table();
+        assert_eq!(routing_table.len(), 2);
+        assert_eq!(
+            *routing_table.get(&Bytes::from("example.alice")).unwrap(),
+            alice_id
+        );
+        assert_eq!(
+            *routing_table
+                .get(&Bytes::from("example.alice.user1.bob"))
+                .unwrap(),
+            bob_id
+        );
+        let mut store_clone = store.clone();
+        store_clone
+            .set_routes(vec![
+                (Bytes::from("example.alice"), accs[1].clone()),
+                (Bytes::from("example.charlie"), accs[1].clone()),
+            ])
+            .and_then(move |_| {
+                let routing_table = store.routing_table();
+                assert_eq!(routing_table.len(), 2);
+                assert_eq!(
+                    *routing_table.get(&Bytes::from("example.alice")).unwrap(),
+                    bob_id
+                );
+                assert_eq!(
+                    *routing_table.get(&Bytes::from("example.charlie")).unwrap(),
+                    bob_id
+                );
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn gets_accounts_to_send_routes_to() {
+    block_on(test_store().and_then(|(store, _accs)| {
+        store
+            .get_accounts_to_send_routes_to(Vec::new())
+            .and_then(|accounts| {
+                // We send to child accounts but not parents
+                assert_eq!(accounts[0].username().as_ref(), "bob");
+                assert_eq!(accounts.len(), 1);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn gets_accounts_to_send_routes_to_and_skips_ignored() {
+    block_on(test_store().and_then(|(store, accs)| {
+        store
+            .get_accounts_to_send_routes_to(vec![accs[1].id()])
+            .and_then(|accounts| {
+                assert!(accounts.is_empty());
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn gets_accounts_to_receive_routes_from() {
+    block_on(test_store().and_then(|(store, _accs)| {
+        store
+            .get_accounts_to_receive_routes_from()
+            .and_then(|accounts| {
+                assert_eq!(
+                    *accounts[0].ilp_address(),
+                    interledger_packet::Address::from_str("example.alice").unwrap()
+                );
+                assert_eq!(accounts.len(), 1);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn static_routes_override_others() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let alice_id = accs[0].id();
+        let bob_id = accs[1].id();
+        let mut store_clone = store.clone();
+        store
+            .set_static_routes(vec![
+                ("example.a".to_string(), alice_id),
+                ("example.b".to_string(), bob_id),
+            ])
+            .and_then(move |_| {
+                store_clone.set_routes(vec![(Bytes::from("example.a"), accs[1].clone())])
+            })
+            .and_then(move |_| {
+                let routing_table = store.routing_table();
+                assert_eq!(
+                    *routing_table.get(&Bytes::from("example.a")).unwrap(),
+                    alice_id
+                );
+                assert_eq!(
+                    *routing_table.get(&Bytes::from("example.b")).unwrap(),
+                    bob_id
+                );
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn default_route() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let bob_id = accs[1].id();
+        let store_clone = store.clone();
+        store.set_default_route(bob_id).and_then(move |_| {
+            let routing_table = store_clone.routing_table();
+            assert_eq!(*routing_table.get(&Bytes::from("")).unwrap(), bob_id);
+            Ok(())
+        })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn static_route_for_unknown_account_fails() {
+    block_on(test_store().and_then(|(store, _accs)| {
+        store
+            .set_static_route("example.a".to_string(), AccountId::new())
+            .then(|result| {
+                assert!(result.is_err());
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn gets_routing_weights() {
+    block_on(test_store().and_then(|(_store, accs)| {
+        // Alice uses the default weight
+        assert_eq!(accs[0].routing_weight(), 1);
+        assert_eq!(accs[1].routing_weight(), 2);
+        Ok(())
+    }))
+    .unwrap()
+}
+
+#[test]
+fn gets_route_policies() {
+    block_on(test_store().and_then(|(_store, accs)| {
+        assert!(accs[0].route_policy().is_none());
+        let policy = accs[1].route_policy().unwrap();
+        assert_eq!(policy.import_deny, vec!["example.private.".to_string()]);
+        assert_eq!(policy.local_preference, 10);
+        assert!(policy.import_allow.is_empty());
+        Ok(())
+    }))
+    .unwrap()
+}
+
+#[test]
+fn saves_alternate_routes() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let next_hops = vec![
+            NextHop {
+                account_id: accs[0].id(),
+                cost: 0,
+                weight: 1,
+            },
+            NextHop {
+                account_id: accs[1].id(),
+                cost: 1,
+                weight: 2,
+            },
+        ];
+        let mut store_clone = store.clone();
+        store_clone
+            .set_alternate_routes(vec![(Bytes::from("example.charlie"), next_hops.clone())])
+            .and_then(move |_| {
+                let alternate_routes = store.alternate_routes();
+                assert_eq!(alternate_routes.len(), 1);
+                assert_eq!(alternate_routes[&Bytes::from("example.charlie")], next_hops);
+                store_clone
+                    .set_alternate_routes(Vec::new())
+                    .map(move |_| store)
+            })
+            .and_then(move |store| {
+                assert!(store.alternate_routes().is_empty());
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
@@ -0,0 +1,165 @@
+use crate::common::*;
+use interledger_api::NodeStore;
+use interledger_service::{Account as AccountTrait, AccountStore};
+use interledger_service_util::BalanceStore;
+use interledger_settlement::{LeftoversStore, SettlementAccount, SettlementStore};
+use num_bigint::BigUint;
+use url::Url;
+
+#[test]
+fn credits_prepaid_amount() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let account = accs[0].clone();
+        let store_clone = store.clone();
+        store
+            .update_balance_for_incoming_settlement(account.id(), 100, Some("id1".to_string()))
+            .and_then(move |_| store_clone.get_balance(account))
+            .and_then(|balance| {
+                assert_eq!(balance, 100);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn saves_and_enforces_idempotency_key() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let account = accs[0].clone();
+        let store_clone = store.clone();
+        let store_clone_2 = store.clone();
+        store
+            .update_balance_for_incoming_settlement(account.id(), 100, Some("id1".to_string()))
+            .and_then(move |_| {
+                store_clone.update_balance_for_incoming_settlement(
+                    account.id(),
+                    100,
+                    Some("id1".to_string()),
+                )
+            })
+            .and_then(move |_| store_clone_2.get_balance(accs[0].clone()))
+            .and_then(|balance| {
+                // the second settlement must have been ignored
+                assert_eq!(balance, 100);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn credits_balance_owed() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let account = accs[0].clone();
+        let store_clone = store.clone();
+        let store_clone_2 = store.clone();
+        store
+            .update_balances_for_prepare(account.clone(), 100)
+            .and_then(move |_| {
+                store_clone.update_balance_for_incoming_settlement(account.id(), 30, None)
+            })
+            .and_then(move |_| store_clone_2.get_balance(accs[0].clone()))
+            .and_then(|balance| {
+                assert_eq!(balance, -70);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn clears_balance_owed_and_puts_remainder_as_prepaid() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let account = accs[0].clone();
+        let store_clone = store.clone();
+        let store_clone_2 = store.clone();
+        store
+            .update_balances_for_prepare(account.clone(), 40)
+            .and_then(move |_| {
+                store_clone.update_balance_for_incoming_settlement(account.id(), 100, None)
+            })
+            .and_then(move |_| store_clone_2.get_balance(accs[0].clone()))
+            .and_then(|balance| {
+                assert_eq!(balance, 60);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn refunds_settlement() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let account = accs[0].clone();
+        let store_clone = store.clone();
+        store
+            .refund_settlement(account.id(), 150)
+            .and_then(move |_| store_clone.get_balance(account))
+            .and_then(|balance| {
+                assert_eq!(balance, 150);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn errors_for_unknown_account() {
+    block_on(test_store().and_then(|(store, _accs)| {
+        store
+            .update_balance_for_incoming_settlement(AccountId::new(), 100, None)
+            .then(|result| {
+                assert!(result.is_err());
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn uses_asset_settlement_engine_as_fallback() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let store_clone = store.clone();
+        let url = Url::parse("http://settlement.example/abc").unwrap();
+        let url_clone = url.clone();
+        store
+            .set_settlement_engines(vec![("ABC".to_string(), url.clone())])
+            .and_then(move |_| store_clone.get_asset_settlement_engine("ABC"))
+            .and_then(move |engine| {
+                assert_eq!(engine, Some(url_clone));
+                Ok(())
+            })
+            .and_then(move |_| store.get_accounts(vec![accs[1].id()]))
+            .and_then(move |accounts| {
+                assert_eq!(accounts[0].settlement_engine_details().unwrap().url, url);
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
+
+#[test]
+fn saves_and_loads_leftovers() {
+    block_on(test_store().and_then(|(store, accs)| {
+        let id = accs[0].id();
+        let store_clone = store.clone();
+        let store_clone_2 = store.clone();
+        store
+            .save_uncredited_settlement_amount(id, (BigUint::from(5u32), 11))
+            .and_then(move |_| {
+                store_clone.save_uncredited_settlement_amount(id, (BigUint::from(2u32), 9))
+            })
+            .and_then(move |_| store_clone_2.load_uncredited_settlement_amount(id, 9))
+            .and_then(move |amount| {
+                // 5 (scale 11) + 200 (scale 11) = 205, which is 2 at scale 9
+                // with 5 left over
+                assert_eq!(amount, BigUint::from(2u32));
+                store.get_uncredited_settlement_amount(id)
+            })
+            .and_then(|leftovers| {
+                assert_eq!(leftovers, (BigUint::from(5u32), 11));
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
@@ -2,10 +2,10 @@ use super::crypto::{decrypt_token, encrypt_token};
 use bytes::Bytes;
 use interledger_api::AccountDetails;
 use interledger_btp::BtpAccount;
-use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
-use interledger_http::HttpAccount;
+use interledger_ccp::{CcpRoutingAccount, RoutePolicy, RoutingRelation, DEFAULT_ROUTING_WEIGHT};
+use interledger_http::{HttpAccount, HttpClientSettings};
 use interledger_packet::Address;
-use interledger_service::{Account as AccountTrait, Username};
+use interledger_service::{Account as AccountTrait, CertificateFingerprint, Username};
 use interledger_service_util::{
     MaxPacketAmountAccount, RateLimitAccount, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
 };
@@ -26,7 +26,7 @@ use std::{
 use uuid::{parser::ParseError, Uuid};
 
 use url::Url;
-const ACCOUNT_DETAILS_FIELDS: usize = 21;
+const ACCOUNT_DETAILS_FIELDS: usize = 27;
 
 use secrecy::ExposeSecret;
 use secrecy::SecretBytes;
@@ -47,6 +47,10 @@ pub struct Account {
     pub(crate) ilp_over_http_incoming_token: Option<SecretBytes>,
     #[serde(serialize_with = "optional_secret_bytes_to_utf8")]
     pub(crate) ilp_over_http_outgoing_token: Option<SecretBytes>,
+    pub(crate) ilp_over_http_pool_size: Option<u32>,
+    pub(crate) ilp_over_http_http2: bool,
+    pub(crate) ilp_over_http_streaming: bool,
+    pub(crate) client_certificate_fingerprint: Option<CertificateFingerprint>,
     pub(crate) ilp_over_btp_url: Option<Url>,
     #[serde(serialize_with = "optional_secret_bytes_to_utf8")]
     pub(crate) ilp_over_btp_incoming_token: Option<SecretBytes>,
@@ -56,6 +60,8 @@ pub struct Account {
     pub(crate) settle_to: Option<i64>,
     pub(crate) routing_relation: RoutingRelation,
     pub(crate) round_trip_time: u32,
+    pub(crate) routing_weight: u32,
+    pub(crate) route_policy: Option<RoutePolicy>,
     pub(crate) packets_per_minute_limit: Option<u32>,
     pub(crate) amount_per_minute_limit: Option<u64>,
     pub(crate) settlement_engine_url: Option<Url>,
@@ -135,6 +141,10 @@ impl Account {
             ilp_over_http_outgoing_token: details
                 .ilp_over_http_outgoing_token
                 .map(|token| SecretBytes::new(token.expose_secret().to_string())),
+            ilp_over_http_pool_size: details.ilp_over_http_pool_size,
+            ilp_over_http_http2: details.ilp_over_http_http2,
+            ilp_over_http_streaming: details.ilp_over_http_streaming,
+            client_certificate_fingerprint: details.client_certificate_fingerprint,
             ilp_over_btp_url,
             ilp_over_btp_incoming_token: details
                 .ilp_over_btp_incoming_token
@@ -146,6 +156,8 @@ impl Account {
             settle_threshold: details.settle_threshold,
             routing_relation,
             round_trip_time: details.round_trip_time.unwrap_or(DEFAULT_ROUND_TRIP_TIME),
+            routing_weight: details.routing_weight.unwrap_or(DEFAULT_ROUTING_WEIGHT),
+            route_policy: details.route_policy,
             packets_per_minute_limit: details.packets_per_minute_limit,
             amount_per_minute_limit: details.amount_per_minute_limit,
             settlement_engine_url,
@@ -288,6 +300,13 @@ impl ToRedisArgs for AccountWithEncryptedTokens {
             .write_redis_args(&mut rv);
         "round_trip_time".write_redis_args(&mut rv);
         account.round_trip_time.write_redis_args(&mut rv);
+        "routing_weight".write_redis_args(&mut rv);
+        account.routing_weight.write_redis_args(&mut rv);
+        // Stored as a number because redis-rs cannot read back the bools it writes
+        "ilp_over_http_http2".write_redis_args(&mut rv);
+        u8::from(account.ilp_over_http_http2).write_redis_args(&mut rv);
+        "ilp_over_http_streaming".write_redis_args(&mut rv);
+        u8::from(account.ilp_over_http_streaming).write_redis_args(&mut rv);
 
         // Write optional fields
         if let Some(ilp_over_http_url) = account.ilp_over_http_url.as_ref() {
@@ -308,6 +327,14 @@ impl ToRedisArgs for AccountWithEncryptedTokens {
                 .as_ref()
                 .write_redis_args(&mut rv);
         }
+        if let Some(pool_size) = account.ilp_over_http_pool_size {
+            "ilp_over_http_pool_size".write_redis_args(&mut rv);
+            pool_size.write_redis_args(&mut rv);
+        }
+        if let Some(fingerprint) = account.client_certificate_fingerprint {
+            "client_certificate_fingerprint".write_redis_args(&mut rv);
+            fingerprint.to_string().write_redis_args(&mut rv);
+        }
         if let Some(ilp_over_btp_url) = account.ilp_over_btp_url.as_ref() {
             "ilp_over_btp_url".write_redis_args(&mut rv);
             ilp_over_btp_url.as_str().write_redis_args(&mut rv);
@@ -350,6 +377,12 @@ impl ToRedisArgs for AccountWithEncryptedTokens {
             "settlement_engine_url".write_redis_args(&mut rv);
             settlement_engine_url.as_str().write_redis_args(&mut rv);
         }
+        if let Some(route_policy) = &account.route_policy {
+            "route_policy".write_redis_args(&mut rv);
+            serde_json::to_string(route_policy)
+                .unwrap()
+                .write_redis_args(&mut rv);
+        }
 
         debug_assert!(rv.len() <= ACCOUNT_DETAILS_FIELDS * 2);
         debug_assert!((rv.len() % 2) == 0);
@@ -376,6 +409,11 @@ impl FromRedisValue for AccountWithEncryptedTokens {
         };
         let round_trip_time: Option<u32> = get_value_option("round_trip_time", &hash)?;
         let round_trip_time: u32 = round_trip_time.unwrap_or(DEFAULT_ROUND_TRIP_TIME);
+        let routing_weight: Option<u32> = get_value_option("routing_weight", &hash)?;
+        let routing_weight: u32 = routing_weight.unwrap_or(DEFAULT_ROUTING_WEIGHT);
+        let ilp_over_http_http2: Option<u8> = get_value_option("ilp_over_http_http2", &hash)?;
+        let ilp_over_http_streaming: Option<u8> =
+            get_value_option("ilp_over_http_streaming", &hash)?;
 
         Ok(AccountWithEncryptedTokens {
             account: Account {
@@ -395,6 +433,13 @@ impl FromRedisValue for AccountWithEncryptedTokens {
                     &hash,
                 )?
                 .map(SecretBytes::from),
+                ilp_over_http_pool_size: get_value_option("ilp_over_http_pool_size", &hash)?,
+                ilp_over_http_http2: ilp_over_http_http2.unwrap_or(0) != 0,
+                ilp_over_http_streaming: ilp_over_http_streaming.unwrap_or(0) != 0,
+                client_certificate_fingerprint: get_fingerprint_option(
+                    "client_certificate_fingerprint",
+                    &hash,
+                )?,
                 ilp_over_btp_url: get_url_option("ilp_over_btp_url", &hash)?,
                 ilp_over_btp_incoming_token: get_bytes_option(
                     "ilp_over_btp_incoming_token",
@@ -412,6 +457,8 @@ impl FromRedisValue for AccountWithEncryptedTokens {
                 settle_to: get_value_option("settle_to", &hash)?,
                 routing_relation,
                 round_trip_time,
+                routing_weight,
+                route_policy: get_route_policy_option("route_policy", &hash)?,
                 packets_per_minute_limit: get_value_option("packets_per_minute_limit", &hash)?,
                 amount_per_minute_limit: get_value_option("amount_per_minute_limit", &hash)?,
                 settlement_engine_url: get_url_option("settlement_engine_url", &hash)?,
@@ -468,6 +515,44 @@ fn get_url_option(key: &str, map: &HashMap<String, Value>) -> Result<Option<Url>
     }
 }
 
+fn get_fingerprint_option(
+    key: &str,
+    map: &HashMap<String, Value>,
+) -> Result<Option<CertificateFingerprint>, RedisError> {
+    if let Some(ref value) = map.get(key) {
+        let value: String = from_redis_value(value)?;
+        if let Ok(fingerprint) = CertificateFingerprint::from_str(&value) {
+            Ok(Some(fingerprint))
+        } else {
+            Err(RedisError::from((
+                ErrorKind::TypeError,
+                "Invalid client certificate fingerprint",
+            )))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+fn get_route_policy_option(
+    key: &str,
+    map: &HashMap<String, Value>,
+) -> Result<Option<RoutePolicy>, RedisError> {
+    if let Some(ref value) = map.get(key) {
+        let value: String = from_redis_value(value)?;
+        if let Ok(policy) = serde_json::from_str(&value) {
+            Ok(Some(policy))
+        } else {
+            Err(RedisError::from((
+                ErrorKind::TypeError,
+                "Invalid route policy",
+            )))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
 impl AccountTrait for Account {
     type AccountId = AccountId;
 
@@ -502,6 +587,14 @@ impl HttpAccount for Account {
             .as_ref()
             .map(|s| str::from_utf8(s.expose_secret().as_ref()).unwrap_or_default())
     }
+
+    fn get_http_client_settings(&self) -> HttpClientSettings {
+        HttpClientSettings {
+            pool_size: self.ilp_over_http_pool_size.map(|size| size as usize),
+            http2_prior_knowledge: self.ilp_over_http_http2,
+            streaming: self.ilp_over_http_streaming,
+        }
+    }
 }
 
 impl BtpAccount for Account {
@@ -528,6 +621,14 @@ impl CcpRoutingAccount for Account {
     fn routing_relation(&self) -> RoutingRelation {
         self.routing_relation
     }
+
+    fn routing_weight(&self) -> u32 {
+        self.routing_weight
+    }
+
+    fn route_policy(&self) -> Option<&RoutePolicy> {
+        self.route_policy.as_ref()
+    }
 }
 
 impl RoundTripTimeAccount for Account {
@@ -580,9 +681,15 @@ mod redis_account {
             settle_to: Some(-1000),
             routing_relation: Some("Peer".to_string()),
             round_trip_time: Some(600),
+            routing_weight: None,
+            route_policy: None,
             amount_per_minute_limit: None,
             packets_per_minute_limit: None,
             settlement_engine_url: None,
+            ilp_over_http_pool_size: None,
+            ilp_over_http_http2: false,
+            ilp_over_http_streaming: false,
+            client_certificate_fingerprint: None,
         };
     }
 
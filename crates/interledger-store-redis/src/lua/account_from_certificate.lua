@@ -0,0 +1,7 @@
+local fingerprint = ARGV[1]
+local id_from_fingerprint = redis.call('HGET', 'client_certificates', fingerprint)
+if id_from_fingerprint then
+    return redis.call('HGETALL', 'accounts:' .. id_from_fingerprint)
+else
+    return nil
+end
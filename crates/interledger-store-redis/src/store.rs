@@ -5,7 +5,9 @@
 //   rates:current          hash        exchange rates
 //   routes:current         hash        dynamic routing table
 //   routes:static          hash        static routing table
+//   routes:alternates      hash        ranked next hops (JSON) for prefixes with more than one route
 //   accounts:<id>          hash        information for each account
+//   client_certificates    hash        account ID for each TLS client certificate fingerprint
 //   btp_outgoing
 // For interactive exploration of the store,
 // use the redis-cli tool included with your redis install.
@@ -37,8 +39,10 @@ use interledger_http::{
     HttpStore,
 };
 use interledger_packet::Address;
-use interledger_router::RouterStore;
-use interledger_service::{Account as AccountTrait, AccountStore, AddressStore, Username};
+use interledger_router::{AlternateRoutes, NextHop, RouterStore};
+use interledger_service::{
+    Account as AccountTrait, AccountStore, AddressStore, CertificateFingerprint, Username,
+};
 use interledger_service_util::{BalanceStore, ExchangeRateStore, RateLimitError, RateLimitStore};
 use interledger_settlement::{
     scale_with_precision_loss, Convert, ConvertDetails, LeftoversStore, SettlementStore,
@@ -71,6 +75,7 @@ static PARENT_ILP_KEY: &str = "parent_node_account_address";
 static ROUTES_KEY: &str = "routes:current";
 static STATIC_ROUTES_KEY: &str = "routes:static";
 static DEFAULT_ROUTE_KEY: &str = "routes:default";
+static ALTERNATE_ROUTES_KEY: &str = "routes:alternates";
 static STREAM_NOTIFICATIONS_PREFIX: &str = "stream_notifications:";
 static SETTLEMENT_ENGINES_KEY: &str = "settlement_engines";
 
@@ -98,6 +103,10 @@ lazy_static! {
     /// MUST ensure that the returned account is authenticated.
     static ref ACCOUNT_FROM_USERNAME: Script = Script::new(include_str!("lua/account_from_username.lua"));
 
+    /// This lua script fetches the account a TLS client certificate fingerprint is mapped to.
+    /// The client MUST check that the returned account still has that certificate.
+    static ref ACCOUNT_FROM_CERTIFICATE: Script = Script::new(include_str!("lua/account_from_certificate.lua"));
+
     /// Load a list of accounts
     /// If an account does not have a settlement_engine_url set
     /// but there is one configured for that account's currency,
@@ -188,7 +197,8 @@ impl RedisStoreBuilder {
                             connection,
                             subscriptions: Arc::new(RwLock::new(HashMap::new())),
                             exchange_rates: Arc::new(RwLock::new(HashMap::new())),
-                            routes: Arc::new(RwLock::new(HashMap::new())),
+                            routes: Arc::new(RwLock::new(Arc::default())),
+                            alternate_routes: Arc::new(RwLock::new(Arc::default())),
                             encryption_key: Arc::new(encryption_key),
                             decryption_key: Arc::new(decryption_key),
                         };
@@ -198,18 +208,24 @@ impl RedisStoreBuilder {
                         let connection_clone = Arc::downgrade(&store.connection.conn);
                         let redis_info = store.connection.redis_info.clone();
                         let routing_table = store.routes.clone();
+                        let alternate_routes = store.alternate_routes.clone();
                         let poll_routes =
                             Interval::new(Instant::now(), Duration::from_millis(poll_interval))
                                 .map_err(|err| error!("Interval error: {:?}", err))
                                 .for_each(move |_| {
                                     if let Some(conn) = connection_clone.upgrade() {
-                                        Either::A(update_routes(
-                                            RedisReconnect {
-                                                conn,
-                                                redis_info: redis_info.clone(),
-                                            },
-                                            routing_table.clone(),
-                                        ))
+                                        let connection = RedisReconnect {
+                                            conn,
+                                            redis_info: redis_info.clone(),
+                                        };
+                                        Either::A(
+                                            update_routes(connection.clone(), routing_table.clone())
+                                                .join(update_alternate_routes(
+                                                    connection,
+                                                    alternate_routes.clone(),
+                                                ))
+                                                .map(|_| ()),
+                                        )
                                     } else {
                                         debug!("Not polling routes anymore because connection was closed");
                                         // TODO make sure the interval stops
@@ -277,7 +293,8 @@ pub struct RedisStore {
     connection: RedisReconnect,
     subscriptions: Arc<RwLock<HashMap<AccountId, UnboundedSender<PaymentNotification>>>>,
     exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
-    routes: Arc<RwLock<HashMap<Bytes, AccountId>>>,
+    routes: Arc<RwLock<Arc<interledger_router::RoutingTable<AccountId>>>>,
+    alternate_routes: Arc<RwLock<Arc<AlternateRoutes<AccountId>>>>,
     encryption_key: Arc<Secret<EncryptionKey>>,
     decryption_key: Arc<Secret<DecryptionKey>>,
 }
@@ -303,6 +320,9 @@ impl RedisStore {
         let mut pipe = redis::pipe();
         pipe.exists(accounts_key(account.id));
         pipe.hexists("usernames", account.username().as_ref());
+        if let Some(fingerprint) = account.client_certificate_fingerprint {
+            pipe.hexists("client_certificates", fingerprint.to_string());
+        }
         if account.routing_relation == RoutingRelation::Parent {
             pipe.exists(PARENT_ILP_KEY);
         }
@@ -330,6 +350,11 @@ impl RedisStore {
                 // Save map for Username -> Account ID
                 pipe.hset("usernames", account.username().as_ref(), account.id).ignore();
 
+                // Save map for client certificate -> Account ID
+                if let Some(fingerprint) = account.client_certificate_fingerprint {
+                    pipe.hset("client_certificates", fingerprint.to_string(), account.id).ignore();
+                }
+
                 // Set account details
                 pipe.cmd("HMSET")
                     .arg(accounts_key(account.id))
@@ -375,18 +400,30 @@ impl RedisStore {
         let account = encrypted.account.clone();
         let connection = self.connection.clone();
         let routing_table = self.routes.clone();
+        let fingerprint = account
+            .client_certificate_fingerprint
+            .map(|fingerprint| fingerprint.to_string());
+        // Check to make sure an account with this ID already exists,
+        // and find which client certificates it and the new one belong to
+        let mut pipe = redis::pipe();
+        pipe.exists(accounts_key(account.id));
+        pipe.hget(accounts_key(account.id), "client_certificate_fingerprint");
+        pipe.hget(
+            "client_certificates",
+            fingerprint.clone().unwrap_or_default(),
+        );
         Box::new(
-            // Check to make sure an account with this ID already exists
-            redis::cmd("EXISTS")
-                .arg(accounts_key(account.id))
-                // TODO this needs to be atomic with the insertions later,
-                // waiting on #186
-                // TODO: Do not allow this update to happen if
-                // AccountDetails.RoutingRelation == Parent and parent is
-                // already set
-                .query_async(connection.clone())
+            // TODO this needs to be atomic with the insertions later,
+            // waiting on #186
+            // TODO: Do not allow this update to happen if
+            // AccountDetails.RoutingRelation == Parent and parent is
+            // already set
+            pipe.query_async(connection.clone())
                 .map_err(|err| error!("Error checking whether ID exists: {:?}", err))
-                .and_then(move |(connection, exists): (RedisReconnect, bool)| {
+                .and_then(move |(connection, (exists, previous_fingerprint, certificate_owner)): (
+                    RedisReconnect,
+                    (bool, Option<String>, Option<AccountId>),
+                )| {
                     if !exists {
                         warn!(
                             "No account exists with ID {}, cannot update account {:?}",
@@ -394,9 +431,34 @@ impl RedisStore {
                         );
                         return Either::A(err(()));
                     }
+                    if let Some(owner) = certificate_owner {
+                        if owner != account.id {
+                            warn!(
+                                "Client certificate is already used by account {}, cannot update account {}",
+                                owner, account.id
+                            );
+                            return Either::A(err(()));
+                        }
+                    }
                     let mut pipe = redis::pipe();
                     pipe.atomic();
 
+                    // Update the map for client certificate -> Account ID
+                    if let Some(previous_fingerprint) = previous_fingerprint {
+                        pipe.hdel("client_certificates", previous_fingerprint).ignore();
+                    }
+                    if let Some(fingerprint) = fingerprint {
+                        pipe.hset("client_certificates", fingerprint, account.id).ignore();
+                    } else {
+                        // HMSET only sets the fields that the account has
+                        pipe.hdel(accounts_key(account.id), "client_certificate_fingerprint")
+                            .ignore();
+                    }
+
+                    if account.route_policy.is_none() {
+                        pipe.hdel(accounts_key(account.id), "route_policy").ignore();
+                    }
+
                     // Add the account key to the list of accounts
                     pipe.sadd("accounts", account.id).ignore();
 
@@ -528,6 +590,43 @@ impl RedisStore {
         )
     }
 
+    fn redis_get_account_from_certificate(
+        &self,
+        fingerprint: &CertificateFingerprint,
+    ) -> Box<dyn Future<Item = Account, Error = ()> + Send> {
+        let decryption_key = self.decryption_key.clone();
+        let fingerprint = *fingerprint;
+        Box::new(
+            ACCOUNT_FROM_CERTIFICATE
+                .arg(fingerprint.to_string())
+                .invoke_async(self.connection.clone())
+                .map_err(|err| {
+                    error!(
+                        "Error getting account from TLS client certificate: {:?}",
+                        err
+                    )
+                })
+                .and_then(
+                    move |(_connection, account): (_, Option<AccountWithEncryptedTokens>)| {
+                        match account {
+                            Some(ref account)
+                                if account.account.client_certificate_fingerprint
+                                    == Some(fingerprint) =>
+                            {
+                                Ok(account
+                                    .clone()
+                                    .decrypt_tokens(&decryption_key.expose_secret().0))
+                            }
+                            _ => {
+                                warn!("No account found with given TLS client certificate");
+                                Err(())
+                            }
+                        }
+                    },
+                ),
+        )
+    }
+
     fn redis_delete_account(
         &self,
         id: AccountId,
@@ -543,6 +642,10 @@ impl RedisStore {
 
             pipe.del(accounts_key(account.id)).ignore();
             pipe.hdel("usernames", account.username().as_ref()).ignore();
+            if let Some(fingerprint) = account.client_certificate_fingerprint {
+                pipe.hdel("client_certificates", fingerprint.to_string())
+                    .ignore();
+            }
 
             if account.should_send_routes() {
                 pipe.srem("send_routes_to", account.id).ignore();
@@ -860,6 +963,13 @@ impl BtpStore for RedisStore {
         )
     }
 
+    fn get_account_from_btp_certificate(
+        &self,
+        fingerprint: &CertificateFingerprint,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        self.redis_get_account_from_certificate(fingerprint)
+    }
+
     fn get_btp_outgoing_accounts(
         &self,
     ) -> Box<dyn Future<Item = Vec<Self::Account>, Error = ()> + Send> {
@@ -951,12 +1061,25 @@ impl HttpStore for RedisStore {
                 ),
         )
     }
+
+    fn get_account_from_http_certificate(
+        &self,
+        fingerprint: &CertificateFingerprint,
+    ) -> Box<dyn Future<Item = Self::Account, Error = ()> + Send> {
+        self.redis_get_account_from_certificate(fingerprint)
+    }
 }
 
 impl RouterStore for RedisStore {
-    fn routing_table(&self) -> HashMap<Bytes, <Self::Account as AccountTrait>::AccountId> {
+    fn routing_table(
+        &self,
+    ) -> Arc<interledger_router::RoutingTable<<Self::Account as AccountTrait>::AccountId>> {
         self.routes.read().clone()
     }
+
+    fn alternate_routes(&self) -> Arc<AlternateRoutes<AccountId>> {
+        self.alternate_routes.read().clone()
+    }
 }
 
 impl NodeStore for RedisStore {
@@ -1536,6 +1659,42 @@ impl RouteManagerStore for RedisStore {
                 }),
         )
     }
+
+    fn set_alternate_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (Bytes, Vec<NextHop<AccountId>>)>,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let routes: Vec<(String, String)> = routes
+            .into_iter()
+            .filter_map(|(prefix, next_hops)| {
+                let prefix = String::from_utf8(prefix.to_vec()).ok()?;
+                let next_hops: Vec<(AccountId, u32, u32)> = next_hops
+                    .iter()
+                    .map(|hop| (hop.account_id, hop.cost, hop.weight))
+                    .collect();
+                Some((prefix, serde_json::to_string(&next_hops).ok()?))
+            })
+            .collect();
+        let num_routes = routes.len();
+
+        let alternate_routes = self.alternate_routes.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(ALTERNATE_ROUTES_KEY).ignore();
+        if !routes.is_empty() {
+            pipe.hset_multiple(ALTERNATE_ROUTES_KEY, &routes).ignore();
+        }
+        Box::new(
+            pipe.query_async(self.connection.clone())
+                .map_err(|err| error!("Error setting alternate routes: {:?}", err))
+                .and_then(move |(connection, _): (RedisReconnect, Value)| {
+                    trace!(
+                        "Saved alternate routes for {} prefixes to Redis",
+                        num_routes
+                    );
+                    update_alternate_routes(connection, alternate_routes)
+                }),
+        )
+    }
 }
 
 impl RateLimitStore for RedisStore {
@@ -1937,7 +2096,7 @@ type RouteVec = Vec<(String, AccountId)>;
 // TODO replace this with pubsub when async pubsub is added upstream: https://github.com/mitsuhiko/redis-rs/issues/183
 fn update_routes(
     connection: RedisReconnect,
-    routing_table: Arc<RwLock<HashMap<Bytes, AccountId>>>,
+    routing_table: Arc<RwLock<Arc<interledger_router::RoutingTable<AccountId>>>>,
 ) -> impl Future<Item = (), Error = ()> {
     let mut pipe = redis::pipe();
     pipe.hgetall(ROUTES_KEY)
@@ -1961,7 +2120,7 @@ fn update_routes(
                 let default_route_iter = iter::once(default_route)
                     .filter_map(|r| r)
                     .map(|account_id| (String::new(), account_id));
-                let routes = HashMap::from_iter(
+                let routes = interledger_router::RoutingTable::from_iter(
                     routes
                         .into_iter()
                         // Include the default route if there is one
@@ -1974,12 +2133,52 @@ fn update_routes(
                 // TODO we may not want to print this because the routing table will be very big
                 // if the node has a lot of local accounts
                 trace!("Routing table is: {:?}", routes);
-                *routing_table.write() = routes;
+                *routing_table.write() = Arc::new(routes);
                 Ok(())
             },
         )
 }
 
+fn update_alternate_routes(
+    connection: RedisReconnect,
+    alternate_routes: Arc<RwLock<Arc<AlternateRoutes<AccountId>>>>,
+) -> impl Future<Item = (), Error = ()> {
+    cmd("HGETALL")
+        .arg(ALTERNATE_ROUTES_KEY)
+        .query_async(connection)
+        .map_err(|err| error!("Error polling for alternate route updates: {:?}", err))
+        .and_then(move |(_connection, routes): (_, Vec<(String, String)>)| {
+            let routes: AlternateRoutes<AccountId> = routes
+                .into_iter()
+                .filter_map(|(prefix, next_hops)| {
+                    match serde_json::from_str::<Vec<(AccountId, u32, u32)>>(&next_hops) {
+                        Ok(next_hops) => Some((
+                            Bytes::from(prefix),
+                            next_hops
+                                .into_iter()
+                                .map(|(account_id, cost, weight)| NextHop {
+                                    account_id,
+                                    cost,
+                                    weight,
+                                })
+                                .collect(),
+                        )),
+                        Err(err) => {
+                            warn!(
+                                "Ignoring invalid alternate routes for prefix {}: {:?}",
+                                prefix, err
+                            );
+                            None
+                        }
+                    }
+                })
+                .collect();
+            trace!("Alternate routes are: {:?}", routes);
+            *alternate_routes.write() = Arc::new(routes);
+            Ok(())
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -9,7 +9,7 @@ use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
 use interledger_http::{HttpAccount, HttpStore};
 use interledger_packet::Address;
 use interledger_service::Account as AccountTrait;
-use interledger_service::{AccountStore, AddressStore, Username};
+use interledger_service::{AccountStore, AddressStore, CertificateFingerprint, Username};
 use interledger_service_util::BalanceStore;
 use interledger_store_redis::AccountId;
 use log::{debug, error};
@@ -403,6 +403,67 @@ fn duplicate_btp_incoming_auth_works() {
     .unwrap();
 }
 
+#[test]
+fn gets_account_from_client_certificate() {
+    let fingerprint = ACCOUNT_DETAILS_0.client_certificate_fingerprint.unwrap();
+    block_on(test_store().and_then(move |(store, context, accs)| {
+        store
+            .get_account_from_btp_certificate(&fingerprint)
+            .join(store.get_account_from_http_certificate(&fingerprint))
+            .and_then(move |(btp_account, http_account)| {
+                assert_eq!(btp_account.id(), accs[0].id());
+                assert_eq!(http_account.id(), accs[0].id());
+                store
+                    .get_account_from_http_certificate(&CertificateFingerprint::new([0; 32]))
+                    .then(move |result| {
+                        assert!(result.is_err());
+                        let _ = context;
+                        Ok(())
+                    })
+            })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn rejects_duplicate_client_certificates() {
+    let mut acc = ACCOUNT_DETAILS_2.clone();
+    acc.client_certificate_fingerprint = ACCOUNT_DETAILS_0.client_certificate_fingerprint;
+    block_on(test_store().and_then(|(store, context, accs)| {
+        store.insert_account(acc.clone()).then(move |result| {
+            assert!(result.is_err());
+            store.update_account(accs[1].id(), acc).then(move |result| {
+                assert!(result.is_err());
+                let _ = context;
+                Ok(())
+            })
+        })
+    }))
+    .unwrap();
+}
+
+#[test]
+fn removes_client_certificate_on_update() {
+    let fingerprint = ACCOUNT_DETAILS_0.client_certificate_fingerprint.unwrap();
+    let mut new = ACCOUNT_DETAILS_0.clone();
+    new.client_certificate_fingerprint = None;
+    block_on(test_store().and_then(move |(store, context, accs)| {
+        store
+            .update_account(accs[0].id(), new)
+            .and_then(move |account| {
+                assert_eq!(account.id(), accs[0].id());
+                store
+                    .get_account_from_btp_certificate(&fingerprint)
+                    .then(move |result| {
+                        assert!(result.is_err());
+                        let _ = context;
+                        Ok(())
+                    })
+            })
+    }))
+    .unwrap();
+}
+
 #[test]
 fn get_all_accounts() {
     block_on(test_store().and_then(|(store, context, _accs)| {
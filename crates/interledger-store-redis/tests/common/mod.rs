@@ -1,3 +1,4 @@
+#[path = "../../../interledger-store-memory/tests/suite/fixtures.rs"]
 mod fixtures;
 mod redis_helpers;
 mod store_helpers;
@@ -3,9 +3,9 @@ mod common;
 use bytes::Bytes;
 use common::*;
 use interledger_api::{AccountDetails, NodeStore};
-use interledger_ccp::RouteManagerStore;
+use interledger_ccp::{CcpRoutingAccount, RouteManagerStore};
 use interledger_packet::Address;
-use interledger_router::RouterStore;
+use interledger_router::{NextHop, RouterStore};
 use interledger_service::{Account as AccountTrait, AddressStore, Username};
 use interledger_store_redis::AccountId;
 use std::str::FromStr;
@@ -53,9 +53,15 @@ fn polls_for_route_updates() {
                                 settle_to: None,
                                 routing_relation: Some("Peer".to_owned()),
                                 round_trip_time: None,
+                                routing_weight: None,
+                                route_policy: None,
                                 amount_per_minute_limit: None,
                                 packets_per_minute_limit: None,
                                 settlement_engine_url: None,
+                                ilp_over_http_pool_size: None,
+                                ilp_over_http_http2: false,
+                                ilp_over_http_streaming: false,
+                                client_certificate_fingerprint: None,
                             })
                             .and_then(move |bob| {
                                 let routing_table = store_clone_2.routing_table();
@@ -393,3 +399,64 @@ fn returns_configured_routes_for_route_manager() {
     }))
     .unwrap()
 }
+
+#[test]
+fn gets_routing_weights() {
+    block_on(test_store().and_then(|(_store, context, accs)| {
+        // Alice uses the default weight
+        assert_eq!(accs[0].routing_weight(), 1);
+        assert_eq!(accs[1].routing_weight(), 2);
+        let _ = context;
+        Ok(())
+    }))
+    .unwrap()
+}
+
+#[test]
+fn gets_route_policies() {
+    block_on(test_store().and_then(|(_store, context, accs)| {
+        assert!(accs[0].route_policy().is_none());
+        let policy = accs[1].route_policy().unwrap();
+        assert_eq!(policy.import_deny, vec!["example.private.".to_string()]);
+        assert_eq!(policy.local_preference, 10);
+        assert!(policy.import_allow.is_empty());
+        let _ = context;
+        Ok(())
+    }))
+    .unwrap()
+}
+
+#[test]
+fn saves_alternate_routes() {
+    block_on(test_store().and_then(|(store, context, accs)| {
+        let next_hops = vec![
+            NextHop {
+                account_id: accs[0].id(),
+                cost: 0,
+                weight: 1,
+            },
+            NextHop {
+                account_id: accs[1].id(),
+                cost: 1,
+                weight: 2,
+            },
+        ];
+        let mut store_clone = store.clone();
+        store_clone
+            .set_alternate_routes(vec![(Bytes::from("example.charlie"), next_hops.clone())])
+            .and_then(move |_| {
+                let alternate_routes = store.alternate_routes();
+                assert_eq!(alternate_routes.len(), 1);
+                assert_eq!(alternate_routes[&Bytes::from("example.charlie")], next_hops);
+                store_clone
+                    .set_alternate_routes(Vec::new())
+                    .map(move |_| store)
+            })
+            .and_then(move |store| {
+                assert!(store.alternate_routes().is_empty());
+                let _ = context;
+                Ok(())
+            })
+    }))
+    .unwrap()
+}
@@ -0,0 +1,85 @@
+-- Initial schema for the SQL store.
+-- Only BIGINT and TEXT columns are used so that the same schema works on
+-- SQLite and PostgreSQL. Unsigned 64-bit amounts do not fit in a BIGINT and
+-- are stored as decimal strings. Auth tokens are encrypted and hex-encoded.
+
+CREATE TABLE accounts (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    -- usernames are case-insensitive, so uniqueness is enforced on the lowercased value
+    normalized_username TEXT NOT NULL UNIQUE,
+    ilp_address TEXT NOT NULL,
+    asset_code TEXT NOT NULL,
+    asset_scale BIGINT NOT NULL,
+    max_packet_amount TEXT NOT NULL,
+    min_balance BIGINT,
+    ilp_over_http_url TEXT,
+    ilp_over_http_incoming_token TEXT,
+    ilp_over_http_outgoing_token TEXT,
+    ilp_over_btp_url TEXT,
+    ilp_over_btp_incoming_token TEXT,
+    ilp_over_btp_outgoing_token TEXT,
+    settle_threshold BIGINT,
+    settle_to BIGINT,
+    routing_relation TEXT NOT NULL,
+    round_trip_time BIGINT NOT NULL,
+    packets_per_minute_limit BIGINT,
+    amount_per_minute_limit TEXT,
+    settlement_engine_url TEXT
+);
+
+-- Balances are kept in their own table so that they can be audited separately
+-- from the account details. Both values are from the account holder's perspective.
+CREATE TABLE balances (
+    account_id TEXT PRIMARY KEY REFERENCES accounts (id),
+    balance BIGINT NOT NULL DEFAULT 0,
+    prepaid_amount BIGINT NOT NULL DEFAULT 0
+);
+
+-- Routes learned via CCP (and the routes to the local accounts)
+CREATE TABLE routes (
+    prefix TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL
+);
+
+-- Routes configured by the node operator. These take precedence over the other routes.
+CREATE TABLE static_routes (
+    prefix TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL
+);
+
+CREATE TABLE settlement_engines (
+    asset_code TEXT PRIMARY KEY,
+    url TEXT NOT NULL
+);
+
+-- Node-wide values, such as the ILP address received from a parent and the default route
+CREATE TABLE node_settings (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+
+-- Responses saved for the idempotency keys used with the settlement API
+CREATE TABLE idempotency_keys (
+    idempotency_key TEXT PRIMARY KEY,
+    input_hash TEXT NOT NULL,
+    status_code BIGINT NOT NULL,
+    body TEXT NOT NULL,
+    created_at BIGINT NOT NULL
+);
+
+-- Idempotency keys of the incoming settlements that were already credited
+CREATE TABLE settlement_idempotency_keys (
+    idempotency_key TEXT PRIMARY KEY,
+    created_at BIGINT NOT NULL
+);
+
+-- Settlement amounts that could not be credited yet because of precision loss
+CREATE TABLE uncredited_settlement_amounts (
+    account_id TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    scale BIGINT NOT NULL
+);
+
+CREATE INDEX uncredited_settlement_amounts_account_id
+    ON uncredited_settlement_amounts (account_id);
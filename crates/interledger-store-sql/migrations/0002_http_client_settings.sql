@@ -0,0 +1,5 @@
+-- Per-account settings for the ILP-over-HTTP client.
+-- ilp_over_http_http2 is 0 or 1, because SQLite has no boolean type.
+
+ALTER TABLE accounts ADD COLUMN ilp_over_http_pool_size BIGINT;
+ALTER TABLE accounts ADD COLUMN ilp_over_http_http2 BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- Whether to send ILP-over-HTTP packets to the account over one long-lived stream.
+-- It is 0 or 1, because SQLite has no boolean type.
+
+ALTER TABLE accounts ADD COLUMN ilp_over_http_streaming BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+-- The SHA-256 fingerprint (hex-encoded) of the TLS client certificate that a peer
+-- may authenticate with instead of an auth token.
+-- Each certificate can only belong to one account. Both SQLite and PostgreSQL
+-- allow any number of accounts without one (NULL).
+
+ALTER TABLE accounts ADD COLUMN client_certificate_fingerprint TEXT;
+CREATE UNIQUE INDEX accounts_client_certificate_fingerprint ON accounts (client_certificate_fingerprint);
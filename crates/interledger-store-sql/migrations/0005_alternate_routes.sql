@@ -0,0 +1,15 @@
+-- The share of the packets an account gets when the node splits the traffic
+-- between equally good routes.
+
+ALTER TABLE accounts ADD COLUMN routing_weight BIGINT NOT NULL DEFAULT 1;
+
+-- The ranked next hops for the prefixes learned via CCP that have more than one route.
+-- Each row is one next hop, and position is its rank among the next hops for the prefix.
+CREATE TABLE alternate_routes (
+    prefix TEXT NOT NULL,
+    position BIGINT NOT NULL,
+    account_id TEXT NOT NULL,
+    cost BIGINT NOT NULL,
+    weight BIGINT NOT NULL,
+    PRIMARY KEY (prefix, position)
+);
@@ -0,0 +1,4 @@
+-- The rules for the routes exchanged with an account via CCP (JSON-encoded).
+-- Accounts without one (NULL) use the node's default route policy.
+
+ALTER TABLE accounts ADD COLUMN route_policy TEXT;
@@ -0,0 +1,19 @@
+//! # interledger-store-sql
+//!
+//! A Store that uses an SQL database, either [SQLite](https://sqlite.org/) or
+//! [PostgreSQL](https://www.postgresql.org/), for storing account details, balances, the routing table, etc.
+//!
+//! SQLite support is enabled by default. PostgreSQL support is enabled with the `postgres` feature
+//! and is experimental. Rate limits and exchange rates are kept in memory, so they are per node.
+
+#[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+compile_error!("At least one of the \"sqlite\" or \"postgres\" features must be enabled");
+
+mod account;
+mod backend;
+mod crypto;
+mod migrations;
+mod store;
+
+pub use account::{Account, AccountId};
+pub use store::{SqlStore, SqlStoreBuilder};
@@ -0,0 +1,40 @@
+use super::backend::{Transaction, Value};
+use log::debug;
+
+/// Schema migrations, in the order they must be applied.
+/// Migrations that were already applied MUST NOT be modified; add a new one instead.
+static MIGRATIONS: &[(i64, &str)] = &[
+    (1, include_str!("../migrations/0001_initial.sql")),
+    (
+        2,
+        include_str!("../migrations/0002_http_client_settings.sql"),
+    ),
+    (3, include_str!("../migrations/0003_http_streaming.sql")),
+    (
+        4,
+        include_str!("../migrations/0004_client_certificates.sql"),
+    ),
+    (5, include_str!("../migrations/0005_alternate_routes.sql")),
+    (6, include_str!("../migrations/0006_route_policies.sql")),
+];
+
+/// Apply all of the migrations that have not yet been run on this database
+pub(crate) fn run_migrations(tx: &Transaction) -> Result<(), ()> {
+    tx.batch_execute("CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY)")?;
+    let applied = tx.query("SELECT version FROM schema_migrations", &[], |row| {
+        row.required_i64(0)
+    })?;
+
+    for (version, sql) in MIGRATIONS.iter() {
+        if applied.contains(version) {
+            continue;
+        }
+        debug!("Applying database migration {}", version);
+        tx.batch_execute(sql)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version) VALUES (?)",
+            &[Value::from(*version)],
+        )?;
+    }
+    Ok(())
+}
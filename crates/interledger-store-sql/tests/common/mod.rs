@@ -0,0 +1,8 @@
+#[path = "../../../interledger-store-memory/tests/suite/fixtures.rs"]
+mod fixtures;
+mod store_helpers;
+
+pub use fixtures::*;
+pub use futures::Future;
+pub use interledger_store_sql::*;
+pub use store_helpers::*;
@@ -0,0 +1,77 @@
+use super::fixtures::*;
+use env_logger;
+use futures::Future;
+use interledger_api::NodeStore;
+use interledger_packet::Address;
+use interledger_service::{Account as AccountTrait, AddressStore};
+use interledger_store_sql::{Account, SqlStore, SqlStoreBuilder};
+use std::{env, fs, path::PathBuf, str::FromStr};
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+pub fn test_store() -> impl Future<Item = (SqlStore, Vec<Account>), Error = ()> {
+    // Each test gets its own in-memory database
+    connect_store("sqlite://:memory:").and_then(|store| {
+        let store_clone = store.clone();
+        let mut accs = Vec::new();
+        store
+            .clone()
+            .insert_account(ACCOUNT_DETAILS_0.clone())
+            .and_then(move |acc| {
+                accs.push(acc.clone());
+                // alice is a Parent, so the store's ilp address is updated to
+                // the value that would be received by the ILDCP request. here,
+                // we just assume alice appended some data to her address
+                store
+                    .clone()
+                    .set_ilp_address(acc.ilp_address().with_suffix(b"user1").unwrap())
+                    .and_then(move |_| {
+                        store_clone
+                            .insert_account(ACCOUNT_DETAILS_1.clone())
+                            .and_then(move |acc| {
+                                accs.push(acc.clone());
+                                Ok((store, accs))
+                            })
+                    })
+            })
+    })
+}
+
+pub fn connect_store(database_url: &str) -> impl Future<Item = SqlStore, Error = ()> {
+    SqlStoreBuilder::new(database_url.to_string(), [0; 32])
+        .node_ilp_address(Address::from_str("example.node").unwrap())
+        .connect()
+}
+
+/// A SQLite database file that is deleted when this is dropped
+pub struct TestDatabase {
+    path: PathBuf,
+}
+
+impl TestDatabase {
+    pub fn new() -> Self {
+        let path = env::temp_dir().join(format!("interledger-store-sql-{}.db", Uuid::new_v4()));
+        TestDatabase { path }
+    }
+
+    pub fn url(&self) -> String {
+        format!("sqlite://{}", self.path.display())
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub fn block_on<F>(f: F) -> Result<F::Item, F::Error>
+where
+    F: Future + Send + 'static,
+    F::Item: Send,
+    F::Error: Send,
+{
+    let _ = env_logger::try_init();
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(f)
+}
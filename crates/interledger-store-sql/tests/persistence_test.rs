@@ -0,0 +1,81 @@
+mod common;
+
+use bytes::Bytes;
+use common::*;
+use interledger_api::NodeStore;
+use interledger_http::HttpStore;
+use interledger_packet::Address;
+use interledger_router::RouterStore;
+use interledger_service::{Account as AccountTrait, AccountStore, AddressStore, Username};
+use interledger_service_util::BalanceStore;
+use std::str::FromStr;
+
+#[test]
+fn data_survives_reconnecting() {
+    let database = TestDatabase::new();
+    let url = database.url();
+    let parent_address = Address::from_str("example.parent.node").unwrap();
+
+    let (alice, bob) = block_on(connect_store(&url).and_then({
+        let parent_address = parent_address.clone();
+        move |store| {
+            let store_clone = store.clone();
+            store
+                .insert_account(ACCOUNT_DETAILS_0.clone())
+                .join(store.insert_account(ACCOUNT_DETAILS_1.clone()))
+                .and_then(move |(alice, bob)| {
+                    store_clone
+                        .set_ilp_address(parent_address)
+                        .and_then({
+                            let store = store_clone.clone();
+                            let alice = alice.clone();
+                            move |_| store.set_static_route("example.other".to_string(), alice.id())
+                        })
+                        .and_then({
+                            let store = store_clone.clone();
+                            let alice = alice.clone();
+                            move |_| store.update_balances_for_prepare(alice, 100)
+                        })
+                        .and_then(move |_| Ok((alice, bob)))
+                })
+        }
+    }))
+    .unwrap();
+
+    block_on(connect_store(&url).and_then(move |store| {
+        assert_eq!(store.get_ilp_address(), parent_address);
+        let routes = store.routing_table();
+        assert_eq!(routes[&Bytes::from("example.other")], alice.id());
+        // bob's route was updated when the node's address changed
+        assert_eq!(
+            routes[&parent_address.with_suffix(b"bob").unwrap().to_bytes()],
+            bob.id()
+        );
+
+        let store_clone = store.clone();
+        store
+            .get_accounts(vec![alice.id(), bob.id()])
+            .and_then(move |accounts| {
+                assert_eq!(accounts[0].username().as_ref(), "alice");
+                assert_eq!(
+                    *accounts[1].ilp_address(),
+                    parent_address.with_suffix(b"bob").unwrap()
+                );
+                store_clone
+                    .get_balance(accounts[0].clone())
+                    .and_then(move |balance| {
+                        assert_eq!(balance, -100);
+                        // the auth tokens are decrypted with the key derived from the same secret
+                        store_clone.get_account_from_http_auth(
+                            &Username::from_str("bob").unwrap(),
+                            "incoming_auth_token",
+                        )
+                    })
+            })
+            .and_then(move |account| {
+                assert_eq!(account.id(), bob.id());
+                Ok(())
+            })
+    }))
+    .unwrap();
+}
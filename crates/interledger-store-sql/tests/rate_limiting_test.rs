@@ -0,0 +1,4 @@
+mod common;
+
+#[path = "../../interledger-store-memory/tests/suite/rate_limiting.rs"]
+mod rate_limiting;
@@ -0,0 +1,4 @@
+mod common;
+
+#[path = "../../interledger-store-memory/tests/suite/settlement.rs"]
+mod settlement;
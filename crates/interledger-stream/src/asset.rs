@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+/// The asset one side of a STREAM connection denominates its amounts in
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AssetDetails {
+    pub asset_code: String,
+    pub asset_scale: u8,
+}
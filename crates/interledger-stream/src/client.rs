@@ -1,37 +1,190 @@
+use super::asset::AssetDetails;
 use super::congestion::CongestionController;
 use super::crypto::*;
+use super::data::{IncomingData, OutgoingData, MAX_DATA_PER_PACKET};
 use super::error::Error;
 use super::packet::*;
-use bytes::Bytes;
-use futures::{Async, Future, Poll};
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::{loop_fn, result, Either, Loop},
+    Async, Future, Poll,
+};
 use interledger_ildcp::get_ildcp_info;
 use interledger_packet::{
-    Address, ErrorClass, ErrorCode as IlpErrorCode, Fulfill, PacketType as IlpPacketType,
-    PrepareBuilder, Reject,
+    Address, ErrorClass, ErrorCode as IlpErrorCode, Fulfill, MaxPacketAmountDetails,
+    PacketType as IlpPacketType, PrepareBuilder, Reject,
 };
 use interledger_service::*;
 use log::{debug, error, warn};
 use std::{
     cell::Cell,
-    cmp::min,
+    cmp::{max, min},
     str,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
+/// How many test packets to send before giving up on determining the exchange rate
+const MAX_PROBE_ATTEMPTS: u64 = 5;
+
+/// The worst exchange rate a payment may be sent at.
+///
+/// Exchange rates are the number of the receiver's units delivered per unit sent,
+/// so they include any difference between the sender's and receiver's asset scales.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExchangeRateLimit {
+    /// Send the payment at whatever rate the path offers
+    None,
+    /// The minimum number of the receiver's units each unit sent must deliver
+    MinExchangeRate(f64),
+    /// How much worse than the rate measured when the payment starts the rate
+    /// may become while the payment is being sent, for example 0.01 for 1%
+    MaxSlippage(f64),
+}
+
+/// The outcome of sending a payment
+#[derive(Debug)]
+pub struct PaymentResult {
+    /// The amount sent, in the sender's units (including any packets still in flight if the payment failed)
+    pub source_amount: u64,
+    /// The amount delivered, as reported by the receiver and in the receiver's units
+    pub delivered_amount: u64,
+    /// The receiver's asset details, if it sent them
+    pub destination_asset: Option<AssetDetails>,
+    /// The number of packets carrying money that were fulfilled
+    pub fulfilled_packets: u64,
+    /// The number of packets carrying money that were rejected
+    pub rejected_packets: u64,
+    /// Why the payment stopped before it was complete, if it did
+    pub error: Option<Error>,
+    /// How long it took to send the payment
+    pub duration: Duration,
+}
+
+impl PaymentResult {
+    /// Returns true if the whole payment was sent
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
 /// Send a given amount of money using the STREAM transport protocol.
 ///
-/// This returns the amount delivered, as reported by the receiver and in the receiver's asset's units.
+/// Unless the `rate_limit` is `ExchangeRateLimit::None`, the exchange rate of the path is
+/// first measured using unfulfillable test packets and the payment is stopped if the
+/// rate is or becomes worse than the limit.
+///
+/// If the payment stops part of the way through (for example because the receiver's limit was
+/// reached or the exchange rate got too bad), the future still resolves and the `PaymentResult`
+/// says why and how much was delivered. The future only fails before any money is sent, which is
+/// the case if the sender's ILDCP info or the exchange rate of the path could not be determined,
+/// or if the measured rate is already below the `ExchangeRateLimit::MinExchangeRate`.
 pub fn send_money<S, A>(
     service: S,
     from_account: &A,
     destination_account: Address,
     shared_secret: &[u8],
     source_amount: u64,
-) -> impl Future<Item = (u64, S), Error = Error>
+    rate_limit: ExchangeRateLimit,
+) -> impl Future<Item = (PaymentResult, S), Error = Error>
 where
     S: IncomingService<A> + Clone,
     A: Account,
 {
+    send_money_with_data(
+        service,
+        from_account,
+        destination_account,
+        shared_secret,
+        source_amount,
+        rate_limit,
+        Bytes::new(),
+    )
+    .map(|(result, _data, service)| (result, service))
+}
+
+/// Send a given amount of money together with some data using the STREAM transport protocol.
+///
+/// The data is sent on the same stream as the money, split over as many packets as needed
+/// and within the limits the receiver advertises for how much data it will buffer.
+/// Either the amount or the data may be empty.
+///
+/// This also returns any data the receiver sent back on the stream before the connection was closed.
+pub fn send_money_with_data<S, A>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    source_amount: u64,
+    rate_limit: ExchangeRateLimit,
+    data: Bytes,
+) -> impl Future<Item = (PaymentResult, Bytes, S), Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    send_payment(
+        service,
+        from_account,
+        destination_account,
+        shared_secret,
+        source_amount,
+        None,
+        rate_limit,
+        data,
+    )
+}
+
+/// Send however much money it takes to deliver the given amount (in the receiver's units)
+/// using the STREAM transport protocol, without spending more than the `max_source_amount`.
+///
+/// The exchange rate of the path is measured with unfulfillable test packets to figure out
+/// how much to send, and the amount the receiver reports having received is used to
+/// decide when to stop. The payment fails if the budget is spent before the full amount
+/// is delivered or if the `rate_limit` is exceeded.
+pub fn send_money_to_deliver<S, A>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    destination_amount: u64,
+    max_source_amount: u64,
+    rate_limit: ExchangeRateLimit,
+) -> impl Future<Item = (PaymentResult, S), Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    send_payment(
+        service,
+        from_account,
+        destination_account,
+        shared_secret,
+        max_source_amount,
+        Some(destination_amount),
+        rate_limit,
+        Bytes::new(),
+    )
+    .map(|(result, _data, service)| (result, service))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn send_payment<S, A>(
+    service: S,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    source_amount: u64,
+    delivery_target: Option<u64>,
+    rate_limit: ExchangeRateLimit,
+    data: Bytes,
+) -> impl Future<Item = (PaymentResult, Bytes, S), Error = Error>
+where
+    S: IncomingService<A> + Clone,
+    A: Account,
+{
+    let started_at = Instant::now();
+    let mut outgoing_data = OutgoingData::default();
+    outgoing_data.write(data);
     let shared_secret = Bytes::from(shared_secret);
     let from_account = from_account.clone();
     // TODO can/should we avoid cloning the account?
@@ -44,27 +197,217 @@ where
                 destination_account.scheme(),
                 source_account.scheme());
             }
+            let source_asset = AssetDetails {
+                asset_code: String::from_utf8_lossy(account_details.asset_code()).to_string(),
+                asset_scale: account_details.asset_scale(),
+            };
 
-            SendMoneyFuture {
+            // Paying a fixed delivery amount needs the rate to know how much to send
+            let needs_rate = delivery_target.is_some() || rate_limit != ExchangeRateLimit::None;
+            let probe = if !needs_rate || source_amount == 0 {
+                Either::A(result(Ok((None, None, service, 1))))
+            } else {
+                Either::B(
+                    probe_exchange_rate(
+                        service,
+                        from_account.clone(),
+                        destination_account.clone(),
+                        shared_secret.clone(),
+                        source_asset.clone(),
+                        source_amount,
+                    )
+                    .map(|(rate, destination_asset, service, sequence)| {
+                        (Some(rate), destination_asset, service, sequence)
+                    }),
+                )
+            };
+            probe.and_then(move |(path_rate, destination_asset, service, sequence)| {
+                let min_exchange_rate = match (rate_limit, path_rate) {
+                    (ExchangeRateLimit::MinExchangeRate(min_rate), Some(path_rate)) => {
+                        if path_rate < min_rate {
+                            return Err(Error::SendMoneyError(format!(
+                                "Exchange rate of the path ({}) is below the minimum: {}",
+                                path_rate, min_rate
+                            )));
+                        }
+                        min_rate
+                    }
+                    (ExchangeRateLimit::MaxSlippage(slippage), Some(path_rate)) => {
+                        path_rate * (1.0 - slippage)
+                    }
+                    _ => 0.0,
+                };
+                let path_rate = path_rate.unwrap_or(0.0);
+                if let Some(destination_amount) = delivery_target {
+                    if destination_amount > 0 && path_rate <= 0.0 {
+                        return Err(Error::SendMoneyError(
+                            "Path does not deliver any money to the receiver".to_string(),
+                        ));
+                    }
+                }
+                Ok(SendMoneyFuture {
                 state: SendMoneyFutureState::SendMoney,
                 next: Some(service),
                 from_account,
                 source_account,
+                source_asset,
                 destination_account,
+                destination_asset,
                 shared_secret,
                 source_amount,
+                max_source_amount: source_amount,
                 // Try sending the full amount first
                 // TODO make this configurable -- in different scenarios you might prioritize
                 // sending as much as possible per packet vs getting money flowing ASAP differently
                 congestion_controller: CongestionController::new(source_amount, source_amount / 10, 2.0),
                 pending_requests: Cell::new(Vec::new()),
                 delivered_amount: 0,
+                reported_received: 0,
+                delivery_target,
+                outgoing_data,
+                incoming_data: IncomingData::default(),
+                received_data: BytesMut::new(),
+                data_blocked_at: None,
+                path_rate,
+                min_exchange_rate,
                 should_send_source_account: true,
-                sequence: 1,
+                sequence,
+                fulfilled_packets: 0,
                 rejected_packets: 0,
+                started_at,
                 error: None,
-            }
+            })
+            })
         })
+        .and_then(|send_money_future| send_money_future)
+}
+
+/// Measure the exchange rate of the path to the receiver by sending it an unfulfillable
+/// test packet and checking how much arrived. If the packet is too large for the path,
+/// it is retried with the max packet amount reported by the connector.
+///
+/// The test packet also tells the receiver our asset details, so that it responds with its own.
+///
+/// This returns the rate, the receiver's asset details, the service, and the next sequence number to use.
+fn probe_exchange_rate<S, A>(
+    service: S,
+    from_account: A,
+    destination_account: Address,
+    shared_secret: Bytes,
+    source_asset: AssetDetails,
+    amount: u64,
+) -> impl Future<Item = (f64, Option<AssetDetails>, S, u64), Error = Error>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    loop_fn(
+        (service, amount, 1),
+        move |(mut service, amount, sequence): (S, u64, u64)| {
+            let stream_packet = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames: &[
+                    Frame::StreamMoney(StreamMoneyFrame {
+                        stream_id: 1,
+                        shares: 1,
+                    }),
+                    Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                        source_asset_code: &source_asset.asset_code,
+                        source_asset_scale: source_asset.asset_scale,
+                    }),
+                ],
+            }
+            .build();
+            let data = stream_packet.into_encrypted(&shared_secret);
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount,
+                execution_condition: &random_condition(),
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &data[..],
+            }
+            .build();
+            debug!(
+                "Sending test packet {} with amount {} to measure the exchange rate",
+                sequence, amount
+            );
+
+            let shared_secret = shared_secret.clone();
+            service
+                .handle_request(IncomingRequest {
+                    from: from_account.clone(),
+                    prepare,
+                })
+                .then(move |result| {
+                    let reject = match result {
+                        Ok(_) => {
+                            return Err(Error::SendMoneyError(
+                                "Test packet was unexpectedly fulfilled".to_string(),
+                            ))
+                        }
+                        Err(reject) => reject,
+                    };
+                    // The receiver tells us how much arrived
+                    if let Ok(packet) =
+                        StreamPacket::from_encrypted(&shared_secret, BytesMut::from(reject.data()))
+                    {
+                        let rate = packet.prepare_amount() as f64 / amount as f64;
+                        debug!(
+                            "Test packet of {} delivered {}, exchange rate is: {}",
+                            amount,
+                            packet.prepare_amount(),
+                            rate
+                        );
+                        let destination_asset = packet.frames().find_map(|frame| match frame {
+                            Frame::ConnectionAssetDetails(frame) => Some(AssetDetails {
+                                asset_code: frame.source_asset_code.to_string(),
+                                asset_scale: frame.source_asset_scale,
+                            }),
+                            _ => None,
+                        });
+                        return Ok(Loop::Break((
+                            rate,
+                            destination_asset,
+                            service,
+                            sequence + 1,
+                        )));
+                    }
+
+                    let error = Error::SendMoneyError(format!(
+                        "Test packet was rejected with error: {} {}",
+                        reject.code(),
+                        str::from_utf8(reject.message()).unwrap_or_default(),
+                    ));
+                    if sequence >= MAX_PROBE_ATTEMPTS {
+                        return Err(error);
+                    }
+                    match (reject.code().class(), reject.code()) {
+                        (ErrorClass::Temporary, _) => {
+                            Ok(Loop::Continue((service, amount, sequence + 1)))
+                        }
+                        (_, IlpErrorCode::F08_AMOUNT_TOO_LARGE) => {
+                            let details = MaxPacketAmountDetails::from_bytes(reject.data())
+                                .map_err(|_| error)?;
+                            let max_amount = u128::from(amount) * u128::from(details.max_amount())
+                                / u128::from(details.amount_received()).max(1);
+                            if max_amount == 0 {
+                                return Err(Error::SendMoneyError(
+                                    "Path does not allow any money to be sent".to_string(),
+                                ));
+                            }
+                            Ok(Loop::Continue((
+                                service,
+                                min(max_amount, u128::from(amount)) as u64,
+                                sequence + 1,
+                            )))
+                        }
+                        _ => Err(error),
+                    }
+                })
+        },
+    )
 }
 
 struct SendMoneyFuture<S: IncomingService<A>, A: Account> {
@@ -72,21 +415,42 @@ struct SendMoneyFuture<S: IncomingService<A>, A: Account> {
     next: Option<S>,
     from_account: A,
     source_account: Address,
+    source_asset: AssetDetails,
     destination_account: Address,
+    /// The receiver's asset, once it tells us about it
+    destination_asset: Option<AssetDetails>,
     shared_secret: Bytes,
     source_amount: u64,
     congestion_controller: CongestionController,
+    /// The budget (or the exact amount to send if there is no `delivery_target`)
+    max_source_amount: u64,
     pending_requests: Cell<Vec<PendingRequest>>,
     delivered_amount: u64,
+    /// The highest total the receiver reported having received on our stream
+    reported_received: u64,
+    /// The amount to deliver, if paying a fixed amount in the receiver's units
+    delivery_target: Option<u64>,
+    outgoing_data: OutgoingData,
+    incoming_data: IncomingData,
+    received_data: BytesMut,
+    /// The receiver's max offset when we last told it that we are blocked from sending more data
+    data_blocked_at: Option<u64>,
+    /// The exchange rate measured before sending (or 0 if it was not measured)
+    path_rate: f64,
+    min_exchange_rate: f64,
     should_send_source_account: bool,
     sequence: u64,
+    fulfilled_packets: u64,
     rejected_packets: u64,
+    started_at: Instant,
     error: Option<Error>,
 }
 
 struct PendingRequest {
     sequence: u64,
     amount: u64,
+    min_destination_amount: u64,
+    data: Option<(u64, Bytes)>,
     future: BoxedIlpFuture,
 }
 
@@ -105,33 +469,74 @@ where
 {
     fn try_send_money(&mut self) -> Result<bool, Error> {
         // Fire off requests until the congestion controller tells us to stop or we've sent the total amount
+        // (and until we've sent all of the data or the receiver's buffer is full)
         let mut sent_packets = false;
         loop {
             // Determine the amount to send
             let amount = min(
-                self.source_amount,
+                min(self.source_amount, self.amount_left_to_deliver()),
                 self.congestion_controller.get_max_amount(),
             );
-            if amount == 0 {
+            let data = self.outgoing_data.next_chunk(MAX_DATA_PER_PACKET);
+            let data_blocked = amount == 0
+                && data.is_none()
+                && self.outgoing_data.is_blocked()
+                && self.pending_requests.get_mut().is_empty();
+            if data_blocked {
+                // Ask the receiver to make room for more data but give up if it already
+                // told us it cannot accept more since the last time we asked
+                let max_offset = self.outgoing_data.remote_max_offset();
+                if self.data_blocked_at == Some(max_offset) {
+                    return Err(Error::SendMoneyError(format!(
+                        "Receiver is not accepting more data (max offset: {})",
+                        max_offset
+                    )));
+                }
+                self.data_blocked_at = Some(max_offset);
+            } else if amount == 0 && data.is_none() {
                 break;
             }
             self.source_amount -= amount;
 
             // Load up the STREAM packet
             let sequence = self.next_sequence();
-            let mut frames = vec![Frame::StreamMoney(StreamMoneyFrame {
+            let mut frames = vec![Frame::StreamMaxData(StreamMaxDataFrame {
                 stream_id: 1,
-                shares: 1,
+                max_offset: self.incoming_data.max_offset(),
             })];
+            if amount > 0 {
+                frames.push(Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                }));
+            }
+            if let Some((offset, ref data)) = data {
+                frames.push(Frame::StreamData(StreamDataFrame {
+                    stream_id: 1,
+                    offset,
+                    data: &data[..],
+                }));
+            }
+            if data_blocked {
+                frames.push(Frame::StreamDataBlocked(StreamDataBlockedFrame {
+                    stream_id: 1,
+                    max_offset: self.outgoing_data.remote_max_offset(),
+                }));
+            }
             if self.should_send_source_account {
                 frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
                     source_account: self.source_account.clone(),
                 }));
+                frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: &self.source_asset.asset_code,
+                    source_asset_scale: self.source_asset.asset_scale,
+                }));
             }
+            // The receiver rejects the packet if less than this arrives
+            let min_destination_amount = (amount as f64 * self.min_exchange_rate).floor() as u64;
             let stream_packet = StreamPacketBuilder {
                 ilp_packet_type: IlpPacketType::Prepare,
-                // TODO enforce min exchange rate
-                prepare_amount: 0,
+                prepare_amount: min_destination_amount,
                 sequence,
                 frames: &frames,
             }
@@ -142,15 +547,20 @@ where
                 "Sending packet {} with amount: {} and encrypted STREAM packet: {:?}",
                 sequence, amount, stream_packet
             );
-            let data = stream_packet.into_encrypted(&self.shared_secret);
-            let execution_condition = generate_condition(&self.shared_secret, &data);
+            let encrypted = stream_packet.into_encrypted(&self.shared_secret);
+            // Packets with data are fulfilled too, so we know when the receiver accepted the data
+            let execution_condition = if amount > 0 || data.is_some() {
+                generate_condition(&self.shared_secret, &encrypted)
+            } else {
+                random_condition()
+            };
             let prepare = PrepareBuilder {
                 destination: self.destination_account.clone(),
                 amount,
                 execution_condition: &execution_condition,
                 expires_at: SystemTime::now() + Duration::from_secs(30),
                 // TODO don't copy the data
-                data: &data[..],
+                data: &encrypted[..],
             }
             .build();
 
@@ -164,6 +574,8 @@ where
                 self.pending_requests.get_mut().push(PendingRequest {
                     sequence,
                     amount,
+                    min_destination_amount,
+                    data,
                     future: Box::new(send_request),
                 });
                 sent_packets = true;
@@ -207,6 +619,8 @@ where
             self.pending_requests.get_mut().push(PendingRequest {
                 sequence,
                 amount: 0,
+                min_destination_amount: 0,
+                data: None,
                 future: Box::new(send_request),
             });
         } else {
@@ -226,7 +640,7 @@ where
                     None
                 }
                 Err(reject) => {
-                    self.handle_reject(pending_request.sequence, pending_request.amount, reject);
+                    self.handle_reject(pending_request, reject);
                     None
                 }
             })
@@ -247,12 +661,16 @@ where
         // TODO should we check the fulfillment and expiry or can we assume the plugin does that?
         self.congestion_controller.fulfill(amount);
         self.should_send_source_account = false;
+        if amount > 0 {
+            self.fulfilled_packets += 1;
+        }
 
         if let Ok(packet) = StreamPacket::from_encrypted(&self.shared_secret, fulfill.into_data()) {
             if packet.ilp_packet_type() == IlpPacketType::Fulfill {
                 // TODO check that the sequence matches our outgoing packet
                 self.delivered_amount += packet.prepare_amount();
             }
+            self.handle_response_frames(&packet);
         } else {
             warn!(
                 "Unable to parse STREAM packet from fulfill data for sequence {}",
@@ -266,10 +684,30 @@ where
         );
     }
 
-    fn handle_reject(&mut self, sequence: u64, amount: u64, reject: Reject) {
+    fn handle_reject(&mut self, request: PendingRequest, reject: Reject) {
+        let PendingRequest {
+            sequence,
+            amount,
+            min_destination_amount,
+            data,
+            ..
+        } = request;
         self.source_amount += amount;
         self.congestion_controller.reject(amount, &reject);
-        self.rejected_packets += 1;
+        if amount > 0 {
+            self.rejected_packets += 1;
+        }
+
+        // The data is only known to be accepted once a packet carrying it is fulfilled
+        if let Some((offset, data)) = data {
+            self.outgoing_data.retry(offset, data);
+        }
+        let response =
+            StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(reject.data())).ok();
+        if let Some(ref packet) = response {
+            self.handle_response_frames(packet);
+        }
+
         debug!(
             "Prepare {} with amount {} was rejected with code: {} ({} left to send)",
             sequence,
@@ -284,7 +722,21 @@ where
                 // Handled by the congestion controller
             }
             (_, IlpErrorCode::F99_APPLICATION_ERROR) => {
-                // TODO handle STREAM errors
+                // TODO handle other STREAM errors
+                // (the receiver also rejects our own ConnectionClose packet)
+                if self.state == SendMoneyFutureState::SendMoney {
+                    if let Some(ref packet) = response {
+                        if packet.prepare_amount() < min_destination_amount {
+                            self.error = Some(Error::SendMoneyError(format!(
+                                "Exchange rate dropped below the minimum (packet of {} delivered {} instead of at least {})",
+                                amount,
+                                packet.prepare_amount(),
+                                min_destination_amount
+                            )));
+                        }
+                        self.handle_receiver_limits(packet);
+                    }
+                }
             }
             _ => {
                 self.error = Some(Error::SendMoneyError(format!(
@@ -296,6 +748,107 @@ where
         }
     }
 
+    /// Stop sending if the receiver closed the connection or stream,
+    /// or if it cannot accept any more money
+    fn handle_receiver_limits(&mut self, packet: &StreamPacket) {
+        for frame in packet.frames() {
+            match frame {
+                Frame::ConnectionClose(frame) => {
+                    self.error = Some(Error::SendMoneyError(format!(
+                        "Receiver closed the connection with code: {:?} and message: {}",
+                        frame.code, frame.message
+                    )));
+                }
+                Frame::StreamClose(frame) => {
+                    self.error = Some(Error::SendMoneyError(format!(
+                        "Receiver closed stream {} with code: {:?} and message: {}",
+                        frame.stream_id, frame.code, frame.message
+                    )));
+                }
+                Frame::StreamMaxMoney(frame)
+                    if packet.prepare_amount()
+                        > frame.receive_max.saturating_sub(frame.total_received) =>
+                {
+                    self.error = Some(Error::SendMoneyError(format!(
+                        "Receiver cannot accept any more money (received {} of receive max {})",
+                        frame.total_received, frame.receive_max
+                    )));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handle the flow control and data frames the receiver sent back on our stream
+    fn handle_response_frames(&mut self, packet: &StreamPacket) {
+        for frame in packet.frames() {
+            match frame {
+                Frame::ConnectionAssetDetails(ref frame) => {
+                    self.destination_asset = Some(AssetDetails {
+                        asset_code: frame.source_asset_code.to_string(),
+                        asset_scale: frame.source_asset_scale,
+                    });
+                }
+                Frame::StreamMaxMoney(ref frame) if frame.stream_id == 1 => {
+                    self.reported_received = max(self.reported_received, frame.total_received);
+                }
+                Frame::StreamMaxData(ref frame) if frame.stream_id == 1 => {
+                    self.outgoing_data.set_remote_max_offset(frame.max_offset);
+                }
+                Frame::StreamData(ref frame) if frame.stream_id == 1 => {
+                    let is_buffered = self.incoming_data.push(frame.offset, frame.data);
+                    if !is_buffered {
+                        warn!(
+                            "Receiver sent more data than we can buffer (offset: {}, max offset: {})",
+                            frame.offset,
+                            self.incoming_data.max_offset()
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.received_data
+            .extend_from_slice(&self.incoming_data.read()[..]);
+    }
+
+    /// The amount delivered so far, as reported by the receiver
+    fn total_delivered(&self) -> u64 {
+        max(self.delivered_amount, self.reported_received)
+    }
+
+    /// How much more (in our units) needs to be sent to reach the delivery target,
+    /// taking into account what is already on its way
+    fn amount_left_to_deliver(&mut self) -> u64 {
+        let destination_amount = match self.delivery_target {
+            Some(destination_amount) => destination_amount,
+            None => return u64::MAX,
+        };
+        let in_flight: u64 = self
+            .pending_requests
+            .get_mut()
+            .iter()
+            .map(|request| request.amount)
+            .sum();
+        let expected_delivery = self.total_delivered() as f64 + in_flight as f64 * self.path_rate;
+        let remaining = destination_amount as f64 - expected_delivery;
+        if remaining <= 0.0 {
+            0
+        } else {
+            (remaining / self.path_rate).ceil() as u64
+        }
+    }
+
+    /// Whether all of the money that should be sent has been sent
+    fn is_money_sent(&self) -> bool {
+        match self.delivery_target {
+            Some(destination_amount) => {
+                self.total_delivered() >= destination_amount || self.source_amount == 0
+            }
+            None => self.source_amount == 0,
+        }
+    }
+
     fn next_sequence(&mut self) -> u64 {
         let seq = self.sequence;
         self.sequence += 1;
@@ -303,32 +856,37 @@ where
     }
 }
 
-impl<S, A> Future for SendMoneyFuture<S, A>
+impl<S, A> SendMoneyFuture<S, A>
 where
     S: IncomingService<A>,
     A: Account,
 {
-    type Item = (u64, S);
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    /// Send the packets until the payment is finished or fails
+    fn poll_payment(&mut self) -> Poll<(), Error> {
         // TODO maybe don't have loops here and in try_send_money
         loop {
             self.poll_pending_requests()?;
 
-            if self.source_amount == 0 && self.pending_requests.get_mut().is_empty() {
+            if self.is_money_sent()
+                && self.outgoing_data.is_empty()
+                && self.pending_requests.get_mut().is_empty()
+            {
                 if self.state == SendMoneyFutureState::SendMoney {
+                    if let Some(destination_amount) = self.delivery_target {
+                        if self.total_delivered() < destination_amount {
+                            return Err(Error::SendMoneyError(format!(
+                                "Sent {} (the maximum source amount) but only delivered {} of {}",
+                                self.max_source_amount - self.source_amount,
+                                self.total_delivered(),
+                                destination_amount
+                            )));
+                        }
+                    }
                     self.state = SendMoneyFutureState::Closing;
                     self.try_send_connection_close()?;
                 } else {
                     self.state = SendMoneyFutureState::Closed;
-                    debug!(
-                        "Send money future finished. Delivered: {} ({} packets fulfilled, {} packets rejected)", self.delivered_amount, self.sequence - 1, self.rejected_packets,
-                    );
-                    return Ok(Async::Ready((
-                        self.delivered_amount,
-                        self.next.take().unwrap(),
-                    )));
+                    return Ok(Async::Ready(()));
                 }
             } else if !self.try_send_money()? {
                 return Ok(Async::NotReady);
@@ -337,6 +895,41 @@ where
     }
 }
 
+impl<S, A> Future for SendMoneyFuture<S, A>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    type Item = (PaymentResult, Bytes, S);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let error = match self.poll_payment() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => None,
+            Err(error) => Some(error),
+        };
+        let result = PaymentResult {
+            source_amount: self.max_source_amount - self.source_amount,
+            delivered_amount: self.total_delivered(),
+            destination_asset: self.destination_asset.take(),
+            fulfilled_packets: self.fulfilled_packets,
+            rejected_packets: self.rejected_packets,
+            error,
+            duration: self.started_at.elapsed(),
+        };
+        debug!(
+            "Send money future finished. Delivered: {} ({} packets fulfilled, {} packets rejected)",
+            result.delivered_amount, result.fulfilled_packets, result.rejected_packets,
+        );
+        Ok(Async::Ready((
+            result,
+            self.received_data.take().freeze(),
+            self.next.take().unwrap(),
+        )))
+    }
+}
+
 #[cfg(test)]
 mod send_money_tests {
     use super::*;
@@ -373,9 +966,10 @@ mod send_money_tests {
             Address::from_str("example.destination").unwrap(),
             &[0; 32][..],
             100,
+            ExchangeRateLimit::None,
         )
         .wait();
-        assert!(result.is_err());
+        assert!(result.unwrap().0.error.is_some());
         assert_eq!(requests.lock().len(), 1);
     }
 }
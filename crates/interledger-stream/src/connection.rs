@@ -0,0 +1,906 @@
+use super::asset::AssetDetails;
+use super::congestion::CongestionController;
+use super::crypto::*;
+use super::data::{IncomingData, OutgoingData, MAX_DATA_PER_PACKET};
+use super::error::Error;
+use super::packet::*;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    task::{self, Task},
+    Async, Future, Poll,
+};
+use interledger_ildcp::get_ildcp_info;
+use interledger_packet::{
+    Address, ErrorClass, ErrorCode as IlpErrorCode, Fulfill, PacketType as IlpPacketType,
+    PrepareBuilder, Reject,
+};
+use interledger_service::*;
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use std::{
+    cmp::{max, min},
+    collections::HashMap,
+    mem, str,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::timer::Delay;
+
+/// The highest stream ID we assume the receiver allows until it tells us otherwise
+const DEFAULT_MAX_STREAM_ID: u64 = 20;
+/// How long to wait before asking a receiver whose data buffer is full for more room again
+const DATA_BLOCKED_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct StreamState {
+    /// Total amount the application asked to send on this stream
+    send_max: u64,
+    total_sent: u64,
+    in_flight: u64,
+    total_delivered: u64,
+    outgoing_data: OutgoingData,
+    incoming_data: IncomingData,
+    /// The receiver's max offset when we last told it that we are blocked from sending more data
+    data_blocked_at: Option<u64>,
+    /// Set when the application closed the stream but it still has money or data to send
+    closing: bool,
+    closed: bool,
+    error: Option<String>,
+    tasks: Vec<Task>,
+}
+
+impl StreamState {
+    fn money_left(&self) -> u64 {
+        if self.closed || self.error.is_some() {
+            0
+        } else {
+            self.send_max - self.total_sent - self.in_flight
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.money_left() == 0 && self.in_flight == 0 && self.outgoing_data.is_empty()
+    }
+
+    /// Stop sending money on this stream
+    fn fail(&mut self, error: String) {
+        debug!("{}", error);
+        self.send_max = self.total_sent + self.in_flight;
+        self.error = Some(error);
+    }
+}
+
+struct ConnectionState {
+    destination_account: Address,
+    destination_asset: Option<AssetDetails>,
+    streams: HashMap<u64, StreamState>,
+    next_stream_id: u64,
+    remote_max_stream_id: u64,
+    stream_id_blocked: bool,
+    closing: bool,
+    closed: bool,
+    error: Option<String>,
+    driver_task: Option<Task>,
+    close_tasks: Vec<Task>,
+}
+
+impl ConnectionState {
+    fn new(destination_account: Address) -> Self {
+        ConnectionState {
+            destination_account,
+            destination_asset: None,
+            streams: HashMap::new(),
+            // Streams opened by the side that initiated the connection use odd IDs
+            next_stream_id: 1,
+            remote_max_stream_id: DEFAULT_MAX_STREAM_ID,
+            stream_id_blocked: false,
+            closing: false,
+            closed: false,
+            error: None,
+            driver_task: None,
+            close_tasks: Vec::new(),
+        }
+    }
+
+    fn notify_driver(&mut self) {
+        if let Some(task) = self.driver_task.take() {
+            task.notify();
+        }
+    }
+
+    fn notify_all(&mut self) {
+        for stream in self.streams.values_mut() {
+            for task in stream.tasks.drain(..) {
+                task.notify();
+            }
+        }
+        for task in self.close_tasks.drain(..) {
+            task.notify();
+        }
+    }
+
+    fn check_error(&self) -> Result<(), Error> {
+        if let Some(ref error) = self.error {
+            Err(Error::SendMoneyError(error.clone()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A long-lived STREAM connection to a receiver.
+///
+/// Money and data are sent on streams opened with `new_stream`. Any amount of money
+/// and data can be sent on each stream over time, and the packets for all of the streams
+/// are sent by a task spawned when the connection is opened (so `open` must be called
+/// from within a Tokio runtime). That task closes the connection when `close` is called
+/// or when the `Connection` and all of its streams are dropped.
+#[derive(Clone)]
+pub struct Connection {
+    state: Arc<Mutex<ConnectionState>>,
+}
+
+impl Connection {
+    /// Open a connection to the receiver identified by the `destination_account` and `shared_secret`
+    /// (for example, as returned by an SPSP query).
+    pub fn open<S, A>(
+        service: S,
+        from_account: &A,
+        destination_account: Address,
+        shared_secret: &[u8],
+    ) -> impl Future<Item = Connection, Error = Error>
+    where
+        S: IncomingService<A> + Clone + Send + 'static,
+        A: Account + 'static,
+    {
+        let shared_secret = Bytes::from(shared_secret);
+        let from_account = from_account.clone();
+        get_ildcp_info(&mut service.clone(), from_account.clone())
+            .map_err(|_err| Error::ConnectionError("Unable to get ILDCP info".to_string()))
+            .map(move |account_details| {
+                let source_account = account_details.ilp_address();
+                if source_account.scheme() != destination_account.scheme() {
+                    warn!("Destination ILP address starts with a different scheme prefix (\"{}\') than ours (\"{}\'), this probably isn't going to work",
+                    destination_account.scheme(),
+                    source_account.scheme());
+                }
+
+                let source_asset = AssetDetails {
+                    asset_code: String::from_utf8_lossy(account_details.asset_code()).to_string(),
+                    asset_scale: account_details.asset_scale(),
+                };
+
+                let state = Arc::new(Mutex::new(ConnectionState::new(destination_account)));
+                tokio::spawn(ConnectionDriver {
+                    state: state.clone(),
+                    next: service,
+                    from_account,
+                    source_account,
+                    source_asset,
+                    shared_secret,
+                    congestion_controller: None,
+                    pending_requests: Vec::new(),
+                    sequence: 1,
+                    should_send_source_account: true,
+                    last_money_stream_id: 0,
+                    data_blocked_retry: None,
+                    close_sent: false,
+                });
+                Connection { state }
+            })
+    }
+
+    /// Open a new stream on this connection.
+    ///
+    /// This fails if the receiver does not allow any more streams to be opened. In that case
+    /// the receiver is asked to raise the limit, which it will usually do once other streams are closed.
+    pub fn new_stream(&self) -> Result<DataAndMoneyStream, Error> {
+        let mut state = self.state.lock();
+        state.check_error()?;
+        if state.closing || state.closed {
+            return Err(Error::ConnectionError("Connection is closed".to_string()));
+        }
+        if state.next_stream_id > state.remote_max_stream_id {
+            state.stream_id_blocked = true;
+            state.notify_driver();
+            return Err(Error::ConnectionError(format!(
+                "Receiver does not allow opening more streams (max stream ID: {})",
+                state.remote_max_stream_id
+            )));
+        }
+        let id = state.next_stream_id;
+        state.next_stream_id += 2;
+        state.streams.insert(id, StreamState::default());
+        Ok(DataAndMoneyStream {
+            id,
+            state: self.state.clone(),
+        })
+    }
+
+    /// The receiver's address. This may change if the receiver tells us about a new address.
+    pub fn destination_account(&self) -> Address {
+        self.state.lock().destination_account.clone()
+    }
+
+    /// The receiver's asset code and scale, once the receiver has told us about them.
+    /// Amounts delivered on the connection's streams are denominated in this asset.
+    pub fn destination_asset(&self) -> Option<AssetDetails> {
+        self.state.lock().destination_asset.clone()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+
+    /// Close the connection once all of the money and data on its streams has been sent
+    pub fn close(&self) -> impl Future<Item = (), Error = Error> {
+        let mut state = self.state.lock();
+        state.closing = true;
+        state.notify_driver();
+        CloseFuture {
+            state: self.state.clone(),
+        }
+    }
+}
+
+struct CloseFuture {
+    state: Arc<Mutex<ConnectionState>>,
+}
+
+impl Future for CloseFuture {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        let mut state = self.state.lock();
+        if state.closed {
+            state.check_error()?;
+            Ok(Async::Ready(()))
+        } else {
+            state.close_tasks.push(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A stream within a `Connection` that money and data can be sent on.
+#[derive(Clone)]
+pub struct DataAndMoneyStream {
+    id: u64,
+    state: Arc<Mutex<ConnectionState>>,
+}
+
+impl DataAndMoneyStream {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Send the given amount on this stream, in addition to any amount that is already being sent.
+    ///
+    /// This resolves to the total amount delivered on the stream (in the receiver's units)
+    /// once all of the money sent on it so far has arrived.
+    pub fn send_money(&self, amount: u64) -> impl Future<Item = u64, Error = Error> {
+        let mut state = self.state.lock();
+        let (target, is_closed) = {
+            let stream = state.streams.get_mut(&self.id).unwrap();
+            let is_closed = stream.closing || stream.closed;
+            if !is_closed {
+                stream.send_max = stream.send_max.saturating_add(amount);
+            }
+            (stream.send_max, is_closed && amount > 0)
+        };
+        state.notify_driver();
+        SendMoneyOnStream {
+            stream_id: self.id,
+            target,
+            is_closed,
+            state: self.state.clone(),
+        }
+    }
+
+    /// Queue data to be sent on this stream
+    pub fn write_data(&self, data: Bytes) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        {
+            let stream = state.streams.get_mut(&self.id).unwrap();
+            if stream.closing || stream.closed {
+                return Err(Error::ConnectionError(format!(
+                    "Stream {} is closed",
+                    self.id
+                )));
+            }
+            stream.outgoing_data.write(data);
+        }
+        state.notify_driver();
+        Ok(())
+    }
+
+    /// Read the data the receiver sent back on this stream so far
+    pub fn read_data(&self) -> Bytes {
+        self.state
+            .lock()
+            .streams
+            .get_mut(&self.id)
+            .unwrap()
+            .incoming_data
+            .read()
+    }
+
+    /// Total amount sent on this stream, in our units
+    pub fn total_sent(&self) -> u64 {
+        self.state.lock().streams[&self.id].total_sent
+    }
+
+    /// Total amount delivered on this stream, in the receiver's units
+    pub fn total_delivered(&self) -> u64 {
+        self.state.lock().streams[&self.id].total_delivered
+    }
+
+    /// Close the stream once all of the money and data sent on it has arrived
+    pub fn close(&self) {
+        let mut state = self.state.lock();
+        state.streams.get_mut(&self.id).unwrap().closing = true;
+        state.notify_driver();
+    }
+}
+
+struct SendMoneyOnStream {
+    stream_id: u64,
+    target: u64,
+    /// Set if the money was sent on a stream that was already closed
+    is_closed: bool,
+    state: Arc<Mutex<ConnectionState>>,
+}
+
+impl Future for SendMoneyOnStream {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<u64, Error> {
+        if self.is_closed {
+            return Err(Error::SendMoneyError(format!(
+                "Stream {} is closed",
+                self.stream_id
+            )));
+        }
+        let mut state = self.state.lock();
+        state.check_error()?;
+        let connection_closed = state.closed;
+        let stream = state.streams.get_mut(&self.stream_id).unwrap();
+        if let Some(ref error) = stream.error {
+            Err(Error::SendMoneyError(error.clone()))
+        } else if stream.total_sent >= self.target {
+            Ok(Async::Ready(stream.total_delivered))
+        } else if stream.closed || connection_closed {
+            Err(Error::SendMoneyError(format!(
+                "Stream {} was closed before all of the money was sent",
+                self.stream_id
+            )))
+        } else {
+            stream.tasks.push(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+struct PendingRequest {
+    sequence: u64,
+    amount: u64,
+    /// The stream the money in this packet was sent on
+    stream_id: u64,
+    data: Vec<(u64, u64, Bytes)>,
+    future: BoxedIlpFuture,
+}
+
+/// The task that sends the packets for a `Connection`
+struct ConnectionDriver<S, A> {
+    state: Arc<Mutex<ConnectionState>>,
+    next: S,
+    from_account: A,
+    source_account: Address,
+    source_asset: AssetDetails,
+    shared_secret: Bytes,
+    /// Created when money is first sent, so the initial window can be based on that amount
+    congestion_controller: Option<CongestionController>,
+    pending_requests: Vec<PendingRequest>,
+    sequence: u64,
+    should_send_source_account: bool,
+    last_money_stream_id: u64,
+    data_blocked_retry: Option<Delay>,
+    close_sent: bool,
+}
+
+impl<S, A> ConnectionDriver<S, A>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    fn poll_pending_requests(&mut self) {
+        let mut results = Vec::new();
+        let pending_requests = mem::take(&mut self.pending_requests);
+        for mut pending_request in pending_requests.into_iter() {
+            match pending_request.future.poll() {
+                Ok(Async::NotReady) => self.pending_requests.push(pending_request),
+                Ok(Async::Ready(fulfill)) => results.push((pending_request, Ok(fulfill))),
+                Err(reject) => results.push((pending_request, Err(reject))),
+            }
+        }
+        if results.is_empty() {
+            return;
+        }
+
+        let state = self.state.clone();
+        let mut state = state.lock();
+        for (pending_request, result) in results.into_iter() {
+            match result {
+                Ok(fulfill) => self.handle_fulfill(&mut state, pending_request, fulfill),
+                Err(reject) => self.handle_reject(&mut state, pending_request, reject),
+            }
+        }
+        state.notify_all();
+    }
+
+    fn handle_fulfill(
+        &mut self,
+        state: &mut ConnectionState,
+        request: PendingRequest,
+        fulfill: Fulfill,
+    ) {
+        if let Some(ref mut congestion_controller) = self.congestion_controller {
+            congestion_controller.fulfill(request.amount);
+        }
+        self.should_send_source_account = false;
+        if let Some(stream) = state.streams.get_mut(&request.stream_id) {
+            stream.in_flight -= request.amount;
+            stream.total_sent += request.amount;
+        }
+
+        match StreamPacket::from_encrypted(&self.shared_secret, fulfill.into_data()) {
+            Ok(packet) => {
+                if packet.ilp_packet_type() == IlpPacketType::Fulfill {
+                    if let Some(stream) = state.streams.get_mut(&request.stream_id) {
+                        stream.total_delivered += packet.prepare_amount();
+                    }
+                }
+                self.handle_response_frames(state, &packet, None);
+            }
+            Err(_) => warn!(
+                "Unable to parse STREAM packet from fulfill data for sequence {}",
+                request.sequence
+            ),
+        }
+        debug!(
+            "Prepare {} with amount {} was fulfilled",
+            request.sequence, request.amount
+        );
+    }
+
+    fn handle_reject(
+        &mut self,
+        state: &mut ConnectionState,
+        request: PendingRequest,
+        reject: Reject,
+    ) {
+        if let Some(ref mut congestion_controller) = self.congestion_controller {
+            congestion_controller.reject(request.amount, &reject);
+        }
+        if let Some(stream) = state.streams.get_mut(&request.stream_id) {
+            stream.in_flight -= request.amount;
+        }
+        debug!(
+            "Prepare {} with amount {} was rejected with code: {}",
+            request.sequence,
+            request.amount,
+            reject.code()
+        );
+
+        if let Ok(packet) =
+            StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(reject.data()))
+        {
+            self.handle_response_frames(state, &packet, Some(&request));
+        }
+        // The data is only known to be accepted once a packet carrying it is fulfilled
+        for (stream_id, offset, data) in request.data.into_iter() {
+            if let Some(stream) = state.streams.get_mut(&stream_id) {
+                if !stream.closed {
+                    stream.outgoing_data.retry(offset, data);
+                }
+            }
+        }
+
+        match (reject.code().class(), reject.code()) {
+            (ErrorClass::Temporary, _) => {}
+            (_, IlpErrorCode::F08_AMOUNT_TOO_LARGE) => {
+                // Handled by the congestion controller
+            }
+            (_, IlpErrorCode::F99_APPLICATION_ERROR) => {
+                // Handled using the frames in the receiver's response
+            }
+            _ => {
+                state.error = Some(format!(
+                    "Packet was rejected with error: {} {}",
+                    reject.code(),
+                    str::from_utf8(reject.message()).unwrap_or_default(),
+                ));
+            }
+        }
+    }
+
+    fn handle_response_frames(
+        &mut self,
+        state: &mut ConnectionState,
+        packet: &StreamPacket,
+        rejected_request: Option<&PendingRequest>,
+    ) {
+        for frame in packet.frames() {
+            match frame {
+                Frame::ConnectionClose(frame) if !self.close_sent && state.error.is_none() => {
+                    state.error = Some(format!(
+                        "Receiver closed the connection with code: {:?} and message: {}",
+                        frame.code, frame.message
+                    ));
+                }
+                Frame::ConnectionNewAddress(frame) => {
+                    debug!("Receiver's address changed to: {}", frame.source_account);
+                    state.destination_account = frame.source_account;
+                }
+                Frame::ConnectionAssetDetails(frame) => {
+                    state.destination_asset = Some(AssetDetails {
+                        asset_code: frame.source_asset_code.to_string(),
+                        asset_scale: frame.source_asset_scale,
+                    });
+                }
+                Frame::ConnectionMaxStreamId(frame) => {
+                    state.remote_max_stream_id =
+                        max(state.remote_max_stream_id, frame.max_stream_id);
+                }
+                Frame::StreamClose(frame) => {
+                    if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                        if !stream.is_idle() {
+                            stream.fail(format!(
+                                "Receiver closed stream {} with code: {:?} and message: {}",
+                                frame.stream_id, frame.code, frame.message
+                            ));
+                        }
+                        stream.closed = true;
+                    }
+                }
+                Frame::StreamMaxMoney(frame)
+                    if rejected_request
+                        .map(|request| request.stream_id == frame.stream_id)
+                        .unwrap_or(false)
+                        && packet.prepare_amount()
+                            > frame.receive_max.saturating_sub(frame.total_received) =>
+                {
+                    if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                        stream.fail(format!(
+                            "Receiver cannot accept any more money on stream {} (received {} of receive max {})",
+                            frame.stream_id, frame.total_received, frame.receive_max
+                        ));
+                    }
+                }
+                Frame::StreamMaxData(frame) => {
+                    if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                        stream.outgoing_data.set_remote_max_offset(frame.max_offset);
+                    }
+                }
+                Frame::StreamData(frame) => {
+                    if let Some(stream) = state.streams.get_mut(&frame.stream_id) {
+                        let is_buffered = stream.incoming_data.push(frame.offset, frame.data);
+                        if !is_buffered {
+                            warn!(
+                                "Receiver sent more data on stream {} than we can buffer",
+                                frame.stream_id
+                            );
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Pick the next stream to send money on, going through the streams in turn
+    fn next_money_stream(&self, state: &ConnectionState) -> Option<u64> {
+        let mut stream_ids: Vec<u64> = state
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.money_left() > 0)
+            .map(|(id, _)| *id)
+            .collect();
+        stream_ids.sort();
+        stream_ids
+            .iter()
+            .find(|id| **id > self.last_money_stream_id)
+            .or_else(|| stream_ids.first())
+            .cloned()
+    }
+
+    /// Send packets until there is no more money or data to send or the congestion controller
+    /// and the receiver's flow control limits tell us to stop. Returns true if any were sent.
+    fn send_packets(&mut self, state: &mut ConnectionState) -> bool {
+        let mut sent_packets = false;
+        loop {
+            // Determine how much to send and on which stream
+            let money_stream_id = self.next_money_stream(state);
+            let amount = if let Some(stream_id) = money_stream_id {
+                let money_left = state.streams[&stream_id].money_left();
+                let congestion_controller = self.congestion_controller.get_or_insert_with(|| {
+                    // Try sending the full amount first
+                    CongestionController::new(money_left, max(money_left / 10, 1), 2.0)
+                });
+                min(money_left, congestion_controller.get_max_amount())
+            } else {
+                0
+            };
+
+            let mut stream_ids: Vec<u64> = state.streams.keys().cloned().collect();
+            stream_ids.sort();
+            let mut data: Vec<(u64, u64, Bytes)> = Vec::new();
+            let mut data_left = MAX_DATA_PER_PACKET;
+            let mut blocked_streams: Vec<(u64, u64)> = Vec::new();
+            let mut closed_streams: Vec<u64> = Vec::new();
+            let nothing_in_flight = self.pending_requests.is_empty();
+            for stream_id in stream_ids.iter() {
+                let stream = state.streams.get_mut(stream_id).unwrap();
+                if stream.closed {
+                    continue;
+                }
+                while let Some((offset, chunk)) = stream.outgoing_data.next_chunk(data_left) {
+                    data_left -= chunk.len();
+                    data.push((*stream_id, offset, chunk));
+                }
+                let remote_max_offset = stream.outgoing_data.remote_max_offset();
+                if stream.outgoing_data.is_blocked()
+                    && nothing_in_flight
+                    && stream.data_blocked_at != Some(remote_max_offset)
+                {
+                    stream.data_blocked_at = Some(remote_max_offset);
+                    blocked_streams.push((*stream_id, remote_max_offset));
+                }
+                if stream.closing && stream.is_idle() && nothing_in_flight {
+                    stream.closed = true;
+                    closed_streams.push(*stream_id);
+                }
+            }
+            let stream_id_blocked = state.stream_id_blocked;
+            state.stream_id_blocked = false;
+
+            if amount == 0
+                && data.is_empty()
+                && blocked_streams.is_empty()
+                && closed_streams.is_empty()
+                && !stream_id_blocked
+            {
+                break;
+            }
+
+            // Load up the STREAM packet
+            let sequence = self.next_sequence();
+            let mut frames: Vec<Frame> = stream_ids
+                .iter()
+                .filter(|id| !state.streams[id].closed)
+                .map(|id| {
+                    Frame::StreamMaxData(StreamMaxDataFrame {
+                        stream_id: *id,
+                        max_offset: state.streams[id].incoming_data.max_offset(),
+                    })
+                })
+                .collect();
+            if amount > 0 {
+                let stream_id = money_stream_id.unwrap();
+                frames.push(Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id,
+                    shares: 1,
+                }));
+                self.last_money_stream_id = stream_id;
+            }
+            for (stream_id, offset, chunk) in data.iter() {
+                frames.push(Frame::StreamData(StreamDataFrame {
+                    stream_id: *stream_id,
+                    offset: *offset,
+                    data: &chunk[..],
+                }));
+            }
+            for (stream_id, max_offset) in blocked_streams.iter() {
+                frames.push(Frame::StreamDataBlocked(StreamDataBlockedFrame {
+                    stream_id: *stream_id,
+                    max_offset: *max_offset,
+                }));
+            }
+            for stream_id in closed_streams.iter() {
+                frames.push(Frame::StreamClose(StreamCloseFrame {
+                    stream_id: *stream_id,
+                    code: ErrorCode::NoError,
+                    message: "",
+                }));
+            }
+            if stream_id_blocked {
+                frames.push(Frame::ConnectionStreamIdBlocked(
+                    ConnectionStreamIdBlockedFrame {
+                        max_stream_id: state.remote_max_stream_id,
+                    },
+                ));
+            }
+            if self.should_send_source_account {
+                frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                    source_account: self.source_account.clone(),
+                }));
+                frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: &self.source_asset.asset_code,
+                    source_asset_scale: self.source_asset.asset_scale,
+                }));
+            }
+            let stream_packet = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames: &frames,
+            }
+            .build();
+            debug!(
+                "Sending packet {} with amount: {} and encrypted STREAM packet: {:?}",
+                sequence, amount, stream_packet
+            );
+
+            // Packets with data are fulfilled too, so we know when the receiver accepted the data
+            let encrypted = stream_packet.into_encrypted(&self.shared_secret);
+            let execution_condition = if amount > 0 || !data.is_empty() {
+                generate_condition(&self.shared_secret, &encrypted)
+            } else {
+                random_condition()
+            };
+            let prepare = PrepareBuilder {
+                destination: state.destination_account.clone(),
+                amount,
+                execution_condition: &execution_condition,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &encrypted[..],
+            }
+            .build();
+
+            // Send it!
+            let stream_id = money_stream_id.unwrap_or(0);
+            if amount > 0 {
+                state.streams.get_mut(&stream_id).unwrap().in_flight += amount;
+                if let Some(ref mut congestion_controller) = self.congestion_controller {
+                    congestion_controller.prepare(amount);
+                }
+            }
+            let send_request = self.next.handle_request(IncomingRequest {
+                from: self.from_account.clone(),
+                prepare,
+            });
+            self.pending_requests.push(PendingRequest {
+                sequence,
+                amount,
+                stream_id,
+                data,
+                future: Box::new(send_request),
+            });
+            sent_packets = true;
+        }
+        sent_packets
+    }
+
+    fn send_connection_close(&mut self, state: &ConnectionState) {
+        let sequence = self.next_sequence();
+        let stream_packet = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence,
+            frames: &[Frame::ConnectionClose(ConnectionCloseFrame {
+                code: ErrorCode::NoError,
+                message: "",
+            })],
+        }
+        .build();
+        let data = stream_packet.into_encrypted(&self.shared_secret);
+        let prepare = PrepareBuilder {
+            destination: state.destination_account.clone(),
+            amount: 0,
+            execution_condition: &random_condition(),
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            data: &data[..],
+        }
+        .build();
+
+        debug!("Closing connection");
+        let send_request = self.next.handle_request(IncomingRequest {
+            from: self.from_account.clone(),
+            prepare,
+        });
+        self.pending_requests.push(PendingRequest {
+            sequence,
+            amount: 0,
+            stream_id: 0,
+            data: Vec::new(),
+            future: Box::new(send_request),
+        });
+        self.close_sent = true;
+    }
+
+    /// Wait before asking the receiver for more room for our data again
+    fn poll_data_blocked_retry(&mut self, state: &mut ConnectionState) -> bool {
+        let has_blocked_streams = state
+            .streams
+            .values()
+            .any(|stream| stream.outgoing_data.is_blocked() && stream.data_blocked_at.is_some());
+        if !has_blocked_streams {
+            self.data_blocked_retry = None;
+            return false;
+        }
+        let retry = self
+            .data_blocked_retry
+            .get_or_insert_with(|| Delay::new(Instant::now() + DATA_BLOCKED_RETRY_INTERVAL));
+        match retry.poll() {
+            Ok(Async::NotReady) => false,
+            _ => {
+                self.data_blocked_retry = None;
+                for stream in state.streams.values_mut() {
+                    stream.data_blocked_at = None;
+                }
+                true
+            }
+        }
+    }
+
+    fn next_sequence(&mut self) -> u64 {
+        let seq = self.sequence;
+        self.sequence += 1;
+        seq
+    }
+}
+
+impl<S, A> Future for ConnectionDriver<S, A>
+where
+    S: IncomingService<A>,
+    A: Account,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            self.poll_pending_requests();
+
+            let is_unused = Arc::strong_count(&self.state) == 1;
+            let state = self.state.clone();
+            let mut state = state.lock();
+            if let Some(ref error) = state.error {
+                error!("STREAM connection stopped because of error: {}", error);
+                state.closed = true;
+            }
+            if state.closed {
+                state.notify_all();
+                return Ok(Async::Ready(()));
+            }
+            // Close the connection once no one is using it anymore
+            if is_unused && !state.closing {
+                debug!("Connection is no longer used, closing it");
+                state.closing = true;
+            }
+
+            if self.send_packets(&mut state) {
+                continue;
+            }
+            if self.pending_requests.is_empty() {
+                let all_sent = state.streams.values().all(StreamState::is_idle);
+                if self.close_sent {
+                    debug!("Connection closed");
+                    state.closed = true;
+                    continue;
+                } else if state.closing && all_sent {
+                    self.send_connection_close(&state);
+                    continue;
+                }
+            }
+            if self.poll_data_blocked_retry(&mut state) {
+                continue;
+            }
+
+            state.driver_task = Some(task::current());
+            return Ok(Async::NotReady);
+        }
+    }
+}
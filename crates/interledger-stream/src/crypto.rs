@@ -102,6 +102,10 @@ pub fn decrypt(shared_secret: &[u8], mut ciphertext: BytesMut) -> Result<BytesMu
         .expect("Failed to create a new opening key for decrypting data!");
     let key = aead::LessSafeKey::new(key);
 
+    if ciphertext.len() < NONCE_LENGTH + AUTH_TAG_LENGTH {
+        return Err(());
+    }
+
     let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
     nonce.copy_from_slice(&ciphertext.split_to(NONCE_LENGTH));
 
@@ -187,4 +191,9 @@ mod encrypt_decrypt_test {
         let decrypted = decrypt(SHARED_SECRET, ciphertext);
         assert_eq!(&decrypted.unwrap()[..], PLAINTEXT);
     }
+
+    #[test]
+    fn it_rejects_truncated_ciphertext() {
+        assert!(decrypt(SHARED_SECRET, BytesMut::from(&[0; 20][..])).is_err());
+    }
 }
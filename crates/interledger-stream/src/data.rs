@@ -0,0 +1,355 @@
+use bytes::{Bytes, BytesMut};
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+
+/// The maximum number of bytes of stream data that are put in a single packet
+pub const MAX_DATA_PER_PACKET: usize = 16_384;
+/// How many bytes are buffered for each stream before the data is read.
+/// This is also the window assumed for the other side until it sends a `StreamMaxData` frame.
+pub const DEFAULT_MAX_BUFFER: u64 = 65_536;
+
+/// Reassembles the data received on a stream, which may arrive out of order or more than once.
+///
+/// Only data up to `max_offset` is buffered, so the other side should be told about
+/// the new `max_offset` (using a `StreamMaxData` frame) as the data is read.
+#[derive(Debug)]
+pub struct IncomingData {
+    chunks: BTreeMap<u64, Bytes>,
+    read_offset: u64,
+    max_buffer: u64,
+}
+
+impl Default for IncomingData {
+    fn default() -> Self {
+        IncomingData::new(DEFAULT_MAX_BUFFER)
+    }
+}
+
+impl IncomingData {
+    pub fn new(max_buffer: u64) -> Self {
+        IncomingData {
+            chunks: BTreeMap::new(),
+            read_offset: 0,
+            max_buffer,
+        }
+    }
+
+    /// The offset up to which the other side may send data
+    pub fn max_offset(&self) -> u64 {
+        self.read_offset.saturating_add(self.max_buffer)
+    }
+
+    /// Buffer the given data. Returns false if it would exceed the `max_offset`,
+    /// in which case none of it is kept.
+    pub fn push(&mut self, offset: u64, data: &[u8]) -> bool {
+        let end = offset.saturating_add(data.len() as u64);
+        if end > self.max_offset() {
+            return false;
+        }
+        // Ignore the parts that were already read
+        if end <= self.read_offset || data.is_empty() {
+            return true;
+        }
+        let (offset, data) = if offset < self.read_offset {
+            (
+                self.read_offset,
+                &data[(self.read_offset - offset) as usize..],
+            )
+        } else {
+            (offset, data)
+        };
+        let is_longer = self
+            .chunks
+            .get(&offset)
+            .map(|chunk| chunk.len() < data.len())
+            .unwrap_or(true);
+        if is_longer {
+            self.chunks.insert(offset, Bytes::from(data));
+        }
+        true
+    }
+
+    /// Read all of the data that arrived in order so far
+    pub fn read(&mut self) -> Bytes {
+        let mut buffer = BytesMut::new();
+        while let Some((&offset, _)) = self.chunks.iter().next() {
+            if offset > self.read_offset {
+                break;
+            }
+            let chunk = self.chunks.remove(&offset).unwrap();
+            let end = offset + chunk.len() as u64;
+            if end > self.read_offset {
+                buffer.extend_from_slice(&chunk[(self.read_offset - offset) as usize..]);
+                self.read_offset = end;
+            }
+        }
+        buffer.freeze()
+    }
+
+    /// Returns true if no data is buffered
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+/// Data written to a stream that has not been delivered to the other side yet.
+///
+/// The sender takes chunks from the front of the buffer with `next_chunk` and puts them
+/// back if the packet carrying them does not reach the other side.
+///
+/// The receiver can only send data in its responses, and it cannot tell whether they
+/// arrived. It uses `unacknowledged_chunks` instead, which keeps the data buffered
+/// until the other side's `StreamMaxData` frames show that it was read.
+#[derive(Debug)]
+pub struct OutgoingData {
+    unsent: BTreeMap<u64, Bytes>,
+    write_offset: u64,
+    remote_max_offset: u64,
+    /// The offset up to which `unacknowledged_chunks` has sent the data
+    send_offset: u64,
+    /// The size of the other side's buffer, learned from the first `StreamMaxData` frame
+    remote_max_buffer: Option<u64>,
+}
+
+impl Default for OutgoingData {
+    fn default() -> Self {
+        OutgoingData {
+            unsent: BTreeMap::new(),
+            write_offset: 0,
+            remote_max_offset: DEFAULT_MAX_BUFFER,
+            send_offset: 0,
+            remote_max_buffer: None,
+        }
+    }
+}
+
+impl OutgoingData {
+    pub fn write(&mut self, data: Bytes) {
+        if data.is_empty() {
+            return;
+        }
+        let len = data.len() as u64;
+        self.unsent.insert(self.write_offset, data);
+        self.write_offset += len;
+    }
+
+    /// Update the offset up to which the other side accepts data
+    pub fn set_remote_max_offset(&mut self, max_offset: u64) {
+        if max_offset > self.remote_max_offset {
+            self.remote_max_offset = max_offset;
+        }
+    }
+
+    pub fn remote_max_offset(&self) -> u64 {
+        self.remote_max_offset
+    }
+
+    /// Take the next chunk of at most `max_len` bytes that the other side will accept
+    pub fn next_chunk(&mut self, max_len: usize) -> Option<(u64, Bytes)> {
+        let offset = *self.unsent.keys().next()?;
+        if offset >= self.remote_max_offset || max_len == 0 {
+            return None;
+        }
+        let mut chunk = self.unsent.remove(&offset).unwrap();
+        let len = min(
+            min(chunk.len() as u64, self.remote_max_offset - offset),
+            max_len as u64,
+        ) as usize;
+        if len < chunk.len() {
+            let rest = chunk.split_off(len);
+            self.unsent.insert(offset + len as u64, rest);
+        }
+        Some((offset, chunk))
+    }
+
+    /// Put back a chunk that was not delivered so it is sent again
+    pub fn retry(&mut self, offset: u64, data: Bytes) {
+        self.unsent.insert(offset, data);
+    }
+
+    /// Get the next chunks to send, up to `max_len` bytes in total, without removing them.
+    /// Once all of the data was sent, the data that was not acknowledged yet is sent again,
+    /// in case the packets carrying it did not reach the other side.
+    pub fn unacknowledged_chunks(&mut self, max_len: usize) -> Vec<(u64, Bytes)> {
+        let end = min(self.write_offset, self.remote_max_offset);
+        if self.send_offset >= end {
+            self.send_offset = self
+                .unsent
+                .keys()
+                .next()
+                .cloned()
+                .unwrap_or(self.write_offset);
+        }
+        let mut chunks = Vec::new();
+        let mut len_left = max_len as u64;
+        for (offset, data) in self.unsent.range(..end) {
+            let data_end = min(offset + data.len() as u64, end);
+            if data_end <= self.send_offset {
+                continue;
+            }
+            if len_left == 0 {
+                break;
+            }
+            let start = max(*offset, self.send_offset);
+            let stop = min(data_end, start + len_left);
+            chunks.push((
+                start,
+                data.slice((start - offset) as usize, (stop - offset) as usize),
+            ));
+            len_left -= stop - start;
+            self.send_offset = stop;
+        }
+        chunks
+    }
+
+    /// Handle a `StreamMaxData` frame from the other side. Its max offset is the offset
+    /// up to which it read the data plus the size of its buffer, so the data before that
+    /// offset is removed. The size of the buffer is learned from the first frame, which
+    /// arrives before any data was sent (until then, the default buffer size is assumed).
+    pub fn acknowledge_max_offset(&mut self, max_offset: u64) {
+        if self.remote_max_buffer.is_none() && self.send_offset == 0 {
+            self.remote_max_buffer = Some(max_offset);
+        }
+        self.set_remote_max_offset(max_offset);
+        let read_offset = min(
+            max_offset.saturating_sub(self.remote_max_buffer.unwrap_or(DEFAULT_MAX_BUFFER)),
+            self.send_offset,
+        );
+        while let Some(offset) = self.unsent.keys().next().cloned() {
+            if offset >= read_offset {
+                break;
+            }
+            let mut data = self.unsent.remove(&offset).unwrap();
+            if offset + data.len() as u64 > read_offset {
+                let rest = data.split_off((read_offset - offset) as usize);
+                self.unsent.insert(read_offset, rest);
+                break;
+            }
+        }
+    }
+
+    /// Returns true if there is data to send but the other side will not accept it yet
+    pub fn is_blocked(&self) -> bool {
+        self.unsent
+            .keys()
+            .next()
+            .map(|offset| *offset >= self.remote_max_offset)
+            .unwrap_or(false)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unsent.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod incoming_data {
+    use super::*;
+
+    #[test]
+    fn reassembles_out_of_order_data() {
+        let mut incoming = IncomingData::default();
+        assert!(incoming.push(5, b"world"));
+        assert_eq!(incoming.read(), Bytes::new());
+        assert!(incoming.push(0, b"hello"));
+        assert_eq!(incoming.read(), Bytes::from("helloworld"));
+        assert!(incoming.push(10, b"!"));
+        assert_eq!(incoming.read(), Bytes::from("!"));
+    }
+
+    #[test]
+    fn ignores_duplicate_data() {
+        let mut incoming = IncomingData::default();
+        assert!(incoming.push(0, b"hello"));
+        assert!(incoming.push(0, b"hel"));
+        assert!(incoming.push(3, b"lo world"));
+        assert_eq!(incoming.read(), Bytes::from("hello world"));
+        assert!(incoming.push(0, b"hello"));
+        assert_eq!(incoming.read(), Bytes::new());
+    }
+
+    #[test]
+    fn enforces_max_offset() {
+        let mut incoming = IncomingData::new(4);
+        assert!(!incoming.push(0, b"hello"));
+        assert!(incoming.push(0, b"hell"));
+        assert_eq!(incoming.max_offset(), 4);
+        assert_eq!(incoming.read(), Bytes::from("hell"));
+        assert_eq!(incoming.max_offset(), 8);
+        assert!(incoming.push(4, b"o"));
+        assert_eq!(incoming.read(), Bytes::from("o"));
+    }
+}
+
+#[cfg(test)]
+mod outgoing_data {
+    use super::*;
+
+    #[test]
+    fn splits_data_into_chunks() {
+        let mut outgoing = OutgoingData::default();
+        outgoing.write(Bytes::from("hello"));
+        outgoing.write(Bytes::from("world"));
+        assert_eq!(outgoing.next_chunk(3), Some((0, Bytes::from("hel"))));
+        assert_eq!(outgoing.next_chunk(100), Some((3, Bytes::from("lo"))));
+        assert_eq!(outgoing.next_chunk(100), Some((5, Bytes::from("world"))));
+        assert_eq!(outgoing.next_chunk(100), None);
+        assert!(outgoing.is_empty());
+    }
+
+    #[test]
+    fn resends_chunks_in_order() {
+        let mut outgoing = OutgoingData::default();
+        outgoing.write(Bytes::from("hello world"));
+        let (offset, chunk) = outgoing.next_chunk(5).unwrap();
+        outgoing.retry(offset, chunk);
+        assert_eq!(outgoing.next_chunk(100), Some((0, Bytes::from("hello"))));
+        assert_eq!(outgoing.next_chunk(100), Some((5, Bytes::from(" world"))));
+    }
+
+    #[test]
+    fn respects_remote_max_offset() {
+        let mut outgoing = OutgoingData::default();
+        outgoing.write(Bytes::from(vec![0; DEFAULT_MAX_BUFFER as usize + 10]));
+        let (_, chunk) = outgoing.next_chunk(usize::max_value()).unwrap();
+        assert_eq!(chunk.len(), DEFAULT_MAX_BUFFER as usize);
+        assert!(outgoing.is_blocked());
+        assert_eq!(outgoing.next_chunk(100), None);
+
+        outgoing.set_remote_max_offset(DEFAULT_MAX_BUFFER + 5);
+        assert_eq!(
+            outgoing.next_chunk(100),
+            Some((DEFAULT_MAX_BUFFER, Bytes::from(vec![0; 5])))
+        );
+        assert!(outgoing.is_blocked());
+        // The max offset is never lowered
+        outgoing.set_remote_max_offset(0);
+        assert_eq!(outgoing.remote_max_offset(), DEFAULT_MAX_BUFFER + 5);
+    }
+
+    #[test]
+    fn resends_unacknowledged_chunks() {
+        let mut outgoing = OutgoingData::default();
+        outgoing.acknowledge_max_offset(10);
+        outgoing.write(Bytes::from("hello"));
+        outgoing.write(Bytes::from("world"));
+        assert_eq!(
+            outgoing.unacknowledged_chunks(7),
+            vec![(0, Bytes::from("hello")), (5, Bytes::from("wo"))]
+        );
+        // The other side read "hel"
+        outgoing.acknowledge_max_offset(13);
+        assert_eq!(
+            outgoing.unacknowledged_chunks(100),
+            vec![(7, Bytes::from("rld"))]
+        );
+        // Everything was sent, so the rest is sent again
+        assert_eq!(
+            outgoing.unacknowledged_chunks(100),
+            vec![(3, Bytes::from("lo")), (5, Bytes::from("world"))]
+        );
+        outgoing.acknowledge_max_offset(20);
+        assert!(outgoing.is_empty());
+        assert!(outgoing.unacknowledged_chunks(100).is_empty());
+    }
+}
@@ -4,17 +4,25 @@
 //!
 //! STREAM is responsible for splitting larger payments and messages into smaller chunks of money and data, and sending them over ILP.
 
+mod asset;
 mod client;
 mod congestion;
+mod connection;
 mod crypto;
+mod data;
 mod error;
 mod packet;
 mod server;
 
-pub use client::send_money;
+pub use asset::AssetDetails;
+pub use client::{
+    send_money, send_money_to_deliver, send_money_with_data, ExchangeRateLimit, PaymentResult,
+};
+pub use connection::{Connection, DataAndMoneyStream};
 pub use error::Error;
 pub use server::{
-    ConnectionGenerator, PaymentNotification, StreamNotificationsStore, StreamReceiverService,
+    ConnectionGenerator, PaymentNotification, StreamConnections, StreamNotificationsStore,
+    StreamReceiverService,
 };
 
 #[cfg(test)]
@@ -23,12 +31,12 @@ pub mod test_helpers {
     use bytes::Bytes;
     use futures::{future::ok, sync::mpsc::UnboundedSender, Future};
     use interledger_packet::Address;
-    use interledger_router::RouterStore;
+    use interledger_router::{RouterStore, RoutingTable};
     use interledger_service::{Account, AccountStore, AddressStore, Username};
     use lazy_static::lazy_static;
-    use std::collections::HashMap;
     use std::iter::FromIterator;
     use std::str::FromStr;
+    use std::sync::Arc;
 
     lazy_static! {
         pub static ref EXAMPLE_CONNECTOR: Address = Address::from_str("example.connector").unwrap();
@@ -109,8 +117,10 @@ pub mod test_helpers {
     }
 
     impl RouterStore for TestStore {
-        fn routing_table(&self) -> HashMap<Bytes, u64> {
-            HashMap::from_iter(vec![(self.route.0.clone(), self.route.1.id())].into_iter())
+        fn routing_table(&self) -> Arc<RoutingTable<u64>> {
+            Arc::new(RoutingTable::from_iter(
+                vec![(self.route.0.clone(), self.route.1.id())].into_iter(),
+            ))
         }
     }
 
@@ -136,16 +146,25 @@ pub mod test_helpers {
 
 #[cfg(test)]
 mod send_money_to_receiver {
+    use super::packet::StreamPacketBuilder;
     use super::test_helpers::*;
     use super::*;
     use bytes::Bytes;
+    use futures::future::{err, Either};
     use futures::Future;
     use interledger_ildcp::IldcpService;
     use interledger_packet::Address;
-    use interledger_packet::{ErrorCode, RejectBuilder};
+    use interledger_packet::MaxPacketAmountDetails;
+    use interledger_packet::{ErrorCode, PacketType as IlpPacketType, RejectBuilder};
     use interledger_router::Router;
-    use interledger_service::outgoing_service_fn;
+    use interledger_service::{
+        incoming_service_fn, outgoing_service_fn, IncomingRequest, IncomingService,
+    };
     use std::str::FromStr;
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
     use tokio::runtime::Runtime;
 
     #[test]
@@ -193,13 +212,575 @@ mod send_money_to_receiver {
             destination_account,
             &shared_secret[..],
             100,
+            ExchangeRateLimit::None,
         )
-        .and_then(|(delivered_amount, _service)| {
-            assert_eq!(delivered_amount, 100);
+        .and_then(|(result, _service)| {
+            assert!(result.is_complete());
+            assert_eq!(result.source_amount, 100);
+            assert_eq!(result.delivered_amount, 100);
+            assert_eq!(result.rejected_packets, 0);
+            assert!(result.fulfilled_packets > 0);
+            assert_eq!(
+                result.destination_asset,
+                Some(AssetDetails {
+                    asset_code: "XYZ".to_string(),
+                    asset_scale: 9,
+                })
+            );
             Ok(())
         })
         .map_err(|err| panic!(err));
         let runtime = Runtime::new().unwrap();
         runtime.block_on_all(run).unwrap();
     }
+
+    #[test]
+    fn stops_at_receive_max() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Address::from_str("example.receiver").unwrap();
+        let account = TestAccount {
+            id: 0,
+            ilp_address: destination_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (destination_address.to_bytes(), account.clone()),
+        };
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let connections = server.connections();
+        let server = Router::new(store, server);
+        let server = IldcpService::new(server);
+
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address);
+        connections.set_receive_max(&destination_account, 50);
+
+        let run = send_money(
+            server,
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            100,
+            ExchangeRateLimit::None,
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, _service) = runtime.block_on_all(run).unwrap();
+        assert!(result.error.is_some());
+        assert_eq!(result.source_amount, 0);
+        assert_eq!(result.delivered_amount, 0);
+        assert_eq!(result.rejected_packets, 1);
+        assert_eq!(connections.total_received(&destination_account), Some(0));
+    }
+
+    fn test_receiver() -> (
+        impl IncomingService<TestAccount> + Clone,
+        StreamConnections,
+        Address,
+        [u8; 32],
+    ) {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Address::from_str("example.receiver").unwrap();
+        let account = TestAccount {
+            id: 0,
+            ilp_address: destination_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+        };
+        let store = TestStore {
+            route: (destination_address.to_bytes(), account),
+        };
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let connections = server.connections();
+        let server = IldcpService::new(Router::new(store, server));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address);
+        (server, connections, destination_account, shared_secret)
+    }
+
+    #[test]
+    fn sends_data_with_money() {
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        connections.write_data(&destination_account, 1, Bytes::from("thanks!"));
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+
+        let run = send_money_with_data(
+            server,
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            100,
+            ExchangeRateLimit::None,
+            Bytes::from("invoice 1234"),
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, data, _service) = runtime.block_on_all(run).unwrap();
+        assert_eq!(result.delivered_amount, 100);
+        assert_eq!(data, Bytes::from("thanks!"));
+        assert_eq!(
+            connections.read_data(&destination_account, 1),
+            Bytes::from("invoice 1234")
+        );
+    }
+
+    #[test]
+    fn resends_data_when_packet_is_rejected() {
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+
+        // Reject the first packet sent to the receiver without passing it on, even though
+        // the rejection carries a STREAM packet as if it came from the receiver
+        let rejected = Arc::new(AtomicU64::new(0));
+        let rejected_clone = rejected.clone();
+        let receiver_address = destination_account.clone();
+        let service = incoming_service_fn(move |request: IncomingRequest<TestAccount>| {
+            if request.prepare.destination() == receiver_address
+                && rejected_clone.fetch_add(1, Ordering::SeqCst) == 0
+            {
+                let response = StreamPacketBuilder {
+                    sequence: 1,
+                    ilp_packet_type: IlpPacketType::Reject,
+                    prepare_amount: 0,
+                    frames: &[],
+                }
+                .build()
+                .into_encrypted(&shared_secret[..]);
+                return Either::B(err(RejectBuilder {
+                    code: ErrorCode::F99_APPLICATION_ERROR,
+                    message: &[],
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &response[..],
+                }
+                .build()));
+            }
+            Either::A(server.clone().handle_request(request))
+        });
+
+        let run = send_money_with_data(
+            service,
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            100,
+            ExchangeRateLimit::None,
+            Bytes::from("invoice 1234"),
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, _data, _service) = runtime.block_on_all(run).unwrap();
+        assert!(result.error.is_none());
+        assert_eq!(result.delivered_amount, 100);
+        assert!(rejected.load(Ordering::SeqCst) > 1);
+        assert_eq!(
+            connections.read_data(&destination_account, 1),
+            Bytes::from("invoice 1234")
+        );
+    }
+
+    #[test]
+    fn resends_receiver_data_when_response_is_lost() {
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        connections.write_data(&destination_account, 1, Bytes::from("thanks!"));
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+
+        // The receiver handles the first packet, but its response is replaced
+        // by one without any frames on the way back to the sender
+        let rejected = Arc::new(AtomicU64::new(0));
+        let rejected_clone = rejected.clone();
+        let receiver_address = destination_account.clone();
+        let service = incoming_service_fn(move |request: IncomingRequest<TestAccount>| {
+            if request.prepare.destination() == receiver_address
+                && rejected_clone.fetch_add(1, Ordering::SeqCst) == 0
+            {
+                let response = StreamPacketBuilder {
+                    sequence: 1,
+                    ilp_packet_type: IlpPacketType::Reject,
+                    prepare_amount: 0,
+                    frames: &[],
+                }
+                .build()
+                .into_encrypted(&shared_secret[..]);
+                let reject = RejectBuilder {
+                    code: ErrorCode::F99_APPLICATION_ERROR,
+                    message: &[],
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &response[..],
+                }
+                .build();
+                return Either::B(
+                    server
+                        .clone()
+                        .handle_request(request)
+                        .then(move |_| Err(reject)),
+                );
+            }
+            Either::A(server.clone().handle_request(request))
+        });
+
+        let run = send_money_with_data(
+            service,
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            100,
+            ExchangeRateLimit::None,
+            Bytes::from("invoice 1234"),
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, data, _service) = runtime.block_on_all(run).unwrap();
+        assert!(result.error.is_none());
+        assert!(rejected.load(Ordering::SeqCst) > 1);
+        assert_eq!(data, Bytes::from("thanks!"));
+    }
+
+    #[test]
+    fn stops_when_receiver_buffer_is_full() {
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+
+        // The receiver never reads the data so only the first 64KB fit in its buffer
+        let run = send_money_with_data(
+            server,
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            0,
+            ExchangeRateLimit::None,
+            Bytes::from(vec![1; 100_000]),
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, _data, _service) = runtime.block_on_all(run).unwrap();
+        assert!(result.error.is_some());
+        assert_eq!(connections.read_data(&destination_account, 1).len(), 65_536);
+    }
+
+    #[test]
+    fn sends_money_on_multiple_streams() {
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+
+        let run = Connection::open(
+            server,
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+        )
+        .and_then(|connection| {
+            let stream_a = connection.new_stream().unwrap();
+            let stream_b = connection.new_stream().unwrap();
+            assert_eq!(stream_a.id(), 1);
+            assert_eq!(stream_b.id(), 3);
+            stream_a
+                .send_money(100)
+                .join(stream_b.send_money(50))
+                .and_then(move |(delivered_a, delivered_b)| {
+                    assert_eq!(delivered_a, 100);
+                    assert_eq!(delivered_b, 50);
+                    // Send more money on a stream that was already used
+                    stream_a.send_money(25)
+                })
+                .and_then(move |delivered_a| {
+                    assert_eq!(delivered_a, 125);
+                    assert_eq!(connection.destination_asset().unwrap().asset_scale, 9);
+                    connection.close()
+                })
+        });
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on_all(run).unwrap();
+        assert_eq!(connections.total_received(&destination_account), Some(175));
+        assert_eq!(
+            connections.source_asset(&destination_account),
+            Some(AssetDetails {
+                asset_code: "XYZ".to_string(),
+                asset_scale: 9,
+            })
+        );
+        assert!(connections.is_closed(&destination_account));
+    }
+
+    #[test]
+    fn sends_data_on_connection_streams() {
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        connections.write_data(&destination_account, 3, Bytes::from("pong"));
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+
+        let run = Connection::open(
+            server,
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+        )
+        .and_then(|connection| {
+            let stream_a = connection.new_stream().unwrap();
+            let stream_b = connection.new_stream().unwrap();
+            stream_a.write_data(Bytes::from("hello")).unwrap();
+            stream_b.write_data(Bytes::from("ping")).unwrap();
+            connection.close().map(move |_| {
+                assert_eq!(stream_a.read_data(), Bytes::new());
+                assert_eq!(stream_b.read_data(), Bytes::from("pong"));
+            })
+        });
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on_all(run).unwrap();
+        assert_eq!(
+            connections.read_data(&destination_account, 1),
+            Bytes::from("hello")
+        );
+        assert_eq!(
+            connections.read_data(&destination_account, 3),
+            Bytes::from("ping")
+        );
+    }
+
+    #[test]
+    fn limits_number_of_open_streams() {
+        let (server, _connections, destination_account, shared_secret) = test_receiver();
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+
+        let run = Connection::open(server, &account, destination_account, &shared_secret[..])
+            .and_then(|connection| {
+                let streams: Vec<DataAndMoneyStream> =
+                    (0..10).map(|_| connection.new_stream().unwrap()).collect();
+                assert!(connection.new_stream().is_err());
+
+                // Closing a stream lets the receiver raise the max stream ID
+                let first = streams[0].clone();
+                first.send_money(10).and_then(move |_| {
+                    first.close();
+                    streams[1].send_money(10).map(move |_| connection)
+                })
+            })
+            .and_then(|connection| {
+                assert!(connection.new_stream().is_ok());
+                connection.close()
+            });
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on_all(run).unwrap();
+    }
+
+    /// Simulates a connector that applies an exchange rate (which may depend on how many
+    /// packets with money were sent through it so far) and has a max packet amount
+    fn with_exchange_rate(
+        server: impl IncomingService<TestAccount> + Clone + Send + 'static,
+        rate: fn(u64) -> f64,
+        max_packet_amount: u64,
+    ) -> impl IncomingService<TestAccount> + Clone {
+        let packets = Arc::new(AtomicU64::new(0));
+        incoming_service_fn(move |mut request: IncomingRequest<TestAccount>| {
+            let amount = request.prepare.amount();
+            if amount == 0 {
+                return Either::A(server.clone().handle_request(request));
+            }
+            let packet_number = packets.fetch_add(1, Ordering::SeqCst) + 1;
+            if amount > max_packet_amount {
+                return Either::B(err(RejectBuilder {
+                    code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    message: &[],
+                    triggered_by: Some(&EXAMPLE_CONNECTOR),
+                    data: &MaxPacketAmountDetails::new(amount, max_packet_amount).to_bytes()[..],
+                }
+                .build()));
+            }
+            let rate = rate(packet_number);
+            request
+                .prepare
+                .set_amount((amount as f64 * rate).floor() as u64);
+            Either::A(server.clone().handle_request(request))
+        })
+    }
+
+    #[test]
+    fn enforces_min_exchange_rate() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+        let runtime = Runtime::new().unwrap();
+
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        let run = send_money(
+            with_exchange_rate(server, |_| 0.5, 1000),
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            100,
+            ExchangeRateLimit::MinExchangeRate(0.6),
+        );
+        assert!(runtime.block_on_all(run).is_err());
+        assert_eq!(
+            connections
+                .total_received(&destination_account)
+                .unwrap_or(0),
+            0
+        );
+
+        let (server, _connections, destination_account, shared_secret) = test_receiver();
+        let run = send_money(
+            with_exchange_rate(server, |_| 0.5, 1000),
+            &account,
+            destination_account,
+            &shared_secret[..],
+            100,
+            ExchangeRateLimit::MinExchangeRate(0.4),
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, _service) = runtime.block_on_all(run).unwrap();
+        assert_eq!(result.delivered_amount, 50);
+        // The receiver's asset details are learned from the test packet
+        assert_eq!(result.destination_asset.unwrap().asset_code, "XYZ");
+    }
+
+    #[test]
+    fn stops_when_exchange_rate_drops() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        // The first test packet is too large, the second one measures the rate,
+        // and then the rate drops while the payment is being sent
+        let run = send_money(
+            with_exchange_rate(
+                server,
+                |packet_number| if packet_number > 6 { 0.5 } else { 2.0 },
+                10,
+            ),
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            100,
+            ExchangeRateLimit::MaxSlippage(0.1),
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, _service) = runtime.block_on_all(run).unwrap();
+        assert!(result.error.is_some());
+        let total_received = connections.total_received(&destination_account).unwrap();
+        assert!(total_received > 0);
+        assert!(total_received < 200);
+    }
+
+    #[test]
+    fn delivers_fixed_amount() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        let run = send_money_to_deliver(
+            with_exchange_rate(server, |_| 0.5, 30),
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            75,
+            1000,
+            ExchangeRateLimit::None,
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, _service) = runtime.block_on_all(run).unwrap();
+        assert!(result.is_complete());
+        assert_eq!(result.source_amount, 150);
+        assert_eq!(result.delivered_amount, 75);
+        assert_eq!(
+            connections.total_received(&destination_account).unwrap(),
+            75
+        );
+    }
+
+    #[test]
+    fn stops_at_max_source_amount() {
+        let account = TestAccount {
+            id: 0,
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.receiver").unwrap(),
+        };
+        let (server, connections, destination_account, shared_secret) = test_receiver();
+        let run = send_money_to_deliver(
+            with_exchange_rate(server, |_| 0.5, 30),
+            &account,
+            destination_account.clone(),
+            &shared_secret[..],
+            75,
+            100,
+            ExchangeRateLimit::None,
+        );
+        let runtime = Runtime::new().unwrap();
+        let (result, _service) = runtime.block_on_all(run).unwrap();
+        assert_eq!(
+            result.error.unwrap().to_string(),
+            "Error polling: Sent 100 (the maximum source amount) but only delivered 50 of 75"
+        );
+        assert_eq!(result.source_amount, 100);
+        assert_eq!(result.delivered_amount, 50);
+        assert_eq!(
+            connections.total_received(&destination_account).unwrap(),
+            50
+        );
+    }
 }
@@ -1,5 +1,7 @@
+use super::asset::AssetDetails;
 use super::crypto::*;
-use super::packet::*;
+use super::data::{IncomingData, OutgoingData, MAX_DATA_PER_PACKET};
+use super::packet::{ErrorCode as StreamErrorCode, *};
 use base64;
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
@@ -11,10 +13,13 @@ use interledger_packet::{
 };
 use interledger_service::{Account, BoxedIlpFuture, OutgoingRequest, OutgoingService, Username};
 use log::debug;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::marker::PhantomData;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_secret_generator";
 
@@ -109,15 +114,211 @@ pub trait StreamNotificationsStore {
     fn publish_payment_notification(&self, _payment: PaymentNotification);
 }
 
+/// How long the state of a closed connection is kept, so that packets
+/// which arrive after the connection was closed are still rejected
+const CLOSED_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+/// Connections are forgotten after they are idle for this long, even if they have their own receive max
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(3600);
+/// The highest stream ID a sender may use before it closes any streams.
+/// Senders use odd stream IDs, so this allows 10 open streams per connection.
+const DEFAULT_MAX_STREAM_ID: u64 = 20;
+
+#[derive(Debug, Default)]
+struct StreamState {
+    total_received: u64,
+    incoming_data: IncomingData,
+    outgoing_data: OutgoingData,
+}
+
+#[derive(Debug)]
+struct ConnectionState {
+    total_received: u64,
+    /// Set if the receive max was configured for this connection specifically
+    receive_max: Option<u64>,
+    closed: bool,
+    streams: HashMap<u64, StreamState>,
+    /// Streams the sender closed. Their state is removed once their data was read,
+    /// but money sent on them must still be rejected.
+    closed_streams: HashSet<u64>,
+    max_stream_id: u64,
+    /// The asset the sender said it is sending, if it told us
+    source_asset: Option<AssetDetails>,
+    last_activity: Instant,
+}
+
+impl ConnectionState {
+    fn new() -> Self {
+        ConnectionState {
+            total_received: 0,
+            receive_max: None,
+            closed: false,
+            streams: HashMap::new(),
+            closed_streams: HashSet::new(),
+            max_stream_id: DEFAULT_MAX_STREAM_ID,
+            source_asset: None,
+            last_activity: Instant::now(),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        let idle = self.last_activity.elapsed();
+        (self.closed && idle > CLOSED_CONNECTION_TIMEOUT) || idle > IDLE_CONNECTION_TIMEOUT
+    }
+}
+
+struct Connections {
+    default_receive_max: u64,
+    connections: HashMap<Bytes, ConnectionState>,
+}
+
+impl Connections {
+    fn get_or_create(&mut self, destination_account: &Address) -> &mut ConnectionState {
+        let key = destination_account.to_bytes();
+        if !self.connections.contains_key(&key) {
+            // Clean up the old connections whenever a new one is opened
+            self.connections
+                .retain(|_, connection| !connection.is_expired());
+            self.connections.insert(key.clone(), ConnectionState::new());
+        }
+        self.connections.get_mut(&key).unwrap()
+    }
+}
+
+/// The state of the STREAM connections handled by a receiver.
+///
+/// This keeps track of how much money was received on each connection and stream,
+/// so that the receiver can report the totals back to the sender and stop accepting
+/// money once a connection's receive max is reached. Connections are identified by
+/// the `destination_account` generated for them by the `ConnectionGenerator`.
+///
+/// All clones refer to the same state, so this can be used to configure or close
+/// connections from outside of the `StreamReceiverService`.
+#[derive(Clone)]
+pub struct StreamConnections {
+    inner: Arc<Mutex<Connections>>,
+}
+
+impl Default for StreamConnections {
+    fn default() -> Self {
+        StreamConnections {
+            inner: Arc::new(Mutex::new(Connections {
+                default_receive_max: u64::max_value(),
+                connections: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl StreamConnections {
+    /// Set the maximum amount that may be received on connections that do not have
+    /// their own receive max. Defaults to `u64::max_value()`.
+    pub fn set_default_receive_max(&self, receive_max: u64) {
+        self.inner.lock().default_receive_max = receive_max;
+    }
+
+    /// Set the maximum amount that may be received on the given connection, for example
+    /// the amount of the invoice the connection was generated for.
+    pub fn set_receive_max(&self, destination_account: &Address, receive_max: u64) {
+        self.inner
+            .lock()
+            .get_or_create(destination_account)
+            .receive_max = Some(receive_max);
+    }
+
+    /// Total amount received on the given connection, if it is known
+    pub fn total_received(&self, destination_account: &Address) -> Option<u64> {
+        self.inner
+            .lock()
+            .connections
+            .get(&destination_account.to_bytes())
+            .map(|connection| connection.total_received)
+    }
+
+    /// The asset code and scale of the sender on the given connection, if it sent them
+    pub fn source_asset(&self, destination_account: &Address) -> Option<AssetDetails> {
+        self.inner
+            .lock()
+            .connections
+            .get(&destination_account.to_bytes())
+            .and_then(|connection| connection.source_asset.clone())
+    }
+
+    /// Close the given connection. All further packets sent on it will be rejected.
+    pub fn close(&self, destination_account: &Address) {
+        let mut inner = self.inner.lock();
+        let connection = inner.get_or_create(destination_account);
+        connection.closed = true;
+        connection.last_activity = Instant::now();
+    }
+
+    /// Read the data received in order on the given stream so far.
+    ///
+    /// Reading the data makes room in the stream's buffer, so that the sender can send more.
+    pub fn read_data(&self, destination_account: &Address, stream_id: u64) -> Bytes {
+        let mut inner = self.inner.lock();
+        let connection = match inner.connections.get_mut(&destination_account.to_bytes()) {
+            Some(connection) => connection,
+            None => return Bytes::new(),
+        };
+        let data = connection
+            .streams
+            .get_mut(&stream_id)
+            .map(|stream| stream.incoming_data.read())
+            .unwrap_or_default();
+        // Nothing more will arrive on a closed stream, so its state can go once it was read
+        if connection.closed_streams.contains(&stream_id) {
+            connection.streams.remove(&stream_id);
+        }
+        data
+    }
+
+    /// Queue data to be sent to the sender on the given stream.
+    ///
+    /// Because the receiver cannot send packets of its own, the data is included
+    /// in the responses to the next packets the sender sends on the connection.
+    /// It is sent again until the sender's `StreamMaxData` frames show that it was read.
+    ///
+    /// Data written to a stream the sender already closed is dropped.
+    pub fn write_data(&self, destination_account: &Address, stream_id: u64, data: Bytes) {
+        let mut inner = self.inner.lock();
+        let connection = inner.get_or_create(destination_account);
+        if connection.closed_streams.contains(&stream_id) {
+            debug!(
+                "Not writing data to stream {} because it is closed",
+                stream_id
+            );
+            return;
+        }
+        connection
+            .streams
+            .entry(stream_id)
+            .or_default()
+            .outgoing_data
+            .write(data);
+    }
+
+    /// Returns true if the connection was closed by either side
+    pub fn is_closed(&self, destination_account: &Address) -> bool {
+        self.inner
+            .lock()
+            .connections
+            .get(&destination_account.to_bytes())
+            .map(|connection| connection.closed)
+            .unwrap_or(false)
+    }
+}
+
 /// An OutgoingService that fulfills incoming STREAM packets.
 ///
-/// Note this does **not** maintain STREAM state, but instead fulfills
-/// all incoming packets to collect the money.
+/// This keeps track of the money received on each connection and stream (see
+/// `StreamConnections`), reports the totals back to the sender, and rejects
+/// packets that would exceed the connection's receive max or are sent on a closed connection.
 ///
-/// This does not currently support handling data sent via STREAM.
+/// Data sent on each stream is buffered until it is read with `StreamConnections::read_data`.
 #[derive(Clone)]
 pub struct StreamReceiverService<S, O: OutgoingService<A>, A: Account> {
     connection_generator: ConnectionGenerator,
+    connections: StreamConnections,
     next: O,
     account_type: PhantomData<A>,
     store: S,
@@ -133,11 +334,17 @@ where
         let connection_generator = ConnectionGenerator::new(server_secret);
         StreamReceiverService {
             connection_generator,
+            connections: StreamConnections::default(),
             next,
             account_type: PhantomData,
             store,
         }
     }
+
+    /// Returns a handle to the state of the connections handled by this service
+    pub fn connections(&self) -> StreamConnections {
+        self.connections.clone()
+    }
 }
 
 impl<S, O, A> OutgoingService<A> for StreamReceiverService<S, O, A>
@@ -162,24 +369,33 @@ where
 
         let destination = request.prepare.destination();
         let to_address = request.to.ilp_address();
+        let to_asset = AssetDetails {
+            asset_code: request.to.asset_code().to_string(),
+            asset_scale: request.to.asset_scale(),
+        };
         let dest: &[u8] = destination.as_ref();
 
         // The case where the request is bound for this server
         if dest.starts_with(to_address.as_ref()) {
             if let Ok(shared_secret) = self.connection_generator.rederive_secret(&destination) {
                 return Box::new(
-                    result(receive_money(&shared_secret, &to_address, request.prepare)).and_then(
-                        move |fulfill| {
-                            store.publish_payment_notification(PaymentNotification {
-                                to_username,
-                                from_username,
-                                amount,
-                                destination: destination.clone(),
-                                timestamp: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
-                            });
-                            Ok(fulfill)
-                        },
-                    ),
+                    result(receive_money(
+                        &shared_secret,
+                        &to_address,
+                        &to_asset,
+                        request.prepare,
+                        &self.connections,
+                    ))
+                    .and_then(move |fulfill| {
+                        store.publish_payment_notification(PaymentNotification {
+                            to_username,
+                            from_username,
+                            amount,
+                            destination: destination.clone(),
+                            timestamp: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
+                        });
+                        Ok(fulfill)
+                    }),
                 );
             }
         }
@@ -187,11 +403,38 @@ where
     }
 }
 
-// TODO send asset code and scale back to sender also
+/// Split the amount between the streams according to their shares.
+/// Any remainder is given to the last stream.
+fn split_amount(amount: u64, stream_shares: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let total_shares: u128 = stream_shares
+        .iter()
+        .map(|(_, shares)| u128::from(*shares))
+        .sum();
+    if total_shares == 0 {
+        return stream_shares.iter().map(|(id, _)| (*id, 0)).collect();
+    }
+    let mut remaining = amount;
+    stream_shares
+        .iter()
+        .enumerate()
+        .map(|(index, (stream_id, shares))| {
+            let stream_amount = if index == stream_shares.len() - 1 {
+                remaining
+            } else {
+                (u128::from(amount) * u128::from(*shares) / total_shares) as u64
+            };
+            remaining -= stream_amount;
+            (*stream_id, stream_amount)
+        })
+        .collect()
+}
+
 fn receive_money(
     shared_secret: &[u8; 32],
     ilp_address: &Address,
+    asset_details: &AssetDetails,
     prepare: Prepare,
+    connections: &StreamConnections,
 ) -> Result<Fulfill, Reject> {
     // Generate fulfillment
     let fulfillment = generate_fulfillment(&shared_secret[..], prepare.data());
@@ -201,6 +444,7 @@ fn receive_money(
     // Parse STREAM packet
     // TODO avoid copying data
     let prepare_amount = prepare.amount();
+    let destination = prepare.destination();
     let stream_packet =
         StreamPacket::from_encrypted(shared_secret, prepare.into_data()).map_err(|_| {
             debug!("Unable to parse data, rejecting Prepare packet");
@@ -213,24 +457,222 @@ fn receive_money(
             .build()
         })?;
 
-    let mut response_frames: Vec<Frame> = Vec::new();
+    let mut connections = connections.inner.lock();
+    let default_receive_max = connections.default_receive_max;
+    let connection = connections.get_or_create(&destination);
+    connection.last_activity = Instant::now();
 
     // Handle STREAM frames
-    // TODO reject if they send data?
+    let mut stream_shares: Vec<(u64, u64)> = Vec::new();
+    let mut data_streams: Vec<u64> = Vec::new();
+    let mut exceeds_max_stream_id = false;
+    let mut exceeds_max_offset = false;
+    let mut send_max_stream_id = false;
+    let mut send_asset_details = false;
     for frame in stream_packet.frames() {
-        // Tell the sender the stream can handle lots of money
-        if let Frame::StreamMoney(frame) = frame {
+        match frame {
+            Frame::ConnectionClose(frame) => {
+                debug!(
+                    "Sender closed the connection with code: {:?} and message: {}",
+                    frame.code, frame.message
+                );
+                connection.closed = true;
+            }
+            Frame::StreamClose(StreamCloseFrame { stream_id, .. })
+            | Frame::StreamMoney(StreamMoneyFrame { stream_id, .. })
+            | Frame::StreamData(StreamDataFrame { stream_id, .. })
+            | Frame::StreamMaxData(StreamMaxDataFrame { stream_id, .. })
+            | Frame::StreamDataBlocked(StreamDataBlockedFrame { stream_id, .. })
+                if stream_id > connection.max_stream_id =>
+            {
+                exceeds_max_stream_id = true;
+            }
+            // Closes for streams that were never opened or are already closed are ignored,
+            // so that they cannot be used to raise the max stream ID
+            Frame::StreamClose(frame)
+                if connection.streams.contains_key(&frame.stream_id)
+                    && !connection.closed_streams.contains(&frame.stream_id) =>
+            {
+                debug!(
+                    "Sender closed stream {} with code: {:?} and message: {}",
+                    frame.stream_id, frame.code, frame.message
+                );
+                connection.closed_streams.insert(frame.stream_id);
+                // Keep the data received on the stream until it is read
+                let stream = connection.streams.get_mut(&frame.stream_id).unwrap();
+                if stream.incoming_data.is_empty() {
+                    connection.streams.remove(&frame.stream_id);
+                } else {
+                    stream.outgoing_data = OutgoingData::default();
+                }
+                // Let the sender open another stream in place of the closed one
+                connection.max_stream_id += 2;
+                send_max_stream_id = true;
+            }
+            Frame::StreamMoney(frame) => {
+                stream_shares.push((frame.stream_id, frame.shares));
+            }
+            Frame::StreamData(frame) if !connection.closed_streams.contains(&frame.stream_id) => {
+                let stream = connection.streams.entry(frame.stream_id).or_default();
+                if !stream.incoming_data.push(frame.offset, frame.data) {
+                    debug!(
+                        "Ignoring data on stream {} because it exceeds the max offset: {}",
+                        frame.stream_id,
+                        stream.incoming_data.max_offset()
+                    );
+                    exceeds_max_offset = true;
+                }
+                data_streams.push(frame.stream_id);
+            }
+            Frame::StreamMaxData(frame)
+                if !connection.closed_streams.contains(&frame.stream_id) =>
+            {
+                connection
+                    .streams
+                    .entry(frame.stream_id)
+                    .or_default()
+                    .outgoing_data
+                    .acknowledge_max_offset(frame.max_offset);
+            }
+            Frame::StreamDataBlocked(frame)
+                if !connection.closed_streams.contains(&frame.stream_id) =>
+            {
+                connection.streams.entry(frame.stream_id).or_default();
+                data_streams.push(frame.stream_id);
+            }
+            Frame::ConnectionStreamIdBlocked(_) => {
+                send_max_stream_id = true;
+            }
+            // The sender includes these frames until the connection is established,
+            // so that is when we tell it about our asset too
+            Frame::ConnectionNewAddress(_) => {
+                send_asset_details = true;
+            }
+            Frame::ConnectionAssetDetails(frame) => {
+                connection.source_asset = Some(AssetDetails {
+                    asset_code: frame.source_asset_code.to_string(),
+                    asset_scale: frame.source_asset_scale,
+                });
+                send_asset_details = true;
+            }
+            _ => {}
+        }
+    }
+    data_streams.sort();
+    data_streams.dedup();
+    if exceeds_max_stream_id {
+        // Don't keep any state for streams the sender was not allowed to open
+        stream_shares.clear();
+        data_streams.retain(|stream_id| connection.streams.contains_key(stream_id));
+        send_max_stream_id = true;
+    }
+
+    let stream_amounts = split_amount(prepare_amount, &stream_shares);
+    let receive_max = connection.receive_max.unwrap_or(default_receive_max);
+    let exceeds_receive_max =
+        prepare_amount > receive_max.saturating_sub(connection.total_received);
+    let sent_to_closed_stream = stream_amounts
+        .iter()
+        .any(|(stream_id, amount)| *amount > 0 && connection.closed_streams.contains(stream_id));
+    let is_accepted = is_fulfillable
+        && prepare_amount >= stream_packet.prepare_amount()
+        && !connection.closed
+        && !exceeds_max_stream_id
+        && !exceeds_receive_max
+        && !exceeds_max_offset
+        && !sent_to_closed_stream;
+
+    if is_accepted {
+        connection.total_received += prepare_amount;
+        for (stream_id, amount) in stream_amounts.iter() {
+            if !connection.closed_streams.contains(stream_id) {
+                connection
+                    .streams
+                    .entry(*stream_id)
+                    .or_default()
+                    .total_received += amount;
+            }
+        }
+    }
+
+    // Send back any data queued for the sender
+    let mut outgoing_chunks: Vec<(u64, u64, Bytes)> = Vec::new();
+    if !connection.closed {
+        let mut stream_ids: Vec<u64> = connection.streams.keys().cloned().collect();
+        stream_ids.sort();
+        let mut data_left = MAX_DATA_PER_PACKET;
+        for stream_id in stream_ids {
+            if connection.closed_streams.contains(&stream_id) {
+                continue;
+            }
+            let stream = connection.streams.get_mut(&stream_id).unwrap();
+            // The data is kept until the sender acknowledges it, because this response
+            // might not reach the sender
+            for (offset, data) in stream.outgoing_data.unacknowledged_chunks(data_left) {
+                data_left -= data.len();
+                outgoing_chunks.push((stream_id, offset, data));
+            }
+        }
+    }
+
+    // Tell the sender how much each stream has received and how much more it can receive
+    let connection_remaining = receive_max.saturating_sub(connection.total_received);
+    let mut response_frames: Vec<Frame> = Vec::new();
+    if connection.closed {
+        response_frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+            code: StreamErrorCode::NoError,
+            message: "",
+        }));
+    }
+    if send_asset_details {
+        response_frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+            source_asset_code: &asset_details.asset_code,
+            source_asset_scale: asset_details.asset_scale,
+        }));
+    }
+    if send_max_stream_id {
+        response_frames.push(Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame {
+            max_stream_id: connection.max_stream_id,
+        }));
+    }
+    for (stream_id, _) in stream_amounts.iter() {
+        if connection.closed_streams.contains(stream_id) {
+            response_frames.push(Frame::StreamClose(StreamCloseFrame {
+                stream_id: *stream_id,
+                code: StreamErrorCode::NoError,
+                message: "",
+            }));
+        } else {
+            let total_received = connection
+                .streams
+                .get(stream_id)
+                .map(|stream| stream.total_received)
+                .unwrap_or(0);
             response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
-                stream_id: frame.stream_id,
-                // TODO will returning zero here cause problems?
-                total_received: 0,
-                receive_max: u64::max_value(),
+                stream_id: *stream_id,
+                total_received,
+                receive_max: total_received.saturating_add(connection_remaining),
             }));
         }
     }
+    for stream_id in data_streams.iter() {
+        if let Some(stream) = connection.streams.get(stream_id) {
+            response_frames.push(Frame::StreamMaxData(StreamMaxDataFrame {
+                stream_id: *stream_id,
+                max_offset: stream.incoming_data.max_offset(),
+            }));
+        }
+    }
+    for (stream_id, offset, data) in outgoing_chunks.iter() {
+        response_frames.push(Frame::StreamData(StreamDataFrame {
+            stream_id: *stream_id,
+            offset: *offset,
+            data: &data[..],
+        }));
+    }
 
     // Return Fulfill or Reject Packet
-    if is_fulfillable && prepare_amount >= stream_packet.prepare_amount() {
+    if is_accepted {
         let response_packet = StreamPacketBuilder {
             sequence: stream_packet.sequence(),
             ilp_packet_type: IlpPacketType::Fulfill,
@@ -267,6 +709,22 @@ fn receive_money(
                 prepare_amount,
                 stream_packet.prepare_amount()
             );
+        } else if connection.closed {
+            debug!("Rejecting packet because the connection is closed");
+        } else if exceeds_max_stream_id {
+            debug!(
+                "Rejecting packet because it uses a stream ID above the max stream ID: {}",
+                connection.max_stream_id
+            );
+        } else if exceeds_receive_max {
+            debug!(
+                "Rejecting packet of {} because the connection has already received {} (receive max: {})",
+                prepare_amount, connection.total_received, receive_max
+            );
+        } else if exceeds_max_offset {
+            debug!("Rejecting packet because its data exceeds the max offset of the stream");
+        } else if sent_to_closed_stream {
+            debug!("Rejecting packet because it was sent on a closed stream");
         }
         debug!(
             "Rejecting Prepare and including encrypted stream packet {:?}",
@@ -325,6 +783,14 @@ mod connection_generator {
     }
 }
 
+#[cfg(test)]
+fn asset_details() -> AssetDetails {
+    AssetDetails {
+        asset_code: "XYZ".to_string(),
+        asset_scale: 9,
+    }
+}
+
 #[cfg(test)]
 fn test_stream_packet() -> StreamPacket {
     StreamPacketBuilder {
@@ -370,7 +836,13 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &ilp_address, prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &StreamConnections::default(),
+        );
         assert!(result.is_ok());
     }
 
@@ -398,7 +870,13 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &ilp_address, prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &StreamConnections::default(),
+        );
         assert!(result.is_ok());
     }
 
@@ -427,7 +905,13 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &ilp_address, prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &StreamConnections::default(),
+        );
         assert!(result.is_err());
     }
 
@@ -466,11 +950,434 @@ mod receiving_money {
         let shared_secret = connection_generator
             .rederive_secret(&prepare.destination())
             .unwrap();
-        let result = receive_money(&shared_secret, &ilp_address, prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &StreamConnections::default(),
+        );
         assert!(result.is_err());
     }
 }
 
+#[cfg(test)]
+mod connection_state {
+    use super::*;
+    use bytes::BytesMut;
+    use interledger_packet::PrepareBuilder;
+    use std::str::FromStr;
+    use std::time::UNIX_EPOCH;
+
+    fn build_prepare(
+        destination_account: &Address,
+        shared_secret: &[u8; 32],
+        amount: u64,
+        frames: &[Frame],
+    ) -> Prepare {
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames,
+        }
+        .build()
+        .into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        PrepareBuilder {
+            destination: destination_account.clone(),
+            amount,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build()
+    }
+
+    fn money_frames() -> Vec<Frame<'static>> {
+        vec![Frame::StreamMoney(StreamMoneyFrame {
+            stream_id: 1,
+            shares: 1,
+        })]
+    }
+
+    fn max_money_frame(shared_secret: &[u8; 32], data: &[u8]) -> StreamMaxMoneyFrame {
+        let packet =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(data)).unwrap();
+        let frame = packet
+            .frames()
+            .filter_map(|frame| match frame {
+                Frame::StreamMaxMoney(frame) => Some(frame),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        frame
+    }
+
+    fn setup() -> (Address, Address, [u8; 32], StreamConnections) {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        (
+            ilp_address,
+            destination_account,
+            shared_secret,
+            StreamConnections::default(),
+        )
+    }
+
+    #[test]
+    fn reports_total_received() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        for _ in 0..2 {
+            let prepare = build_prepare(&destination_account, &shared_secret, 100, &money_frames());
+            receive_money(
+                &shared_secret,
+                &ilp_address,
+                &asset_details(),
+                prepare,
+                &connections,
+            )
+            .unwrap();
+        }
+        let prepare = build_prepare(&destination_account, &shared_secret, 100, &money_frames());
+        let fulfill = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections,
+        )
+        .unwrap();
+        let frame = max_money_frame(&shared_secret, fulfill.data());
+        assert_eq!(frame.stream_id, 1);
+        assert_eq!(frame.total_received, 300);
+        assert_eq!(frame.receive_max, u64::max_value());
+        assert_eq!(connections.total_received(&destination_account), Some(300));
+    }
+
+    #[test]
+    fn enforces_receive_max() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        connections.set_receive_max(&destination_account, 150);
+
+        let prepare = build_prepare(&destination_account, &shared_secret, 100, &money_frames());
+        let fulfill = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections,
+        )
+        .unwrap();
+        let frame = max_money_frame(&shared_secret, fulfill.data());
+        assert_eq!(frame.total_received, 100);
+        assert_eq!(frame.receive_max, 150);
+
+        let prepare = build_prepare(&destination_account, &shared_secret, 100, &money_frames());
+        let reject = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections,
+        )
+        .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        let frame = max_money_frame(&shared_secret, reject.data());
+        assert_eq!(frame.total_received, 100);
+        assert_eq!(frame.receive_max, 150);
+        assert_eq!(connections.total_received(&destination_account), Some(100));
+
+        // The remaining amount can still be received
+        let prepare = build_prepare(&destination_account, &shared_secret, 50, &money_frames());
+        assert!(receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections
+        )
+        .is_ok());
+        assert_eq!(connections.total_received(&destination_account), Some(150));
+    }
+
+    #[test]
+    fn default_receive_max_applies_to_new_connections() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        connections.set_default_receive_max(50);
+        let prepare = build_prepare(&destination_account, &shared_secret, 100, &money_frames());
+        assert!(receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections
+        )
+        .is_err());
+        assert_eq!(connections.total_received(&destination_account), Some(0));
+    }
+
+    #[test]
+    fn rejects_packets_after_connection_close() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        let prepare = build_prepare(
+            &destination_account,
+            &shared_secret,
+            0,
+            &[Frame::ConnectionClose(ConnectionCloseFrame {
+                code: StreamErrorCode::NoError,
+                message: "",
+            })],
+        );
+        assert!(receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections
+        )
+        .is_err());
+        assert!(connections.is_closed(&destination_account));
+
+        let prepare = build_prepare(&destination_account, &shared_secret, 100, &money_frames());
+        let reject = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections,
+        )
+        .unwrap_err();
+        let packet =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(reject.data()))
+                .unwrap();
+        assert!(packet.frames().any(|frame| match frame {
+            Frame::ConnectionClose(_) => true,
+            _ => false,
+        }));
+        assert_eq!(connections.total_received(&destination_account), Some(0));
+    }
+
+    fn close_frames(stream_id: u64) -> Vec<Frame<'static>> {
+        vec![Frame::StreamClose(StreamCloseFrame {
+            stream_id,
+            code: StreamErrorCode::NoError,
+            message: "",
+        })]
+    }
+
+    fn send_packet(
+        ilp_address: &Address,
+        destination_account: &Address,
+        shared_secret: &[u8; 32],
+        connections: &StreamConnections,
+        amount: u64,
+        frames: &[Frame],
+    ) -> Result<Fulfill, Reject> {
+        let prepare = build_prepare(destination_account, shared_secret, amount, frames);
+        receive_money(
+            shared_secret,
+            ilp_address,
+            &asset_details(),
+            prepare,
+            connections,
+        )
+    }
+
+    #[test]
+    fn rejects_money_sent_on_closed_stream() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        let send = |amount, frames: &[Frame]| {
+            send_packet(
+                &ilp_address,
+                &destination_account,
+                &shared_secret,
+                &connections,
+                amount,
+                frames,
+            )
+        };
+        assert!(send(100, &money_frames()).is_ok());
+        assert!(send(0, &close_frames(1)).is_ok());
+
+        assert!(send(100, &money_frames()).is_err());
+        assert!(!connections.is_closed(&destination_account));
+        assert_eq!(connections.total_received(&destination_account), Some(100));
+    }
+
+    #[test]
+    fn removes_state_of_closed_streams() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        let send = |amount, frames: &[Frame]| {
+            send_packet(
+                &ilp_address,
+                &destination_account,
+                &shared_secret,
+                &connections,
+                amount,
+                frames,
+            )
+        };
+        assert!(send(100, &money_frames()).is_ok());
+        let data_frames = [Frame::StreamData(StreamDataFrame {
+            stream_id: 3,
+            offset: 0,
+            data: b"hello",
+        })];
+        assert!(send(0, &data_frames).is_ok());
+        assert!(send(0, &close_frames(1)).is_ok());
+        assert!(send(0, &close_frames(3)).is_ok());
+
+        let key = destination_account.to_bytes();
+        {
+            let inner = connections.inner.lock();
+            let connection = &inner.connections[&key];
+            assert!(!connection.streams.contains_key(&1));
+            // The data that was not read yet is kept
+            assert!(connection.streams.contains_key(&3));
+            assert_eq!(connection.max_stream_id, DEFAULT_MAX_STREAM_ID + 4);
+        }
+        assert_eq!(
+            connections.read_data(&destination_account, 3),
+            Bytes::from("hello")
+        );
+        assert!(connections.inner.lock().connections[&key]
+            .streams
+            .is_empty());
+    }
+
+    #[test]
+    fn ignores_close_for_unknown_stream() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        let send = |amount, frames: &[Frame]| {
+            send_packet(
+                &ilp_address,
+                &destination_account,
+                &shared_secret,
+                &connections,
+                amount,
+                frames,
+            )
+        };
+        for stream_id in [1, 3, 5].iter() {
+            assert!(send(0, &close_frames(*stream_id)).is_ok());
+        }
+        {
+            let inner = connections.inner.lock();
+            let connection = &inner.connections[&destination_account.to_bytes()];
+            assert!(connection.streams.is_empty());
+            assert!(connection.closed_streams.is_empty());
+            assert_eq!(connection.max_stream_id, DEFAULT_MAX_STREAM_ID);
+        }
+
+        // The stream can still be opened afterwards
+        assert!(send(100, &money_frames()).is_ok());
+    }
+
+    #[test]
+    fn rejects_money_above_max_stream_id() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        let reject = send_packet(
+            &ilp_address,
+            &destination_account,
+            &shared_secret,
+            &connections,
+            100,
+            &[Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: DEFAULT_MAX_STREAM_ID + 1,
+                shares: 1,
+            })],
+        )
+        .unwrap_err();
+        let packet =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(reject.data()))
+                .unwrap();
+        assert!(packet.frames().any(|frame| match frame {
+            Frame::ConnectionMaxStreamId(frame) => frame.max_stream_id == DEFAULT_MAX_STREAM_ID,
+            _ => false,
+        }));
+        assert!(
+            connections.inner.lock().connections[&destination_account.to_bytes()]
+                .streams
+                .is_empty()
+        );
+        assert_eq!(connections.total_received(&destination_account), Some(0));
+    }
+
+    #[test]
+    fn splits_amount_between_streams() {
+        assert_eq!(split_amount(100, &[(1, 1), (3, 2)]), vec![(1, 33), (3, 67)]);
+        assert_eq!(split_amount(100, &[(1, 0)]), vec![(1, 0)]);
+        assert_eq!(
+            split_amount(u64::max_value(), &[(1, u64::max_value()), (2, 1)]),
+            vec![(1, u64::max_value() - 1), (2, 1)]
+        );
+    }
+    #[test]
+    fn exchanges_asset_details() {
+        let (ilp_address, destination_account, shared_secret, connections) = setup();
+        let frames = [
+            Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                source_asset_code: "ABC",
+                source_asset_scale: 2,
+            }),
+            Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: 1,
+                shares: 1,
+            }),
+        ];
+        let prepare = build_prepare(&destination_account, &shared_secret, 100, &frames);
+        let fulfill = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections,
+        )
+        .unwrap();
+        let packet =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(fulfill.data()))
+                .unwrap();
+        assert!(packet.frames().any(|frame| frame
+            == Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                source_asset_code: "XYZ",
+                source_asset_scale: 9,
+            })));
+        assert_eq!(
+            connections.source_asset(&destination_account),
+            Some(AssetDetails {
+                asset_code: "ABC".to_string(),
+                asset_scale: 2,
+            })
+        );
+
+        // Once the connection is established the details are not sent again
+        let prepare = build_prepare(&destination_account, &shared_secret, 100, &money_frames());
+        let fulfill = receive_money(
+            &shared_secret,
+            &ilp_address,
+            &asset_details(),
+            prepare,
+            &connections,
+        )
+        .unwrap();
+        let packet =
+            StreamPacket::from_encrypted(&shared_secret[..], BytesMut::from(fulfill.data()))
+                .unwrap();
+        assert!(packet
+            .frames()
+            .filter_map(|frame| match frame {
+                Frame::ConnectionAssetDetails(frame) => Some(frame),
+                _ => None,
+            })
+            .next()
+            .is_none());
+    }
+}
+
 #[cfg(test)]
 mod stream_receiver_service {
     use super::*;
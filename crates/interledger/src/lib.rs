@@ -114,12 +114,24 @@ pub mod spsp {
     pub use interledger_spsp::*;
 }
 
+/// Store implementation that keeps all data in memory
+#[cfg(feature = "store-memory")]
+pub mod store_memory {
+    pub use interledger_store_memory::*;
+}
+
 /// Store implementation backed by Redis
 #[cfg(feature = "store-redis")]
 pub mod store_redis {
     pub use interledger_store_redis::*;
 }
 
+/// Store implementation backed by SQLite or PostgreSQL
+#[cfg(feature = "store-sql")]
+pub mod store_sql {
+    pub use interledger_store_sql::*;
+}
+
 /// STREAM Protocol sender and receiver
 #[cfg(feature = "stream")]
 pub mod stream {